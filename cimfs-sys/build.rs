@@ -4,6 +4,11 @@ use std::env;
 use std::path::PathBuf;
 
 fn main() {
+    // `CimFs.h` and `cimfs.lib` only exist in the Windows SDK, on other platforms this crate is empty
+    if env::var("CARGO_CFG_TARGET_OS").as_deref() != Ok("windows") {
+        return;
+    }
+
     // `cimfs.lib` should be included with windows
     println!("cargo:rustc-link-lib=cimfs");
 
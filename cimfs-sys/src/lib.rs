@@ -1,3 +1,6 @@
+//! Bindings are only generated when targeting windows, since `cimfs.lib` and `CimFs.h` are provided by the Windows SDK.
+//!
+#![cfg(windows)]
 #![allow(non_upper_case_globals)]
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
@@ -0,0 +1,237 @@
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::path::Path;
+
+use windows::core::GUID;
+use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_DIRECTORY;
+use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_REPARSE_POINT;
+
+#[cfg(windows)]
+mod ffi;
+mod recording;
+#[cfg(not(windows))]
+mod unsupported;
+
+#[cfg(windows)]
+pub use ffi::CimFsBackend;
+pub use recording::RecordedEntry;
+pub use recording::RecordedImage;
+pub use recording::RecordingBackend;
+#[cfg(not(windows))]
+pub use unsupported::UnsupportedBackend;
+
+/// Backend used by `Image` when one is not specified,
+///
+#[cfg(windows)]
+pub type DefaultBackend = CimFsBackend;
+
+/// Backend used by `Image` when one is not specified,
+///
+/// CimFS is only available on Windows, so every call returns an error on this platform.
+///
+#[cfg(not(windows))]
+pub type DefaultBackend = UnsupportedBackend;
+
+/// Trait covering the CimFS api surface that `Image` is built on top of,
+///
+/// Each function maps to a function exported by `cimfs.dll` (see `cimfs::raw`), which allows the build logic
+/// in `Image` to run against an implementation other than the os.
+///
+pub trait CimBackend {
+    /// Handle to an image opened w/ `create_image`,
+    ///
+    type ImageHandle;
+    /// Handle to a stream opened w/ `create_file` or `create_alternate_stream`,
+    ///
+    type StreamHandle;
+
+    /// Creates a new image in root, optionally based on an existing image in the same root, (CimCreateImage)
+    ///
+    fn create_image(
+        &mut self,
+        root: &Path,
+        existing: Option<&str>,
+        name: &str,
+    ) -> Result<Self::ImageHandle>;
+
+    /// Closes an image handle, discarding any changes that were not committed, (CimCloseImage)
+    ///
+    fn close_image(&mut self, image: Self::ImageHandle);
+
+    /// Commits all changes made to the image, (CimCommitImage)
+    ///
+    fn commit_image(&mut self, image: &mut Self::ImageHandle) -> Result<()>;
+
+    /// Creates a file or directory at the relative path in the image and returns a stream for its data, (CimCreateFile)
+    ///
+    fn create_file(
+        &mut self,
+        image: &mut Self::ImageHandle,
+        path: &Path,
+        metadata: &FileMetadata,
+    ) -> Result<Self::StreamHandle>;
+
+    /// Creates an alternate data stream w/ size, path should be formatted as `file:stream`, (CimCreateAlternateStream)
+    ///
+    fn create_alternate_stream(
+        &mut self,
+        image: &mut Self::ImageHandle,
+        path: &Path,
+        size: u64,
+    ) -> Result<Self::StreamHandle>;
+
+    /// Writes data to a stream, (CimWriteStream)
+    ///
+    fn write_stream(&mut self, stream: &mut Self::StreamHandle, buf: &[u8]) -> Result<()>;
+
+    /// Closes a stream, (CimCloseStream)
+    ///
+    fn close_stream(&mut self, stream: Self::StreamHandle);
+
+    /// Creates a hard link at path to an existing file in the image, (CimCreateHardLink)
+    ///
+    fn create_hard_link(
+        &mut self,
+        image: &mut Self::ImageHandle,
+        path: &Path,
+        existing: &Path,
+    ) -> Result<()>;
+
+    /// Deletes a path from the image, (CimDeletePath)
+    ///
+    fn delete_path(&mut self, image: &mut Self::ImageHandle, path: &Path) -> Result<()>;
+
+    /// Mounts a committed image as a volume w/ the volume id, (CimMountImage)
+    ///
+    fn mount_image(&mut self, root: &Path, name: &str, volume: &GUID) -> Result<()>;
+
+    /// Dismounts a mounted volume, (CimDismountImage)
+    ///
+    fn dismount_image(&mut self, volume: &GUID) -> Result<()>;
+
+    /// Sets a mountpoint for a mounted volume, (SetVolumeMountPointW)
+    ///
+    fn set_volume_mount_point(&mut self, volume: &GUID, mountpoint: &Path) -> Result<()>;
+
+    /// Returns true if images are written to files in the root folder,
+    ///
+    /// If true, `Image` creates images in a staging folder, publishes them in the root folder once committed, and removes the staging folder
+    /// of images that are not committed. The default is true.
+    ///
+    fn writes_files(&self) -> bool {
+        true
+    }
+}
+
+/// Owned counterpart to `CIMFS_FILE_METADATA`,
+///
+/// Timestamps are in the windows file time format, i.e. 100ns intervals since Jan 1, 1601 (UTC).
+///
+/// Empty buffers are passed to CimFS as null.
+///
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub struct FileMetadata {
+    /// File attributes, ex. FILE_ATTRIBUTE_DIRECTORY,
+    ///
+    pub attributes: u32,
+    /// Size of the default data stream,
+    ///
+    pub file_size: u64,
+    /// Creation time,
+    ///
+    pub creation_time: i64,
+    /// Last write time,
+    ///
+    pub last_write_time: i64,
+    /// Change time,
+    ///
+    pub change_time: i64,
+    /// Last access time,
+    ///
+    pub last_access_time: i64,
+    /// Self-relative security descriptor,
+    ///
+    pub security_descriptor: Vec<u8>,
+    /// Reparse data buffer,
+    ///
+    pub reparse_data: Vec<u8>,
+    /// Extended attributes buffer,
+    ///
+    pub ea_buffer: Vec<u8>,
+}
+
+impl FileMetadata {
+    /// Returns true if the directory attribute is set,
+    ///
+    pub fn is_directory(&self) -> bool {
+        self.attributes & FILE_ATTRIBUTE_DIRECTORY.0 != 0
+    }
+
+    /// Returns true if the reparse point attribute is set,
+    ///
+    pub fn is_reparse_point(&self) -> bool {
+        self.attributes & FILE_ATTRIBUTE_REPARSE_POINT.0 != 0
+    }
+}
+
+/// Parses a GUID in the format `04522dcd-f383-4f1c-aea6-af8f93e020d5`,
+///
+pub fn parse_guid(guid: &str) -> Result<GUID> {
+    let valid = guid.len() == 36
+        && guid.char_indices().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        });
+
+    if valid {
+        Ok(GUID::from(guid))
+    } else {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Could not parse guid {guid}"),
+        ))
+    }
+}
+
+/// Generates a new guid,
+///
+#[cfg(windows)]
+pub(crate) fn new_guid() -> Result<GUID> {
+    use windows::Win32::System::Rpc::UuidCreate;
+
+    unsafe {
+        let mut guid = GUID::zeroed();
+
+        let status = UuidCreate(std::ptr::addr_of_mut!(guid));
+        if status.0 != 0 {
+            return Err(Error::other("Could not generate a new uuid"));
+        }
+
+        Ok(guid)
+    }
+}
+
+/// Generates a new random (v4) guid,
+///
+#[cfg(not(windows))]
+pub(crate) fn new_guid() -> Result<GUID> {
+    use std::collections::hash_map::RandomState;
+    use std::hash::BuildHasher;
+    use std::hash::Hasher;
+
+    let random = || {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+        );
+        hasher.finish() as u128
+    };
+
+    let guid = (random() << 64) | random();
+    let guid = (guid & !(0xF << 76) & !(0x3 << 62)) | (0x4 << 76) | (0x2 << 62);
+    Ok(GUID::from_u128(guid))
+}
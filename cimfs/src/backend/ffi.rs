@@ -0,0 +1,483 @@
+use std::ffi::c_void;
+use std::ffi::OsString;
+use std::io::Error;
+use std::io::Result;
+use std::path::Path;
+
+use cimfs_sys::CIM_MOUNT_IMAGE_FLAGS_CIM_MOUNT_IMAGE_NONE;
+use windows::core::GUID;
+use windows::core::HSTRING;
+use windows::core::PCWSTR;
+use windows::Win32::Storage::FileSystem::SetVolumeMountPointW;
+
+use crate::error::CimError;
+use crate::raw::_GUID;
+use crate::raw::CIMFS_FILE_METADATA;
+use crate::raw::CIMFS_IMAGE_HANDLE;
+use crate::raw::CIMFS_STREAM_HANDLE;
+
+use super::CimBackend;
+use super::FileMetadata;
+
+use tracing::*;
+
+/// Backend that calls the functions exported by `cimfs.dll`,
+///
+#[derive(Debug, Default)]
+pub struct CimFsBackend;
+
+impl CimBackend for CimFsBackend {
+    type ImageHandle = CimImageHandleWrapper;
+    type StreamHandle = CIMFS_STREAM_HANDLE;
+
+    fn create_image(
+        &mut self,
+        root: &Path,
+        existing: Option<&str>,
+        name: &str,
+    ) -> Result<Self::ImageHandle> {
+        unsafe {
+            use crate::raw::CimCreateImage;
+
+            // Prepare parameters
+            let root = HSTRING::from(root.as_os_str());
+            let file_name = HSTRING::from(name);
+            let existing = existing.map(HSTRING::from);
+            let existing_name = if let Some(existing) = existing.as_ref() {
+                existing.as_ptr()
+            } else {
+                std::ptr::null()
+            };
+            let mut handle = std::ptr::null_mut();
+
+            check(
+                "CimCreateImage",
+                CimCreateImage(
+                    root.as_ptr(),
+                    existing_name,
+                    file_name.as_ptr(),
+                    std::ptr::addr_of_mut!(handle),
+                ),
+                None,
+            )?;
+
+            trace!("Got image handle -- {:?}", handle);
+            Ok(CimImageHandleWrapper { handle })
+        }
+    }
+
+    fn close_image(&mut self, image: Self::ImageHandle) {
+        // Handle is closed when the wrapper is dropped
+        drop(image);
+    }
+
+    fn commit_image(&mut self, image: &mut Self::ImageHandle) -> Result<()> {
+        unsafe {
+            use crate::raw::CimCommitImage;
+
+            check("CimCommitImage", CimCommitImage(image.handle), None)
+        }
+    }
+
+    fn create_file(
+        &mut self,
+        image: &mut Self::ImageHandle,
+        path: &Path,
+        metadata: &FileMetadata,
+    ) -> Result<Self::StreamHandle> {
+        unsafe {
+            use crate::raw::CimCreateFile;
+
+            let relative_path = path;
+            let path = HSTRING::from(path.as_os_str());
+            // The buffers referenced by `metadata` are borrowed for the duration of this call
+            let metadata = to_raw_metadata(metadata);
+            let mut stream_handle = std::ptr::null_mut();
+
+            check(
+                "CimCreateFile",
+                CimCreateFile(
+                    image.handle,
+                    path.as_ptr(),
+                    std::ptr::addr_of!(metadata),
+                    std::ptr::addr_of_mut!(stream_handle),
+                ),
+                Some(relative_path),
+            )?;
+
+            trace!(
+                "Created file {:?} -- stream_handle_is_null -- {}",
+                path,
+                stream_handle.is_null()
+            );
+            Ok(stream_handle)
+        }
+    }
+
+    fn create_alternate_stream(
+        &mut self,
+        image: &mut Self::ImageHandle,
+        path: &Path,
+        size: u64,
+    ) -> Result<Self::StreamHandle> {
+        unsafe {
+            use crate::raw::CimCreateAlternateStream;
+
+            let relative_path = path;
+            let path = HSTRING::from(path.as_os_str());
+            let mut stream_handle = std::ptr::null_mut();
+
+            check(
+                "CimCreateAlternateStream",
+                CimCreateAlternateStream(
+                    image.handle,
+                    path.as_ptr(),
+                    size,
+                    std::ptr::addr_of_mut!(stream_handle),
+                ),
+                Some(relative_path),
+            )?;
+
+            Ok(stream_handle)
+        }
+    }
+
+    fn write_stream(&mut self, stream: &mut Self::StreamHandle, buf: &[u8]) -> Result<()> {
+        unsafe {
+            use crate::raw::CimWriteStream;
+
+            check(
+                "CimWriteStream",
+                CimWriteStream(*stream, buf.as_ptr() as *const c_void, buf.len() as u32),
+                None,
+            )
+        }
+    }
+
+    fn close_stream(&mut self, stream: Self::StreamHandle) {
+        unsafe {
+            use crate::raw::CimCloseStream;
+
+            CimCloseStream(stream);
+        }
+    }
+
+    fn create_hard_link(
+        &mut self,
+        image: &mut Self::ImageHandle,
+        path: &Path,
+        existing: &Path,
+    ) -> Result<()> {
+        unsafe {
+            use crate::raw::CimCreateHardLink;
+
+            let relative_path = path;
+            let path = HSTRING::from(path.as_os_str());
+            let existing = HSTRING::from(existing.as_os_str());
+
+            check(
+                "CimCreateHardLink",
+                CimCreateHardLink(image.handle, path.as_ptr(), existing.as_ptr()),
+                Some(relative_path),
+            )
+        }
+    }
+
+    fn delete_path(&mut self, image: &mut Self::ImageHandle, path: &Path) -> Result<()> {
+        unsafe {
+            use crate::raw::CimDeletePath;
+
+            let relative_path = path;
+            let path = HSTRING::from(path.as_os_str());
+
+            check(
+                "CimDeletePath",
+                CimDeletePath(image.handle, path.as_ptr()),
+                Some(relative_path),
+            )
+        }
+    }
+
+    fn mount_image(&mut self, root: &Path, name: &str, volume: &GUID) -> Result<()> {
+        unsafe {
+            use crate::raw::CimMountImage;
+
+            trace!("Mounting image");
+            check(
+                "CimMountImage",
+                CimMountImage(
+                    HSTRING::from(root.as_os_str()).as_ptr(),
+                    HSTRING::from(name).as_ptr(),
+                    CIM_MOUNT_IMAGE_FLAGS_CIM_MOUNT_IMAGE_NONE,
+                    volume as *const GUID as *const _GUID,
+                ),
+                None,
+            )
+        }
+    }
+
+    fn dismount_image(&mut self, volume: &GUID) -> Result<()> {
+        unsafe {
+            use crate::raw::CimDismountImage;
+
+            check(
+                "CimDismountImage",
+                CimDismountImage(volume as *const GUID as *const _GUID),
+                None,
+            )
+        }
+    }
+
+    fn set_volume_mount_point(&mut self, volume: &GUID, mountpoint: &Path) -> Result<()> {
+        unsafe {
+            let volume_path = format!("\\\\?\\Volume{{{:?}}}\\", volume);
+            let mut mountpoint = OsString::from(mountpoint.as_os_str());
+            mountpoint.push("\\");
+
+            let mountpoint = HSTRING::from(mountpoint.as_os_str());
+            let volume_path = HSTRING::from(volume_path);
+
+            trace!(
+                "Trying to set mountpoint {} for {}",
+                mountpoint.to_string(),
+                volume_path.to_string()
+            );
+            if SetVolumeMountPointW(PCWSTR(mountpoint.as_ptr()), PCWSTR(volume_path.as_ptr()))
+                .as_bool()
+            {
+                Ok(())
+            } else {
+                Err(Error::last_os_error())
+            }
+        }
+    }
+}
+
+/// Wrapper struct over the image handle so that the image can be closed when this struct is dropped,
+///
+#[derive(Debug)]
+pub struct CimImageHandleWrapper {
+    handle: CIMFS_IMAGE_HANDLE,
+}
+
+impl Drop for CimImageHandleWrapper {
+    fn drop(&mut self) {
+        unsafe {
+            crate::raw::CimCloseImage(self.handle);
+        }
+    }
+}
+
+/// Converts an HRESULT returned by the cimfs function call into a result,
+///
+/// Errors carry a `CimError::Ffi` w/ the HRESULT, the function and the relative path it was called w/.
+///
+fn check(call: &'static str, hresult: i32, relative_path: Option<&Path>) -> Result<()> {
+    if hresult < 0 {
+        Err(CimError::Ffi {
+            call,
+            hresult,
+            relative_path: relative_path.map(Path::to_path_buf),
+        }
+        .into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns the raw metadata struct that borrows the buffers from metadata,
+///
+fn to_raw_metadata(metadata: &FileMetadata) -> CIMFS_FILE_METADATA {
+    fn buffer(b: &[u8]) -> *const c_void {
+        if b.is_empty() {
+            std::ptr::null()
+        } else {
+            b.as_ptr() as *const c_void
+        }
+    }
+
+    CIMFS_FILE_METADATA {
+        Attributes: metadata.attributes,
+        FileSize: metadata.file_size as i64,
+        CreationTime: crate::raw::to_large_int(metadata.creation_time),
+        LastWriteTime: crate::raw::to_large_int(metadata.last_write_time),
+        ChangeTime: crate::raw::to_large_int(metadata.change_time),
+        LastAccessTime: crate::raw::to_large_int(metadata.last_access_time),
+        SecurityDescriptorBuffer: buffer(&metadata.security_descriptor),
+        SecurityDescriptorSize: metadata.security_descriptor.len() as u32,
+        ReparseDataBuffer: buffer(&metadata.reparse_data),
+        ReparseDataSize: metadata.reparse_data.len() as u32,
+        EaBuffer: buffer(&metadata.ea_buffer),
+        EaBufferSize: metadata.ea_buffer.len() as u32,
+    }
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use super::CimFsBackend;
+    use crate::api::Image;
+    use crate::api::Object;
+    use crate::backend::CimBackend;
+    use crate::provenance::ImageDigest;
+    use crate::region::region_files;
+    #[cfg(test)]
+    use crate::scratch::ScratchDir;
+    use std::collections::BTreeSet;
+    use std::ffi::OsString;
+    use std::path::Path;
+    use std::path::PathBuf;
+    use windows::core::GUID;
+
+    /// Dismounts a volume when dropped, so that a failed assertion does not leave the volume mounted,
+    ///
+    #[cfg_attr(not(test), allow(dead_code))]
+    struct Mounted(GUID);
+
+    impl Drop for Mounted {
+        fn drop(&mut self) {
+            let _ = CimFsBackend.dismount_image(&self.0);
+        }
+    }
+
+    /// Writes a tree to root and commits an image of it named test.cim in `<root>/cim`, returns the image,
+    ///
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn commit_tree(root: &Path) -> Image<CimFsBackend> {
+        let tree = root.join("tree");
+        std::fs::create_dir_all(tree.join("dir")).unwrap();
+        std::fs::write(tree.join("hello.txt"), b"hello world").unwrap();
+        std::fs::write(tree.join("dir/nested.txt"), b"nested").unwrap();
+        let cimroot = root.join("cim");
+        std::fs::create_dir_all(&cimroot).unwrap();
+
+        let mut image = Image::with_backend(&cimroot, "test.cim", CimFsBackend);
+        image.create(None).unwrap();
+        let mut o = Object::with_base_dir(&tree, root, "").unwrap();
+        let ancestors = o.resolve_relative_path(true).unwrap();
+        let mut objects = o.expand(None).unwrap();
+        objects.insert(0, o);
+        image.build(objects, ancestors).unwrap();
+        image.commit().unwrap();
+        image
+    }
+
+    #[test]
+    fn test_create_commit() {
+        let scratch = ScratchDir::new("ffi-test-commit");
+        let root = scratch.path();
+        commit_tree(root);
+        assert!(root.join("cim").join("test.cim").exists());
+    }
+
+    #[test]
+    fn test_digest() {
+        let scratch = ScratchDir::new("ffi-test-digest");
+        let root = scratch.path();
+        let image = commit_tree(root);
+        let cimroot = root.join("cim");
+
+        // Every region file in the root folder belongs to test.cim
+        let files = region_files(&cimroot).unwrap();
+        assert!(!files.is_empty());
+        let digest = ImageDigest::compute(&cimroot, "test.cim").unwrap();
+        assert_eq!(
+            files,
+            digest
+                .files
+                .iter()
+                .skip(1)
+                .map(|f| OsString::from(&f.name))
+                .collect::<BTreeSet<_>>()
+        );
+        assert_eq!(image.digest().unwrap(), digest);
+
+        // The files of another image in the same root folder are not part of the digest
+        let mut other = Image::with_backend(&cimroot, "other.cim", CimFsBackend);
+        other.create(None).unwrap();
+        other
+            .create_file(
+                "hello.txt".as_ref(),
+                root.join("tree/hello.txt").as_os_str(),
+            )
+            .unwrap();
+        other.commit().unwrap();
+        assert!(region_files(&cimroot).unwrap().len() > files.len());
+        assert_eq!(digest, ImageDigest::compute(&cimroot, "test.cim").unwrap());
+        assert_eq!(
+            other.digest().unwrap(),
+            ImageDigest::compute(&cimroot, "other.cim").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_fork() {
+        let scratch = ScratchDir::new("ffi-test-fork");
+        let root = scratch.path();
+        commit_tree(root);
+        let cimroot = root.join("cim");
+        let base = ImageDigest::compute(&cimroot, "test.cim").unwrap();
+
+        // Region files of other images are not linked into the staging folder of the fork
+        let mut other = Image::with_backend(&cimroot, "other.cim", CimFsBackend);
+        other.create(None).unwrap();
+        other.commit().unwrap();
+
+        let mut fork = Image::with_backend(&cimroot, "fork.cim", CimFsBackend);
+        fork.create(Some("test.cim")).unwrap();
+        fork.delete_path("tree/dir/nested.txt".as_ref()).unwrap();
+        fork.create_file(
+            "tree/fork.txt".as_ref(),
+            root.join("tree/hello.txt").as_os_str(),
+        )
+        .unwrap();
+        fork.commit().unwrap();
+
+        // The fork references the region sets of its base, and the files written for it
+        let digest = fork.digest().unwrap();
+        assert_eq!(digest, ImageDigest::compute(&cimroot, "fork.cim").unwrap());
+        let files = digest
+            .files
+            .iter()
+            .map(|f| f.name.clone())
+            .collect::<BTreeSet<_>>();
+        assert!(base.files.iter().skip(1).all(|f| files.contains(&f.name)));
+        assert!(files.len() > base.files.len());
+        assert_eq!(base, ImageDigest::compute(&cimroot, "test.cim").unwrap());
+        assert!(std::fs::read_dir(&cimroot).unwrap().all(|e| !e
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .ends_with(".tmp")));
+
+        // Forks of forks link the files of every image in the chain
+        let mut fork = Image::with_backend(&cimroot, "fork2.cim", CimFsBackend);
+        fork.create(Some("fork.cim")).unwrap();
+        fork.commit().unwrap();
+        let digest = ImageDigest::compute(&cimroot, "fork2.cim").unwrap();
+        assert!(files
+            .iter()
+            .filter(|f| *f != "fork.cim")
+            .all(|f| digest.files.iter().any(|d| &d.name == f)));
+    }
+
+    /// Requires elevated permissions to mount the image,
+    ///
+    #[test]
+    #[ignore = "requires elevation"]
+    fn test_mount() {
+        let scratch = ScratchDir::new("ffi-test-mount");
+        let mut image = commit_tree(scratch.path());
+
+        let mounted = Mounted(image.mount(None).unwrap());
+        let volume = PathBuf::from(format!("\\\\?\\Volume{{{:?}}}\\", mounted.0));
+        assert_eq!(
+            b"hello world".to_vec(),
+            std::fs::read(volume.join("tree\\hello.txt")).unwrap()
+        );
+        assert_eq!(
+            b"nested".to_vec(),
+            std::fs::read(volume.join("tree\\dir\\nested.txt")).unwrap()
+        );
+        assert!(volume.join("tree\\dir").is_dir());
+    }
+}
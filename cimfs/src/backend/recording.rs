@@ -0,0 +1,341 @@
+use std::collections::BTreeSet;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::path::Path;
+use std::path::PathBuf;
+
+use windows::core::GUID;
+
+use super::new_guid;
+use super::CimBackend;
+use super::FileMetadata;
+use crate::error::CimError;
+use crate::region::guid_bytes;
+use crate::region::OBJECTID_PREFIX;
+use crate::region::REGION_PREFIX;
+
+/// In-memory backend that records every call made against it,
+///
+/// Useful for testing build logic on platforms without CimFS.
+///
+#[derive(Debug, Default)]
+pub struct RecordingBackend {
+    /// Images created w/ this backend, in the order they were created,
+    ///
+    pub images: Vec<RecordedImage>,
+    /// Images that were mounted, w/ the volume id used,
+    ///
+    pub mounted: Vec<(PathBuf, String, GUID)>,
+    /// Volumes that were dismounted,
+    ///
+    pub dismounted: Vec<GUID>,
+    /// Mountpoints that were set for volumes,
+    ///
+    pub mountpoints: Vec<(GUID, PathBuf)>,
+    /// Paths that do not exist in the image an image was forked from,
+    ///
+    /// `delete_path` fails for these paths, and `create_file` and `create_hard_link` fail for their children until the path is created,
+    /// w/ the HRESULT CimFS returns for paths that do not exist in an image.
+    ///
+    pub missing: BTreeSet<PathBuf>,
+    /// If true, committed images are written to files in their root folder, w/ the names CimFS uses,
+    ///
+    /// Each image gets a region set w/ a new id, its entries are written to `region_<id>_0` and `objectid_<id>_0`, and the image file lists
+    /// the id of the set followed by the ids listed by the image it is based on. Images are then staged, published and removed like images
+    /// committed by CimFS, but the files can't be mounted.
+    ///
+    pub write_files: bool,
+}
+
+/// Image recorded by `RecordingBackend`,
+///
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RecordedImage {
+    /// Root folder passed when the image was created,
+    ///
+    pub root: PathBuf,
+    /// Name of the existing image this image was based on,
+    ///
+    pub existing: Option<String>,
+    /// Name of the image,
+    ///
+    pub name: String,
+    /// Entries in the order they were added,
+    ///
+    pub entries: Vec<RecordedEntry>,
+    /// True if the image was committed,
+    ///
+    pub committed: bool,
+    /// True if the image handle was closed,
+    ///
+    pub closed: bool,
+}
+
+/// Entry added to a recorded image,
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordedEntry {
+    /// File created w/ `create_file`, and the data written to its stream,
+    ///
+    File {
+        path: PathBuf,
+        metadata: FileMetadata,
+        data: Vec<u8>,
+    },
+    /// Alternate stream created w/ `create_alternate_stream`, and the data written to it,
+    ///
+    AlternateStream {
+        path: PathBuf,
+        size: u64,
+        data: Vec<u8>,
+    },
+    /// Hard link created w/ `create_hard_link`,
+    ///
+    HardLink { path: PathBuf, existing: PathBuf },
+    /// Path deleted w/ `delete_path`,
+    ///
+    Delete { path: PathBuf },
+}
+
+impl RecordedEntry {
+    /// Returns the path in the image this entry was recorded for,
+    ///
+    pub fn path(&self) -> &Path {
+        match self {
+            RecordedEntry::File { path, .. }
+            | RecordedEntry::AlternateStream { path, .. }
+            | RecordedEntry::HardLink { path, .. }
+            | RecordedEntry::Delete { path } => path,
+        }
+    }
+}
+
+impl RecordingBackend {
+    /// Returns the most recently created image,
+    ///
+    pub fn last_image(&self) -> Option<&RecordedImage> {
+        self.images.last()
+    }
+}
+
+/// Handle to a recorded image, indexes into `RecordingBackend::images`,
+///
+#[derive(Debug)]
+pub struct RecordedImageHandle(usize);
+
+/// Handle to a recorded stream, indexes into the entries of a recorded image,
+///
+#[derive(Debug)]
+pub struct RecordedStreamHandle {
+    image: usize,
+    entry: usize,
+}
+
+impl CimBackend for RecordingBackend {
+    type ImageHandle = RecordedImageHandle;
+    type StreamHandle = RecordedStreamHandle;
+
+    fn writes_files(&self) -> bool {
+        self.write_files
+    }
+
+    fn create_image(
+        &mut self,
+        root: &Path,
+        existing: Option<&str>,
+        name: &str,
+    ) -> Result<Self::ImageHandle> {
+        self.images.push(RecordedImage {
+            root: root.to_path_buf(),
+            existing: existing.map(String::from),
+            name: name.to_string(),
+            ..Default::default()
+        });
+
+        Ok(RecordedImageHandle(self.images.len() - 1))
+    }
+
+    fn close_image(&mut self, image: Self::ImageHandle) {
+        self.images[image.0].closed = true;
+    }
+
+    fn commit_image(&mut self, image: &mut Self::ImageHandle) -> Result<()> {
+        if self.write_files {
+            self.write_image(image)?;
+        }
+        self.images[image.0].committed = true;
+        Ok(())
+    }
+
+    fn create_file(
+        &mut self,
+        image: &mut Self::ImageHandle,
+        path: &Path,
+        metadata: &FileMetadata,
+    ) -> Result<Self::StreamHandle> {
+        self.check_parent(image, "CimCreateFile", path)?;
+        self.push(
+            image,
+            RecordedEntry::File {
+                path: path.to_path_buf(),
+                metadata: metadata.clone(),
+                data: vec![],
+            },
+        )
+    }
+
+    fn create_alternate_stream(
+        &mut self,
+        image: &mut Self::ImageHandle,
+        path: &Path,
+        size: u64,
+    ) -> Result<Self::StreamHandle> {
+        self.push(
+            image,
+            RecordedEntry::AlternateStream {
+                path: path.to_path_buf(),
+                size,
+                data: vec![],
+            },
+        )
+    }
+
+    fn write_stream(&mut self, stream: &mut Self::StreamHandle, buf: &[u8]) -> Result<()> {
+        match &mut self.images[stream.image].entries[stream.entry] {
+            RecordedEntry::File { data, .. } | RecordedEntry::AlternateStream { data, .. } => {
+                data.extend_from_slice(buf);
+                Ok(())
+            }
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Stream handle does not refer to a stream",
+            )),
+        }
+    }
+
+    fn close_stream(&mut self, _: Self::StreamHandle) {}
+
+    fn create_hard_link(
+        &mut self,
+        image: &mut Self::ImageHandle,
+        path: &Path,
+        existing: &Path,
+    ) -> Result<()> {
+        self.check_parent(image, "CimCreateHardLink", path)?;
+        self.push(
+            image,
+            RecordedEntry::HardLink {
+                path: path.to_path_buf(),
+                existing: existing.to_path_buf(),
+            },
+        )?;
+        Ok(())
+    }
+
+    fn delete_path(&mut self, image: &mut Self::ImageHandle, path: &Path) -> Result<()> {
+        if self.missing.contains(path) {
+            return Err(CimError::Ffi {
+                call: "CimDeletePath",
+                hresult: 0x80070003_u32 as i32,
+                relative_path: Some(path.to_path_buf()),
+            }
+            .into());
+        }
+
+        self.push(
+            image,
+            RecordedEntry::Delete {
+                path: path.to_path_buf(),
+            },
+        )?;
+        Ok(())
+    }
+
+    fn mount_image(&mut self, root: &Path, name: &str, volume: &GUID) -> Result<()> {
+        self.mounted
+            .push((root.to_path_buf(), name.to_string(), *volume));
+        Ok(())
+    }
+
+    fn dismount_image(&mut self, volume: &GUID) -> Result<()> {
+        self.dismounted.push(*volume);
+        Ok(())
+    }
+
+    fn set_volume_mount_point(&mut self, volume: &GUID, mountpoint: &Path) -> Result<()> {
+        self.mountpoints.push((*volume, mountpoint.to_path_buf()));
+        Ok(())
+    }
+}
+
+impl RecordingBackend {
+    /// Writes the files of a recorded image to its root folder, see `write_files`,
+    ///
+    fn write_image(&self, image: &RecordedImageHandle) -> Result<()> {
+        let image = &self.images[image.0];
+        let id = new_guid()?;
+        let region_set = format!("{:?}", id).to_lowercase();
+        std::fs::write(
+            image.root.join(format!("{REGION_PREFIX}{region_set}_0")),
+            format!("{:?}", image.entries),
+        )?;
+        std::fs::write(
+            image.root.join(format!("{OBJECTID_PREFIX}{region_set}_0")),
+            (image.entries.len() as u64).to_le_bytes(),
+        )?;
+
+        let mut data = guid_bytes(&id).to_vec();
+        if let Some(existing) = image.existing.as_ref() {
+            data.extend(std::fs::read(image.root.join(existing))?);
+        }
+        std::fs::write(image.root.join(&image.name), data)
+    }
+
+    /// Returns the error call would return if the parent of path is missing and was not created in the image,
+    ///
+    fn check_parent(
+        &self,
+        image: &RecordedImageHandle,
+        call: &'static str,
+        path: &Path,
+    ) -> Result<()> {
+        let Some(parent) = path.parent().filter(|p| self.missing.contains(*p)) else {
+            return Ok(());
+        };
+
+        let created = self.images[image.0]
+            .entries
+            .iter()
+            .any(|e| matches!(e, RecordedEntry::File { path, .. } if path == parent));
+        if created {
+            Ok(())
+        } else {
+            Err(CimError::Ffi {
+                call,
+                hresult: 0x80070003_u32 as i32,
+                relative_path: Some(path.to_path_buf()),
+            }
+            .into())
+        }
+    }
+
+    /// Records an entry for an open image and returns a stream handle for the entry,
+    ///
+    fn push(
+        &mut self,
+        image: &RecordedImageHandle,
+        entry: RecordedEntry,
+    ) -> Result<RecordedStreamHandle> {
+        let recorded = &mut self.images[image.0];
+        if recorded.closed || recorded.committed {
+            return Err(Error::new(ErrorKind::InvalidInput, "Image is not open"));
+        }
+
+        recorded.entries.push(entry);
+        Ok(RecordedStreamHandle {
+            image: image.0,
+            entry: recorded.entries.len() - 1,
+        })
+    }
+}
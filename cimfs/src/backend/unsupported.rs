@@ -0,0 +1,76 @@
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::path::Path;
+
+use windows::core::GUID;
+
+use super::CimBackend;
+use super::FileMetadata;
+
+/// Backend for platforms where CimFS is not available, every call returns an error,
+///
+#[derive(Debug, Default)]
+pub struct UnsupportedBackend;
+
+/// Returns the error returned by every function of `UnsupportedBackend`,
+///
+fn unsupported<T>() -> Result<T> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "CimFS is only available on Windows",
+    ))
+}
+
+impl CimBackend for UnsupportedBackend {
+    type ImageHandle = ();
+    type StreamHandle = ();
+
+    fn create_image(&mut self, _: &Path, _: Option<&str>, _: &str) -> Result<()> {
+        unsupported()
+    }
+
+    fn close_image(&mut self, _: ()) {}
+
+    fn commit_image(&mut self, _: &mut ()) -> Result<()> {
+        unsupported()
+    }
+
+    fn create_file(&mut self, _: &mut (), _: &Path, _: &FileMetadata) -> Result<()> {
+        unsupported()
+    }
+
+    fn create_alternate_stream(&mut self, _: &mut (), _: &Path, _: u64) -> Result<()> {
+        unsupported()
+    }
+
+    fn write_stream(&mut self, _: &mut (), _: &[u8]) -> Result<()> {
+        unsupported()
+    }
+
+    fn close_stream(&mut self, _: ()) {}
+
+    fn create_hard_link(&mut self, _: &mut (), _: &Path, _: &Path) -> Result<()> {
+        unsupported()
+    }
+
+    fn delete_path(&mut self, _: &mut (), _: &Path) -> Result<()> {
+        unsupported()
+    }
+
+    fn mount_image(&mut self, _: &Path, _: &str, _: &GUID) -> Result<()> {
+        unsupported()
+    }
+
+    fn dismount_image(&mut self, _: &GUID) -> Result<()> {
+        unsupported()
+    }
+
+    fn set_volume_mount_point(&mut self, _: &GUID, _: &Path) -> Result<()> {
+        unsupported()
+    }
+
+    fn writes_files(&self) -> bool {
+        false
+    }
+}
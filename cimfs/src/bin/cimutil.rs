@@ -1,26 +1,33 @@
 use clap::Args;
 use clap::Parser;
 use clap::Subcommand;
+use clap::ValueEnum;
+use indicatif::HumanBytes;
+use indicatif::ProgressBar;
+use indicatif::ProgressStyle;
 use std::collections::BTreeSet;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::path::Path;
 use std::path::PathBuf;
 use tracing::error;
 use tracing::info;
 use tracing::trace;
+use tracing::warn;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::EnvFilter;
-use windows::core::Error;
-use windows::core::Result;
-use windows::core::GUID;
-use windows::core::HRESULT;
-use windows::core::HSTRING;
-use windows::Win32::Foundation::E_INVALIDARG;
 
 use cimfs::api::*;
-use cimfs::raw::CimDismountImage;
-use cimfs::raw::_GUID;
+use cimfs::manifest::Manifest;
+use cimfs::verify::Report;
+use cimfs::verify::Verifier;
 
 /// Command line utility to work with CimFS on Windows
 ///
+/// On other platforms, commands that build, mount or verify images return an error, since they require CimFS. `--dry-run` works on every
+/// platform.
+///
 #[derive(Parser)]
 #[command(name = "cimutil")]
 struct CimUtil {
@@ -30,6 +37,20 @@ struct CimUtil {
     ///
     #[arg(long)]
     trace: bool,
+    /// Disables the progress bar shown while building images,
+    ///
+    /// The progress bar is also disabled when trace logging is enabled or stderr is not a terminal.
+    ///
+    #[arg(long)]
+    no_progress: bool,
+    /// Normalizes the images that are created so that the same inputs produce the same image,
+    ///
+    /// File times are set to `SOURCE_DATE_EPOCH`, or to the unix epoch if it is not set, host specific attributes are cleared and objects
+    /// are added in order of their path in the image. CimFS generates a new region set id for every image, so the entries are normalized
+    /// but the files of the image still differ between builds.
+    ///
+    #[arg(long)]
+    reproducible: bool,
     /// Sets the root path containing the cim images and data,
     ///
     #[arg(long, default_value_t=String::from("."))]
@@ -46,6 +67,18 @@ enum CimFSCommands {
     /// Create and builds a new CIM image based on a pre-existing image,
     ///
     Fork(ForkCimArgs),
+    /// Imports the layers of an image in an OCI image layout as a chain of CIM images,
+    ///
+    /// The first layer is created as a new image, and each subsequent layer is created as a fork of the previous layer's image.
+    ///
+    /// Prints the name of the image containing the last layer to stdout
+    ///
+    ImportOci(ImportOciArgs),
+    /// Compares a CIM image against the directory it was built from,
+    ///
+    /// Prints each missing, extra or changed file to stdout, and fails if there are any differences.
+    ///
+    Verify(VerifyCimArgs),
     /// Mounts a cim image as a read-only volume,
     ///
     /// Prints the mounted volume path to stdout
@@ -69,10 +102,28 @@ struct NewCimArgs {
     ///
     #[arg(long, short)]
     name: String,
-    /// Sets the default max buffer len to use when copying files to the cim,
-    /// 
-    #[arg(long)]
-    transfer_buffer_len: Option<usize>,
+    #[command(flatten)]
+    build: BuildArgs,
+    /// Path to a tar archive to build the image from, use `-` to read the archive from stdin,
+    ///
+    /// Directories, regular files, symlinks and hard links in the archive are added in order, at the path they have in the archive. Cannot be
+    /// combined w/ a list of objects, or the options that only apply to objects, ex. `--prefix` or `--workers`.
+    ///
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "objects",
+            "manifest",
+            "dry_run",
+            "base_dir",
+            "prefix",
+            "max_depth",
+            "workers",
+            "read_ahead_len",
+            "alternate_streams",
+        ]
+    )]
+    tar: Option<String>,
     /// List of paths of objects to add to the new cim image,
     ///
     /// Objects can be either files or directores (read further on information on directories)
@@ -87,9 +138,62 @@ struct NewCimArgs {
     /// This command will also handle adding ancestors for a file. For example if `src\bin\main.rs` is passed,
     /// `src`, `src\bin` will be created before `src\bin\main.rs` is added.
     ///
+    /// Directories are expanded recursively, each entry is added before its children. Reparse points (symlinks, junctions, etc) are
+    /// added as-is and are not followed. Use `--max-depth` to limit how deep directories are expanded.
+    ///
     objects: Vec<String>,
 }
 
+/// Arguments shared by the commands that build an image from a list of objects or a manifest,
+///
+#[derive(Args)]
+struct BuildArgs {
+    #[command(flatten)]
+    image: ImageArgs,
+    /// Copies the alternate data streams of each object, ex. Zone.Identifier,
+    ///
+    #[arg(long)]
+    alternate_streams: bool,
+    /// Number of workers that read objects ahead of time, files are still written to the image in order,
+    ///
+    /// The default is 0, which reads each object on the main thread.
+    ///
+    #[arg(long, default_value_t = 0)]
+    workers: usize,
+    /// Limits the total number of bytes that workers can read ahead of time, the default is 64 MiB,
+    ///
+    #[arg(long)]
+    read_ahead_len: Option<usize>,
+    /// Prints the operations the build would apply instead of creating the image, use `--dry-run=json` to print the plan as JSON,
+    ///
+    /// Files that would be deduplicated are listed as copies, since their contents are not read.
+    ///
+    #[arg(long, value_enum, num_args = 0..=1, require_equals = true, default_missing_value = "text")]
+    dry_run: Option<PlanFormat>,
+    /// Limits how deep directories are expanded, ex. 1 will only add the direct children of a directory,
+    ///
+    #[arg(long)]
+    max_depth: Option<usize>,
+    /// Path to a TOML or JSON manifest listing the entries to add, and for forks the paths to delete,
+    ///
+    /// Relative paths in the manifest are resolved against the directory containing the manifest. Cannot be combined w/ a list of objects.
+    ///
+    #[arg(long, conflicts_with = "objects")]
+    manifest: Option<PathBuf>,
+    /// Directory to strip from the path of each object, ex. w/ `--base-dir out`, `out\bin\app.exe` will be added as `bin\app.exe`,
+    ///
+    /// Objects must be within the base directory.
+    ///
+    #[arg(long)]
+    base_dir: Option<PathBuf>,
+    /// Path in the image to add the objects under, ex. w/ `--prefix app`, `bin\app.exe` will be added as `app\bin\app.exe`,
+    ///
+    /// If `--base-dir` is not set, paths are stripped of the current directory.
+    ///
+    #[arg(long)]
+    prefix: Option<PathBuf>,
+}
+
 /// Set of arguments for forking an existing cim image.
 ///
 /// The forked image will be created in the directory specified by the `--root` argument.
@@ -108,10 +212,15 @@ struct ForkCimArgs {
     ///
     #[arg(long, short)]
     to: String,
-    /// Sets the default max buffer len to use when copying files to the cim,
-    /// 
+    #[command(flatten)]
+    build: BuildArgs,
+    /// Path in the existing image to delete from the fork, can be repeated,
+    ///
+    /// Deletes are applied before any objects are added. Deletes are not validated against the existing image, a missing path is only
+    /// reported if CimDeletePath fails, in which case this command fails w/ a path not found error.
+    ///
     #[arg(long)]
-    transfer_buffer_len: Option<usize>,
+    delete: Vec<String>,
     /// List of paths of objects to add to the new cim image, if a file existed in the previous image that file will be overwritten.
     ///
     /// Objects can be either files or directores (read further on information on directories)
@@ -126,9 +235,107 @@ struct ForkCimArgs {
     /// This command will also handle adding ancestors for a file. For example if `src\bin\main.rs` is passed,
     /// `src`, `src\bin` will be created before `src\bin\main.rs` is added.
     ///
+    /// Directories are expanded recursively, each entry is added before its children. Reparse points (symlinks, junctions, etc) are
+    /// added as-is and are not followed. Use `--max-depth` to limit how deep directories are expanded.
+    ///
     objects: Vec<String>,
 }
 
+/// Arguments shared by every command that writes an image,
+///
+#[derive(Args)]
+struct ImageArgs {
+    /// Sets the default max buffer len to use when copying files to the cim,
+    ///
+    #[arg(long)]
+    transfer_buffer_len: Option<usize>,
+    /// Adds files w/ the same content as a file that was already added as hard links to that file, instead of copying them again,
+    ///
+    /// Files are only linked if their attributes, security descriptor and extended attributes are also the same.
+    ///
+    #[arg(long)]
+    dedup: bool,
+    /// Writes a provenance document next to the image, `<name>.provenance.json`, w/ the digest of the image and the objects it was built from,
+    ///
+    #[arg(long)]
+    provenance: bool,
+}
+
+/// Set of arguments for importing an image from an OCI image layout.
+///
+/// The images will be created in the directory specified by the `--root` argument.
+/// If an existing image exists with the name of any of the layer images, this command will fail. If a layer fails to import, the images
+/// of the layers before it are removed.
+///
+#[derive(Args)]
+struct ImportOciArgs {
+    /// Name of the cim image containing the last layer, ex. image.cim
+    ///
+    /// The images of the other layers are named after the index of the layer, ex. image-0.cim, image-1.cim, etc.
+    ///
+    #[arg(long, short)]
+    name: String,
+    #[command(flatten)]
+    image: ImageArgs,
+    /// Path to the OCI image layout directory, containing an index.json and blobs directory,
+    ///
+    layout: String,
+    /// Reference of the image to import,
+    ///
+    /// Can be either the value of the `org.opencontainers.image.ref.name` annotation, ex. latest, or a manifest digest. If the reference
+    /// names an image index, the manifest for the operating system and architecture of the host is imported.
+    ///
+    reference: String,
+}
+
+/// Formats a build plan can be printed in,
+///
+#[derive(Clone, Copy, ValueEnum)]
+enum PlanFormat {
+    /// One operation per line,
+    ///
+    Text,
+    /// The plan serialized as JSON,
+    ///
+    Json,
+}
+
+/// Arguments to verify a cim image against a directory,
+///
+/// If the image was built w/ `--reproducible`, pass `--reproducible` to normalize the metadata of the directory the same way.
+///
+#[derive(Args)]
+struct VerifyCimArgs {
+    /// Path in the image the directory was added under, the default is the root of the image,
+    ///
+    #[arg(long)]
+    prefix: Option<String>,
+    /// Skips comparing file times,
+    ///
+    #[arg(long)]
+    no_timestamps: bool,
+    /// Skips comparing alternate data streams, ex. if the image was built w/o `--alternate-streams`,
+    ///
+    #[arg(long)]
+    no_alternate_streams: bool,
+    /// Skips comparing security descriptors,
+    ///
+    #[arg(long)]
+    no_security_descriptors: bool,
+    /// Skips comparing extended attributes,
+    ///
+    #[arg(long)]
+    no_extended_attributes: bool,
+    /// Image name to verify, ex. image.cim
+    ///
+    /// The image must exist in the directory specified by the `--root` argument. On Windows the image is mounted while it is verified.
+    ///
+    image: String,
+    /// Path to the directory to compare the image against,
+    ///
+    dir: String,
+}
+
 /// Arguments to mount a CimFS volume,
 ///
 #[derive(Args)]
@@ -183,6 +390,8 @@ fn main() -> Result<()> {
     // Enable logging
     //
     enable_logging(parser.trace);
+    let progress = !parser.no_progress && !parser.trace;
+    let reproducible = parser.reproducible;
 
     let root = parser.root;
     let mut root = PathBuf::from(root);
@@ -197,9 +406,9 @@ fn main() -> Result<()> {
                 error = format!("{e}"),
                 "Encountered error when canonicalizing {:?}", root
             );
-            windows::core::Error::new(
-                E_INVALIDARG,
-                HSTRING::from("Could not canonicalize path to root directory"),
+            Error::new(
+                ErrorKind::InvalidInput,
+                "Could not canonicalize path to root directory",
             )
         })?;
     }
@@ -209,44 +418,89 @@ fn main() -> Result<()> {
             // Setup arguments before starting anything
             let name = args.name;
             if name.is_empty() {
-                return Err(Error::new(E_INVALIDARG, HSTRING::from("Name was empty")));
+                return Err(Error::new(ErrorKind::InvalidInput, "Name was empty"));
             }
 
             trace!("Parsing objects to add");
-            // TODO: Add a way to add this from a file schema, oci-manifest, tar, etc.
-            let (objects, ancestors) = parse_objects_from_args(args.objects)?;
+            let (objects, ancestors) = parse_objects_from_args(args.objects, &args.build)?;
+            let manifest = args
+                .build
+                .manifest
+                .as_ref()
+                .map(Manifest::from_path)
+                .transpose()?;
+
+            if let Some(format) = args.build.dry_run {
+                let image = plan_image(root, name, reproducible)?;
+                let plan = match manifest {
+                    Some(manifest) => manifest.plan(&image)?,
+                    None => image.plan(&objects, &ancestors)?,
+                };
+                return print_plan(&plan, format);
+            }
 
             trace!("Creating new CIM at: {:?}", root.join(&name));
-            let mut image = Image::new(root, name);
-
-            if let Some(buf_len) = args.transfer_buffer_len {
-                image = image.with_transfer_buf_len(buf_len);
-            }
+            let image = new_image(root, name, reproducible)?;
+            let mut image =
+                configure(&args.build, image).with_observer(ProgressObserver::new(progress));
 
             info!("Creating image handle");
             image.create(None)?;
 
             info!("Building image");
-            image.build(objects, ancestors)?;
+            match (args.tar.as_deref(), manifest) {
+                (Some("-"), _) => image.build_from_tar(std::io::stdin().lock())?,
+                (Some(tar), _) => image.build_from_tar(std::fs::File::open(tar)?)?,
+                (None, Some(manifest)) => manifest.build(&mut image)?,
+                (None, None) => image.build(objects, ancestors)?,
+            }
+
+            if let Some(stats) = image.dedup_stats() {
+                info!(
+                    "Deduplicated {} files, {} saved",
+                    stats.files,
+                    HumanBytes(stats.bytes_saved)
+                );
+            }
 
             info!("Committing image");
             image.commit()?;
+
+            if args.build.image.provenance {
+                check_provenance(&image)?;
+                info!("Image digest {}", image.digest()?.digest);
+            }
         }
         CimFSCommands::Fork(args) => {
             // Setup arguments before starting anything
             let from = args.from;
             if from.is_empty() {
-                return Err(Error::new(E_INVALIDARG, HSTRING::from("Name was empty")));
+                return Err(Error::new(ErrorKind::InvalidInput, "Name was empty"));
             }
 
             let to = args.to;
             if to.is_empty() {
-                return Err(Error::new(E_INVALIDARG, HSTRING::from("Name was empty")));
+                return Err(Error::new(ErrorKind::InvalidInput, "Name was empty"));
             }
 
             trace!("Parsing objects to add");
-            // TODO: Add a way to add this from a file schema, oci-manifest, tar, etc.
-            let (objects, ancestors) = parse_objects_from_args(args.objects)?;
+            let (objects, ancestors) = parse_objects_from_args(args.objects, &args.build)?;
+            let manifest = args
+                .build
+                .manifest
+                .as_ref()
+                .map(Manifest::from_path)
+                .transpose()?;
+
+            if let Some(format) = args.build.dry_run {
+                let image = plan_image(root, to, reproducible)?;
+                let plan = match manifest {
+                    Some(manifest) => manifest.plan(&image)?,
+                    None => image.plan(&objects, &ancestors)?,
+                };
+                // Deletes passed as arguments are applied before the deletes of the manifest
+                return print_plan(&plan.with_deletes(args.delete), format);
+            }
 
             trace!(
                 "Creating new CIM at {:?} from {:?}",
@@ -254,26 +508,137 @@ fn main() -> Result<()> {
                 root.join(&from)
             );
 
-            let mut image = Image::new(root, to);
-            
-            if let Some(buf_len) = args.transfer_buffer_len {
-                image = image.with_transfer_buf_len(buf_len);
-            }
+            let image = new_image(root, to, reproducible)?;
+            let mut image =
+                configure(&args.build, image).with_observer(ProgressObserver::new(progress));
 
             info!("Creating image handle");
             image.create(Some(from.as_str()))?;
 
+            for d in args.delete.iter() {
+                info!("Deleting {}", d);
+                image.delete_path(d.as_ref())?;
+            }
+
             info!("Building image fork");
-            image.build(objects, ancestors)?;
+            match manifest {
+                Some(manifest) => manifest.build(&mut image)?,
+                None => image.build(objects, ancestors)?,
+            }
+
+            if let Some(stats) = image.dedup_stats() {
+                info!(
+                    "Deduplicated {} files, {} saved",
+                    stats.files,
+                    HumanBytes(stats.bytes_saved)
+                );
+            }
 
             info!("Committing image");
             image.commit()?;
+
+            if args.build.image.provenance {
+                check_provenance(&image)?;
+                info!("Image digest {}", image.digest()?.digest);
+            }
+        }
+        CimFSCommands::ImportOci(args) => {
+            // Setup arguments before starting anything
+            let name = args.name;
+            if name.is_empty() {
+                return Err(Error::new(ErrorKind::InvalidInput, "Name was empty"));
+            }
+
+            trace!("Reading manifest for {} from {}", args.reference, args.layout);
+            let layout = cimfs::oci::Layout::open(&args.layout)?;
+            let manifest = layout.manifest(&args.reference)?;
+            if manifest.layers.is_empty() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "Manifest does not contain any layers",
+                ));
+            }
+
+            let stem = name.trim_end_matches(".cim");
+            let mut committed = vec![];
+            for (index, layer) in manifest.layers.iter().enumerate() {
+                let layer_name = if index + 1 == manifest.layers.len() {
+                    name.clone()
+                } else {
+                    format!("{stem}-{index}.cim")
+                };
+
+                trace!(
+                    "Creating new CIM at {:?} for layer {}",
+                    root.join(&layer_name),
+                    layer.digest
+                );
+                let result = new_image(&root, layer_name, reproducible).and_then(|image| {
+                    let mut image = configure_image(&args.image, image)
+                        .with_observer(ProgressObserver::new(progress));
+
+                    info!("Creating image handle");
+                    image.create(committed.last().map(|i: &Image<_>| i.name()))?;
+
+                    info!("Building image from layer {}", layer.digest);
+                    image.build_from_oci_layer(layout.open_layer(layer)?)?;
+
+                    info!("Committing image");
+                    image.commit()?;
+                    Ok(image)
+                });
+
+                match result {
+                    Ok(image) => committed.push(image),
+                    Err(err) => {
+                        // The images of the previous layers are removed, so that the import can be retried
+                        for mut image in committed.into_iter().rev() {
+                            info!("Removing {}", image.name());
+                            if let Err(err) = image.remove() {
+                                warn!("Could not remove {} -- {err}", image.name());
+                            }
+                        }
+                        return Err(err);
+                    }
+                }
+            }
+
+            if args.image.provenance {
+                for image in committed.iter() {
+                    check_provenance(image)?;
+                    info!(
+                        "Image digest of {} {}",
+                        image.name(),
+                        image.digest()?.digest
+                    );
+                }
+            }
+            println!("{}", name);
+        }
+        CimFSCommands::Verify(args) => {
+            info!("Verifying {} against {:?}", args.image, args.dir);
+            let report = verify(&root, &args, reproducible)?;
+            for difference in report.differences.iter() {
+                println!("{difference}");
+            }
+
+            if !report.is_ok() {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Found {} differences in {} entries",
+                        report.differences.len(),
+                        report.checked
+                    ),
+                ));
+            }
+            info!("Verified {} entries", report.checked);
         }
         CimFSCommands::Mount(args) => {
             // Setup arguments before starting anything
             let name = args.image;
             if name.is_empty() {
-                return Err(Error::new(E_INVALIDARG, HSTRING::from("Name was empty")));
+                return Err(Error::new(ErrorKind::InvalidInput, "Name was empty"));
             }
 
             info!("Mounting CIM from {:?}", root.join(&name));
@@ -290,7 +655,7 @@ fn main() -> Result<()> {
                 info!("Mounted volume at {}\\", mountvol.trim_end_matches('\\'));
             }
         }
-        CimFSCommands::Dismount(args) => unsafe {
+        CimFSCommands::Dismount(args) => {
             // Sanitize the arguments
             let volume = args
                 .volume
@@ -299,27 +664,282 @@ fn main() -> Result<()> {
                 .trim_start_matches("Volume{")
                 .trim_start_matches("{")
                 .trim_end_matches("}");
-            let volume = GUID::try_from(volume)
-                .map_err(|_| Error::new(E_INVALIDARG, "Invalid GUID".into()))?;
-            HRESULT(CimDismountImage(std::ptr::addr_of!(volume) as *const _GUID)).ok()?;
-        },
+            let volume = parse_guid(volume)?;
+            DefaultBackend::default().dismount_image(&volume)?;
+        }
     }
 
     Ok(())
 }
 
+/// Verifies an image against a directory through the volume it is mounted as, the image is dismounted afterwards,
+///
+#[cfg(windows)]
+fn verify(root: &Path, args: &VerifyCimArgs, reproducible: bool) -> Result<Report> {
+    let mut image = Image::new(root, args.image.clone());
+    let volume = image.mount(None)?;
+    let volume_path = format!("\\\\?\\Volume{{{:?}}}\\", volume);
+    info!("Mounted {} at {:?}", args.image, volume_path);
+
+    let result = configure_verifier(Verifier::mounted(volume_path), args, reproducible)
+        .and_then(|verifier| verifier.verify(&args.dir));
+    if let Err(err) = DefaultBackend::default().dismount_image(&volume) {
+        warn!("Could not dismount {:?} -- {err}", volume);
+    }
+    result
+}
+
+/// Returns an error, images are verified through the volume they are mounted as, which requires CimFS,
+///
+#[cfg(not(windows))]
+fn verify(_: &Path, args: &VerifyCimArgs, _: bool) -> Result<Report> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        format!(
+            "CimFS is only available on Windows, {} cannot be mounted to verify it on this platform",
+            args.image
+        ),
+    ))
+}
+
+/// Returns an error if the provenance document of a committed image could not be written,
+///
+/// `Image::commit()` only logs the error since the image is already published, but a build w/ `--provenance` should not succeed w/o it.
+///
+fn check_provenance<B: CimBackend>(image: &Image<B>) -> Result<()> {
+    match image.provenance_error() {
+        Some(err) => Err(Error::other(format!(
+            "{} was committed, but its provenance document could not be written -- {err}",
+            image.name()
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Returns verifier configured w/ the options in args,
+///
+#[cfg_attr(not(windows), allow(dead_code))]
+fn configure_verifier<'a>(
+    verifier: Verifier<'a>,
+    args: &VerifyCimArgs,
+    reproducible: bool,
+) -> Result<Verifier<'a>> {
+    let verifier = verifier
+        .with_prefix(args.prefix.clone().unwrap_or_default())
+        .with_timestamps(!args.no_timestamps)
+        .with_alternate_streams(!args.no_alternate_streams)
+        .with_security_descriptors(!args.no_security_descriptors)
+        .with_extended_attributes(!args.no_extended_attributes);
+    if reproducible {
+        Ok(verifier.with_normalization(Normalization::from_env()?))
+    } else {
+        Ok(verifier)
+    }
+}
+
+/// Backend images are built w/, CimFS is only available on Windows,
+///
+#[cfg(windows)]
+type BuildBackend = CimFsBackend;
+
+/// Backend images are built w/, CimFS is only available on Windows,
+///
+#[cfg(not(windows))]
+type BuildBackend = UnsupportedBackend;
+
+/// Returns image configured w/ the build options in args,
+///
+fn configure(args: &BuildArgs, image: Image<BuildBackend>) -> Image<BuildBackend> {
+    let mut image = configure_image(&args.image, image)
+        .with_alternate_streams(args.alternate_streams)
+        .with_build_workers(args.workers);
+    if let Some(len) = args.read_ahead_len {
+        image = image.with_build_buffer_budget(len);
+    }
+    image
+}
+
+/// Returns image configured w/ the options in args that apply to every image,
+///
+fn configure_image(args: &ImageArgs, image: Image<BuildBackend>) -> Image<BuildBackend> {
+    let mut image = image
+        .with_deduplication(args.dedup)
+        .with_provenance(args.provenance);
+    if let Some(buf_len) = args.transfer_buffer_len {
+        image = image.with_transfer_buf_len(buf_len);
+    }
+    image
+}
+
+/// Returns a new image to build, normalized if reproducible is set,
+///
+#[cfg(windows)]
+fn new_image(
+    root: impl Into<PathBuf>,
+    name: String,
+    reproducible: bool,
+) -> Result<Image<BuildBackend>> {
+    // CimFS always generates a new region set id
+    if reproducible {
+        warn!("CimFS generates a new region set id for every image, the entries of {name} are normalized but its files are not byte-identical");
+    }
+
+    let image = Image::with_backend(root, name, BuildBackend::default());
+    if reproducible {
+        Ok(image.with_normalization(Normalization::from_env()?))
+    } else {
+        Ok(image)
+    }
+}
+
+/// Returns an error, images can only be built w/ CimFS, which is only available on Windows,
+///
+#[cfg(not(windows))]
+fn new_image(_: impl Into<PathBuf>, name: String, _: bool) -> Result<Image<BuildBackend>> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        format!("CimFS is only available on Windows, {name} cannot be built on this platform"),
+    ))
+}
+
+/// Returns an image to plan a build w/, normalized if reproducible is set,
+///
+/// Planning does not create the image, so the image records calls instead of using CimFS and plans work on every platform.
+///
+fn plan_image(
+    root: impl Into<PathBuf>,
+    name: String,
+    reproducible: bool,
+) -> Result<Image<RecordingBackend>> {
+    let image = Image::with_backend(root, name, RecordingBackend::default());
+    if reproducible {
+        Ok(image.with_normalization(Normalization::from_env()?))
+    } else {
+        Ok(image)
+    }
+}
+
+/// Prints a build plan to stdout,
+///
+fn print_plan(plan: &BuildPlan, format: PlanFormat) -> Result<()> {
+    match format {
+        PlanFormat::Text => {
+            for operation in plan.operations.iter() {
+                println!("{operation}");
+            }
+            info!(
+                "{} operations, {} ancestors, {} skipped, {}",
+                plan.operations.len(),
+                plan.ancestors.len(),
+                plan.skipped.len(),
+                HumanBytes(plan.total_bytes)
+            );
+        }
+        PlanFormat::Json => {
+            let json = serde_json::to_string_pretty(plan).map_err(Error::other)?;
+            println!("{json}");
+        }
+    }
+    Ok(())
+}
+
+/// Build observer that draws a progress bar w/ an ETA on stderr,
+///
+/// The ETA is estimated from the number of entries that were added, so it is only shown when the number of entries is known ahead of time.
+///
+struct ProgressObserver {
+    bar: ProgressBar,
+    bytes: u64,
+}
+
+impl ProgressObserver {
+    /// Returns a new observer, if not enabled the progress bar is hidden,
+    ///
+    fn new(enabled: bool) -> Self {
+        let bar = if enabled {
+            ProgressBar::new(0)
+        } else {
+            ProgressBar::hidden()
+        };
+        Self { bar, bytes: 0 }
+    }
+}
+
+impl BuildObserver for ProgressObserver {
+    fn build_started(&mut self, entries: Option<usize>) {
+        let style = match entries {
+            Some(entries) => {
+                self.bar.set_length(entries as u64);
+                ProgressStyle::with_template(
+                    "[{elapsed_precise}] {wide_bar} {pos}/{len} entries, {msg} (ETA {eta})",
+                )
+            }
+            None => ProgressStyle::with_template("{spinner} [{elapsed_precise}] {pos} entries, {msg}"),
+        };
+        self.bar.set_style(style.expect("should be a valid progress template"));
+        self.bar.reset();
+        self.bytes = 0;
+        self.bar.set_message(HumanBytes(0).to_string());
+    }
+
+    fn bytes_transferred(&mut self, _: &Path, len: u64) {
+        self.bytes += len;
+        self.bar.set_message(HumanBytes(self.bytes).to_string());
+    }
+
+    fn entry_finished(&mut self, _: &Path) {
+        self.bar.inc(1);
+    }
+
+    fn entry_linked(&mut self, _: &Path, _: &Path) {
+        self.bar.inc(1);
+    }
+
+    fn build_finished(&mut self, _: Option<&cimfs::Error>) {
+        self.bar.finish_and_clear();
+        info!("Added {} entries, {}", self.bar.position(), HumanBytes(self.bytes));
+    }
+}
+
 /// Parses a list of object paths into a vector of objects and their required ancestors,
-/// 
-fn parse_objects_from_args(list: Vec<String>) -> Result<(Vec<Object>, BTreeSet<Object>)> {
+///
+/// Directories are expanded into their descendants up to max_depth, objects w/ a relative path that was already added are skipped.
+///
+/// If `--base-dir` or `--prefix` are set, the destination of each object is its path relative to the base dir joined to the prefix.
+///
+fn parse_objects_from_args(
+    list: Vec<String>,
+    args: &BuildArgs,
+) -> Result<(Vec<Object>, BTreeSet<Object>)> {
+    let (base_dir, prefix) = (args.base_dir.as_deref(), args.prefix.as_deref());
+
     let mut objects = vec![];
 
     let mut ancestors = BTreeSet::new();
 
+    let mut added = BTreeSet::new();
+
     for o in list {
-        let mut o = Object::new(o);
+        let mut o = if base_dir.is_some() || prefix.is_some() {
+            Object::with_base_dir(
+                o,
+                base_dir.unwrap_or(Path::new(".")),
+                prefix.unwrap_or(Path::new("")),
+            )?
+        } else {
+            Object::new(o)
+        };
         let mut a = o.resolve_relative_path(true)?;
         ancestors.append(&mut a);
-        objects.push(o);
+
+        let descendants = o.expand(args.max_depth)?;
+        for o in std::iter::once(o).chain(descendants) {
+            if added.insert(o.get_relative_path()?.clone()) {
+                objects.push(o);
+            } else {
+                trace!("Skipping {:?}, already added", o);
+            }
+        }
     }
 
     Ok((objects, ancestors))
@@ -329,7 +949,7 @@ fn parse_objects_from_args(list: Vec<String>) -> Result<(Vec<Object>, BTreeSet<O
 ///
 fn enable_logging(trace: bool) {
     if std::env::var("RUST_LOG").ok().is_none() && !trace {
-        std::env::set_var("RUST_LOG", "cimutil=info");
+        std::env::set_var("RUST_LOG", "cimutil=info,cimfs=warn");
     }
 
     if trace {
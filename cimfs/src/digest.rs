@@ -0,0 +1,203 @@
+//! SHA-256 digests of file contents, computed w/ the `sha2` crate,
+//!
+//! Digests are formatted the same way as the digests in an OCI image layout, ex. `sha256:<hex>`.
+//!
+//! ```
+//! use cimfs::digest::Sha256;
+//!
+//! let mut hasher = Sha256::new();
+//! hasher.update(b"abc");
+//! assert_eq!(
+//!     "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+//!     hasher.finish().to_string()
+//! );
+//! ```
+//!
+use std::fmt::Debug;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Result;
+use std::io::Write;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+use sha2::Digest as _;
+
+/// SHA-256 digest,
+///
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Digest(pub [u8; 32]);
+
+impl Digest {
+    /// Returns the digest of all of the data returned by a reader,
+    ///
+    pub fn from_reader(mut reader: impl Read) -> Result<Self> {
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut reader, &mut hasher)?;
+        Ok(hasher.finish())
+    }
+
+    /// Returns the digest as a lowercase hex string, w/o the algorithm prefix,
+    ///
+    pub fn hex(&self) -> String {
+        self.0.iter().map(|b| format!("{b:02x}")).collect()
+    }
+}
+
+impl Display for Digest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "sha256:{}", self.hex())
+    }
+}
+
+impl Debug for Digest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl FromStr for Digest {
+    type Err = Error;
+
+    /// Parses a digest formatted as `sha256:<hex>`,
+    ///
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid digest {s}"));
+
+        let hex = s
+            .strip_prefix("sha256:")
+            .filter(|hex| hex.len() == 64 && hex.is_ascii())
+            .ok_or_else(invalid)?;
+
+        let mut digest = [0; 32];
+        for (b, i) in digest.iter_mut().zip((0..64).step_by(2)) {
+            *b = u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Digest(digest))
+    }
+}
+
+impl Serialize for Digest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Digest {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Incremental SHA-256 hasher,
+///
+/// Also implements `Write`, so that data can be hashed w/ `std::io::copy()`.
+///
+#[derive(Clone)]
+pub struct Sha256 {
+    inner: sha2::Sha256,
+    /// Total number of bytes hashed,
+    ///
+    len: u64,
+}
+
+impl Sha256 {
+    /// Returns a new hasher,
+    ///
+    pub fn new() -> Self {
+        Self {
+            inner: sha2::Sha256::new(),
+            len: 0,
+        }
+    }
+
+    /// Hashes data,
+    ///
+    pub fn update(&mut self, data: &[u8]) {
+        self.len += data.len() as u64;
+        self.inner.update(data);
+    }
+
+    /// Returns the number of bytes that were hashed,
+    ///
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns true if no data was hashed,
+    ///
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the digest of the data that was hashed,
+    ///
+    pub fn finish(self) -> Digest {
+        Digest(self.inner.finalize().into())
+    }
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Write for Sha256 {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use super::Digest;
+    use super::Sha256;
+
+    #[test]
+    fn test_sha256() {
+        let digest = |data: &[u8]| Digest::from_reader(data).unwrap().hex();
+
+        assert_eq!(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            digest(b"")
+        );
+        assert_eq!(
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+            digest(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")
+        );
+
+        // Data split across updates hashes the same as data passed at once
+        let data = (0..1000).map(|i| i as u8).collect::<Vec<_>>();
+        let mut hasher = Sha256::new();
+        for chunk in data.chunks(7) {
+            hasher.update(chunk);
+        }
+        assert_eq!(1000, hasher.len());
+        assert_eq!(digest(&data), hasher.finish().hex());
+
+        let million = vec![b'a'; 1_000_000];
+        assert_eq!(
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0",
+            digest(&million)
+        );
+
+        let parsed = format!("sha256:{}", digest(b"")).parse::<Digest>().unwrap();
+        assert_eq!(Digest::from_reader(&b""[..]).unwrap(), parsed);
+        assert!("sha256:e3b0".parse::<Digest>().is_err());
+        assert!("md5:e3b0".parse::<Digest>().is_err());
+    }
+}
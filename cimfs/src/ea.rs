@@ -0,0 +1,247 @@
+//! Extended attributes, encoded as a chain of `FILE_FULL_EA_INFORMATION` records,
+//!
+//! Each record has the following layout, and records other than the last are padded to a 4 byte boundary,
+//!
+//! ```text
+//! NextEntryOffset: u32, offset of the next record, 0 for the last record
+//! Flags: u8
+//! EaNameLength: u8, length of the name w/o the null terminator
+//! EaValueLength: u16
+//! EaName: [u8; EaNameLength + 1], null terminated ascii name
+//! EaValue: [u8; EaValueLength]
+//! ```
+//!
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+
+use bytes::BufMut;
+
+/// Flag set on extended attributes that a file can not be interpreted w/o,
+///
+pub const FILE_NEED_EA: u8 = 0x80;
+
+/// Size of a record w/o its name and value,
+///
+const HEADER_SIZE: usize = 8;
+
+/// Characters that are not allowed in the name of an extended attribute,
+///
+const INVALID_NAME_CHARS: &[char] = &[
+    '"', '*', '/', ':', '<', '>', '?', '\\', '|', '+', ',', ';', '=', '[', ']',
+];
+
+/// Extended attribute of a file,
+///
+#[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord, Hash)]
+pub struct ExtendedAttribute {
+    /// Name of the attribute,
+    ///
+    name: String,
+    /// Value of the attribute,
+    ///
+    value: Vec<u8>,
+    /// Flags of the attribute, ex. `FILE_NEED_EA`,
+    ///
+    flags: u8,
+}
+
+impl ExtendedAttribute {
+    /// Returns a new extended attribute,
+    ///
+    /// Returns an error if the name is empty, longer than 255 bytes, or contains characters other than printable ascii, or if the value is longer than 65535 bytes.
+    ///
+    pub fn new(name: impl Into<String>, value: impl Into<Vec<u8>>) -> Result<Self> {
+        let name = name.into();
+        let value = value.into();
+
+        if name.is_empty()
+            || name.len() > u8::MAX as usize
+            || !name.chars().all(|c| c.is_ascii_graphic() || c == ' ')
+            || name.contains(INVALID_NAME_CHARS)
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid extended attribute name {:?}", name),
+            ));
+        }
+
+        if value.len() > u16::MAX as usize {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Value of extended attribute {name} is too long, {} bytes",
+                    value.len()
+                ),
+            ));
+        }
+
+        Ok(Self {
+            name,
+            value,
+            flags: 0,
+        })
+    }
+
+    /// Returns self w/ flags, chainable
+    ///
+    pub fn with_flags(mut self, flags: u8) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Returns the name of the attribute,
+    ///
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the value of the attribute,
+    ///
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+
+    /// Returns the flags of the attribute,
+    ///
+    pub fn flags(&self) -> u8 {
+        self.flags
+    }
+
+    /// Returns the size of the record w/o padding,
+    ///
+    fn record_len(&self) -> usize {
+        HEADER_SIZE + self.name.len() + 1 + self.value.len()
+    }
+}
+
+/// Encodes extended attributes into a chain of `FILE_FULL_EA_INFORMATION` records,
+///
+/// Names are compared case-insensitively, if a name is repeated the last attribute w/ that name is used. Returns an empty buffer if there are no attributes.
+///
+pub fn encode(attributes: &[ExtendedAttribute]) -> Vec<u8> {
+    let mut unique: Vec<&ExtendedAttribute> = vec![];
+    for a in attributes.iter() {
+        match unique
+            .iter_mut()
+            .find(|u| u.name.eq_ignore_ascii_case(&a.name))
+        {
+            Some(existing) => *existing = a,
+            None => unique.push(a),
+        }
+    }
+
+    let mut buf = vec![];
+    for (i, a) in unique.iter().enumerate() {
+        let is_last = i + 1 == unique.len();
+        let len = a.record_len();
+        let padded = (len + 3) & !3;
+
+        buf.put_u32_le(if is_last { 0 } else { padded as u32 });
+        buf.put_u8(a.flags);
+        buf.put_u8(a.name.len() as u8);
+        buf.put_u16_le(a.value.len() as u16);
+        buf.put_slice(a.name.as_bytes());
+        buf.put_u8(0);
+        buf.put_slice(&a.value);
+        if !is_last {
+            buf.put_bytes(0, padded - len);
+        }
+    }
+    buf
+}
+
+/// Decodes a chain of `FILE_FULL_EA_INFORMATION` records,
+///
+pub fn decode(buf: &[u8]) -> Result<Vec<ExtendedAttribute>> {
+    let mut attributes = vec![];
+    let mut offset = 0;
+
+    while offset < buf.len() {
+        let record = &buf[offset..];
+        if record.len() < HEADER_SIZE {
+            return Err(invalid_data("Extended attribute record is truncated"));
+        }
+
+        let next = u32::from_le_bytes([record[0], record[1], record[2], record[3]]) as usize;
+        let flags = record[4];
+        let name_len = record[5] as usize;
+        let value_len = u16::from_le_bytes([record[6], record[7]]) as usize;
+
+        let name_end = HEADER_SIZE + name_len;
+        let value_end = name_end + 1 + value_len;
+        if record.len() < value_end || (next != 0 && next < value_end) || record[name_end] != 0 {
+            return Err(invalid_data("Extended attribute record is invalid"));
+        }
+
+        let name = std::str::from_utf8(&record[HEADER_SIZE..name_end])
+            .map_err(|_| invalid_data("Extended attribute name is not ascii"))?;
+        attributes.push(
+            ExtendedAttribute::new(name, &record[name_end + 1..value_end])
+                .map_err(|e| invalid_data(e.to_string()))?
+                .with_flags(flags),
+        );
+
+        if next == 0 {
+            break;
+        }
+        offset += next;
+    }
+
+    Ok(attributes)
+}
+
+/// Returns an invalid data error,
+///
+fn invalid_data(msg: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.into())
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use super::decode;
+    use super::encode;
+    use super::ExtendedAttribute;
+    use super::FILE_NEED_EA;
+
+    #[test]
+    fn test_encode_decode() {
+        let attributes = vec![
+            ExtendedAttribute::new("APP.VERSION", "1.2").unwrap(),
+            ExtendedAttribute::new("EMPTY", vec![]).unwrap(),
+            ExtendedAttribute::new("NEEDED", vec![1, 2, 3])
+                .unwrap()
+                .with_flags(FILE_NEED_EA),
+        ];
+
+        let buf = encode(&attributes);
+        #[rustfmt::skip]
+        let expected: &[u8] = &[
+            0x18, 0x00, 0x00, 0x00, 0x00, 0x0b, 0x03, 0x00,
+            b'A', b'P', b'P', b'.', b'V', b'E', b'R', b'S', b'I', b'O', b'N', 0x00, b'1', b'.', b'2', 0x00,
+            0x10, 0x00, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00,
+            b'E', b'M', b'P', b'T', b'Y', 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x80, 0x06, 0x03, 0x00,
+            b'N', b'E', b'E', b'D', b'E', b'D', 0x00, 0x01, 0x02, 0x03,
+        ];
+        assert_eq!(expected, buf);
+        assert_eq!(attributes, decode(&buf).unwrap());
+
+        let replaced = encode(&[
+            ExtendedAttribute::new("name", "a").unwrap(),
+            ExtendedAttribute::new("NAME", "b").unwrap(),
+        ]);
+        assert_eq!(
+            vec![ExtendedAttribute::new("NAME", "b").unwrap()],
+            decode(&replaced).unwrap()
+        );
+
+        assert!(encode(&[]).is_empty());
+        assert!(decode(&[]).unwrap().is_empty());
+        assert!(decode(&buf[..20]).is_err());
+        assert!(ExtendedAttribute::new("", "").is_err());
+        assert!(ExtendedAttribute::new("a:b", "").is_err());
+        assert!(ExtendedAttribute::new("a".repeat(256), "").is_err());
+        assert!(ExtendedAttribute::new("a", vec![0; 65536]).is_err());
+    }
+}
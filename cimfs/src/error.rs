@@ -0,0 +1,427 @@
+//! Errors returned by this crate,
+//!
+//! The image apis in `cimfs::api` return `cimfs::Result`, its error is a `CimError` w/ the context of the failure, ex.
+//!
+//! ```
+//! use cimfs::api::Image;
+//! use cimfs::error::CimError;
+//!
+//! let mut image = Image::new(".cimroot", "app.cim");
+//! let err = image.create_file("app.exe".as_ref(), "app.exe".as_ref()).unwrap_err();
+//! assert!(matches!(err, CimError::ImageNotOpen));
+//! ```
+//!
+//! The other modules of this crate and the `CimBackend` trait return `std::io::Result`. A `CimError` converts into an `std::io::Error` w/
+//! `?`, and the `CimError` can be retrieved again w/ `CimError::from_io()`. An `std::io::Error` converts into a `CimError::Io`, unless it
+//! carries a `CimError`, which is returned as-is.
+//!
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// HRESULT facility of HRESULTs wrapping a win32 error code,
+///
+const FACILITY_WIN32: i32 = 7;
+
+/// Error w/ the context of a failed image operation,
+///
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum CimError {
+    /// An image handle is required, but `create()` has not been called or the image was already committed,
+    ///
+    ImageNotOpen,
+    /// A path in the image is not valid, ex. a tar entry outside of the archive root,
+    ///
+    PathInvalid {
+        relative_path: PathBuf,
+        reason: String,
+    },
+    /// A path does not exist in the image this image was forked from,
+    ///
+    PathNotFound { relative_path: PathBuf },
+    /// Data or metadata could not be read from a src,
+    ///
+    SourceUnreadable {
+        relative_path: PathBuf,
+        /// Path of the src, if the data is read from a file,
+        ///
+        src: Option<PathBuf>,
+        source: Error,
+    },
+    /// A CimFS function returned a failing HRESULT,
+    ///
+    Ffi {
+        /// Name of the CimFS function, ex. `CimCreateFile`,
+        ///
+        call: &'static str,
+        hresult: i32,
+        relative_path: Option<PathBuf>,
+    },
+    /// A backend other than CimFS failed, ex. `RecordingBackend` writing the files of an image,
+    ///
+    Backend {
+        /// Name of the CimFS function the backend implements, ex. `CimCreateFile`,
+        ///
+        call: &'static str,
+        relative_path: Option<PathBuf>,
+        source: Error,
+    },
+    /// An io error w/o the context of an image operation, ex. from reading the root folder,
+    ///
+    Io(Error),
+}
+
+impl CimError {
+    /// Returns the `CimError` carried by an io error,
+    ///
+    pub fn from_io(err: &Error) -> Option<&CimError> {
+        err.get_ref().and_then(|e| e.downcast_ref::<CimError>())
+    }
+
+    /// Returns the HRESULT of a failed CimFS call,
+    ///
+    pub fn hresult(&self) -> Option<i32> {
+        match self {
+            CimError::Ffi { hresult, .. } => Some(*hresult),
+            _ => None,
+        }
+    }
+
+    /// Returns the path in the image the error is about,
+    ///
+    pub fn relative_path(&self) -> Option<&Path> {
+        match self {
+            CimError::ImageNotOpen | CimError::Io(_) => None,
+            CimError::PathInvalid { relative_path, .. }
+            | CimError::PathNotFound { relative_path }
+            | CimError::SourceUnreadable { relative_path, .. } => Some(relative_path),
+            CimError::Ffi { relative_path, .. } | CimError::Backend { relative_path, .. } => {
+                relative_path.as_deref()
+            }
+        }
+    }
+
+    /// Returns the path of the src the error is about,
+    ///
+    pub fn src(&self) -> Option<&Path> {
+        match self {
+            CimError::SourceUnreadable { src, .. } => src.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the CimFS function that failed,
+    ///
+    pub fn call(&self) -> Option<&'static str> {
+        match self {
+            CimError::Ffi { call, .. } | CimError::Backend { call, .. } => Some(call),
+            _ => None,
+        }
+    }
+
+    /// Returns the kind of the io error this error converts into,
+    ///
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            CimError::ImageNotOpen => ErrorKind::Other,
+            CimError::PathInvalid { .. } => ErrorKind::InvalidInput,
+            CimError::PathNotFound { .. } => ErrorKind::NotFound,
+            CimError::SourceUnreadable { source, .. }
+            | CimError::Backend { source, .. }
+            | CimError::Io(source) => source.kind(),
+            CimError::Ffi { hresult, .. } => hresult_error(*hresult).kind(),
+        }
+    }
+}
+
+impl Display for CimError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CimError::ImageNotOpen => {
+                write!(f, "Image handle is not open, create() must be called first")
+            }
+            CimError::PathInvalid {
+                relative_path,
+                reason,
+            } => write!(f, "Invalid path {:?}, {reason}", relative_path),
+            CimError::PathNotFound { relative_path } => write!(
+                f,
+                "{:?} does not exist in the image this image was forked from",
+                relative_path
+            ),
+            CimError::SourceUnreadable {
+                relative_path,
+                src: Some(src),
+                source,
+            } => write!(
+                f,
+                "Could not read {:?} for {:?} -- {source}",
+                src, relative_path
+            ),
+            CimError::SourceUnreadable {
+                relative_path,
+                src: None,
+                source,
+            } => write!(f, "Could not read data for {:?} -- {source}", relative_path),
+            CimError::Ffi {
+                call,
+                hresult,
+                relative_path,
+            } => {
+                write!(f, "{call} failed w/ HRESULT {:#010x}", *hresult as u32)?;
+                if let Some(relative_path) = relative_path {
+                    write!(f, " for {:?}", relative_path)?;
+                }
+                // Only windows can format the message of an HRESULT, other platforms would read it as an errno
+                #[cfg(windows)]
+                write!(f, " -- {}", Error::from_raw_os_error(*hresult))?;
+                Ok(())
+            }
+            CimError::Backend {
+                call,
+                relative_path: Some(relative_path),
+                source,
+            } => write!(f, "{call} failed for {:?} -- {source}", relative_path),
+            CimError::Backend {
+                call,
+                relative_path: None,
+                source,
+            } => write!(f, "{call} failed -- {source}"),
+            CimError::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for CimError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CimError::SourceUnreadable { source, .. } | CimError::Backend { source, .. } => {
+                Some(source)
+            }
+            CimError::Io(err) => std::error::Error::source(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<CimError> for Error {
+    fn from(value: CimError) -> Self {
+        match value {
+            CimError::Io(err) => err,
+            value => Error::new(value.kind(), value),
+        }
+    }
+}
+
+impl From<Error> for CimError {
+    fn from(err: Error) -> Self {
+        if CimError::from_io(&err).is_none() {
+            return CimError::Io(err);
+        }
+
+        let kind = err.kind();
+        match err.into_inner().map(|inner| inner.downcast::<CimError>()) {
+            Some(Ok(cim_error)) => *cim_error,
+            Some(Err(inner)) => CimError::Io(Error::new(kind, inner)),
+            None => CimError::Io(kind.into()),
+        }
+    }
+}
+
+/// Returns the io error for an HRESULT, w/ the win32 error code if the HRESULT wraps one,
+///
+/// `From<windows::core::Error> for std::io::Error` passes the whole HRESULT as an os error code, which isn't a code the os knows,
+/// so the error kind is always `Other`.
+///
+pub(crate) fn hresult_error(hresult: i32) -> Error {
+    if (hresult >> 16) & 0x1FFF == FACILITY_WIN32 {
+        Error::from_raw_os_error(hresult & 0xFFFF)
+    } else {
+        Error::other(format!("HRESULT {:#010x}", hresult as u32))
+    }
+}
+
+/// HRESULTs CimFS returns for paths that do not exist in an image, (ERROR_FILE_NOT_FOUND, ERROR_PATH_NOT_FOUND)
+///
+const NOT_FOUND_HRESULTS: [u32; 2] = [0x80070002, 0x80070003];
+
+/// Returns true if an error returned by a backend means the path does not exist in the image,
+///
+pub(crate) fn is_not_found(err: &Error) -> bool {
+    match CimError::from_io(err) {
+        Some(cim_error @ CimError::Ffi { .. }) => is_backend_not_found(cim_error),
+        Some(cim_error) => cim_error.kind() == ErrorKind::NotFound,
+        None => err.kind() == ErrorKind::NotFound,
+    }
+}
+
+/// Returns true if a backend call failed because a path does not exist in the image,
+///
+/// Unlike `is_not_found()`, errors reading a src are not included, ex. a src file that was removed.
+///
+pub(crate) fn is_backend_not_found(err: &CimError) -> bool {
+    match err {
+        CimError::Ffi { hresult, .. } => NOT_FOUND_HRESULTS.contains(&(*hresult as u32)),
+        CimError::Backend { source, .. } => source.kind() == ErrorKind::NotFound,
+        _ => false,
+    }
+}
+
+/// Returns a function that adds the failing call and relative path to an error returned by a backend,
+///
+/// Errors that already carry a `CimError`, ex. from the CimFS backend, are returned as-is.
+///
+pub(crate) fn backend_error<'a>(
+    call: &'static str,
+    relative_path: Option<&'a Path>,
+) -> impl FnOnce(Error) -> Error + 'a {
+    move |source| {
+        if CimError::from_io(&source).is_some() {
+            source
+        } else {
+            CimError::Backend {
+                call,
+                relative_path: relative_path.map(Path::to_path_buf),
+                source,
+            }
+            .into()
+        }
+    }
+}
+
+/// Returns a function that adds the relative path and src to an error returned while reading a src,
+///
+/// Errors that already carry a `CimError` are returned as-is.
+///
+pub(crate) fn source_error<'a>(
+    relative_path: &'a Path,
+    src: Option<&'a Path>,
+) -> impl FnOnce(Error) -> Error + 'a {
+    move |source| {
+        if CimError::from_io(&source).is_some() {
+            source
+        } else {
+            CimError::SourceUnreadable {
+                relative_path: relative_path.to_path_buf(),
+                src: src.map(Path::to_path_buf),
+                source,
+            }
+            .into()
+        }
+    }
+}
+
+/// Reader that adds the relative path and src to errors returned by the inner reader,
+///
+pub(crate) struct ReadContext<'a, R> {
+    inner: R,
+    relative_path: &'a Path,
+    src: &'a Path,
+}
+
+impl<'a, R: Read> ReadContext<'a, R> {
+    /// Returns a new reader over inner, reading the data of src for relative_path,
+    ///
+    pub(crate) fn new(inner: R, relative_path: &'a Path, src: &'a Path) -> Self {
+        Self {
+            inner,
+            relative_path,
+            src,
+        }
+    }
+}
+
+impl<R: Read> Read for ReadContext<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf).map_err(|e| match e.kind() {
+            ErrorKind::Interrupted => e,
+            _ => source_error(self.relative_path, Some(self.src))(e),
+        })
+    }
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use super::CimError;
+    use std::io::Error;
+    use std::io::ErrorKind;
+    use std::path::Path;
+
+    #[test]
+    fn test_cim_error() {
+        let err: Error = CimError::Ffi {
+            call: "CimCreateFile",
+            hresult: 0x80070002_u32 as i32,
+            relative_path: Some("a/b.txt".into()),
+        }
+        .into();
+        assert_eq!(ErrorKind::NotFound, err.kind());
+        assert!(err
+            .to_string()
+            .starts_with("CimCreateFile failed w/ HRESULT 0x80070002 for \"a/b.txt\""));
+        #[cfg(not(windows))]
+        assert_eq!(
+            "CimCreateFile failed w/ HRESULT 0x80070002 for \"a/b.txt\"",
+            err.to_string()
+        );
+        #[cfg(windows)]
+        assert!(err.to_string().contains(" -- "));
+
+        // HRESULTs are zero padded
+        let padded = CimError::Ffi {
+            call: "CimCommitImage",
+            hresult: 1,
+            relative_path: None,
+        };
+        assert!(padded
+            .to_string()
+            .starts_with("CimCommitImage failed w/ HRESULT 0x00000001"));
+
+        let win32 = super::hresult_error(0x80070003_u32 as i32);
+        assert_eq!(Some(3), win32.raw_os_error());
+        #[cfg(windows)]
+        assert_eq!(ErrorKind::NotFound, win32.kind());
+        let other = super::hresult_error(0x80004005_u32 as i32);
+        assert_eq!(None, other.raw_os_error());
+        assert_eq!("HRESULT 0x80004005", other.to_string());
+
+        let cim_error = CimError::from_io(&err).unwrap();
+        assert_eq!(Some(0x80070002_u32 as i32), cim_error.hresult());
+        assert_eq!(Some("CimCreateFile"), cim_error.call());
+        assert_eq!(Some(Path::new("a/b.txt")), cim_error.relative_path());
+
+        let source = Error::new(ErrorKind::PermissionDenied, "denied");
+        let err = super::source_error(Path::new("a.txt"), Some(Path::new("/src/a.txt")))(source);
+        assert_eq!(ErrorKind::PermissionDenied, err.kind());
+        assert_eq!(
+            Some(Path::new("/src/a.txt")),
+            CimError::from_io(&err).unwrap().src()
+        );
+
+        // Errors that already carry context are not wrapped again
+        let err = super::backend_error("CimCommitImage", None)(err);
+        assert!(matches!(
+            CimError::from_io(&err),
+            Some(CimError::SourceUnreadable { .. })
+        ));
+        assert!(CimError::from_io(&Error::other("other")).is_none());
+
+        // Converting back into a CimError returns the CimError an io error carries
+        assert!(matches!(
+            CimError::from(err),
+            CimError::SourceUnreadable { .. }
+        ));
+        let io = CimError::from(Error::new(ErrorKind::NotFound, "missing"));
+        assert!(matches!(io, CimError::Io(_)));
+        assert_eq!(ErrorKind::NotFound, io.kind());
+        assert_eq!(None, io.relative_path());
+        let err: Error = io.into();
+        assert!(CimError::from_io(&err).is_none());
+        assert_eq!("missing", err.to_string());
+    }
+}
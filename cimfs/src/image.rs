@@ -1,91 +1,260 @@
+use std::collections::BTreeMap;
 use std::collections::BTreeSet;
-use std::ffi::c_ulong;
-use std::ffi::c_void;
 use std::ffi::OsStr;
-use std::ffi::OsString;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::path::Path;
 use std::path::PathBuf;
 
 use bytes::BytesMut;
-use cimfs_sys::CimMountImage;
-use cimfs_sys::CIM_MOUNT_IMAGE_FLAGS_CIM_MOUNT_IMAGE_NONE;
-use cimfs_sys::_GUID;
-use windows::core::Error;
-use windows::core::Result;
 use windows::core::GUID;
-use windows::core::HRESULT;
-use windows::core::HSTRING;
-use windows::core::PCWSTR;
-use windows::Win32::Foundation::*;
-use windows::Win32::Storage::FileSystem::CreateFileW;
-use windows::Win32::Storage::FileSystem::*;
-use windows::Win32::System::Rpc::UuidCreate;
-use windows::Win32::System::IO::DeviceIoControl;
-
-// TODO -- Used w/ security descriptors
-// use cimfs_sys::ACCESS_SYSTEM_SECURITY;
-// use bytes::BufMut;
-// use cimfs_sys::CIMFS_IMAGE_HANDLE__;
-// use cimfs_sys::PROCESS_TRUST_LABEL_SECURITY_INFORMATION;
-// use windows::Win32::Security::Authorization::GetSecurityInfo;
-// use windows::Win32::Security::Authorization::SE_FILE_OBJECT;
-// use windows::Win32::Security::DACL_SECURITY_INFORMATION;
-// use windows::Win32::Security::*;
-
+use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_DIRECTORY;
+use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_NORMAL;
+use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_REPARSE_POINT;
+
+use crate::backend::CimBackend;
+use crate::backend::DefaultBackend;
+use crate::backend::FileMetadata;
+use crate::error::backend_error;
+use crate::error::is_not_found;
+use crate::error::source_error;
+use crate::error::CimError;
+use crate::error::ReadContext;
 use crate::object::Object;
-use crate::raw::CIMFS_IMAGE_HANDLE;
-use crate::raw::FSCTL_GET_REPARSE_POINT;
+use crate::object::Overrides;
+use crate::provenance::BaseImage;
+use crate::provenance::BuildOptions;
+use crate::provenance::ImageDigest;
+use crate::provenance::Input;
+use crate::provenance::Provenance;
+use crate::reparse::ReparseData;
+use crate::Result;
 
 use tracing::*;
 
+mod async_image;
+mod dedup;
+mod normalization;
+mod observer;
+mod plan;
+mod prefetch;
+mod staging;
+mod tar;
+
+pub use async_image::AsyncImage;
+use dedup::Dedup;
+pub use dedup::DedupStats;
+use dedup::Hashing;
+pub use normalization::Normalization;
+pub use observer::BuildObserver;
+pub use plan::BuildOperation;
+pub use plan::BuildPlan;
+pub use plan::EntryKind;
+use prefetch::Job;
+use prefetch::Prefetched;
+use staging::Staging;
+
+/// Overrides of the jobs that create ancestors,
+///
+static NO_OVERRIDES: Overrides = Overrides {
+    attributes: None,
+    creation_time: None,
+    last_write_time: None,
+    change_time: None,
+    last_access_time: None,
+    security_descriptor: None,
+    extended_attributes: None,
+};
+
 /// Struct providing wrappers around CimFS image apis,
 ///
-pub struct Image {
+pub struct Image<B: CimBackend = DefaultBackend> {
     /// Name of this image,
     ///
     name: String,
     /// Root directory containing this image,
     ///
     root_folder: PathBuf,
+    /// Backend implementing the CimFS api,
+    ///
+    backend: B,
     /// Cimfs image handle,
     ///
-    image_handle: Option<CimImageHandleWrapper>,
+    image_handle: Option<B::ImageHandle>,
     /// Volume id,
     ///
     volume: Option<GUID>,
     /// Max buffer len to use when transfering files,
-    /// 
+    ///
     _max_buffer_len: usize,
-}
-
-impl Image {
-
+    /// Number of workers reading srcs ahead of time during `build()`, if 0 srcs are read on the calling thread,
+    ///
+    build_workers: usize,
+    /// Total number of bytes workers can read ahead of time during `build()`,
+    ///
+    build_buffer_budget: usize,
+    /// If true, alternate data streams of src files are copied,
+    ///
+    copy_alternate_streams: bool,
+    /// Observer notified of the progress of builds,
+    ///
+    observer: Option<Box<dyn BuildObserver>>,
+    /// Staging folder of the image until it is committed, if the backend writes files,
+    ///
+    staging: Option<Staging>,
+    /// Region and object id files written for the committed image, if it was written to a staging folder,
+    ///
+    written: Option<BTreeSet<String>>,
+    /// Normalization applied to the entries added to the image, for reproducible builds,
+    ///
+    normalization: Option<Normalization>,
+    /// Index of the contents of the files added by `build()`, if deduplication is enabled,
+    ///
+    dedup: Option<Dedup>,
+    /// Name of the image this image was forked from,
+    ///
+    existing: Option<String>,
+    /// True once it was logged that deletes are not validated against the existing image, so that it is only logged once per image,
+    ///
+    unvalidated_deletes: bool,
+    /// Srcs added to the image, if a provenance document is written on commit,
+    ///
+    inputs: Option<Vec<Input>>,
+    /// Error writing the provenance document of the committed image, if it could not be written,
+    ///
+    provenance_error: Option<CimError>,
 }
 
 impl Image {
     /// Creates a new image w/ root_folder containing the images and a name for this image,
     ///
     pub fn new(root_folder: impl Into<PathBuf>, name: impl Into<String>) -> Self {
+        Self::with_backend(root_folder, name, DefaultBackend::default())
+    }
+}
+
+impl<B: CimBackend> Image<B> {
+    /// Creates a new image that uses backend to call the CimFS api,
+    ///
+    pub fn with_backend(
+        root_folder: impl Into<PathBuf>,
+        name: impl Into<String>,
+        backend: B,
+    ) -> Self {
         Self {
             name: name.into(),
             root_folder: root_folder.into(),
+            backend,
             image_handle: None,
             volume: None,
-            _max_buffer_len: 20971520 // 20 MiB
+            _max_buffer_len: 20971520, // 20 MiB
+            build_workers: 0,
+            build_buffer_budget: 67108864, // 64 MiB
+            copy_alternate_streams: false,
+            observer: None,
+            staging: None,
+            written: None,
+            normalization: None,
+            dedup: None,
+            existing: None,
+            unvalidated_deletes: false,
+            provenance_error: None,
+            inputs: None,
         }
     }
 
     /// Returns self w/ a different buffer length for streaming,
-    /// 
+    ///
     /// The default is 20 MiB.
-    /// 
-    pub fn with_transfer_buf_len(self, len: usize) -> Image {
-        Image {
-            name: self.name,
-            root_folder: self.root_folder,
-            image_handle: self.image_handle,
-            volume: self.volume,
-            _max_buffer_len: len,
-        }
+    ///
+    pub fn with_transfer_buf_len(mut self, len: usize) -> Self {
+        self._max_buffer_len = len;
+        self
+    }
+
+    /// Returns self w/ a pool of workers that open and read srcs ahead of time during `build()`,
+    ///
+    /// Files are still written to the image one at a time and in the same order. The default is 0, which reads each src on the calling thread.
+    ///
+    pub fn with_build_workers(mut self, workers: usize) -> Self {
+        self.build_workers = workers;
+        self
+    }
+
+    /// Returns self w/ a different limit on the total number of bytes that build workers can read ahead of time,
+    ///
+    /// Files that do not fit in the remaining budget are read while they are written instead. The default is 64 MiB.
+    ///
+    pub fn with_build_buffer_budget(mut self, len: usize) -> Self {
+        self.build_buffer_budget = len;
+        self
+    }
+
+    /// Returns self w/ copying of alternate data streams enabled or disabled,
+    ///
+    /// When enabled, `create_file()` and `build()` copy every named stream of a src file, ex. `Zone.Identifier`. The default is disabled.
+    ///
+    pub fn with_alternate_streams(mut self, enabled: bool) -> Self {
+        self.copy_alternate_streams = enabled;
+        self
+    }
+
+    /// Returns self w/ an observer that is notified of the progress of builds,
+    ///
+    pub fn with_observer(mut self, observer: impl BuildObserver + 'static) -> Self {
+        self.observer = Some(Box::new(observer));
+        self
+    }
+
+    /// Returns self w/ the metadata and order of added entries normalized, so that two builds of the same input produce the same image,
+    ///
+    /// Applies to every entry, including entries added by `create_file()` and the tar builds. Images written by `CimFsBackend` are never
+    /// byte-identical, since CimFS generates a new region set id for every image, normalization only makes their entries the same.
+    ///
+    pub fn with_normalization(mut self, normalization: Normalization) -> Self {
+        self.normalization = Some(normalization);
+        self
+    }
+
+    /// Returns self w/ deduplication of file contents during `build()` enabled or disabled,
+    ///
+    /// When enabled, the contents of each file are hashed while they are read, and a file w/ the same contents as a file that was already
+    /// added is created as a hard link to that file instead of being written again. Since hard links share metadata, files are only linked
+    /// if their attributes, security descriptor and extended attributes are the same, and the link has the file times of the first copy.
+    ///
+    /// The number of files and bytes that were deduplicated is returned by `dedup_stats()`. The default is disabled.
+    ///
+    pub fn with_deduplication(mut self, enabled: bool) -> Self {
+        self.dedup = enabled.then(Dedup::default);
+        self
+    }
+
+    /// Returns the number of files and bytes that were deduplicated since the image was created, if deduplication is enabled,
+    ///
+    pub fn dedup_stats(&self) -> Option<DedupStats> {
+        self.dedup.as_ref().map(Dedup::stats)
+    }
+
+    /// Returns the error writing the provenance document of the committed image, if provenance is enabled and the document could not be
+    /// written,
+    ///
+    /// The image is already published when the document is written, so the error does not fail `commit()`.
+    ///
+    pub fn provenance_error(&self) -> Option<&CimError> {
+        self.provenance_error.as_ref()
+    }
+
+    /// Returns self w/ writing a provenance document on commit enabled or disabled,
+    ///
+    /// The document is written next to the image as `<name>.provenance.json`, and records the digest of the image, the image it was forked
+    /// from, the srcs that were added and the options that affect the contents of the image, see `cimfs::provenance`. The default is disabled.
+    ///
+    pub fn with_provenance(mut self, enabled: bool) -> Self {
+        self.inputs = enabled.then(Vec::new);
+        self
     }
 
     /// Sets the volume id, chainable
@@ -95,63 +264,214 @@ impl Image {
         self
     }
 
+    /// Returns a reference to the backend,
+    ///
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Returns the name of the image file,
+    ///
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     /// Builds the image from a list of objects and a set of their ancestors,
     ///
+    /// Objects whose src shares a file id w/ the src of an object that was already added are created as hard links, so that the data is only stored once.
+    /// Metadata overrides of the hard link are ignored, since all links share the metadata of the first file.
+    ///
+    /// If the build fails, the image is closed w/o being committed and the files written for it are removed.
+    ///
     pub fn build(&mut self, objects: Vec<Object>, ancestors: BTreeSet<Object>) -> Result<()> {
-        // Create ancestors
+        let result = self.build_objects(objects, ancestors);
+        if result.is_err() {
+            self.discard();
+        }
+        result
+    }
+
+    /// Adds objects and their ancestors to the image,
+    ///
+    fn build_objects(&mut self, objects: Vec<Object>, ancestors: BTreeSet<Object>) -> Result<()> {
+        let (jobs, _, skipped) = self.jobs(&objects, &ancestors)?;
+
+        if self.image_handle.is_none() {
+            return Err(image_not_open());
+        }
+
+        self.notify(|o| {
+            o.build_started(Some(jobs.len()));
+            for relative_path in skipped {
+                o.entry_skipped(relative_path, "included in ancestors");
+            }
+        });
+
+        let (workers, budget) = (self.build_workers, self.build_buffer_budget);
+        let mut links = BTreeMap::new();
+        let mut write = |job: &Job, prefetched: Prefetched| {
+            trace!(
+                "Creating file at {:?} w/ src {:?}",
+                job.relative_path,
+                job.src
+            );
+            let links = if job.link { Some(&mut links) } else { None };
+            self.create_prefetched(&job.relative_path, &job.src, prefetched, links)
+        };
+
+        let result = if workers == 0 {
+            jobs.iter().try_for_each(|job| {
+                write(
+                    job,
+                    Prefetched::read(&job.src, &job.relative_path, job.overrides, None)?,
+                )
+            })
+        } else {
+            prefetch::run(&jobs, workers, budget, write)
+        };
+
+        if let (Ok(()), Some(inputs)) = (result.as_ref(), self.inputs.as_mut()) {
+            inputs.extend(jobs.iter().filter(|j| j.link).map(|j| Input {
+                relative_path: j.relative_path.clone(),
+                src: j.src.clone(),
+            }));
+        }
+
+        self.notify(|o| o.build_finished(result.as_ref().err()));
+        result
+    }
+
+    /// Returns the operations `build()` would apply to add objects and their ancestors, w/o creating or writing to the image,
+    ///
+    /// The metadata of each src is read to find hard links and the size of files. Files that would be deduplicated are planned as copies,
+    /// since their content is not read. Alternate data streams are not part of the plan.
+    ///
+    /// ```no_run
+    /// use cimfs::api::Image;
+    /// use cimfs::api::Object;
+    ///
+    /// let mut o = Object::new("src");
+    /// let ancestors = o.resolve_relative_path(true).unwrap();
+    /// let mut objects = o.expand(None).unwrap();
+    /// objects.insert(0, o);
+    ///
+    /// let plan = Image::new(".cimroot", "src.cim").plan(&objects, &ancestors).unwrap();
+    /// for operation in plan.operations.iter() {
+    ///     println!("{operation}");
+    /// }
+    /// ```
+    ///
+    pub fn plan(&self, objects: &[Object], ancestors: &BTreeSet<Object>) -> Result<BuildPlan> {
+        let (jobs, ancestor_jobs, skipped) = self.jobs(objects, ancestors)?;
+        let skipped = skipped.into_iter().cloned().collect();
+        Ok(BuildPlan::from_jobs(&jobs, ancestor_jobs, skipped)?)
+    }
+
+    /// Returns the jobs adding ancestors and then objects, the number of ancestor jobs, and the paths of objects that are included in ancestors,
+    ///
+    fn jobs<'a>(
+        &self,
+        objects: &'a [Object],
+        ancestors: &'a BTreeSet<Object>,
+    ) -> Result<(Vec<Job<'a>>, usize, Vec<&'a PathBuf>)> {
+        let mut jobs = vec![];
+        let mut skipped = vec![];
+
+        // Create ancestors, objects w/ different srcs can share an ancestor, ex. `bin` for `out/bin/a.exe` and `tools/bin/b.exe` added
+        // under `bin`, so ancestors are only created once per relative path
+        let mut ancestor_paths = BTreeSet::new();
         for a in ancestors.iter() {
-            let relative_path = a.get_relative_path()?;
-            let src_path = a.get_src_path()?;
+            let relative_path = a.get_relative_path().map_err(unresolved)?;
+            if !ancestor_paths.insert(relative_path) {
+                trace!("Skipping ancestor {:?}, already created", a);
+                continue;
+            }
+
             trace!("Creating ancestor {:?}", a);
-            self.create_file(relative_path.as_os_str(), src_path.as_os_str())?;
+            jobs.push(Job {
+                relative_path: relative_path.clone(),
+                src: src_path(
+                    a.get_src_path()
+                        .map_err(source_error(relative_path, None))?
+                        .as_os_str(),
+                ),
+                overrides: &NO_OVERRIDES,
+                link: false,
+            });
         }
 
         // Create objects
-        for o in objects {
-            let relative_path = o.get_relative_path()?;
-            if ancestors.contains(&o) {
+        let ancestor_jobs = jobs.len();
+        for o in objects.iter() {
+            let relative_path = o.get_relative_path().map_err(unresolved)?;
+            if ancestor_paths.contains(relative_path) {
                 trace!("Skipping {:?}, included in ancestors", relative_path);
+                skipped.push(relative_path);
                 continue;
             }
 
-            let src_path = o.get_src_path()?;
+            jobs.push(Job {
+                relative_path: relative_path.clone(),
+                src: src_path(
+                    o.get_src_path()
+                        .map_err(source_error(relative_path, None))?
+                        .as_os_str(),
+                ),
+                overrides: o.get_overrides(),
+                link: true,
+            });
+        }
 
-            trace!("Creating file at {:?} w/ src {:?}", relative_path, src_path);
-            self.create_file(relative_path.as_os_str(), src_path.as_os_str())?;
+        if self.normalization.as_ref().is_some_and(|n| n.sort_objects) {
+            jobs[ancestor_jobs..].sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
         }
 
-        Ok(())
+        Ok((jobs, ancestor_jobs, skipped))
     }
 
     /// Creates the current image,
     ///
+    /// The image is written to a staging folder in the root folder and published under its name by `commit()`. If the image is not
+    /// committed, ex. a build fails or the image is dropped, the staging folder is removed, so a partial image is never left behind. Returns an
+    /// error if an image w/ the same name already exists.
+    ///
     pub fn create(&mut self, existing: Option<&str>) -> Result<()> {
-        unsafe {
-            use crate::raw::CimCreateImage;
-
-            // Prepare parameters
-            let root = HSTRING::from(self.root_folder.as_os_str());
-            let file_name = HSTRING::from(self.name.as_str());
-            let existing_name = if let Some(existing) = existing.as_ref() {
-                HSTRING::from(*existing).as_ptr()
-            } else {
-                std::ptr::null()
-            };
-            let mut handle = std::ptr::null_mut();
+        self.discard();
 
-            let result: HRESULT = HRESULT(CimCreateImage(
-                root.as_ptr(),
-                existing_name,
-                file_name.as_ptr(),
-                std::ptr::addr_of_mut!(handle),
-            ));
-            if result.is_err() {
-                return Err(result.into());
+        let staging = if self.backend.writes_files() {
+            Some(
+                Staging::new(&self.root_folder, &self.name, existing)
+                    .map_err(backend_error("CimCreateImage", None))?,
+            )
+        } else {
+            None
+        };
+        let root = staging
+            .as_ref()
+            .map_or(self.root_folder.as_path(), |s| s.dir());
+
+        let handle = self
+            .backend
+            .create_image(root, existing, &self.name)
+            .map_err(backend_error("CimCreateImage", None));
+        if handle.is_err() {
+            if let Some(staging) = staging.as_ref() {
+                staging.discard();
             }
+        }
 
-            trace!("Got image handle -- {:?}", handle);
-            self.image_handle = Some(CimImageHandleWrapper { handle });
+        self.image_handle = Some(handle?);
+        self.staging = staging;
+        self.written = None;
+        if let Some(dedup) = self.dedup.as_mut() {
+            *dedup = Dedup::default();
         }
+        if let Some(inputs) = self.inputs.as_mut() {
+            inputs.clear();
+        }
+        self.existing = existing.map(str::to_string);
+        self.unvalidated_deletes = false;
+        self.provenance_error = None;
 
         Ok(())
     }
@@ -159,219 +479,659 @@ impl Image {
     /// Adds a file to the image at the relative path in the image, copying data from src,
     ///
     pub fn create_file(&mut self, relative_path: &OsStr, src: &OsStr) -> Result<()> {
-        let relative_path = relative_path.to_str().unwrap();
-        let src = src.to_str().unwrap().trim_start_matches("\\\\?\\");
-        trace!("Creating cim file for {} at {}", src, relative_path,);
-
-        if let Some(image_handle_wrapper) = self.image_handle.take() {
-            unsafe {
-                trace!("image handle -- {:?}", image_handle_wrapper);
-                use crate::raw::CimCloseStream;
-                use crate::raw::CimCreateFile;
-                use crate::raw::CimWriteStream;
-                use crate::raw::CIMFS_FILE_METADATA;
-
-                // Setup parameters
-                let relative_path = HSTRING::from(relative_path);
-                trace!("Getting handle for {}", src);
-                let handle = CreateFileW(
-                    &HSTRING::from(src).into(),
-                    GENERIC_READ.0, // (GENERIC_READ | GENERIC_ACCESS_RIGHTS(ACCESS_SYSTEM_SECURITY)).0,
-                    FILE_SHARE_READ,
-                    None,
-                    OPEN_EXISTING,
-                    FILE_FLAG_BACKUP_SEMANTICS | FILE_FLAG_OPEN_REPARSE_POINT,
-                    None,
-                )?;
-
-                let mut basic_info = FILE_BASIC_INFO::default();
-
-                GetFileInformationByHandleEx(
-                    handle,
-                    FileBasicInfo,
-                    std::ptr::addr_of_mut!(basic_info) as *mut c_void,
-                    std::mem::size_of_val(&basic_info) as u32,
-                )
-                .ok()?;
-
-                trace!("Got file info -- {:#?}", basic_info);
-
-                let mut metadata = CIMFS_FILE_METADATA {
-                    Attributes: basic_info.FileAttributes,
-                    CreationTime: crate::raw::to_large_int(basic_info.CreationTime),
-                    LastWriteTime: crate::raw::to_large_int(basic_info.LastWriteTime),
-                    ChangeTime: crate::raw::to_large_int(basic_info.ChangeTime),
-                    LastAccessTime: crate::raw::to_large_int(basic_info.LastAccessTime),
-                    FileSize: 0,
-                    SecurityDescriptorBuffer: std::ptr::null(),
-                    SecurityDescriptorSize: 0,
-                    ReparseDataBuffer: std::ptr::null(),
-                    ReparseDataSize: 0,
-                    EaBuffer: std::ptr::null(),
-                    EaBufferSize: 0,
-                };
+        self.create_file_from_src(relative_path, src, &Overrides::default(), None)
+    }
 
-                let mut is_dir = false;
-                if basic_info.FileAttributes & FILE_ATTRIBUTE_DIRECTORY.0 != 0 {
-                    metadata.FileSize = 0;
-                    is_dir = true;
-                } else {
-                    let mut file_size = 0;
-                    GetFileSizeEx(handle, std::ptr::addr_of_mut!(file_size)).ok()?;
-                    metadata.FileSize = file_size;
+    /// Adds a file to the image at the relative path in the image, copying data from src and applying overrides to its metadata,
+    ///
+    /// Overrides can be used to attach extended attributes or a security descriptor that the src does not have, ex.
+    ///
+    /// ```no_run
+    /// use cimfs::api::Image;
+    /// use cimfs::api::Overrides;
+    /// use cimfs::ea::ExtendedAttribute;
+    ///
+    /// let mut image = Image::new(".cimroot", "app.cim");
+    /// image.create(None).unwrap();
+    ///
+    /// let overrides = Overrides {
+    ///     extended_attributes: Some(vec![ExtendedAttribute::new("APP.VERSION", "1.2.3").unwrap()]),
+    ///     ..Default::default()
+    /// };
+    /// image
+    ///     .create_file_with_overrides("app.exe".as_ref(), "target/app.exe".as_ref(), &overrides)
+    ///     .unwrap();
+    /// ```
+    ///
+    pub fn create_file_with_overrides(
+        &mut self,
+        relative_path: &OsStr,
+        src: &OsStr,
+        overrides: &Overrides,
+    ) -> Result<()> {
+        self.create_file_from_src(relative_path, src, overrides, None)
+    }
+
+    /// Adds a file to the image at the relative path in the image, copying data from src and applying overrides to its metadata,
+    ///
+    /// If links is set, a src w/ multiple hard links that was already added is linked to the existing file instead of being copied.
+    ///
+    fn create_file_from_src(
+        &mut self,
+        relative_path: &OsStr,
+        src: &OsStr,
+        overrides: &Overrides,
+        links: Option<&mut BTreeMap<(u64, u64), PathBuf>>,
+    ) -> Result<()> {
+        let relative_path = Path::new(relative_path);
+        let src = src_path(src);
+        trace!("Creating cim file for {:?} at {:?}", src, relative_path);
+
+        if self.image_handle.is_none() {
+            return Err(image_not_open());
+        }
+
+        trace!("Getting handle for {:?}", src);
+        let prefetched = Prefetched::read(&src, relative_path, overrides, None)?;
+        self.create_prefetched(relative_path, &src, prefetched, links)?;
+
+        if let Some(inputs) = self.inputs.as_mut() {
+            inputs.push(Input {
+                relative_path: relative_path.to_path_buf(),
+                src,
+            });
+        }
+        Ok(())
+    }
+
+    /// Adds a src that was opened, and possibly read, ahead of time to the image at the relative path in the image,
+    ///
+    fn create_prefetched(
+        &mut self,
+        relative_path: &Path,
+        src: &Path,
+        prefetched: Prefetched,
+        links: Option<&mut BTreeMap<(u64, u64), PathBuf>>,
+    ) -> Result<()> {
+        let (mut file, id, metadata, data) = match prefetched {
+            Prefetched::Symlink(metadata) => {
+                trace!("Creating symlink for {:?}", src);
+                return self.create_entry(relative_path.as_os_str(), &metadata, std::io::empty());
+            }
+            Prefetched::File {
+                file,
+                id,
+                metadata,
+                data,
+            } => (file, id, metadata, data),
+        };
+
+        // Only files added by build() are deduplicated
+        let dedupable = links.is_some() && self.dedupable(relative_path, src, &metadata)?;
+        let links = match (links, id) {
+            (Some(links), Some(id)) => {
+                if let Some(existing) = links.get(&id).cloned() {
+                    trace!("{:?} is a hard link to {:?}", relative_path, existing);
+                    return self.create_hard_link(existing.as_os_str(), relative_path.as_os_str());
+                }
+                Some((links, id))
+            }
+            _ => None,
+        };
+
+        // Files are only hashed before they are written if a file of the same size was added, otherwise they are hashed while they are written
+        let mut content = None;
+        if dedupable
+            && self
+                .dedup
+                .as_ref()
+                .is_some_and(|d| d.has_size(metadata.file_size))
+        {
+            let position = file
+                .stream_position()
+                .map_err(source_error(relative_path, Some(src)))?;
+            let mut reader = Hashing::new(read_data(data.as_ref(), &mut file, relative_path, src));
+            std::io::copy(&mut reader, &mut std::io::sink())
+                .map_err(source_error(relative_path, Some(src)))?;
+            let (len, digest) = reader.finish();
+            file.seek(SeekFrom::Start(position))
+                .map_err(source_error(relative_path, Some(src)))?;
+
+            let existing = self
+                .dedup
+                .as_ref()
+                .and_then(|d| d.find(len, digest, &metadata))
+                .filter(|_| len == metadata.file_size)
+                .map(Path::to_path_buf);
+            if let Some(existing) = existing {
+                trace!("{:?} has the same content as {:?}", relative_path, existing);
+                self.create_hard_link(existing.as_os_str(), relative_path.as_os_str())?;
+                if let Some(dedup) = self.dedup.as_mut() {
+                    dedup.linked(len);
+                    dedup.insert(relative_path, len, digest, &metadata);
                 }
-                trace!("Getting file size -- {}", metadata.FileSize);
-
-                // Check for reparse point
-                let mut buf = BytesMut::with_capacity(MAXIMUM_REPARSE_DATA_BUFFER_SIZE as usize);
-                if basic_info.FileAttributes & FILE_ATTRIBUTE_REPARSE_POINT.0 != 0 {
-                    trace!("Getting reparse data");
-                    let mut bytes: c_ulong = 0;
-                    buf.set_len(MAXIMUM_REPARSE_DATA_BUFFER_SIZE as usize);
-
-                    DeviceIoControl(
-                        handle,
-                        FSCTL_GET_REPARSE_POINT,
-                        None,
-                        0,
-                        Some(buf.as_mut_ptr() as *mut c_void),
-                        buf.len() as c_ulong,
-                        Some(std::ptr::addr_of_mut!(bytes)),
-                        None,
-                    )
-                    .ok()?;
-
-                    metadata.ReparseDataBuffer = buf.freeze().as_ptr() as *const c_void;
-                    metadata.ReparseDataSize = bytes;
+                if let Some((links, id)) = links {
+                    links.insert(id, relative_path.to_path_buf());
                 }
+                return Ok(());
+            }
+            content = Some((len, digest));
+        }
 
-                // TODO: There seems to be issues getting this to work w/ cimfs
-                // let sec_info = DACL_SECURITY_INFORMATION
-                //     | LABEL_SECURITY_INFORMATION
-                //     | GROUP_SECURITY_INFORMATION
-                //     | OWNER_SECURITY_INFORMATION
-                //     | SACL_SECURITY_INFORMATION
-                //     | OBJECT_SECURITY_INFORMATION(PROCESS_TRUST_LABEL_SECURITY_INFORMATION);
-                // let mut desc: PSECURITY_DESCRIPTOR = PSECURITY_DESCRIPTOR::default();
-
-                // GetSecurityInfo(
-                //     handle,
-                //     SE_FILE_OBJECT,
-                //     sec_info.0,
-                //     None,
-                //     None,
-                //     None,
-                //     None,
-                //     Some(std::ptr::addr_of_mut!(desc)),
-                // )
-                // .ok()?;
-
-                // if desc.is_invalid() {
-                //     return Err(STATUS_UNSUCCESSFUL.into());
-                // }
-
-                // metadata.SecurityDescriptorBuffer = std::ptr::addr_of!(desc) as *const c_void;
-                // metadata.SecurityDescriptorSize = GetSecurityDescriptorLength(desc);
-                // trace!(
-                //     "Getting security information -- {:?} {}",
-                //     desc,
-                //     metadata.SecurityDescriptorSize
-                // );
-
-                // let ea = FILE_FULL_EA_INFORMATION::default();
-                // metadata.EaBuffer = std::ptr::addr_of!(ea) as *const c_void;
-                // metadata.EaBufferSize = std::mem::size_of_val(&ea) as u32;
-
-                let path = relative_path.as_wide();
-                let path = path.as_ptr();
-                let metadata_p = std::ptr::addr_of!(metadata);
-                trace!(
-                    "Creating file and getting stream handle, {:?} {:?} {:?} {:?}",
-                    relative_path,
-                    image_handle_wrapper.handle.is_null(),
-                    path.is_null(),
-                    metadata_p.is_null(),
-                );
-                let mut stream_handle = std::ptr::null_mut();
-
-                let result = HRESULT(CimCreateFile(
-                    image_handle_wrapper.handle,
-                    path,
-                    metadata_p,
-                    std::ptr::addr_of_mut!(stream_handle),
-                ));
-
-                trace!(
-                    "Stream handle result {:?} -- stream_handle_is_null -- {}",
-                    result,
-                    stream_handle.is_null()
-                );
+        let reader = read_data(data.as_ref(), &mut file, relative_path, src);
+        if dedupable && content.is_none() {
+            let mut reader = Hashing::new(reader);
+            self.create_entry(relative_path.as_os_str(), &metadata, &mut reader)?;
+            content = Some(reader.finish());
+        } else if metadata.is_directory() {
+            self.create_directory(relative_path.as_os_str(), &metadata)?;
+        } else {
+            self.create_entry(relative_path.as_os_str(), &metadata, reader)?;
+        }
 
-                result.ok()?;
-
-                let mut buffer = BytesMut::with_capacity(self._max_buffer_len);
-                buffer.set_len(self._max_buffer_len);
-
-                if !is_dir {
-                    trace!("Starting read");
-                    let mut total = 0;
-                    loop {
-                        let mut read = 0;
-                        ReadFile(
-                            handle,
-                            Some(buffer.as_mut_ptr() as *mut c_void),
-                            buffer.len() as u32,
-                            Some(std::ptr::addr_of_mut!(read)),
-                            None,
-                        )
-                        .ok()?;
-
-                        total += read;
-
-                        HRESULT(CimWriteStream(
-                            stream_handle,
-                            buffer.as_ptr() as *const c_void,
-                            read,
-                        ))
-                        .ok()?;
-
-                        if read == 0 {
-                            break;
-                        }
-                        trace!("{} of {} bytes transferred", total, metadata.FileSize);
-                        buffer.truncate(0);
-                        buffer.set_len(self._max_buffer_len);
-                    }
-                    trace!("Closing stream - total written {}", total);
-                }
+        if self.copy_alternate_streams {
+            let streams = crate::source::alternate_streams(src)
+                .map_err(source_error(relative_path, Some(src)))?;
+            for name in streams {
+                trace!("Copying alternate stream {:?} of {:?}", name, src);
+                let stream = crate::source::open_alternate_stream(src, &name)
+                    .map_err(source_error(relative_path, Some(src)))?;
+                let len = stream
+                    .metadata()
+                    .map_err(source_error(relative_path, Some(src)))?
+                    .len();
+                self.create_alternate_stream(relative_path.as_os_str(), &name, len, stream)?;
+            }
+        }
 
-                CimCloseStream(stream_handle);
-                CloseHandle(handle).ok()?;
+        if let Some((links, id)) = links {
+            links.insert(id, relative_path.to_path_buf());
+        }
 
-                // Restore the handle
-                self.image_handle = Some(image_handle_wrapper);
+        if let (Some(dedup), Some((len, digest))) = (self.dedup.as_mut(), content) {
+            // The src changed while it was read
+            if len == metadata.file_size {
+                dedup.insert(relative_path, len, digest, &metadata);
             }
-            Ok(())
+        }
+        Ok(())
+    }
+
+    /// Returns true if the contents of a src can be deduplicated,
+    ///
+    /// Directories, reparse points and empty files are not deduplicated, nor are files w/ alternate streams if they are copied, since hard links
+    /// share their streams.
+    ///
+    fn dedupable(&self, relative_path: &Path, src: &Path, metadata: &FileMetadata) -> Result<bool> {
+        if self.dedup.is_none()
+            || metadata.is_directory()
+            || metadata.file_size == 0
+            || metadata.attributes & FILE_ATTRIBUTE_REPARSE_POINT.0 != 0
+        {
+            return Ok(false);
+        }
+
+        if self.copy_alternate_streams {
+            let streams = crate::source::alternate_streams(src)
+                .map_err(source_error(relative_path, Some(src)))?;
+            return Ok(streams.is_empty());
+        }
+        Ok(true)
+    }
+
+    /// Adds an alternate data stream named stream_name to the file at relative_path in the image, copying data from a reader,
+    ///
+    /// The file must already exist in the image. len must be set to the number of bytes the reader will return, since the size of the stream
+    /// must be known when it is created, the data is then written in chunks like the data of `create_entry()`.
+    ///
+    pub fn create_alternate_stream(
+        &mut self,
+        relative_path: &OsStr,
+        stream_name: &str,
+        len: u64,
+        data: impl Read,
+    ) -> Result<()> {
+        if stream_name.is_empty() || stream_name.contains([':', '\\', '/']) {
+            return Err(CimError::PathInvalid {
+                relative_path: PathBuf::from(relative_path),
+                reason: format!("invalid alternate stream name {:?}", stream_name),
+            });
+        }
+
+        let mut path = relative_path.to_os_string();
+        path.push(":");
+        path.push(stream_name);
+        let path = PathBuf::from(path);
+
+        trace!("Creating alternate stream {:?}, {} bytes", path, len);
+
+        let image_handle = self.image_handle.as_mut().ok_or_else(image_not_open)?;
+        let mut stream_handle = self
+            .backend
+            .create_alternate_stream(image_handle, &path, len)
+            .map_err(backend_error("CimCreateAlternateStream", Some(&path)))?;
+
+        let result = self.write_stream(&mut stream_handle, &path, len, data, |_, _| {});
+
+        self.backend.close_stream(stream_handle);
+        result
+    }
+
+    /// Creates a hard link at relative_path in the image to an existing file in the image,
+    ///
+    /// The existing file can also be a file in the image this image was forked from.
+    ///
+    pub fn create_hard_link(&mut self, existing: &OsStr, relative_path: &OsStr) -> Result<()> {
+        trace!("Creating hard link {:?} -> {:?}", relative_path, existing);
+        let image_handle = self.image_handle.as_mut().ok_or_else(image_not_open)?;
+        if let Some(dedup) = self.dedup.as_mut() {
+            dedup.forget(Path::new(relative_path));
+        }
+        self.backend
+            .create_hard_link(image_handle, Path::new(relative_path), Path::new(existing))
+            .map_err(backend_error(
+                "CimCreateHardLink",
+                Some(Path::new(relative_path)),
+            ))?;
+
+        self.notify(|o| o.entry_linked(Path::new(relative_path), Path::new(existing)));
+        Ok(())
+    }
+
+    /// Creates a symlink at relative_path in the image to target, w/o a src symlink on disk,
+    ///
+    /// Set directory if the target is a directory. The times of the symlink are set to the current time.
+    ///
+    pub fn create_symlink(
+        &mut self,
+        relative_path: &OsStr,
+        target: &str,
+        directory: bool,
+    ) -> Result<()> {
+        let now = crate::source::now();
+        let metadata = FileMetadata {
+            attributes: if directory {
+                FILE_ATTRIBUTE_DIRECTORY.0
+            } else {
+                FILE_ATTRIBUTE_NORMAL.0
+            },
+            creation_time: now,
+            last_write_time: now,
+            change_time: now,
+            last_access_time: now,
+            ..Default::default()
+        };
+
+        self.create_reparse_point(relative_path, &ReparseData::symlink(target), &metadata)
+    }
+
+    /// Creates a reparse point at relative_path in the image w/o a src, ex. a symlink or junction,
+    ///
+    /// The attributes and times are taken from metadata, the reparse point attribute is always set and the reparse data of metadata is replaced.
+    ///
+    pub fn create_reparse_point(
+        &mut self,
+        relative_path: &OsStr,
+        reparse_data: &ReparseData,
+        metadata: &FileMetadata,
+    ) -> Result<()> {
+        trace!(
+            "Creating reparse point {:?} -- {:?}",
+            relative_path,
+            reparse_data
+        );
+        let mut metadata = FileMetadata {
+            file_size: 0,
+            reparse_data: reparse_data
+                .to_bytes()
+                .map_err(|err| CimError::PathInvalid {
+                    relative_path: PathBuf::from(relative_path),
+                    reason: err.to_string(),
+                })?,
+            ..metadata.clone()
+        };
+        metadata.attributes |= FILE_ATTRIBUTE_REPARSE_POINT.0;
+        metadata.attributes &= !FILE_ATTRIBUTE_NORMAL.0;
+
+        self.create_entry(relative_path, &metadata, std::io::empty())
+    }
+
+    /// Creates a directory at relative_path in the image w/ metadata, w/o a src,
+    ///
+    /// The directory attribute is always set and the file size of metadata is ignored.
+    ///
+    pub fn create_directory(
+        &mut self,
+        relative_path: &OsStr,
+        metadata: &FileMetadata,
+    ) -> Result<()> {
+        trace!("Creating directory {:?}", relative_path);
+        let mut metadata = FileMetadata {
+            file_size: 0,
+            ..metadata.clone()
+        };
+        metadata.attributes |= FILE_ATTRIBUTE_DIRECTORY.0;
+        metadata.attributes &= !FILE_ATTRIBUTE_NORMAL.0;
+
+        self.create_entry(relative_path, &metadata, std::io::empty())
+    }
+
+    /// Adds an entry to the image at the relative path in the image w/ metadata, copying data from a reader, ex. a generated file,
+    ///
+    /// The file size of metadata must be set to the number of bytes the reader will return. Data is not read for directories, see
+    /// `create_directory()`. Files created from src files w/ `create_file()` and `build()` are also added w/ this function.
+    ///
+    /// Returns an InvalidData error carrying `CimError::SourceUnreadable` if the reader returns fewer or more bytes than the file size, ex.
+    /// if a src file changed after its metadata was read. Data past the file size is not written to the image.
+    ///
+    /// ```no_run
+    /// use cimfs::api::FileMetadata;
+    /// use cimfs::api::Image;
+    ///
+    /// let mut image = Image::new(".cimroot", "app.cim");
+    /// image.create(None).unwrap();
+    ///
+    /// let config = b"level = \"debug\"";
+    /// let metadata = FileMetadata {
+    ///     attributes: 0x80, // FILE_ATTRIBUTE_NORMAL
+    ///     file_size: config.len() as u64,
+    ///     ..Default::default()
+    /// };
+    /// image.create_directory("etc".as_ref(), &FileMetadata::default()).unwrap();
+    /// image.create_entry("etc/app.toml".as_ref(), &metadata, &config[..]).unwrap();
+    /// image.commit().unwrap();
+    /// ```
+    ///
+    pub fn create_entry(
+        &mut self,
+        relative_path: &OsStr,
+        metadata: &FileMetadata,
+        mut data: impl Read,
+    ) -> Result<()> {
+        let relative_path = Path::new(relative_path);
+        let normalized = self.normalization.as_ref().map(|n| {
+            let mut metadata = metadata.clone();
+            n.apply(&mut metadata);
+            metadata
+        });
+        let metadata = normalized.as_ref().unwrap_or(metadata);
+
+        let image_handle = self.image_handle.as_mut().ok_or_else(image_not_open)?;
+        if let Some(dedup) = self.dedup.as_mut() {
+            dedup.forget(relative_path);
+        }
+        if let Some(o) = self.observer.as_mut() {
+            o.entry_started(relative_path, metadata);
+        }
+
+        let mut stream_handle = self
+            .backend
+            .create_file(image_handle, relative_path, metadata)
+            .map_err(backend_error("CimCreateFile", Some(relative_path)))?;
+
+        let result = if !metadata.is_directory() {
+            trace!("Starting read");
+            self.write_stream(
+                &mut stream_handle,
+                relative_path,
+                metadata.file_size,
+                &mut data,
+                |image, read| {
+                    if let Some(o) = image.observer.as_mut() {
+                        o.bytes_transferred(relative_path, read);
+                    }
+                },
+            )
         } else {
-            Err(STATUS_UNSUCCESSFUL.into())
+            Ok(())
+        };
+
+        trace!("Closing stream");
+        self.backend.close_stream(stream_handle);
+        result?;
+
+        self.notify(|o| o.entry_finished(relative_path));
+        Ok(())
+    }
+
+    /// Copies data to an open stream in the image in chunks of the transfer buffer len, calling transferred w/ the length of each chunk,
+    ///
+    /// Returns an InvalidData error if data returns fewer or more than len bytes, data past len is not written.
+    ///
+    fn write_stream(
+        &mut self,
+        stream_handle: &mut B::StreamHandle,
+        path: &Path,
+        len: u64,
+        mut data: impl Read,
+        mut transferred: impl FnMut(&mut Self, u64),
+    ) -> Result<()> {
+        let mut buffer = BytesMut::zeroed(self._max_buffer_len);
+        let mut total = 0;
+        loop {
+            let read = match data.read(&mut buffer) {
+                Ok(0) if total == len => return Ok(()),
+                Ok(0) => return Err(size_mismatch(path, len, total).into()),
+                Ok(read) => read,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(source_error(path, None)(err).into()),
+            };
+
+            // The reader returned more data than the stream len
+            if total + read as u64 > len {
+                return Err(size_mismatch(path, len, total + read as u64).into());
+            }
+
+            self.backend
+                .write_stream(stream_handle, &buffer[..read])
+                .map_err(backend_error("CimWriteStream", Some(path)))?;
+
+            total += read as u64;
+            trace!("{} of {} bytes transferred", total, len);
+            transferred(self, read as u64);
+        }
+    }
+
+    /// Calls f w/ the observer if one is set,
+    ///
+    fn notify(&mut self, f: impl FnOnce(&mut dyn BuildObserver)) {
+        if let Some(o) = self.observer.as_deref_mut() {
+            f(o);
         }
     }
 
+    /// Deletes a path from the image, such as a file inherited from the image this image was forked from,
+    ///
+    /// Returns a `CimError::PathNotFound` error if CimDeletePath fails w/ ERROR_FILE_NOT_FOUND or ERROR_PATH_NOT_FOUND. The on-disk format of
+    /// CimFS images is not documented, so the path is not validated against the image this image was forked from, a warning is logged on the
+    /// first delete since a missing path is only reported if CimDeletePath fails.
+    ///
+    pub fn delete_path(&mut self, relative_path: &OsStr) -> Result<()> {
+        let relative_path = Path::new(relative_path);
+        trace!("Deleting {:?}", relative_path);
+
+        if !self.unvalidated_deletes {
+            warn!(
+                "Deletes from {} are not validated against {}, a missing path is only reported if CimDeletePath fails",
+                self.name,
+                self.existing.as_deref().unwrap_or("the existing image")
+            );
+            self.unvalidated_deletes = true;
+        }
+
+        let image_handle = self.image_handle.as_mut().ok_or_else(image_not_open)?;
+        if let Some(dedup) = self.dedup.as_mut() {
+            dedup.forget(relative_path);
+        }
+        self.backend
+            .delete_path(image_handle, relative_path)
+            .map_err(|err| {
+                if is_not_found(&err) {
+                    trace!("{:?} does not exist in the image -- {err}", relative_path);
+                    CimError::PathNotFound {
+                        relative_path: relative_path.to_path_buf(),
+                    }
+                } else {
+                    backend_error("CimDeletePath", Some(relative_path))(err).into()
+                }
+            })
+    }
+
     /// Commits the image,
     ///
     pub fn commit(&mut self) -> Result<()> {
         trace!("Committing image");
 
-        if let Some(image_handle) = self.image_handle.take() {
-            unsafe {
-                use crate::raw::CimCommitImage;
+        let mut image_handle = self.image_handle.take().ok_or_else(image_not_open)?;
+        let result = self
+            .backend
+            .commit_image(&mut image_handle)
+            .map_err(backend_error("CimCommitImage", None));
+        self.backend.close_image(image_handle);
+
+        let result = match (result, self.staging.as_ref()) {
+            (Ok(()), Some(staging)) => staging
+                .publish(&self.root_folder, &self.name)
+                .map(Some)
+                .map_err(backend_error("CimCommitImage", None)),
+            (result, _) => result.map(|_| None),
+        };
 
-                HRESULT(CimCommitImage(image_handle.handle)).ok()?;
+        if result.is_err() {
+            self.discard();
+        }
+        self.staging = None;
+        self.written = result?.map(|written| {
+            written
+                .into_iter()
+                .map(|f| f.to_string_lossy().into_owned())
+                .collect()
+        });
+
+        // The image is published at this point, so failing to describe it does not fail the commit
+        if self.inputs.is_some() {
+            if let Err(err) = self.write_provenance() {
+                warn!("Could not write the provenance of {} -- {err}", self.name);
+                self.provenance_error = Some(err);
             }
+        }
+        Ok(())
+    }
 
-            Ok(())
-        } else {
-            Err(STATUS_UNSUCCESSFUL.into())
+    /// Removes the committed image, its provenance document and the region and object id files written for it,
+    ///
+    /// Can be used to roll back a chain of forks when building a later image fails. Only an image committed by this `Image` can be removed,
+    /// since the files written for other images are not known. The files of the image it was forked from are kept.
+    ///
+    pub fn remove(&mut self) -> Result<()> {
+        let Some(written) = self.written.take() else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} was not committed by this image", self.name),
+            )
+            .into());
+        };
+
+        // The image file is removed first, so that the image can't be opened w/ some of its files missing
+        let provenance = Provenance::path(&self.root_folder, &self.name);
+        let mut result = Ok(());
+        for path in std::iter::once(self.root_folder.join(&self.name))
+            .chain(written.iter().map(|f| self.root_folder.join(f)))
+            .chain([provenance])
+        {
+            trace!("Removing {:?}", path);
+            match std::fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => {
+                    warn!("Could not remove {:?} -- {err}", path);
+                    if result.is_ok() {
+                        result = Err(Error::new(err.kind(), format!("{err} -- {:?}", path)));
+                    }
+                }
+            }
+        }
+        Ok(result?)
+    }
+
+    /// Returns the digest of the committed image, computed over the image file and every region and object id file it references,
+    ///
+    /// If the image was committed by this `Image`, the files are the files written for the image and the files of the image it was forked
+    /// from, otherwise they are the files of the region sets the image file references, see `ImageDigest::compute()`.
+    ///
+    pub fn digest(&self) -> Result<ImageDigest> {
+        let Some(written) = self.written.as_ref() else {
+            return Ok(ImageDigest::compute(&self.root_folder, &self.name)?);
+        };
+
+        let mut files = written.clone();
+        if let Some(existing) = self.existing.as_ref() {
+            let base = self.base_digest(existing)?;
+            files.extend(base.files.into_iter().skip(1).map(|f| f.name));
+        }
+        Ok(ImageDigest::from_files(
+            &self.root_folder,
+            &self.name,
+            files,
+        )?)
+    }
+
+    /// Returns the digest of the image this image was forked from,
+    ///
+    /// The digest and files are read from the provenance document of the existing image if it has one, so that the base of a fork is
+    /// described the same way as when the base was committed.
+    ///
+    fn base_digest(&self, existing: &str) -> Result<ImageDigest> {
+        match Provenance::read(&self.root_folder, existing) {
+            Ok(Provenance { digest, files, .. }) => Ok(ImageDigest { digest, files }),
+            Err(err) => {
+                trace!("Could not read the provenance of {existing} -- {err}");
+                Ok(ImageDigest::compute(&self.root_folder, existing)?)
+            }
+        }
+    }
+
+    /// Writes the provenance document of the committed image,
+    ///
+    fn write_provenance(&mut self) -> Result<()> {
+        let ImageDigest { digest, files } = self.digest()?;
+        let base = match self.existing.as_ref() {
+            Some(existing) => Some(BaseImage {
+                image: existing.clone(),
+                digest: self.base_digest(existing)?.digest,
+            }),
+            None => None,
+        };
+
+        let provenance = Provenance {
+            image: self.name.clone(),
+            digest,
+            files,
+            base,
+            inputs: self.inputs.take().unwrap_or_default(),
+            options: BuildOptions {
+                alternate_streams: self.copy_alternate_streams,
+                deduplication: self.dedup.is_some(),
+                normalization: self.normalization.clone(),
+            },
+            builder: format!("cimfs {}", env!("CARGO_PKG_VERSION")),
+        };
+
+        trace!("Writing provenance of {}", self.name);
+        let result = provenance.write(&self.root_folder);
+        self.inputs = Some(vec![]);
+        Ok(result?)
+    }
+
+    /// Closes the image handle w/o committing and removes the files written for the image,
+    ///
+    fn discard(&mut self) {
+        if let Some(image_handle) = self.image_handle.take() {
+            self.backend.close_image(image_handle);
+        }
+
+        if let Some(staging) = self.staging.take() {
+            staging.discard();
         }
     }
 
@@ -381,33 +1141,18 @@ impl Image {
     ///
     pub fn mount(&mut self, volume_guid: Option<String>) -> Result<GUID> {
         let guid = if let Some(volume) = volume_guid {
-            GUID::try_from(volume.as_str())
-                .map_err(|_| Error::new(E_INVALIDARG, "Could not parse guid".into()))?
+            crate::backend::parse_guid(volume.as_str())
+                .map_err(backend_error("CimMountImage", None))?
         } else if let Some(existing) = self.volume.take() {
             existing
         } else {
-            unsafe {
-                let mut guid = GUID::zeroed();
-
-                let status = UuidCreate(std::ptr::addr_of_mut!(guid));
-                if status.0 != 0 {
-                    return Err(Error::new(E_FAIL, "Could not generate a new uuid".into()));
-                }
-
-                guid
-            }
+            crate::backend::new_guid().map_err(backend_error("CimMountImage", None))?
         };
 
-        unsafe {
-            trace!("Mounting image");
-            HRESULT(CimMountImage(
-                HSTRING::from(self.root_folder.as_os_str()).as_ptr(),
-                HSTRING::from(self.name.as_str()).as_ptr(),
-                CIM_MOUNT_IMAGE_FLAGS_CIM_MOUNT_IMAGE_NONE,
-                std::ptr::addr_of!(guid) as *const _GUID,
-            ))
-            .ok()?;
-        }
+        trace!("Mounting image");
+        self.backend
+            .mount_image(&self.root_folder, &self.name, &guid)
+            .map_err(backend_error("CimMountImage", None))?;
 
         self.volume = Some(guid);
 
@@ -418,45 +1163,1464 @@ impl Image {
     ///
     /// Returns an error if mount() was not called in the same process or with_volume() was not used.
     ///
-    pub fn mount_volume(&self, mountpoint: impl Into<PathBuf>) -> Result<()> {
+    pub fn mount_volume(&mut self, mountpoint: impl Into<PathBuf>) -> Result<()> {
         if let Some(volume) = self.volume.as_ref() {
-            unsafe {
-                let volume_path = format!("\\\\?\\Volume{{{:?}}}\\", volume);
-                let mut mountpoint = mountpoint.into();
+            self.backend
+                .set_volume_mount_point(volume, &mountpoint.into())
+                .map_err(backend_error("SetVolumeMountPointW", None))?;
+            Ok(())
+        } else {
+            Err(backend_error("SetVolumeMountPointW", None)(Error::new(ErrorKind::NotFound, "A volume id does not exist in the cache, it's likely mount() or with_volume() have yet been called")).into())
+        }
+    }
+}
 
-                let mountpoint = mountpoint.as_mut_os_string();
-                mountpoint.push(OsString::from("\\"));
+impl<B: CimBackend> Drop for Image<B> {
+    fn drop(&mut self) {
+        self.discard();
+    }
+}
 
-                let mountpoint = HSTRING::from(mountpoint.as_os_str());
-                let volume_path = HSTRING::from(volume_path);
+/// Returns the path of a src w/o the `\\?\` prefix,
+///
+fn src_path(src: &OsStr) -> PathBuf {
+    PathBuf::from(src.to_string_lossy().trim_start_matches("\\\\?\\"))
+}
 
-                trace!(
-                    "Trying to set mountpoint {} for {}",
-                    mountpoint.to_string(),
-                    volume_path.to_string()
-                );
-                SetVolumeMountPointW(PCWSTR(mountpoint.as_ptr()), PCWSTR(volume_path.as_ptr()))
-                    .ok()?;
-            }
+/// Returns a reader over the data of a src, starting w/ the data that was read ahead of time if any,
+///
+fn read_data<'a>(
+    data: Option<&'a prefetch::Buffered>,
+    file: &'a mut std::fs::File,
+    relative_path: &'a Path,
+    src: &'a Path,
+) -> ReadContext<'a, Box<dyn Read + 'a>> {
+    let reader: Box<dyn Read + 'a> = match data {
+        Some(data) => Box::new(data.chain(file)),
+        None => Box::new(file),
+    };
+    ReadContext::new(reader, relative_path, src)
+}
 
-            Ok(())
+/// Returns the error returned when the data of an entry does not match the file size of its metadata,
+///
+/// read is the number of bytes read when the mismatch was detected, which is not the total length of the data if it is too long.
+///
+fn size_mismatch(relative_path: &Path, file_size: u64, read: u64) -> Error {
+    source_error(relative_path, None)(Error::new(
+        ErrorKind::InvalidData,
+        if read < file_size {
+            format!("Data ended after {read} bytes, expected {file_size} bytes")
         } else {
-            Err(Error::new(E_NOINTERFACE, "A volume id does not exist in the cache, it's likely mount() or with_volume() have yet been called".into()))
-        }
+            format!("Data is longer than the expected {file_size} bytes")
+        },
+    ))
+}
+
+/// Returns the error returned when the relative path of an object has not been resolved,
+///
+fn unresolved(err: Error) -> CimError {
+    CimError::PathInvalid {
+        relative_path: PathBuf::new(),
+        reason: err.to_string(),
     }
 }
 
-/// Wrapper struct over the image handle so that the image can be closed when this struct is dropped,
+/// Returns the error returned when an image handle is required but create() has not been called,
 ///
-#[derive(Debug)]
-struct CimImageHandleWrapper {
-    handle: CIMFS_IMAGE_HANDLE,
+fn image_not_open() -> CimError {
+    CimError::ImageNotOpen
 }
 
-impl Drop for CimImageHandleWrapper {
-    fn drop(&mut self) {
-        unsafe {
-            crate::raw::CimCloseImage(self.handle);
+#[allow(unused_imports)]
+mod tests {
+    use super::AsyncImage;
+    use super::BuildObserver;
+    use super::BuildOperation;
+    use super::BuildPlan;
+    use super::DedupStats;
+    use super::Image;
+    use super::Normalization;
+    use crate::api::Object;
+    use crate::api::Overrides;
+    use crate::backend::FileMetadata;
+    use crate::backend::RecordedEntry;
+    use crate::backend::RecordingBackend;
+    use crate::error::CimError;
+    use crate::provenance::ImageDigest;
+    use crate::provenance::Provenance;
+    use crate::region::referenced_files;
+    use crate::reparse::ReparseData;
+    #[cfg(test)]
+    use crate::scratch::ScratchDir;
+    use std::cell::RefCell;
+    use std::collections::BTreeSet;
+    use std::path::Path;
+    use std::path::PathBuf;
+    use std::rc::Rc;
+    use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_DIRECTORY;
+    use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_NORMAL;
+    use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_READONLY;
+
+    /// Returns a backend that writes the files of committed images to their root folder, like CimFS does,
+    ///
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn file_backend() -> RecordingBackend {
+        RecordingBackend {
+            write_files: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_build() {
+        let mut objects = vec![];
+        let mut ancestors = BTreeSet::new();
+        for o in ["src/lib.rs", "src/bin/cimutil.rs"] {
+            let mut o = Object::new(o);
+            ancestors.append(&mut o.resolve_relative_path(true).unwrap());
+            objects.push(o);
+        }
+
+        let mut image = Image::with_backend(".cimroot", "test.cim", RecordingBackend::default())
+            .with_transfer_buf_len(64);
+        image.create(None).unwrap();
+        image.build(objects, ancestors).unwrap();
+        image.commit().unwrap();
+
+        let recorded = image.backend().last_image().unwrap();
+        assert!(recorded.committed);
+        assert!(recorded.closed);
+        assert_eq!("test.cim", recorded.name);
+
+        let paths = recorded
+            .entries
+            .iter()
+            .map(|e| e.path().to_path_buf())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                PathBuf::from("src"),
+                PathBuf::from("src/bin"),
+                PathBuf::from("src/lib.rs"),
+                PathBuf::from("src/bin/cimutil.rs"),
+            ],
+            paths
+        );
+
+        match &recorded.entries[2] {
+            RecordedEntry::File { metadata, data, .. } => {
+                assert!(!metadata.is_directory());
+                assert_eq!(std::fs::read("src/lib.rs").unwrap(), *data);
+                assert_eq!(data.len() as u64, metadata.file_size);
+            }
+            e => panic!("unexpected entry {:?}", e),
+        }
+
+        match &recorded.entries[0] {
+            RecordedEntry::File { metadata, data, .. } => {
+                assert!(metadata.is_directory());
+                assert!(data.is_empty());
+            }
+            e => panic!("unexpected entry {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_build_from_tar() {
+        let mut builder = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_mtime(1);
+        header.set_mode(0o644);
+
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        builder
+            .append_data(&mut header.clone(), "./a/", &[][..])
+            .unwrap();
+
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(5);
+        builder
+            .append_data(&mut header.clone(), "a/b/c.txt", &b"hello"[..])
+            .unwrap();
+
+        header.set_size(0);
+        header.set_entry_type(tar::EntryType::Link);
+        builder
+            .append_link(&mut header.clone(), "a/d.txt", "a/b/c.txt")
+            .unwrap();
+
+        header.set_entry_type(tar::EntryType::Symlink);
+        builder
+            .append_link(&mut header.clone(), "a/e", "b/c.txt")
+            .unwrap();
+
+        header.set_entry_type(tar::EntryType::Fifo);
+        builder
+            .append_data(&mut header.clone(), "a/fifo", &[][..])
+            .unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        let mut image = Image::with_backend(".cimroot", "test.cim", RecordingBackend::default());
+        image.create(None).unwrap();
+        image.build_from_tar(archive.as_slice()).unwrap();
+        image.commit().unwrap();
+
+        let recorded = image.backend().last_image().unwrap();
+        assert_eq!(
+            vec![
+                PathBuf::from("a"),
+                PathBuf::from("a/b"),
+                PathBuf::from("a/b/c.txt"),
+                PathBuf::from("a/d.txt"),
+                PathBuf::from("a/e"),
+            ],
+            recorded
+                .entries
+                .iter()
+                .map(|e| e.path().to_path_buf())
+                .collect::<Vec<_>>()
+        );
+
+        match &recorded.entries[2] {
+            RecordedEntry::File { metadata, data, .. } => {
+                assert_eq!(b"hello".to_vec(), *data);
+                assert_eq!(5, metadata.file_size);
+                assert_eq!(crate::source::to_file_time(1, 0), metadata.last_write_time);
+            }
+            e => panic!("unexpected entry {:?}", e),
+        }
+
+        match &recorded.entries[3] {
+            RecordedEntry::HardLink { existing, .. } => {
+                assert_eq!(PathBuf::from("a/b/c.txt"), *existing)
+            }
+            e => panic!("unexpected entry {:?}", e),
+        }
+
+        match &recorded.entries[4] {
+            RecordedEntry::File { metadata, .. } => {
+                assert!(metadata.is_reparse_point());
+                assert_eq!(0xA000000C_u32.to_le_bytes(), metadata.reparse_data[..4]);
+            }
+            e => panic!("unexpected entry {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_build_from_oci_layer_keeps_base_directories() {
+        let layer = |mtime: u64, entries: &[(&str, tar::EntryType)]| {
+            let mut builder = tar::Builder::new(vec![]);
+            let mut header = tar::Header::new_gnu();
+            header.set_mtime(mtime);
+            header.set_mode(0o644);
+            header.set_size(0);
+            for (path, entry_type) in entries {
+                header.set_entry_type(*entry_type);
+                builder
+                    .append_data(&mut header.clone(), path, &[][..])
+                    .unwrap();
+            }
+            builder.into_inner().unwrap()
+        };
+
+        // d is in the base but not in the layer, and a whiteout of a path missing from the base is skipped
+        let mut backend = RecordingBackend::default();
+        backend.missing.insert(PathBuf::from("d/missing"));
+        let mut image = Image::with_backend(".cimroot", "fork.cim", backend);
+        image.create(Some("base.cim")).unwrap();
+        image
+            .build_from_oci_layer(
+                layer(
+                    9,
+                    &[
+                        ("d/b", tar::EntryType::Regular),
+                        ("d/.wh.missing", tar::EntryType::Regular),
+                    ],
+                )
+                .as_slice(),
+            )
+            .unwrap();
+        image.commit().unwrap();
+
+        // d keeps the metadata of the base
+        let recorded = image.backend().last_image().unwrap();
+        assert_eq!(
+            vec![PathBuf::from("d/b")],
+            recorded
+                .entries
+                .iter()
+                .map(|e| e.path().to_path_buf())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_build_from_tar_fork_creates_missing_ancestors() {
+        let mut builder = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(5);
+        builder
+            .append_data(&mut header.clone(), "a/b/c/d.txt", &b"hello"[..])
+            .unwrap();
+        builder
+            .append_data(&mut header.clone(), "a/b/e.txt", &b"hello"[..])
+            .unwrap();
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_size(0);
+        builder
+            .append_link(&mut header.clone(), "x/link", "a/b/e.txt")
+            .unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        // base.cim contains a but not a/b or x
+        let mut backend = RecordingBackend::default();
+        backend
+            .missing
+            .extend(["a/b", "a/b/c", "x"].map(PathBuf::from));
+        let mut image = Image::with_backend(".cimroot", "fork.cim", backend);
+        image.create(Some("base.cim")).unwrap();
+        image.build_from_tar(archive.as_slice()).unwrap();
+        image.commit().unwrap();
+
+        // a keeps the metadata of the base, only the ancestors that CimFS could not find are created
+        let recorded = image.backend().last_image().unwrap();
+        assert_eq!(
+            ["a/b", "a/b/c", "a/b/c/d.txt", "a/b/e.txt", "x", "x/link"]
+                .map(PathBuf::from)
+                .to_vec(),
+            recorded
+                .entries
+                .iter()
+                .map(|e| e.path().to_path_buf())
+                .collect::<Vec<_>>()
+        );
+        match &recorded.entries[2] {
+            RecordedEntry::File { data, .. } => assert_eq!(b"hello".to_vec(), *data),
+            e => panic!("unexpected entry {:?}", e),
+        }
+
+        // The target of a hard link in a missing directory may be the path that is missing, so the directory is not created
+        let mut builder = tar::Builder::new(vec![]);
+        builder
+            .append_link(&mut header.clone(), "x/link", "a/e.txt")
+            .unwrap();
+        let archive = builder.into_inner().unwrap();
+        let mut backend = RecordingBackend::default();
+        backend.missing.insert(PathBuf::from("x"));
+        let mut image = Image::with_backend(".cimroot", "fork.cim", backend);
+        image.create(Some("base.cim")).unwrap();
+        let err = image.build_from_tar(archive.as_slice()).unwrap_err();
+        assert_eq!(Some("CimCreateHardLink"), err.call());
+        assert!(image.backend().last_image().unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn test_build_from_oci_layer_opaque_after_children() {
+        let mut builder = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(1);
+        builder
+            .append_data(&mut header.clone(), "d/x", &b"x"[..])
+            .unwrap();
+
+        // The marker clears d, but d/x was already added by this layer
+        header.set_size(0);
+        builder
+            .append_data(&mut header.clone(), "d/.wh..wh..opq", &[][..])
+            .unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        let mut image = Image::with_backend(".cimroot", "test.cim", RecordingBackend::default());
+        image.create(None).unwrap();
+        let err = image.build_from_oci_layer(archive.as_slice()).unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+        assert!(err.to_string().contains("must precede"), "{err}");
+        assert_eq!(Some(Path::new("d")), err.relative_path());
+    }
+
+    #[test]
+    fn test_build_from_oci_layer_missing_whiteouts() {
+        let mut builder = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(0);
+        builder
+            .append_data(&mut header.clone(), "a/.wh.missing", &[][..])
+            .unwrap();
+        builder
+            .append_data(&mut header.clone(), "d/.wh..wh..opq", &[][..])
+            .unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        // Deletes fail the way CimDeletePath does for paths missing from the base
+        let mut backend = RecordingBackend::default();
+        backend.missing.insert(PathBuf::from("a/missing"));
+        backend.missing.insert(PathBuf::from("d"));
+
+        let mut image = Image::with_backend(".cimroot", "fork.cim", backend);
+        image.create(Some("base.cim")).unwrap();
+        image.build_from_oci_layer(archive.as_slice()).unwrap();
+        image.commit().unwrap();
+
+        let recorded = image.backend().last_image().unwrap();
+        assert!(recorded.committed);
+        match recorded.entries.as_slice() {
+            [RecordedEntry::File { path, metadata, .. }] => {
+                assert_eq!(Path::new("d"), path);
+                assert!(metadata.is_directory());
+            }
+            entries => panic!("unexpected entries {:?}", entries),
+        }
+    }
+
+    #[test]
+    fn test_build_hard_links() {
+        let scratch = ScratchDir::new("image-test-hard-links");
+        let root = scratch.path();
+        std::fs::write(root.join("a.txt"), b"hello").unwrap();
+        std::fs::hard_link(root.join("a.txt"), root.join("b.txt")).unwrap();
+        std::fs::write(root.join("c.txt"), b"hello").unwrap();
+
+        let objects = ["a.txt", "b.txt", "c.txt"]
+            .iter()
+            .map(|name| Object::with_destination(root.join(name), name))
+            .collect::<Vec<_>>();
+
+        let mut image = Image::with_backend(".cimroot", "test.cim", RecordingBackend::default());
+        image.create(None).unwrap();
+        image.build(objects, BTreeSet::new()).unwrap();
+        image
+            .create_hard_link("c.txt".as_ref(), "d.txt".as_ref())
+            .unwrap();
+        image.commit().unwrap();
+
+        let recorded = image.backend().last_image().unwrap();
+        assert!(matches!(&recorded.entries[0], RecordedEntry::File { .. }));
+        assert_eq!(
+            RecordedEntry::HardLink {
+                path: PathBuf::from("b.txt"),
+                existing: PathBuf::from("a.txt")
+            },
+            recorded.entries[1]
+        );
+        assert!(matches!(&recorded.entries[2], RecordedEntry::File { .. }));
+        assert_eq!(
+            RecordedEntry::HardLink {
+                path: PathBuf::from("d.txt"),
+                existing: PathBuf::from("c.txt")
+            },
+            recorded.entries[3]
+        );
+    }
+
+    #[test]
+    fn test_build_plan() {
+        let scratch = ScratchDir::new("image-test-build-plan");
+        let root = scratch.path();
+        std::fs::create_dir_all(root.join("tree/dir")).unwrap();
+        std::fs::write(root.join("tree/dir/a.txt"), b"hello").unwrap();
+        std::fs::hard_link(root.join("tree/dir/a.txt"), root.join("tree/dir/b.txt")).unwrap();
+        std::fs::write(root.join("tree/dir/c.txt"), b"hi").unwrap();
+
+        let mut o = Object::with_base_dir(root.join("tree/dir"), root, "").unwrap();
+        let ancestors = o.resolve_relative_path(true).unwrap();
+        let mut objects = o.expand(None).unwrap();
+        objects.insert(0, o);
+
+        let mut image = Image::with_backend(".cimroot", "test.cim", RecordingBackend::default());
+        let plan = image.plan(&objects, &ancestors).unwrap();
+        assert_eq!(vec![PathBuf::from("tree")], plan.ancestors);
+        assert!(plan.skipped.is_empty());
+        assert_eq!(7, plan.total_bytes);
+        assert_eq!(
+            BuildOperation::Link {
+                relative_path: PathBuf::from("tree/dir/b.txt"),
+                existing: PathBuf::from("tree/dir/a.txt"),
+            },
+            plan.operations[3]
+        );
+
+        let json = serde_json::to_string(&plan).unwrap();
+        assert_eq!(plan, serde_json::from_str::<BuildPlan>(&json).unwrap());
+
+        // The plan matches what build() does
+        image.create(None).unwrap();
+        image.build(objects, ancestors).unwrap();
+        image.commit().unwrap();
+        let recorded = image
+            .backend()
+            .last_image()
+            .unwrap()
+            .entries
+            .iter()
+            .map(|e| match e {
+                RecordedEntry::File { path, .. } => (path.clone(), false),
+                RecordedEntry::HardLink { path, .. } => (path.clone(), true),
+                e => panic!("unexpected entry {e:?}"),
+            })
+            .collect::<Vec<_>>();
+        let planned = plan
+            .operations
+            .iter()
+            .zip(plan.destinations())
+            .map(|(o, path)| (path.clone(), matches!(o, BuildOperation::Link { .. })))
+            .collect::<Vec<_>>();
+        assert_eq!(recorded, planned);
+
+        let plan = plan.with_deletes(["old.txt"]);
+        assert_eq!(
+            BuildOperation::Delete {
+                relative_path: PathBuf::from("old.txt")
+            },
+            plan.operations[0]
+        );
+        assert_eq!(5, plan.destinations().count());
+    }
+
+    #[test]
+    fn test_build_shared_ancestors() {
+        let scratch = ScratchDir::new("image-test-build-shared-ancestors");
+        let root = scratch.path();
+        std::fs::create_dir_all(root.join("x")).unwrap();
+        std::fs::create_dir_all(root.join("y")).unwrap();
+        std::fs::write(root.join("x/a.txt"), b"a").unwrap();
+        std::fs::write(root.join("y/b.txt"), b"b").unwrap();
+
+        // Both objects need `bin`, but w/ a different src
+        let mut objects = vec![
+            Object::with_destination(root.join("x/a.txt"), "bin/a.txt"),
+            Object::with_destination(root.join("y/b.txt"), "bin/b.txt"),
+        ];
+        let mut ancestors = BTreeSet::new();
+        for o in objects.iter_mut() {
+            ancestors.append(&mut o.resolve_relative_path(true).unwrap());
+        }
+        assert_eq!(2, ancestors.len());
+
+        let mut image = Image::with_backend(".cimroot", "test.cim", RecordingBackend::default());
+        let plan = image.plan(&objects, &ancestors).unwrap();
+        assert_eq!(vec![PathBuf::from("bin")], plan.ancestors);
+
+        image.create(None).unwrap();
+        image.build(objects, ancestors).unwrap();
+        image.commit().unwrap();
+        let paths = image
+            .backend()
+            .last_image()
+            .unwrap()
+            .entries
+            .iter()
+            .map(|e| match e {
+                RecordedEntry::File { path, .. } => path.clone(),
+                e => panic!("unexpected entry {e:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                PathBuf::from("bin"),
+                PathBuf::from("bin/a.txt"),
+                PathBuf::from("bin/b.txt")
+            ],
+            paths
+        );
+    }
+
+    #[test]
+    fn test_build_workers() {
+        let scratch = ScratchDir::new("image-test-build-workers");
+        let root = scratch.path();
+        std::fs::create_dir_all(root.join("dir")).unwrap();
+        for i in 0..64 {
+            std::fs::write(root.join(format!("dir/{i}.txt")), vec![i as u8; i * 100]).unwrap();
+        }
+        std::fs::hard_link(root.join("dir/10.txt"), root.join("link.txt")).unwrap();
+
+        let build = |workers: usize| {
+            let mut objects = vec![];
+            let mut ancestors = BTreeSet::new();
+            for name in (0..64)
+                .map(|i| format!("dir/{i}.txt"))
+                .chain(["link.txt".into()])
+            {
+                let mut o = Object::with_base_dir(root.join(name), root, "").unwrap();
+                ancestors.append(&mut o.resolve_relative_path(true).unwrap());
+                objects.push(o);
+            }
+
+            // The budget only fits a few files, the rest are read when they are written
+            let mut image =
+                Image::with_backend(".cimroot", "test.cim", RecordingBackend::default())
+                    .with_build_workers(workers)
+                    .with_build_buffer_budget(10000)
+                    .with_transfer_buf_len(64);
+            image.create(None).unwrap();
+            image.build(objects, ancestors).unwrap();
+            image.commit().unwrap();
+            let mut entries = image.backend().last_image().unwrap().entries.clone();
+            // Reading a src can update its access time
+            for e in entries.iter_mut() {
+                if let RecordedEntry::File { metadata, .. } = e {
+                    metadata.last_access_time = 0;
+                }
+            }
+            entries
+        };
+
+        let sequential = build(0);
+        assert_eq!(66, sequential.len());
+        assert_eq!(
+            RecordedEntry::HardLink {
+                path: PathBuf::from("link.txt"),
+                existing: PathBuf::from("dir/10.txt")
+            },
+            sequential[65]
+        );
+        assert_eq!(sequential, build(1));
+        assert_eq!(sequential, build(4));
+
+        // A src that can not be opened stops the build after the files before it are written
+        #[cfg(unix)]
+        {
+            let _socket = std::os::unix::net::UnixListener::bind(root.join("socket")).unwrap();
+            let mut image =
+                Image::with_backend(".cimroot", "test.cim", RecordingBackend::default())
+                    .with_build_workers(4);
+            image.create(None).unwrap();
+            let objects = ["dir/1.txt", "socket", "dir/2.txt"]
+                .iter()
+                .map(|name| Object::with_destination(root.join(name), name))
+                .collect::<Vec<_>>();
+            let err = image.build(objects, BTreeSet::new()).unwrap_err();
+            let recorded = image.backend().last_image().unwrap();
+            assert_eq!(1, recorded.entries.len());
+
+            assert!(matches!(err, CimError::SourceUnreadable { .. }));
+            assert_eq!(Some(Path::new("socket")), err.relative_path());
+            assert_eq!(Some(root.join("socket").as_path()), err.src());
+        }
+    }
+
+    #[test]
+    fn test_build_dedup() {
+        let scratch = ScratchDir::new("image-test-build-dedup");
+        let root = scratch.path();
+        std::fs::create_dir_all(root.join("dir")).unwrap();
+        std::fs::write(root.join("a.txt"), vec![b'a'; 1000]).unwrap();
+        std::fs::write(root.join("c.txt"), vec![b'c'; 1000]).unwrap();
+        std::fs::write(root.join("dir/b.txt"), vec![b'a'; 1000]).unwrap();
+        std::fs::write(root.join("e.txt"), vec![b'c'; 1000]).unwrap();
+
+        for workers in [0, 2] {
+            let mut objects = vec![];
+            let mut ancestors = BTreeSet::new();
+            for name in ["a.txt", "c.txt", "dir/b.txt", "e.txt"] {
+                let mut o = Object::with_base_dir(root.join(name), root, "").unwrap();
+                ancestors.append(&mut o.resolve_relative_path(true).unwrap());
+                objects.push(o);
+            }
+
+            let mut image =
+                Image::with_backend(".cimroot", "test.cim", RecordingBackend::default())
+                    .with_build_workers(workers)
+                    .with_deduplication(true);
+            image.create(None).unwrap();
+            image.build(objects, ancestors).unwrap();
+            assert_eq!(
+                Some(DedupStats {
+                    files: 2,
+                    bytes_saved: 2000
+                }),
+                image.dedup_stats()
+            );
+            image.commit().unwrap();
+
+            let entries = &image.backend().last_image().unwrap().entries;
+            assert_eq!(5, entries.len());
+            assert!(matches!(
+                &entries[2],
+                RecordedEntry::File { path, data, .. } if path == Path::new("c.txt") && *data == vec![b'c'; 1000]
+            ));
+            assert_eq!(
+                RecordedEntry::HardLink {
+                    path: PathBuf::from("dir/b.txt"),
+                    existing: PathBuf::from("a.txt")
+                },
+                entries[3]
+            );
+            assert_eq!(
+                RecordedEntry::HardLink {
+                    path: PathBuf::from("e.txt"),
+                    existing: PathBuf::from("c.txt")
+                },
+                entries[4]
+            );
+        }
+    }
+
+    #[test]
+    fn test_build_dedup_metadata_mismatch() {
+        let scratch = ScratchDir::new("image-test-build-dedup-mismatch");
+        let root = scratch.path();
+        std::fs::write(root.join("a.txt"), vec![b'a'; 1000]).unwrap();
+        std::fs::write(root.join("b.txt"), vec![b'a'; 1000]).unwrap();
+
+        // Same content, but hard links would share the attributes of a.txt
+        let a = Object::with_base_dir(root.join("a.txt"), root, "").unwrap();
+        let b = Object::with_base_dir(root.join("b.txt"), root, "")
+            .unwrap()
+            .with_overrides(Overrides {
+                attributes: Some(FILE_ATTRIBUTE_READONLY.0),
+                ..Default::default()
+            });
+
+        let mut image = Image::with_backend(".cimroot", "test.cim", RecordingBackend::default())
+            .with_deduplication(true);
+        image.create(None).unwrap();
+        image.build(vec![a, b], BTreeSet::new()).unwrap();
+        assert_eq!(
+            Some(DedupStats {
+                files: 0,
+                bytes_saved: 0
+            }),
+            image.dedup_stats()
+        );
+        image.commit().unwrap();
+
+        let entries = &image.backend().last_image().unwrap().entries;
+        assert_eq!(2, entries.len());
+        match &entries[1] {
+            RecordedEntry::File {
+                path,
+                metadata,
+                data,
+            } => {
+                assert_eq!(Path::new("b.txt"), path);
+                assert_eq!(FILE_ATTRIBUTE_READONLY.0, metadata.attributes);
+                assert_eq!(vec![b'a'; 1000], *data);
+            }
+            e => panic!("unexpected entry {:?}", e),
         }
     }
+
+    #[test]
+    fn test_build_observer() {
+        #[derive(Clone, Default)]
+        struct Events(Rc<RefCell<Vec<String>>>);
+
+        impl BuildObserver for Events {
+            fn build_started(&mut self, entries: Option<usize>) {
+                self.0.borrow_mut().push(format!("started {entries:?}"));
+            }
+
+            fn entry_started(&mut self, relative_path: &Path, metadata: &FileMetadata) {
+                let event = format!("entry {:?} {}", relative_path, metadata.file_size);
+                self.0.borrow_mut().push(event);
+            }
+
+            fn bytes_transferred(&mut self, relative_path: &Path, len: u64) {
+                let mut events = self.0.borrow_mut();
+                let event = format!("bytes {:?}", relative_path);
+                if events.last() != Some(&event) {
+                    events.push(event);
+                }
+                assert!(len <= 64);
+            }
+
+            fn entry_finished(&mut self, relative_path: &Path) {
+                self.0
+                    .borrow_mut()
+                    .push(format!("finished {:?}", relative_path));
+            }
+
+            fn entry_linked(&mut self, relative_path: &Path, existing: &Path) {
+                let event = format!("linked {:?} {:?}", relative_path, existing);
+                self.0.borrow_mut().push(event);
+            }
+
+            fn entry_skipped(&mut self, relative_path: &Path, _: &str) {
+                self.0
+                    .borrow_mut()
+                    .push(format!("skipped {:?}", relative_path));
+            }
+
+            fn build_finished(&mut self, error: Option<&crate::Error>) {
+                self.0
+                    .borrow_mut()
+                    .push(format!("done {}", error.is_none()));
+            }
+        }
+
+        let mut objects = vec![Object::new("src")];
+        let mut ancestors = BTreeSet::new();
+        let mut o = Object::new("src/lib.rs");
+        ancestors.append(&mut o.resolve_relative_path(true).unwrap());
+        objects[0].resolve_relative_path(true).unwrap();
+        objects.push(o);
+
+        let events = Events::default();
+        let mut image = Image::with_backend(".cimroot", "test.cim", RecordingBackend::default())
+            .with_transfer_buf_len(64)
+            .with_observer(events.clone());
+        image.create(None).unwrap();
+        image.build(objects, ancestors).unwrap();
+        image
+            .create_hard_link("src/lib.rs".as_ref(), "lib.rs".as_ref())
+            .unwrap();
+
+        let len = std::fs::metadata("src/lib.rs").unwrap().len();
+        assert_eq!(
+            vec![
+                "started Some(2)".to_string(),
+                "skipped \"src\"".to_string(),
+                "entry \"src\" 0".to_string(),
+                "finished \"src\"".to_string(),
+                format!("entry \"src/lib.rs\" {len}"),
+                "bytes \"src/lib.rs\"".to_string(),
+                "finished \"src/lib.rs\"".to_string(),
+                "done true".to_string(),
+                "linked \"lib.rs\" \"src/lib.rs\"".to_string(),
+            ],
+            *events.0.borrow()
+        );
+    }
+
+    #[test]
+    fn test_create_alternate_stream() {
+        let mut image = Image::with_backend(".cimroot", "test.cim", RecordingBackend::default())
+            .with_alternate_streams(true);
+        image.create(None).unwrap();
+        image
+            .create_file("lib.rs".as_ref(), "src/lib.rs".as_ref())
+            .unwrap();
+        let err = image
+            .create_alternate_stream("lib.rs".as_ref(), "short", 5, &b"abc"[..])
+            .unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+        image
+            .create_alternate_stream(
+                "lib.rs".as_ref(),
+                "Zone.Identifier",
+                14,
+                &b"[ZoneTransfer]"[..],
+            )
+            .unwrap();
+        assert!(image
+            .create_alternate_stream("lib.rs".as_ref(), "a:b", 0, &b""[..])
+            .is_err());
+        image.commit().unwrap();
+
+        let recorded = image.backend().last_image().unwrap();
+        assert_eq!(
+            RecordedEntry::AlternateStream {
+                path: PathBuf::from("lib.rs:Zone.Identifier"),
+                size: 14,
+                data: b"[ZoneTransfer]".to_vec()
+            },
+            *recorded.entries.last().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_delete_path() {
+        let scratch = ScratchDir::new("image-test-delete-path");
+        let root = scratch.path();
+
+        let mut image = Image::with_backend(root, "base.cim", file_backend());
+        image.create(None).unwrap();
+        image
+            .create_file("lib.rs".as_ref(), "src/lib.rs".as_ref())
+            .unwrap();
+        image.commit().unwrap();
+
+        let mut backend = file_backend();
+        backend.missing.insert(PathBuf::from("missing.rs"));
+        let mut image = Image::with_backend(root, "fork.cim", backend);
+        image.create(Some("base.cim")).unwrap();
+        let err = image.delete_path("missing.rs".as_ref()).unwrap_err();
+        assert_eq!(std::io::ErrorKind::NotFound, err.kind());
+        image.delete_path("lib.rs".as_ref()).unwrap();
+        image.commit().unwrap();
+
+        let recorded = image.backend().last_image().unwrap();
+        assert_eq!(Some("base.cim"), recorded.existing.as_deref());
+        assert_eq!(
+            vec![RecordedEntry::Delete {
+                path: PathBuf::from("lib.rs")
+            }],
+            recorded.entries
+        );
+        assert!(root.join("fork.cim").exists());
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn test_delete_path_not_found() {
+        // Only the backend knows the path is missing from the base
+        let mut backend = RecordingBackend::default();
+        backend.missing.insert(PathBuf::from("missing.rs"));
+
+        let mut image = Image::with_backend(".cimroot", "fork.cim", backend);
+        image.create(Some("base.cim")).unwrap();
+        let err = image.delete_path("missing.rs".as_ref()).unwrap_err();
+        assert!(matches!(
+            &err,
+            CimError::PathNotFound { relative_path } if relative_path == Path::new("missing.rs")
+        ));
+        assert_eq!(std::io::ErrorKind::NotFound, err.kind());
+        image.delete_path("lib.rs".as_ref()).unwrap();
+        image.commit().unwrap();
+
+        // Skipping the validation is logged once per image
+        logs_assert(|lines| {
+            match lines
+                .iter()
+                .filter(|l| l.contains("WARN") && l.contains("are not validated"))
+                .count()
+            {
+                1 => Ok(()),
+                n => Err(format!("expected 1 warning, found {n}")),
+            }
+        });
+    }
+
+    #[test]
+    fn test_uncommitted_image_is_discarded() {
+        let scratch = ScratchDir::new("image-test-discard");
+        let root = scratch.path();
+        let files = || {
+            let mut files = std::fs::read_dir(root)
+                .unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        };
+
+        let mut image = Image::with_backend(root, "base.cim", file_backend());
+        image.create(None).unwrap();
+        image
+            .create_file("lib.rs".as_ref(), "src/lib.rs".as_ref())
+            .unwrap();
+        image.commit().unwrap();
+        let base = files();
+        assert!(base.iter().all(|f| !f.ends_with(".tmp")));
+
+        // Dropped w/o commit
+        let mut image = Image::with_backend(root, "fork.cim", file_backend());
+        image.create(Some("base.cim")).unwrap();
+        image
+            .create_file("image.rs".as_ref(), "src/image.rs".as_ref())
+            .unwrap();
+        drop(image);
+        assert_eq!(base, files());
+
+        // Failed build
+        let mut image = Image::with_backend(root, "fork.cim", file_backend());
+        image.create(Some("base.cim")).unwrap();
+        let o = Object::with_destination("src/missing.rs", "missing.rs");
+        assert!(image.build(vec![o], BTreeSet::new()).is_err());
+        assert!(image.commit().is_err());
+        assert_eq!(base, files());
+
+        let mut image = Image::with_backend(root, "fork.cim", file_backend());
+        image.create(Some("base.cim")).unwrap();
+        image.commit().unwrap();
+        let base = referenced_files(root, "base.cim").unwrap();
+        assert!(referenced_files(root, "fork.cim")
+            .unwrap()
+            .is_superset(&base));
+        assert!(files().iter().all(|f| !f.ends_with(".tmp")));
+
+        let mut image = Image::with_backend(root, "fork.cim", file_backend());
+        assert_eq!(
+            std::io::ErrorKind::AlreadyExists,
+            image.create(Some("base.cim")).unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn test_concurrent_builds_are_not_discarded() {
+        let scratch = ScratchDir::new("image-test-concurrent-builds");
+        let root = scratch.path();
+
+        let mut image = Image::with_backend(root, "base.cim", file_backend());
+        image.create(None).unwrap();
+        image
+            .create_file("lib.rs".as_ref(), "src/lib.rs".as_ref())
+            .unwrap();
+        image.commit().unwrap();
+
+        // a is built while b is discarded in the same root folder
+        let mut a = Image::with_backend(root, "a.cim", file_backend());
+        a.create(Some("base.cim")).unwrap();
+        a.create_file("image.rs".as_ref(), "src/image.rs".as_ref())
+            .unwrap();
+
+        let mut b = Image::with_backend(root, "b.cim", file_backend());
+        b.create(Some("base.cim")).unwrap();
+        b.create_file("image.rs".as_ref(), "src/image.rs".as_ref())
+            .unwrap();
+        drop(b);
+
+        a.commit().unwrap();
+        assert!(a
+            .backend()
+            .last_image()
+            .unwrap()
+            .entries
+            .iter()
+            .any(|e| e.path() == Path::new("image.rs")));
+        let base = referenced_files(root, "base.cim").unwrap();
+        assert_eq!(2, base.len());
+        let files = referenced_files(root, "a.cim").unwrap();
+        assert_eq!(4, files.len());
+        assert!(files.is_superset(&base));
+        assert!(std::fs::read_dir(root).unwrap().all(|e| !e
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .ends_with(".tmp")));
+    }
+
+    #[test]
+    fn test_reproducible_build() {
+        let scratch = ScratchDir::new("image-test-reproducible");
+        let root = scratch.path();
+        std::fs::create_dir_all(root.join("src/dir")).unwrap();
+        std::fs::write(root.join("src/a.txt"), b"a").unwrap();
+        std::fs::write(root.join("src/dir/b.txt"), vec![7u8; 1000]).unwrap();
+
+        let build = |names: &[&str]| {
+            let mut objects = vec![];
+            let mut ancestors = BTreeSet::new();
+            for name in names {
+                let src = root.join("src").join(name);
+                // Times of the srcs differ between builds
+                std::fs::File::options()
+                    .write(true)
+                    .open(&src)
+                    .unwrap()
+                    .set_modified(std::time::SystemTime::now())
+                    .unwrap();
+
+                let mut o = Object::with_base_dir(src, root.join("src"), "").unwrap();
+                ancestors.append(&mut o.resolve_relative_path(true).unwrap());
+                objects.push(o);
+            }
+
+            let mut image = Image::with_backend(root, "test.cim", RecordingBackend::default())
+                .with_normalization(Normalization::from_unix_time(1_700_000_000));
+            image.create(None).unwrap();
+            image.build(objects, ancestors).unwrap();
+            image.commit().unwrap();
+            image.backend().last_image().unwrap().entries.clone()
+        };
+
+        let first = build(&["a.txt", "dir/b.txt"]);
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        let second = build(&["dir/b.txt", "a.txt"]);
+        assert_eq!(3, first.len());
+        assert!(first == second, "builds should add the same entries");
+
+        match first.iter().find(|e| e.path() == Path::new("dir/b.txt")) {
+            Some(RecordedEntry::File { metadata, .. }) => {
+                assert_eq!(
+                    crate::source::to_file_time(1_700_000_000, 0),
+                    metadata.last_write_time
+                );
+                assert_eq!(FILE_ATTRIBUTE_NORMAL.0, metadata.attributes);
+            }
+            e => panic!("unexpected entry {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_provenance() {
+        let scratch = ScratchDir::new("image-test-provenance");
+        let root = scratch.path();
+
+        let mut image = Image::with_backend(root, "base.cim", file_backend());
+        image.create(None).unwrap();
+        image
+            .create_file("lib.rs".as_ref(), "src/lib.rs".as_ref())
+            .unwrap();
+        image.commit().unwrap();
+        let base = image.digest().unwrap();
+        assert_eq!(3, base.files.len());
+        assert!(!Provenance::path(root, "base.cim").exists());
+
+        let mut image = Image::with_backend(root, "fork.cim", file_backend())
+            .with_provenance(true)
+            .with_deduplication(true);
+        image.create(Some("base.cim")).unwrap();
+        let mut o = Object::new("src/backend");
+        let ancestors = o.resolve_relative_path(true).unwrap();
+        image.build(vec![o], ancestors).unwrap();
+        image
+            .create_file("image.rs".as_ref(), "src/image.rs".as_ref())
+            .unwrap();
+        image.commit().unwrap();
+
+        let provenance = Provenance::read(root, "fork.cim").unwrap();
+        let digest = image.digest().unwrap();
+        assert_eq!(digest.digest, provenance.digest);
+        assert_eq!(digest.files, provenance.files);
+        assert_eq!(5, provenance.files.len());
+        assert_eq!(base.digest, provenance.base.unwrap().digest);
+        assert_eq!(
+            vec![PathBuf::from("src/backend"), PathBuf::from("image.rs")],
+            provenance
+                .inputs
+                .iter()
+                .map(|i| i.relative_path.clone())
+                .collect::<Vec<_>>()
+        );
+        assert!(provenance.inputs[1].src.ends_with("src/image.rs"));
+        assert!(provenance.options.deduplication);
+
+        // The digest does not depend on the name of the image file
+        std::fs::copy(root.join("fork.cim"), root.join("renamed.cim")).unwrap();
+        assert_eq!(
+            digest.digest,
+            ImageDigest::compute(root, "renamed.cim").unwrap().digest
+        );
+        assert_ne!(base.digest, digest.digest);
+    }
+
+    #[test]
+    fn test_provenance_after_publish() {
+        let scratch = ScratchDir::new("image-test-provenance-after-publish");
+        let root = scratch.path();
+
+        // The provenance document can't replace a directory
+        std::fs::create_dir(Provenance::path(root, "base.cim")).unwrap();
+
+        let mut image = Image::with_backend(root, "base.cim", file_backend()).with_provenance(true);
+        image.create(None).unwrap();
+        image
+            .create_file("lib.rs".as_ref(), "src/lib.rs".as_ref())
+            .unwrap();
+        image.commit().unwrap();
+        assert!(root.join("base.cim").exists());
+        assert!(Provenance::read(root, "base.cim").is_err());
+        assert!(image.provenance_error().is_some());
+
+        // A later build of the same image resets the error
+        std::fs::remove_dir(Provenance::path(root, "base.cim")).unwrap();
+        image.remove().unwrap();
+        image.create(None).unwrap();
+        image.commit().unwrap();
+        assert!(image.provenance_error().is_none());
+        assert!(Provenance::read(root, "base.cim").is_ok());
+    }
+
+    #[test]
+    fn test_remove() {
+        let scratch = ScratchDir::new("image-test-remove");
+        let root = scratch.path();
+        let files = || {
+            let mut files = std::fs::read_dir(root)
+                .unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        };
+
+        let mut image = Image::with_backend(root, "base.cim", file_backend());
+        image.create(None).unwrap();
+        image
+            .create_file("lib.rs".as_ref(), "src/lib.rs".as_ref())
+            .unwrap();
+        image.commit().unwrap();
+        let base = files();
+
+        let mut fork = Image::with_backend(root, "fork.cim", file_backend()).with_provenance(true);
+        assert_eq!(
+            std::io::ErrorKind::InvalidInput,
+            fork.remove().unwrap_err().kind()
+        );
+        fork.create(Some("base.cim")).unwrap();
+        fork.create_file("image.rs".as_ref(), "src/image.rs".as_ref())
+            .unwrap();
+        fork.commit().unwrap();
+        assert!(Provenance::path(root, "fork.cim").exists());
+
+        // Only the files of the fork are removed
+        fork.remove().unwrap();
+        assert_eq!(base, files());
+        assert!(fork.remove().is_err());
+
+        // The fork can be built again
+        let mut fork = Image::with_backend(root, "fork.cim", file_backend());
+        fork.create(Some("base.cim")).unwrap();
+        fork.commit().unwrap();
+    }
+
+    #[test]
+    fn test_create_symlink() {
+        let scratch = ScratchDir::new("image-test-symlink");
+        let root = scratch.path();
+        std::fs::create_dir_all(root.join("dir")).unwrap();
+
+        let mut image = Image::with_backend(".cimroot", "test.cim", RecordingBackend::default());
+        image.create(None).unwrap();
+        image
+            .create_symlink("bin".as_ref(), "usr/bin", true)
+            .unwrap();
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("dir", root.join("link")).unwrap();
+            image
+                .create_file("link".as_ref(), root.join("link").as_os_str())
+                .unwrap();
+        }
+        image.commit().unwrap();
+
+        let recorded = image.backend().last_image().unwrap();
+        match &recorded.entries[0] {
+            RecordedEntry::File { metadata, data, .. } => {
+                assert!(metadata.is_reparse_point());
+                assert!(metadata.is_directory());
+                assert!(data.is_empty());
+                assert_eq!(
+                    ReparseData::symlink("usr\\bin"),
+                    ReparseData::from_bytes(&metadata.reparse_data).unwrap()
+                );
+            }
+            e => panic!("unexpected entry {:?}", e),
+        }
+
+        #[cfg(unix)]
+        match &recorded.entries[1] {
+            RecordedEntry::File { metadata, .. } => {
+                assert!(metadata.is_reparse_point());
+                assert!(metadata.is_directory());
+                assert_eq!(
+                    Some("dir"),
+                    ReparseData::from_bytes(&metadata.reparse_data)
+                        .unwrap()
+                        .print_name()
+                );
+            }
+            e => panic!("unexpected entry {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_create_entry() {
+        let scratch = ScratchDir::new("image-test-create-entry");
+        let root = scratch.path();
+
+        let config = b"level = \"debug\"";
+        let metadata = FileMetadata {
+            attributes: FILE_ATTRIBUTE_NORMAL.0,
+            file_size: config.len() as u64,
+            last_write_time: 133_000_000_000_000_000,
+            ..Default::default()
+        };
+
+        let mut image = Image::with_backend(root, "test.cim", RecordingBackend::default());
+        image.create(None).unwrap();
+        // The file size and attributes of a directory are fixed up
+        image.create_directory("etc".as_ref(), &metadata).unwrap();
+        image
+            .create_entry("etc/app.toml".as_ref(), &metadata, &config[..])
+            .unwrap();
+        image.commit().unwrap();
+
+        let recorded = image.backend().last_image().unwrap();
+        match recorded.entries.as_slice() {
+            [RecordedEntry::File {
+                metadata: etc,
+                data: etc_data,
+                ..
+            }, RecordedEntry::File {
+                path,
+                metadata: app,
+                data,
+            }] => {
+                assert!(etc.is_directory());
+                assert_eq!(0, etc.attributes & FILE_ATTRIBUTE_NORMAL.0);
+                assert_eq!(0, etc.file_size);
+                assert!(etc_data.is_empty());
+                assert_eq!(133_000_000_000_000_000, etc.last_write_time);
+
+                assert_eq!(Path::new("etc/app.toml"), path);
+                assert_eq!(metadata.file_size, app.file_size);
+                assert_eq!(config.to_vec(), *data);
+            }
+            entries => panic!("unexpected entries {:?}", entries),
+        }
+    }
+
+    #[test]
+    fn test_create_entry_size_mismatch() {
+        let metadata = FileMetadata {
+            attributes: FILE_ATTRIBUTE_NORMAL.0,
+            file_size: 5,
+            ..Default::default()
+        };
+
+        let mut image = Image::with_backend(".cimroot", "test.cim", RecordingBackend::default())
+            .with_transfer_buf_len(2);
+        image.create(None).unwrap();
+        for data in [&b"hell"[..], &b"hello world"[..], &b""[..]] {
+            let err = image
+                .create_entry("a.txt".as_ref(), &metadata, data)
+                .unwrap_err();
+            assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+            assert!(matches!(
+                &err,
+                CimError::SourceUnreadable { relative_path, .. } if relative_path == Path::new("a.txt")
+            ));
+        }
+        image
+            .create_entry("a.txt".as_ref(), &metadata, &b"hello"[..])
+            .unwrap();
+
+        // The data of directories is not read
+        let directory = FileMetadata {
+            attributes: FILE_ATTRIBUTE_DIRECTORY.0,
+            ..Default::default()
+        };
+        image
+            .create_entry("dir".as_ref(), &directory, &b"ignored"[..])
+            .unwrap();
+    }
+
+    #[test]
+    fn test_create_file_from_reader_size_mismatch() {
+        let scratch = ScratchDir::new("image-test-async-size-mismatch");
+        let root = scratch.path();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let image_root = root.to_path_buf();
+            let image = AsyncImage::spawn(move || {
+                Image::with_backend(image_root, "test.cim", RecordingBackend::default())
+            });
+            image.create(None).await.unwrap();
+
+            let data = vec![7u8; 1024];
+            for file_size in [512, 2048] {
+                let metadata = FileMetadata {
+                    file_size,
+                    ..Default::default()
+                };
+                let err = image
+                    .create_file_from_reader("data.bin", metadata, data.as_slice())
+                    .await
+                    .unwrap_err();
+                assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+            }
+            image.close().await;
+        });
+    }
+
+    #[test]
+    fn test_async_image() {
+        let scratch = ScratchDir::new("image-test-async");
+        let root = scratch.path();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let image_root = root.to_path_buf();
+            let image = AsyncImage::spawn(move || {
+                Image::with_backend(image_root, "test.cim", RecordingBackend::default())
+                    .with_transfer_buf_len(16)
+            });
+            image.create(None).await.unwrap();
+            image.create_file("lib.rs", "src/lib.rs").await.unwrap();
+
+            let data = vec![7u8; 200 * 1024];
+            let metadata = FileMetadata {
+                file_size: data.len() as u64,
+                ..Default::default()
+            };
+            image
+                .create_file_from_reader("data.bin", metadata, data.as_slice())
+                .await
+                .unwrap();
+
+            let mut o = Object::new("src/bin");
+            let ancestors = o.resolve_relative_path(true).unwrap();
+            image.build(vec![o], ancestors).await.unwrap();
+
+            assert!(image
+                .create_file("missing.rs", "src/missing.rs")
+                .await
+                .is_err());
+            image.commit().await.unwrap();
+            assert!(image.commit().await.is_err());
+
+            let recorded = image
+                .run(|image| image.backend().last_image().cloned())
+                .await
+                .unwrap()
+                .unwrap();
+            image.close().await;
+
+            let entry = |path: &str| {
+                recorded
+                    .entries
+                    .iter()
+                    .find_map(|e| match e {
+                        RecordedEntry::File {
+                            path: p,
+                            metadata,
+                            data,
+                        } if p == Path::new(path) => Some((metadata.clone(), data.clone())),
+                        _ => None,
+                    })
+                    .unwrap()
+            };
+            assert_eq!(std::fs::read("src/lib.rs").unwrap(), entry("lib.rs").1);
+            assert_eq!(vec![7u8; 200 * 1024], entry("data.bin").1);
+            assert!(entry("src/bin").0.is_directory());
+        });
+    }
+
+    #[test]
+    fn test_create_file_requires_create() {
+        let mut image = Image::with_backend(".cimroot", "test.cim", RecordingBackend::default());
+
+        let err = image
+            .create_file("lib.rs".as_ref(), "src/lib.rs".as_ref())
+            .unwrap_err();
+        assert!(matches!(err, CimError::ImageNotOpen));
+        assert!(image.commit().is_err());
+
+        image.create(None).unwrap();
+        let err = image
+            .create_alternate_stream("lib.rs".as_ref(), "a:b", 0, &b""[..])
+            .unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidInput, err.kind());
+        assert_eq!(Some(Path::new("lib.rs")), err.relative_path());
+    }
+
+    #[test]
+    fn test_mount_volume_requires_mount() {
+        let mut image = Image::with_backend(".cimroot", "test.cim", RecordingBackend::default());
+
+        let err = image.mount_volume("C:\\mnt\\test").unwrap_err();
+        assert_eq!(Some("SetVolumeMountPointW"), err.call());
+        assert_eq!(std::io::ErrorKind::NotFound, err.kind());
+
+        let volume = image.mount(None).unwrap();
+        image.mount_volume("C:\\mnt\\test").unwrap();
+        assert_eq!(volume, image.backend().mountpoints[0].0);
+    }
 }
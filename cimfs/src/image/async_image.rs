@@ -0,0 +1,265 @@
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::path::PathBuf;
+
+use bytes::Bytes;
+use bytes::BytesMut;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+use windows::core::GUID;
+
+use super::Image;
+use crate::backend::CimBackend;
+use crate::backend::DefaultBackend;
+use crate::backend::FileMetadata;
+use crate::object::Object;
+use crate::Result;
+
+use tracing::*;
+
+/// Length of the chunks read from an async reader,
+///
+const CHUNK_LEN: usize = 64 * 1024;
+
+/// Number of chunks that can be buffered before the async reader waits for the image thread,
+///
+const CHUNK_QUEUE_LEN: usize = 4;
+
+/// Call sent to the image thread,
+///
+type Call<B> = Box<dyn FnOnce(&mut Image<B>) + Send>;
+
+/// Async wrapper around an `Image`, for use inside an async runtime,
+///
+/// The image and its handles are owned by a dedicated thread, and each call is sent to that thread and runs in order. Calls
+/// block that thread rather than a runtime worker, so they do not need to be wrapped in `spawn_blocking`.
+///
+/// ```no_run
+/// # async fn example() -> std::io::Result<()> {
+/// use cimfs::api::AsyncImage;
+/// use cimfs::api::FileMetadata;
+/// use tokio::io::AsyncReadExt;
+///
+/// let image = AsyncImage::new(".cimroot", "app.cim");
+/// image.create(None).await?;
+/// image.create_file("app.exe", "target/release/app.exe").await?;
+///
+/// let config = tokio::io::repeat(b'a').take(10);
+/// let metadata = FileMetadata { file_size: 10, ..Default::default() };
+/// image.create_file_from_reader("app.cfg", metadata, config).await?;
+///
+/// image.commit().await?;
+/// image.close().await;
+/// # Ok(())
+/// # }
+/// ```
+///
+pub struct AsyncImage<B: CimBackend = DefaultBackend> {
+    /// Sends calls to the image thread,
+    ///
+    calls: std::sync::mpsc::Sender<Call<B>>,
+    /// Receives a message once the image thread has dropped the image and exited,
+    ///
+    exited: oneshot::Receiver<()>,
+}
+
+impl AsyncImage {
+    /// Creates a new image w/ root_folder containing the images and a name for this image,
+    ///
+    pub fn new(root_folder: impl Into<PathBuf>, name: impl Into<String>) -> Self {
+        let (root_folder, name) = (root_folder.into(), name.into());
+        Self::spawn(move || Image::new(root_folder, name))
+    }
+}
+
+impl<B: CimBackend + 'static> AsyncImage<B> {
+    /// Starts the image thread, creating the image on that thread w/ init,
+    ///
+    /// Since the image is created on the thread, the backend does not need to be `Send`. Use this to configure the image, ex.
+    /// `AsyncImage::spawn(|| Image::new(root, name).with_alternate_streams(true))`.
+    ///
+    pub fn spawn(init: impl FnOnce() -> Image<B> + Send + 'static) -> Self {
+        let (calls, receiver) = std::sync::mpsc::channel::<Call<B>>();
+        let (exit, exited) = oneshot::channel();
+
+        std::thread::Builder::new()
+            .name("cimfs-image".to_string())
+            .spawn(move || {
+                let mut image = init();
+                while let Ok(call) = receiver.recv() {
+                    call(&mut image);
+                }
+
+                trace!("Image thread exiting");
+                drop(image);
+                let _ = exit.send(());
+            })
+            .expect("should be able to spawn the image thread");
+
+        Self { calls, exited }
+    }
+
+    /// Runs f w/ the image on the image thread, returning its result,
+    ///
+    pub async fn run<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Image<B>) -> T + Send + 'static,
+    ) -> Result<T> {
+        self.send(f)?.await.map_err(|_| thread_exited())
+    }
+
+    /// Creates the image, optionally forking an existing image in the same root folder,
+    ///
+    pub async fn create(&self, existing: Option<String>) -> Result<()> {
+        self.run(move |image| image.create(existing.as_deref()))
+            .await?
+    }
+
+    /// Builds the image from a list of objects and a set of their ancestors,
+    ///
+    pub async fn build(&self, objects: Vec<Object>, ancestors: BTreeSet<Object>) -> Result<()> {
+        self.run(move |image| image.build(objects, ancestors))
+            .await?
+    }
+
+    /// Adds a file to the image at the relative path in the image, copying data from src,
+    ///
+    pub async fn create_file(
+        &self,
+        relative_path: impl Into<OsString>,
+        src: impl Into<OsString>,
+    ) -> Result<()> {
+        let (relative_path, src) = (relative_path.into(), src.into());
+        self.run(move |image| image.create_file(&relative_path, &src))
+            .await?
+    }
+
+    /// Creates a directory at the relative path in the image w/ metadata, w/o a src,
+    ///
+    pub async fn create_directory(
+        &self,
+        relative_path: impl Into<OsString>,
+        metadata: FileMetadata,
+    ) -> Result<()> {
+        let relative_path = relative_path.into();
+        self.run(move |image| image.create_directory(&relative_path, &metadata))
+            .await?
+    }
+
+    /// Adds a file to the image at the relative path in the image w/ metadata, copying data from an async reader,
+    ///
+    /// The file size of metadata must be set to the number of bytes the reader will return. Data is read in chunks on the calling task
+    /// and written by the image thread, at most a few chunks are buffered in between.
+    ///
+    pub async fn create_file_from_reader(
+        &self,
+        relative_path: impl Into<PathBuf>,
+        metadata: FileMetadata,
+        mut reader: impl AsyncRead + Unpin,
+    ) -> Result<()> {
+        let relative_path = relative_path.into();
+        let (chunks, receiver) = mpsc::channel(CHUNK_QUEUE_LEN);
+
+        let result = self.send(move |image| {
+            let mut reader = ChunkReader {
+                receiver,
+                chunk: Bytes::new(),
+            };
+            image.create_entry(relative_path.as_os_str(), &metadata, &mut reader)
+        })?;
+
+        loop {
+            let mut chunk = BytesMut::with_capacity(CHUNK_LEN);
+            let read = match reader.read_buf(&mut chunk).await {
+                Ok(0) => break,
+                Ok(_) => Ok(chunk.freeze()),
+                Err(err) => Err(err),
+            };
+
+            let failed = read.is_err();
+            // If the image thread stopped reading, the error is returned by the call below
+            if chunks.send(read).await.is_err() || failed {
+                break;
+            }
+        }
+        drop(chunks);
+
+        result.await.map_err(|_| thread_exited())?
+    }
+
+    /// Commits the image,
+    ///
+    pub async fn commit(&self) -> Result<()> {
+        self.run(|image| image.commit()).await?
+    }
+
+    /// Mounts the image, returning the volume id it was mounted as,
+    ///
+    pub async fn mount(&self, volume_guid: Option<String>) -> Result<GUID> {
+        self.run(move |image| image.mount(volume_guid)).await?
+    }
+
+    /// Stops the image thread after pending calls complete, waiting for the image to be dropped on that thread,
+    ///
+    /// Dropping an `AsyncImage` also stops the image thread, but does not wait for it.
+    ///
+    pub async fn close(self) {
+        let Self { calls, exited } = self;
+        drop(calls);
+
+        if exited.await.is_err() {
+            error!("Image thread panicked");
+        }
+    }
+
+    /// Sends f to the image thread, returning a receiver for its result,
+    ///
+    fn send<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Image<B>) -> T + Send + 'static,
+    ) -> Result<oneshot::Receiver<T>> {
+        let (sender, receiver) = oneshot::channel();
+
+        self.calls
+            .send(Box::new(move |image| {
+                let _ = sender.send(f(image));
+            }))
+            .map_err(|_| thread_exited())?;
+
+        Ok(receiver)
+    }
+}
+
+/// Reader over chunks sent from an async reader, read on the image thread,
+///
+struct ChunkReader {
+    receiver: mpsc::Receiver<std::io::Result<Bytes>>,
+    chunk: Bytes,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.receiver.blocking_recv() {
+                Some(Ok(chunk)) => self.chunk = chunk,
+                Some(Err(err)) => return Err(err),
+                None => return Ok(0),
+            }
+        }
+
+        let len = buf.len().min(self.chunk.len());
+        buf[..len].copy_from_slice(&self.chunk.split_to(len));
+        Ok(len)
+    }
+}
+
+/// Returns the error returned when the image thread is no longer running,
+///
+fn thread_exited() -> crate::Error {
+    Error::new(ErrorKind::BrokenPipe, "Image thread is not running").into()
+}
@@ -0,0 +1,160 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::backend::FileMetadata;
+use crate::digest::Digest;
+use crate::digest::Sha256;
+
+/// Number of files and bytes that were not written to the image because an identical file was already added,
+///
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DedupStats {
+    /// Number of files that were added as hard links to an identical file,
+    ///
+    pub files: usize,
+    /// Total size of the files that were added as hard links,
+    ///
+    pub bytes_saved: u64,
+}
+
+/// Index of the contents of the files added to an image,
+///
+#[derive(Default)]
+pub struct Dedup {
+    /// Files by content, w/ the metadata they were added w/,
+    ///
+    by_content: HashMap<(u64, Digest), (PathBuf, FileMetadata)>,
+    /// Content of each file that was indexed, by path in the image,
+    ///
+    by_path: BTreeMap<PathBuf, (u64, Digest)>,
+    /// Number of indexed files by size, a file only needs to be hashed before it is written if a file of the same size was indexed,
+    ///
+    sizes: HashMap<u64, usize>,
+    /// Files and bytes that were deduplicated so far,
+    ///
+    stats: DedupStats,
+}
+
+impl Dedup {
+    /// Returns the files and bytes that were deduplicated so far,
+    ///
+    pub fn stats(&self) -> DedupStats {
+        self.stats
+    }
+
+    /// Returns true if a file of size was indexed,
+    ///
+    pub fn has_size(&self, size: u64) -> bool {
+        self.sizes.contains_key(&size)
+    }
+
+    /// Returns the path of an indexed file w/ the same content, that can be linked to a file w/ metadata,
+    ///
+    /// Hard links share metadata, so only files w/ the same attributes, security descriptor, extended attributes and reparse data are
+    /// returned. File times are taken from the indexed file.
+    ///
+    pub fn find(&self, size: u64, digest: Digest, metadata: &FileMetadata) -> Option<&Path> {
+        self.by_content
+            .get(&(size, digest))
+            .filter(|(_, m)| {
+                m.attributes == metadata.attributes
+                    && m.security_descriptor == metadata.security_descriptor
+                    && m.ea_buffer == metadata.ea_buffer
+                    && m.reparse_data == metadata.reparse_data
+            })
+            .map(|(path, _)| path.as_path())
+    }
+
+    /// Indexes the content of the file at relative_path,
+    ///
+    pub fn insert(
+        &mut self,
+        relative_path: &Path,
+        size: u64,
+        digest: Digest,
+        metadata: &FileMetadata,
+    ) {
+        self.forget(relative_path);
+
+        self.by_content
+            .entry((size, digest))
+            .or_insert_with(|| (relative_path.to_path_buf(), metadata.clone()));
+        self.by_path
+            .insert(relative_path.to_path_buf(), (size, digest));
+        *self.sizes.entry(size).or_default() += 1;
+    }
+
+    /// Records that a file of size was added as a hard link,
+    ///
+    pub fn linked(&mut self, size: u64) {
+        self.stats.files += 1;
+        self.stats.bytes_saved += size;
+    }
+
+    /// Removes relative_path and any paths below it from the index, since their content is replaced or deleted,
+    ///
+    pub fn forget(&mut self, relative_path: &Path) {
+        let forgotten = self
+            .by_path
+            .range(relative_path.to_path_buf()..)
+            .take_while(|(path, _)| path.starts_with(relative_path))
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+
+        for path in forgotten {
+            let Some(content) = self.by_path.remove(&path) else {
+                continue;
+            };
+
+            if self
+                .by_content
+                .get(&content)
+                .is_some_and(|(indexed, _)| *indexed == path)
+            {
+                self.by_content.remove(&content);
+            }
+
+            if let Some(count) = self.sizes.get_mut(&content.0) {
+                *count -= 1;
+                if *count == 0 {
+                    self.sizes.remove(&content.0);
+                }
+            }
+        }
+    }
+}
+
+/// Reader that hashes the data read from the inner reader,
+///
+pub struct Hashing<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Hashing<R> {
+    /// Returns a new reader hashing the data read from inner,
+    ///
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Returns the number of bytes read and their digest,
+    ///
+    pub fn finish(self) -> (u64, Digest) {
+        (self.hasher.len(), self.hasher.finish())
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
@@ -0,0 +1,122 @@
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+
+use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_DIRECTORY;
+use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_HIDDEN;
+use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_NORMAL;
+use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_READONLY;
+use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_REPARSE_POINT;
+use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_SYSTEM;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::backend::FileMetadata;
+
+/// Name of the environment variable w/ the timestamp of reproducible builds, in seconds since the unix epoch,
+///
+/// See <https://reproducible-builds.org/specs/source-date-epoch/>.
+///
+const SOURCE_DATE_EPOCH: &str = "SOURCE_DATE_EPOCH";
+
+/// Policy normalizing the metadata and order of the entries added to an image, so that two builds of the same input produce the same image,
+///
+/// Set w/ `Image::with_normalization()`. The default sets every file time to the unix epoch, keeps only the read-only, hidden, system,
+/// directory and reparse point attributes, and sorts the objects passed to `build()` by relative path.
+///
+/// Security descriptors and extended attributes are still copied from each src, use `Overrides` to replace them if they differ between hosts.
+///
+/// Normalization only covers the entries of the image. The files of the image are not byte-identical, since CimFS generates a new region
+/// set id for every image.
+///
+/// ```
+/// use cimfs::api::Image;
+/// use cimfs::api::Normalization;
+///
+/// let image = Image::new(".cimroot", "app.cim").with_normalization(Normalization::from_env()?);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Normalization {
+    /// Time every file time of an entry is set to, in the windows file time format, if None file times are kept,
+    ///
+    pub timestamp: Option<i64>,
+    /// Mask applied to the attributes of each entry, the directory and reparse point attributes are always kept,
+    ///
+    pub attribute_mask: u32,
+    /// If true, `build()` adds objects sorted by relative path instead of in the order they were passed,
+    ///
+    /// Objects w/ the same relative path keep their order, so the last one is still the one in the image.
+    ///
+    pub sort_objects: bool,
+}
+
+impl Normalization {
+    /// Attributes kept by the default attribute mask,
+    ///
+    /// Attributes that are set by the host rather than by the content, ex. archive, not content indexed or compressed, are cleared.
+    ///
+    pub const DEFAULT_ATTRIBUTE_MASK: u32 = FILE_ATTRIBUTE_READONLY.0
+        | FILE_ATTRIBUTE_HIDDEN.0
+        | FILE_ATTRIBUTE_SYSTEM.0
+        | FILE_ATTRIBUTE_DIRECTORY.0
+        | FILE_ATTRIBUTE_REPARSE_POINT.0;
+
+    /// Returns the default normalization w/ every file time set to secs since the unix epoch,
+    ///
+    pub fn from_unix_time(secs: i64) -> Self {
+        Self {
+            timestamp: Some(crate::source::to_file_time(secs, 0)),
+            attribute_mask: Self::DEFAULT_ATTRIBUTE_MASK,
+            sort_objects: true,
+        }
+    }
+
+    /// Returns the default normalization w/ every file time set to `SOURCE_DATE_EPOCH`, or to the unix epoch if it is not set,
+    ///
+    /// Returns an error if `SOURCE_DATE_EPOCH` is not a number of seconds.
+    ///
+    pub fn from_env() -> Result<Self> {
+        match std::env::var(SOURCE_DATE_EPOCH) {
+            Ok(secs) => secs.trim().parse().map(Self::from_unix_time).map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid {SOURCE_DATE_EPOCH} {secs:?} -- {e}"),
+                )
+            }),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// Returns self w/ a different attribute mask,
+    ///
+    pub fn with_attribute_mask(mut self, mask: u32) -> Self {
+        self.attribute_mask = mask;
+        self
+    }
+
+    /// Applies the normalization to metadata,
+    ///
+    pub fn apply(&self, metadata: &mut FileMetadata) {
+        if let Some(timestamp) = self.timestamp {
+            metadata.creation_time = timestamp;
+            metadata.last_write_time = timestamp;
+            metadata.change_time = timestamp;
+            metadata.last_access_time = timestamp;
+        }
+
+        let kept = FILE_ATTRIBUTE_DIRECTORY.0 | FILE_ATTRIBUTE_REPARSE_POINT.0;
+        metadata.attributes &= self.attribute_mask | kept;
+        if metadata.attributes == 0 {
+            metadata.attributes = FILE_ATTRIBUTE_NORMAL.0;
+        }
+    }
+}
+
+impl Default for Normalization {
+    fn default() -> Self {
+        Self::from_unix_time(0)
+    }
+}
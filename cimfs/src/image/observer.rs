@@ -0,0 +1,57 @@
+use std::path::Path;
+
+use crate::backend::FileMetadata;
+use crate::Error;
+
+/// Trait for observing the progress of a build,
+///
+/// Set w/ `Image::with_observer()`. Entry events are sent for every entry added to the image, including by `create_file()` and
+/// `build_from_tar()`, while `build_started()` and `build_finished()` are only sent by `build()` and the tar builds. Every method
+/// has a default implementation that does nothing.
+///
+/// ```
+/// use std::path::Path;
+/// use cimfs::api::BuildObserver;
+///
+/// #[derive(Default)]
+/// struct Counter {
+///     bytes: u64,
+/// }
+///
+/// impl BuildObserver for Counter {
+///     fn bytes_transferred(&mut self, _: &Path, len: u64) {
+///         self.bytes += len;
+///     }
+/// }
+/// ```
+///
+#[allow(unused_variables)]
+pub trait BuildObserver {
+    /// Called before any entries are added, w/ the number of entries that will be added if it is known ahead of time,
+    ///
+    fn build_started(&mut self, entries: Option<usize>) {}
+
+    /// Called when an entry is about to be created w/ the metadata it is created w/,
+    ///
+    fn entry_started(&mut self, relative_path: &Path, metadata: &FileMetadata) {}
+
+    /// Called each time data of the entry that was started last is written to the image,
+    ///
+    fn bytes_transferred(&mut self, relative_path: &Path, len: u64) {}
+
+    /// Called after an entry was created and all of its data was written,
+    ///
+    fn entry_finished(&mut self, relative_path: &Path) {}
+
+    /// Called after an entry was created as a hard link to an existing entry, w/o writing data,
+    ///
+    fn entry_linked(&mut self, relative_path: &Path, existing: &Path) {}
+
+    /// Called when an entry is skipped, ex. an object that is also an ancestor, or an unsupported tar entry,
+    ///
+    fn entry_skipped(&mut self, relative_path: &Path, reason: &str) {}
+
+    /// Called when a build returns, w/ the error if the build failed,
+    ///
+    fn build_finished(&mut self, error: Option<&Error>) {}
+}
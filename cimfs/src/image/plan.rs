@@ -0,0 +1,191 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use serde::Serialize;
+use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_REPARSE_POINT;
+
+use super::prefetch::Job;
+use super::prefetch::Prefetched;
+
+/// Operations that `build()` would apply to an image, computed w/o writing to the image,
+///
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildPlan {
+    /// Operations in the order they are applied,
+    ///
+    pub operations: Vec<BuildOperation>,
+    /// Paths of the ancestors created before the objects, ex. `src` and `src/bin` for an object at `src/bin/main.rs`,
+    ///
+    pub ancestors: Vec<PathBuf>,
+    /// Paths of the objects that are not added since they are included in the ancestors,
+    ///
+    pub skipped: Vec<PathBuf>,
+    /// Total size of the files that are written, hard links are not counted,
+    ///
+    pub total_bytes: u64,
+}
+
+/// Operation applied to an image,
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum BuildOperation {
+    /// Path in the image is deleted,
+    ///
+    Delete { relative_path: PathBuf },
+    /// Entry is created at the path in the image, copying data from src,
+    ///
+    Create {
+        relative_path: PathBuf,
+        src: PathBuf,
+        kind: EntryKind,
+        size: u64,
+    },
+    /// Entry is created as a hard link to an existing path in the image, since their srcs share a file id,
+    ///
+    Link {
+        relative_path: PathBuf,
+        existing: PathBuf,
+    },
+}
+
+/// Kind of entry that is created,
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    Directory,
+    File,
+    ReparsePoint,
+}
+
+impl BuildPlan {
+    /// Returns the plan for a list of jobs, reading the metadata of each src,
+    ///
+    /// `ancestor_jobs` is the number of jobs at the start of the list that create ancestors.
+    ///
+    pub(super) fn from_jobs(
+        jobs: &[Job],
+        ancestor_jobs: usize,
+        skipped: Vec<PathBuf>,
+    ) -> std::io::Result<Self> {
+        let mut plan = BuildPlan {
+            ancestors: jobs[..ancestor_jobs]
+                .iter()
+                .map(|j| j.relative_path.clone())
+                .collect(),
+            skipped,
+            ..Default::default()
+        };
+
+        let mut links = BTreeMap::new();
+        for job in jobs {
+            let (id, metadata) =
+                match Prefetched::read(&job.src, &job.relative_path, job.overrides, None)? {
+                    Prefetched::Symlink(metadata) => (None, metadata),
+                    Prefetched::File { id, metadata, .. } => (id, metadata),
+                };
+
+            if let Some(id) = id.filter(|_| job.link) {
+                if let Some(existing) = links.get(&id) {
+                    plan.operations.push(BuildOperation::Link {
+                        relative_path: job.relative_path.clone(),
+                        existing: PathBuf::clone(existing),
+                    });
+                    continue;
+                }
+                links.insert(id, job.relative_path.clone());
+            }
+
+            let kind = if metadata.attributes & FILE_ATTRIBUTE_REPARSE_POINT.0 != 0 {
+                EntryKind::ReparsePoint
+            } else if metadata.is_directory() {
+                EntryKind::Directory
+            } else {
+                EntryKind::File
+            };
+            let size = if kind == EntryKind::File {
+                metadata.file_size
+            } else {
+                0
+            };
+
+            plan.total_bytes += size;
+            plan.operations.push(BuildOperation::Create {
+                relative_path: job.relative_path.clone(),
+                src: job.src.clone(),
+                kind,
+                size,
+            });
+        }
+
+        Ok(plan)
+    }
+
+    /// Returns self w/ deletes of paths that are applied before any other operation, ex. when forking,
+    ///
+    pub fn with_deletes(mut self, deletes: impl IntoIterator<Item = impl Into<PathBuf>>) -> Self {
+        let deletes = deletes
+            .into_iter()
+            .map(|d| BuildOperation::Delete {
+                relative_path: d.into(),
+            })
+            .collect::<Vec<_>>();
+        self.operations.splice(0..0, deletes);
+        self
+    }
+
+    /// Returns the paths in the image that entries are created at, in order,
+    ///
+    pub fn destinations(&self) -> impl Iterator<Item = &PathBuf> {
+        self.operations.iter().filter_map(|o| match o {
+            BuildOperation::Create { relative_path, .. }
+            | BuildOperation::Link { relative_path, .. } => Some(relative_path),
+            BuildOperation::Delete { .. } => None,
+        })
+    }
+}
+
+impl Display for BuildOperation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildOperation::Delete { relative_path } => {
+                write!(f, "delete {}", relative_path.display())
+            }
+            BuildOperation::Create {
+                relative_path,
+                src,
+                kind: EntryKind::File,
+                size,
+            } => write!(
+                f,
+                "create {} <- {} ({size} bytes)",
+                relative_path.display(),
+                src.display()
+            ),
+            BuildOperation::Create {
+                relative_path,
+                src,
+                kind,
+                ..
+            } => write!(
+                f,
+                "create {} <- {} ({kind:?})",
+                relative_path.display(),
+                src.display()
+            ),
+            BuildOperation::Link {
+                relative_path,
+                existing,
+            } => write!(
+                f,
+                "link   {} -> {}",
+                relative_path.display(),
+                existing.display()
+            ),
+        }
+    }
+}
@@ -0,0 +1,323 @@
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Cursor;
+use std::io::Error;
+use std::io::Read;
+use std::io::Result;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+
+use crate::backend::FileMetadata;
+use crate::error::source_error;
+use crate::object::Overrides;
+
+use tracing::*;
+
+/// Number of sources each worker can read ahead of the source being written,
+///
+/// This bounds the number of open src handles, independent of the buffer budget.
+///
+const READ_AHEAD_PER_WORKER: usize = 8;
+
+/// Source of a file that is added to the image,
+///
+pub struct Job<'a> {
+    /// Path of the file in the image,
+    ///
+    pub relative_path: PathBuf,
+    /// Path of the src file,
+    ///
+    pub src: PathBuf,
+    /// Overrides applied to the metadata of the src,
+    ///
+    pub overrides: &'a Overrides,
+    /// If true, the src can be added as a hard link to a file w/ the same file id,
+    ///
+    pub link: bool,
+}
+
+/// Src file that was opened and read before being written to the image,
+///
+pub enum Prefetched<'a> {
+    /// Symlink, added as a reparse point w/o data,
+    ///
+    Symlink(FileMetadata),
+    /// File or directory,
+    ///
+    File {
+        file: File,
+        id: Option<(u64, u64)>,
+        metadata: FileMetadata,
+        /// Data that was read ahead, the rest of the data is read from file,
+        ///
+        data: Option<Buffered<'a>>,
+    },
+}
+
+impl<'a> Prefetched<'a> {
+    /// Opens src and reads its metadata, applying overrides,
+    ///
+    /// If budget is set and enough of the budget is available, the data of a file is also read. Errors carry the src and the relative path it is added at.
+    ///
+    pub fn read(
+        src: &Path,
+        relative_path: &Path,
+        overrides: &Overrides,
+        budget: Option<&'a Budget>,
+    ) -> Result<Self> {
+        Self::read_src(src, overrides, budget).map_err(source_error(relative_path, Some(src)))
+    }
+
+    /// Opens src and reads its metadata, applying overrides,
+    ///
+    fn read_src(src: &Path, overrides: &Overrides, budget: Option<&'a Budget>) -> Result<Self> {
+        if let Some(mut metadata) = crate::source::symlink_metadata(src)? {
+            overrides.apply(&mut metadata);
+            return Ok(Prefetched::Symlink(metadata));
+        }
+
+        let file = crate::source::open(src)?;
+        let id = crate::source::file_id(&file)?;
+        let mut metadata = crate::source::metadata(&file)?;
+
+        let data = match budget {
+            Some(budget) if !metadata.is_directory() && metadata.file_size > 0 => {
+                budget.reserve(metadata.file_size as usize).map(|len| {
+                    let mut data = Vec::with_capacity(len);
+                    (&file)
+                        .take(len as u64)
+                        .read_to_end(&mut data)
+                        .map(|_| Buffered { data, len, budget })
+                })
+            }
+            _ => None,
+        }
+        .transpose()?;
+
+        overrides.apply(&mut metadata);
+        Ok(Prefetched::File {
+            file,
+            id,
+            metadata,
+            data,
+        })
+    }
+}
+
+/// Data read ahead of time, the reserved length is returned to the budget when dropped,
+///
+pub struct Buffered<'a> {
+    data: Vec<u8>,
+    len: usize,
+    budget: &'a Budget,
+}
+
+impl Buffered<'_> {
+    /// Returns a reader over the buffered data followed by the remaining data of file,
+    ///
+    pub fn chain<'b>(&'b self, file: &'b mut File) -> impl Read + 'b {
+        Cursor::new(&self.data[..]).chain(file)
+    }
+}
+
+impl Drop for Buffered<'_> {
+    fn drop(&mut self) {
+        self.budget.release(self.len);
+    }
+}
+
+/// Total number of bytes that can be buffered by prefetching workers,
+///
+pub struct Budget {
+    available: Mutex<usize>,
+}
+
+impl Budget {
+    /// Returns a new budget of len bytes,
+    ///
+    pub fn new(len: usize) -> Self {
+        Self {
+            available: Mutex::new(len),
+        }
+    }
+
+    /// Reserves len bytes if they are available, w/o waiting,
+    ///
+    /// Workers never wait for the budget, since the source that is written next could be waiting for a worker. If the budget is exhausted
+    /// the data is read while the source is written instead.
+    ///
+    fn reserve(&self, len: usize) -> Option<usize> {
+        let mut available = self
+            .available
+            .lock()
+            .expect("should be able to lock budget");
+        if *available >= len {
+            *available -= len;
+            Some(len)
+        } else {
+            None
+        }
+    }
+
+    /// Returns len bytes to the budget,
+    ///
+    fn release(&self, len: usize) {
+        *self
+            .available
+            .lock()
+            .expect("should be able to lock budget") += len;
+    }
+}
+
+/// Progress of the pipeline shared between the writer and workers,
+///
+#[derive(Default)]
+struct Progress {
+    /// Index of the next job to prefetch,
+    ///
+    next: usize,
+    /// Number of jobs that have been written,
+    ///
+    written: usize,
+    /// Set when the writer stops early, ex. after an error,
+    ///
+    cancelled: bool,
+}
+
+/// Prefetches jobs on a pool of workers, calling write w/ each result on the current thread in the order of jobs,
+///
+/// Workers only read sources within a window of the source being written, so the source that is written next is always being read or
+/// already read. Stops at the first error, returned either by a worker or by write. A worker that panics while reading a source returns an
+/// error w/ the panic message for that source instead, so the panic does not take down the writer.
+///
+pub fn run<'a>(
+    jobs: &[Job<'a>],
+    workers: usize,
+    budget: usize,
+    mut write: impl FnMut(&Job<'a>, Prefetched<'_>) -> crate::Result<()>,
+) -> crate::Result<()> {
+    let budget = Budget::new(budget);
+    let window = workers * READ_AHEAD_PER_WORKER;
+    let progress = (Mutex::new(Progress::default()), Condvar::new());
+
+    std::thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel();
+
+        let (lock, cvar) = &progress;
+        let cancel = || {
+            lock.lock()
+                .expect("should be able to lock progress")
+                .cancelled = true;
+            cvar.notify_all();
+        };
+
+        for i in 0..workers {
+            let (sender, budget, progress) = (sender.clone(), &budget, &progress);
+            let spawned = std::thread::Builder::new()
+                .name(format!("cimfs-prefetch-{i}"))
+                .spawn_scoped(scope, move || loop {
+                    let (lock, cvar) = progress;
+                    let index = {
+                        let mut p = lock.lock().expect("should be able to lock progress");
+                        while !p.cancelled && p.next < jobs.len() && p.next >= p.written + window {
+                            p = cvar.wait(p).expect("should be able to lock progress");
+                        }
+                        if p.cancelled || p.next >= jobs.len() {
+                            break;
+                        }
+                        p.next += 1;
+                        p.next - 1
+                    };
+
+                    let job = &jobs[index];
+                    trace!("Prefetching {:?}", job.src);
+                    let prefetched = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                        Prefetched::read(&job.src, &job.relative_path, job.overrides, Some(budget))
+                    }))
+                    .unwrap_or_else(|payload| Err(panic_error(&job.src, payload)));
+                    if sender.send((index, prefetched)).is_err() {
+                        break;
+                    }
+                });
+
+            if let Err(err) = spawned {
+                cancel();
+                return Err(err.into());
+            }
+        }
+        drop(sender);
+
+        let mut pending = BTreeMap::new();
+        let mut result = Ok(());
+        for (index, job) in jobs.iter().enumerate() {
+            let prefetched = loop {
+                if let Some(p) = pending.remove(&index) {
+                    break p;
+                }
+                match receiver.recv() {
+                    Ok((i, p)) => {
+                        pending.insert(i, p);
+                    }
+                    // Every worker exited w/o sending this job
+                    Err(_) => {
+                        break Err(Error::other(format!(
+                            "Prefetch workers exited before reading {:?}",
+                            job.src
+                        )))
+                    }
+                }
+            };
+
+            result = prefetched
+                .map_err(crate::Error::from)
+                .and_then(|p| write(job, p));
+            if result.is_err() {
+                break;
+            }
+
+            lock.lock()
+                .expect("should be able to lock progress")
+                .written += 1;
+            cvar.notify_all();
+        }
+
+        cancel();
+        drop(receiver);
+        result
+    })
+}
+
+/// Returns the error for a source whose worker panicked, w/ the message of the panic if it has one,
+///
+fn panic_error(src: &Path, payload: Box<dyn Any + Send>) -> Error {
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic");
+    Error::other(format!(
+        "Prefetch worker panicked while reading {src:?} -- {message}"
+    ))
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use std::path::Path;
+
+    #[test]
+    fn test_panic_error() {
+        let payload = std::panic::catch_unwind(|| panic!("budget is {}", 0)).unwrap_err();
+        let err = super::panic_error(Path::new("a.txt"), payload);
+        assert_eq!(std::io::ErrorKind::Other, err.kind());
+        assert!(err.to_string().ends_with("-- budget is 0"), "{err}");
+
+        let payload = std::panic::catch_unwind(|| std::panic::panic_any(7)).unwrap_err();
+        let err = super::panic_error(Path::new("a.txt"), payload);
+        assert!(err.to_string().ends_with("-- unknown panic"), "{err}");
+    }
+}
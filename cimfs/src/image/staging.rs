@@ -0,0 +1,351 @@
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use tracing::*;
+
+use crate::region::referenced_files;
+use crate::region::region_files;
+
+/// Number of images staged by this process, used to make staging folder names unique,
+///
+static STAGED: AtomicUsize = AtomicUsize::new(0);
+
+/// Image that is written to a staging folder until it is committed,
+///
+/// CimFS writes region and object id files while the image is built, and the image file when it is committed. The image is created in a
+/// staging folder in the root folder that belongs to this build only, so every file the build writes can be told apart from the files of
+/// other images, including images that are built in the same root folder at the same time.
+///
+/// An image forked from an existing image needs the existing image in the folder it is created in, so the existing image file and the region
+/// and object id files it references are hard linked into the staging folder first. Forks require a file system w/ hard links, the files
+/// are not copied instead, since copying the region files of a large image would be slow and would double the space it uses.
+///
+/// Staging folders are named `.<name>.<pid>-<n>.tmp`, and the staging folders of processes that are no longer running are removed when a
+/// new image is staged, so that a crashed build does not leave its files behind.
+///
+pub struct Staging {
+    /// Folder the image is created in,
+    ///
+    dir: PathBuf,
+    /// Files linked into the staging folder from the root folder,
+    ///
+    linked: BTreeSet<OsString>,
+}
+
+impl Staging {
+    /// Returns a new staging folder for the image name in root, forked from the existing image if set,
+    ///
+    /// Returns an error if an image w/ name already exists.
+    ///
+    pub fn new(root: &Path, name: &str, existing: Option<&str>) -> Result<Self> {
+        if root.join(name).exists() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("Image {:?} already exists", root.join(name)),
+            ));
+        }
+
+        remove_stale(root);
+
+        let dir = root.join(format!(
+            ".{name}.{}-{}.tmp",
+            std::process::id(),
+            STAGED.fetch_add(1, Ordering::Relaxed)
+        ));
+        trace!("Staging {name} in {:?}", dir);
+        std::fs::create_dir(&dir)?;
+
+        let mut staging = Self {
+            dir,
+            linked: BTreeSet::new(),
+        };
+        if let Some(existing) = existing {
+            if let Err(err) = staging.link_existing(root, existing) {
+                staging.discard();
+                return Err(err);
+            }
+        }
+        Ok(staging)
+    }
+
+    /// Returns the folder the image is created in,
+    ///
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns the names of the region and object id files written for the image,
+    ///
+    pub fn written(&self) -> Result<BTreeSet<OsString>> {
+        Ok(region_files(&self.dir)?
+            .difference(&self.linked)
+            .cloned()
+            .collect())
+    }
+
+    /// Publishes the committed image under name in root, and returns the names of the region and object id files written for the image,
+    ///
+    /// The files written for the image are moved to root first, then the image file is linked to name w/o replacing an existing image, so a
+    /// complete image appears under name at once. If publishing fails, the files that were moved to root are removed.
+    ///
+    pub fn publish(&self, root: &Path, name: &str) -> Result<BTreeSet<OsString>> {
+        let written = self.written()?;
+        let mut moved = vec![];
+        let result = written
+            .iter()
+            .map(|file| file.as_os_str())
+            .chain([OsStr::new(name)])
+            .try_for_each(|file| {
+                let (temp, path) = (self.dir.join(file), root.join(file));
+                trace!("Publishing {:?} as {:?}", temp, path);
+                move_file(&temp, &path)?;
+                moved.push(path);
+                Ok(())
+            });
+
+        if let Err(err) = result {
+            moved.iter().for_each(|path| remove(path));
+            return Err(err);
+        }
+
+        self.discard();
+        Ok(written)
+    }
+
+    /// Removes the staging folder,
+    ///
+    /// Files of the root folder are never removed, the staging folder only holds links to them.
+    ///
+    pub fn discard(&self) {
+        trace!("Discarding {:?}", self.dir);
+        match std::fs::remove_dir_all(&self.dir) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => warn!("Could not remove {:?} -- {err}", self.dir),
+        }
+    }
+
+    /// Links the existing image file, and the region and object id files it references into the staging folder,
+    ///
+    fn link_existing(&mut self, root: &Path, existing: &str) -> Result<()> {
+        for file in referenced_files(root, existing)?
+            .into_iter()
+            .chain([existing.to_string()])
+            .map(OsString::from)
+        {
+            link_file(&root.join(&file), &self.dir.join(&file))?;
+            self.linked.insert(file);
+        }
+        Ok(())
+    }
+}
+
+/// Links a file of the root folder into a staging folder,
+///
+fn link_file(src: &Path, dst: &Path) -> Result<()> {
+    std::fs::hard_link(src, dst).map_err(|err| match err.kind() {
+        ErrorKind::NotFound => Error::new(err.kind(), format!("{err} -- {:?}", src)),
+        _ => Error::new(
+            err.kind(),
+            format!("Could not link {:?} into the staging folder, forks require a file system w/ hard links -- {err}", src),
+        ),
+    })
+}
+
+/// Moves a file from a staging folder to the root folder w/o replacing an existing file,
+///
+fn move_file(temp: &Path, path: &Path) -> Result<()> {
+    match std::fs::hard_link(temp, path) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{:?} already exists", path),
+        )),
+        // Fallback for file systems w/o hard links
+        Err(_) if !path.exists() => std::fs::rename(temp, path),
+        Err(err) => Err(err),
+    }
+}
+
+/// Removes a file, logging errors other than the file not existing,
+///
+fn remove(path: &Path) {
+    match std::fs::remove_file(path) {
+        Ok(()) => trace!("Removed {:?}", path),
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => warn!("Could not remove {:?} -- {err}", path),
+    }
+}
+
+/// Removes the staging folders in root of processes that are no longer running,
+///
+/// Errors are logged, since a staging folder that can't be removed does not affect the image that is staged.
+///
+fn remove_stale(root: &Path) {
+    let entries = match std::fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!("Could not read {:?} -- {err}", root);
+            return;
+        }
+    };
+
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let Some(pid) = staging_pid(&name.to_string_lossy()) else {
+            continue;
+        };
+        if pid == std::process::id() || is_running(pid) || !entry.path().is_dir() {
+            continue;
+        }
+
+        debug!(
+            "Removing {:?}, process {pid} is no longer running",
+            entry.path()
+        );
+        if let Err(err) = std::fs::remove_dir_all(entry.path()) {
+            warn!("Could not remove {:?} -- {err}", entry.path());
+        }
+    }
+}
+
+/// Returns the id of the process that created a staging folder, parsed from its name, `.<name>.<pid>-<n>.tmp`,
+///
+fn staging_pid(name: &str) -> Option<u32> {
+    let (image, id) = name
+        .strip_prefix('.')?
+        .strip_suffix(".tmp")?
+        .rsplit_once('.')?;
+    let (pid, n) = id.split_once('-')?;
+    if image.is_empty() || n.parse::<usize>().is_err() {
+        return None;
+    }
+    pid.parse().ok()
+}
+
+/// Returns true if a process w/ pid is running,
+///
+#[cfg(windows)]
+fn is_running(pid: u32) -> bool {
+    use windows::Win32::Foundation::CloseHandle;
+    use windows::Win32::Foundation::ERROR_INVALID_PARAMETER;
+    use windows::Win32::Foundation::STILL_ACTIVE;
+    use windows::Win32::System::Threading::GetExitCodeProcess;
+    use windows::Win32::System::Threading::OpenProcess;
+    use windows::Win32::System::Threading::PROCESS_QUERY_LIMITED_INFORMATION;
+
+    unsafe {
+        match OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid) {
+            Ok(handle) => {
+                let mut code = 0;
+                let exited = GetExitCodeProcess(handle, &mut code).as_bool()
+                    && code != STILL_ACTIVE.0 as u32;
+                CloseHandle(handle);
+                !exited
+            }
+            // Other errors, ex. access denied, mean that the process exists
+            Err(err) => err.code() != ERROR_INVALID_PARAMETER.to_hresult(),
+        }
+    }
+}
+
+/// Returns true if a process w/ pid is running,
+///
+#[cfg(target_os = "linux")]
+fn is_running(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+/// Returns true, whether a process is running can't be told on this platform, so staging folders are never removed,
+///
+#[cfg(not(any(windows, target_os = "linux")))]
+fn is_running(_: u32) -> bool {
+    true
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use super::staging_pid;
+    use super::Staging;
+    use crate::backend::parse_guid;
+    use crate::region::guid_bytes;
+    #[cfg(test)]
+    use crate::scratch::ScratchDir;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_link_referenced_files() {
+        let scratch = ScratchDir::new("staging-test-link");
+        let root = scratch.path();
+
+        let base = parse_guid("04522dcd-f383-4f1c-aea6-af8f93e020d5").unwrap();
+        let other = parse_guid("5d8e1f2a-3b4c-4d5e-9f6a-7b8c9d0e1f2a").unwrap();
+        for id in [base, other] {
+            let id = format!("{:?}", id).to_lowercase();
+            std::fs::write(root.join(format!("region_{id}_0")), b"").unwrap();
+            std::fs::write(root.join(format!("objectid_{id}_0")), b"").unwrap();
+        }
+        std::fs::write(root.join("base.cim"), guid_bytes(&base)).unwrap();
+
+        let staging = Staging::new(root, "fork.cim", Some("base.cim")).unwrap();
+        let id = format!("{:?}", base).to_lowercase();
+        let linked = std::fs::read_dir(staging.dir())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<BTreeSet<_>>();
+        assert_eq!(
+            BTreeSet::from([
+                "base.cim".to_string(),
+                format!("objectid_{id}_0"),
+                format!("region_{id}_0"),
+            ]),
+            linked
+        );
+        assert!(staging.written().unwrap().is_empty());
+        staging.discard();
+
+        let err = Staging::new(root, "fork.cim", Some("missing.cim"))
+            .err()
+            .unwrap();
+        assert_eq!(std::io::ErrorKind::NotFound, err.kind());
+        assert!(std::fs::read_dir(root).unwrap().all(|e| !e
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .ends_with(".tmp")));
+    }
+
+    #[test]
+    fn test_remove_stale() {
+        let scratch = ScratchDir::new("staging-test-stale");
+        let root = scratch.path();
+
+        // No process has the largest pid
+        let stale = root.join(format!(".base.cim.{}-0.tmp", u32::MAX));
+        let running = root.join(format!(".base.cim.{}-1000.tmp", std::process::id()));
+        let unrelated = root.join(format!(".tmp.{}-0", u32::MAX));
+        for dir in [&stale, &running, &unrelated] {
+            std::fs::create_dir(dir).unwrap();
+        }
+
+        let staging = Staging::new(root, "fork.cim", None).unwrap();
+        #[cfg(any(windows, target_os = "linux"))]
+        assert!(!stale.exists());
+        assert!(running.exists());
+        assert!(unrelated.exists());
+        staging.discard();
+
+        assert_eq!(Some(7), staging_pid(".app.v1.cim.7-0.tmp"));
+        assert_eq!(None, staging_pid(".7-0.tmp"));
+        assert_eq!(None, staging_pid(".app.cim.7.tmp"));
+        assert_eq!(None, staging_pid("app.cim.7-0.tmp"));
+    }
+}
@@ -0,0 +1,387 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+use ::tar::Archive;
+use ::tar::EntryType;
+use ::tar::Header;
+use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_DIRECTORY;
+use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_NORMAL;
+use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_READONLY;
+use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_REPARSE_POINT;
+
+use super::image_not_open;
+use super::Image;
+use crate::backend::CimBackend;
+use crate::backend::FileMetadata;
+use crate::error::is_backend_not_found;
+use crate::error::source_error;
+use crate::error::CimError;
+use crate::reparse::ReparseData;
+use crate::source::to_file_time;
+use crate::Result;
+
+use tracing::*;
+
+/// Prefix of whiteout entries in an OCI image layer,
+///
+const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// Name of the opaque directory marker in an OCI image layer,
+///
+const WHITEOUT_OPAQUE: &str = ".wh..wh..opq";
+
+impl<B: CimBackend> Image<B> {
+    /// Builds the image from a tar archive, streaming the data of each entry directly into the image,
+    ///
+    /// Directories, regular files, symlinks and hard links are added in the order they appear in the archive, other entry types
+    /// (devices, fifos, etc) are skipped. Parent directories missing from the archive are created w/ the modified time of the entry requiring them.
+    ///
+    /// If this image is forked from an existing image, parent directories of the existing image keep their metadata. Each entry is added
+    /// first, and the missing parent directories are only created if CimFS returns that the path of the entry does not exist.
+    ///
+    /// If the build fails, the image is closed w/o being committed and the files written for it are removed.
+    ///
+    pub fn build_from_tar(&mut self, reader: impl Read) -> Result<()> {
+        self.build_from_archive(reader, false)
+    }
+
+    /// Builds the image from an uncompressed OCI image layer, translating whiteouts into deletes,
+    ///
+    /// Entries are added the same way as `build_from_tar()`. A `.wh.<name>` entry deletes `<name>` from the image this image is forked from,
+    /// and a `.wh..wh..opq` entry deletes every entry in its directory that was not added by this layer.
+    ///
+    /// The opaque marker must precede any other entries of its directory in the layer, which is the order written by container runtimes.
+    ///
+    pub fn build_from_oci_layer(&mut self, reader: impl Read) -> Result<()> {
+        self.build_from_archive(reader, true)
+    }
+
+    /// Builds the image from a tar archive, optionally translating OCI whiteouts,
+    ///
+    fn build_from_archive(&mut self, reader: impl Read, whiteouts: bool) -> Result<()> {
+        self.notify(|o| o.build_started(None));
+        let result = self.add_archive_entries(reader, whiteouts);
+        if result.is_err() {
+            self.discard();
+        }
+
+        self.notify(|o| o.build_finished(result.as_ref().err()));
+        result
+    }
+
+    /// Adds the entries of a tar archive to the image, optionally translating OCI whiteouts,
+    ///
+    fn add_archive_entries(&mut self, reader: impl Read, whiteouts: bool) -> Result<()> {
+        if self.image_handle.is_none() {
+            return Err(image_not_open());
+        }
+
+        let mut archive = Archive::new(reader);
+        let mut directories = BTreeMap::<PathBuf, FileMetadata>::new();
+        let mut created = BTreeSet::<PathBuf>::new();
+
+        let archive_error = || source_error(Path::new(""), None);
+        for entry in archive.entries().map_err(archive_error())? {
+            let mut entry = entry.map_err(archive_error())?;
+            let relative_path = tar_path(&entry.path().map_err(archive_error())?)?;
+            if relative_path.as_os_str().is_empty() {
+                trace!("Skipping root entry");
+                continue;
+            }
+
+            let header = entry.header();
+            let entry_type = header.entry_type();
+            let mut metadata = tar_metadata(header).map_err(source_error(&relative_path, None))?;
+            let link_name = header
+                .link_name()
+                .map_err(source_error(&relative_path, None))?
+                .map(|l| l.into_owned());
+
+            let file_name = relative_path
+                .file_name()
+                .map(|f| f.to_string_lossy().to_string())
+                .unwrap_or_default();
+            if whiteouts && file_name.starts_with(WHITEOUT_PREFIX) {
+                let parent = relative_path.parent().unwrap_or(Path::new(""));
+                if file_name == WHITEOUT_OPAQUE {
+                    self.apply_opaque_whiteout(parent, &metadata, &directories, &created)?;
+                } else {
+                    self.apply_whiteout(&parent.join(&file_name[WHITEOUT_PREFIX.len()..]))?;
+                }
+                continue;
+            }
+
+            // The base may contain an ancestor, creating it would replace its metadata, so ancestors that aren't in the archive are only
+            // created once adding the entry fails
+            let deferred = self.existing.is_some();
+            let ancestor = FileMetadata {
+                attributes: FILE_ATTRIBUTE_DIRECTORY.0,
+                ..metadata.clone()
+            };
+            for a in relative_path
+                .ancestors()
+                .skip(1)
+                .filter(|a| !a.as_os_str().is_empty())
+                .collect::<Vec<_>>()
+                .into_iter()
+                .rev()
+            {
+                if directories.contains_key(a) {
+                    continue;
+                }
+
+                if deferred {
+                    trace!("Deferring ancestor {:?}, it may exist in the base image", a);
+                    continue;
+                }
+
+                trace!("Creating missing ancestor {:?}", a);
+                self.create_directory(a.as_os_str(), &ancestor)?;
+                created.insert(a.to_path_buf());
+                directories.insert(a.to_path_buf(), ancestor.clone());
+            }
+
+            let link = match entry_type {
+                EntryType::Directory => {
+                    metadata.attributes |= FILE_ATTRIBUTE_DIRECTORY.0;
+                    metadata.attributes &= !FILE_ATTRIBUTE_NORMAL.0;
+                    None
+                }
+                EntryType::Regular | EntryType::Continuous | EntryType::GNUSparse => {
+                    trace!("Creating file {:?}", relative_path);
+                    metadata.file_size = entry.size();
+                    None
+                }
+                EntryType::Symlink => {
+                    let target = link_name.ok_or_else(|| missing_link_name(&relative_path))?;
+                    trace!("Creating symlink {:?} -> {:?}", relative_path, target);
+                    metadata.attributes |= FILE_ATTRIBUTE_REPARSE_POINT.0;
+                    metadata.attributes &= !FILE_ATTRIBUTE_NORMAL.0;
+                    metadata.reparse_data = ReparseData::symlink(&target.to_string_lossy())
+                        .to_bytes()
+                        .map_err(|err| CimError::PathInvalid {
+                            relative_path: relative_path.clone(),
+                            reason: err.to_string(),
+                        })?;
+                    None
+                }
+                EntryType::Link => Some(tar_path(
+                    &link_name.ok_or_else(|| missing_link_name(&relative_path))?,
+                )?),
+                other => {
+                    warn!(
+                        "Skipping {:?}, unsupported entry type {:?}",
+                        relative_path, other
+                    );
+                    self.notify(|o| o.entry_skipped(&relative_path, "unsupported entry type"));
+                    continue;
+                }
+            };
+
+            let mut add = |image: &mut Self| match (entry_type, link.as_ref()) {
+                (_, Some(existing)) => {
+                    image.create_hard_link(existing.as_os_str(), relative_path.as_os_str())
+                }
+                (EntryType::Directory, _) => {
+                    image.create_directory(relative_path.as_os_str(), &metadata)
+                }
+                (EntryType::Symlink, _) => {
+                    image.create_entry(relative_path.as_os_str(), &metadata, std::io::empty())
+                }
+                _ => image.create_entry(relative_path.as_os_str(), &metadata, &mut entry),
+            };
+
+            // A hard link also fails if its target does not exist, so the parent is only known to be missing if the target was added
+            let retry = deferred && link.as_ref().is_none_or(|l| created.contains(l));
+            match add(self) {
+                Err(err) if retry && is_backend_not_found(&err) => {
+                    debug!(
+                        "Creating the missing ancestors of {:?} -- {err}",
+                        relative_path
+                    );
+                    self.create_missing_ancestors(
+                        &relative_path,
+                        err,
+                        &ancestor,
+                        &mut directories,
+                        &mut created,
+                    )?;
+                    add(self)?;
+                }
+                result => result?,
+            }
+
+            if entry_type == EntryType::Directory {
+                directories.insert(relative_path.clone(), metadata);
+            }
+            created.insert(relative_path);
+        }
+
+        Ok(())
+    }
+
+    /// Creates the ancestors of relative_path that do not exist in the image w/ metadata, after adding relative_path failed w/ err,
+    ///
+    /// Used when the image this image is forked from can't be read. Ancestors are created starting w/ the parent, and an ancestor is only
+    /// created if creating the ancestor below it failed because it does not exist, so ancestors of the existing image keep their metadata.
+    ///
+    /// Returns err if every ancestor up to a directory that is known to exist was not found either.
+    ///
+    fn create_missing_ancestors(
+        &mut self,
+        relative_path: &Path,
+        mut err: CimError,
+        metadata: &FileMetadata,
+        directories: &mut BTreeMap<PathBuf, FileMetadata>,
+        created: &mut BTreeSet<PathBuf>,
+    ) -> Result<()> {
+        let mut missing = vec![];
+        for a in relative_path
+            .ancestors()
+            .skip(1)
+            .take_while(|a| !a.as_os_str().is_empty() && !directories.contains_key(*a))
+        {
+            match self.create_directory(a.as_os_str(), metadata) {
+                Ok(()) => {
+                    // The ancestors below it can be created now that their parent exists
+                    for (i, a) in std::iter::once(a)
+                        .chain(missing.into_iter().rev())
+                        .enumerate()
+                    {
+                        if i > 0 {
+                            self.create_directory(a.as_os_str(), metadata)?;
+                        }
+                        trace!("Created missing ancestor {:?}", a);
+                        created.insert(a.to_path_buf());
+                        directories.insert(a.to_path_buf(), metadata.clone());
+                    }
+                    return Ok(());
+                }
+                Err(e) if is_backend_not_found(&e) => {
+                    trace!("Ancestor {:?} does not exist either -- {e}", a);
+                    missing.push(a);
+                    err = e;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(err)
+    }
+
+    /// Deletes a path removed by a whiteout,
+    ///
+    /// Whiteouts of paths that do not exist in the existing image are ignored, any other error is returned.
+    ///
+    fn apply_whiteout(&mut self, relative_path: &Path) -> Result<()> {
+        trace!("Applying whiteout for {:?}", relative_path);
+        match self.delete_path(relative_path.as_os_str()) {
+            Err(err @ CimError::PathNotFound { .. }) => {
+                warn!("Skipping whiteout for {:?} -- {err}", relative_path);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    /// Clears a directory marked as opaque by deleting and re-creating it,
+    ///
+    /// The directory is re-created w/ the metadata from its entry in the layer, if the layer contained one.
+    ///
+    fn apply_opaque_whiteout(
+        &mut self,
+        relative_path: &Path,
+        marker: &FileMetadata,
+        directories: &BTreeMap<PathBuf, FileMetadata>,
+        created: &BTreeSet<PathBuf>,
+    ) -> Result<()> {
+        if relative_path.as_os_str().is_empty() {
+            warn!("Skipping opaque marker for the root directory");
+            return Ok(());
+        }
+
+        if let Some(c) = created
+            .iter()
+            .find(|c| c.starts_with(relative_path) && *c != relative_path)
+        {
+            return Err(source_error(relative_path, None)(Error::new(
+                ErrorKind::InvalidData,
+                format!("Opaque marker must precede {:?} in the layer", c),
+            ))
+            .into());
+        }
+
+        trace!("Applying opaque marker for {:?}", relative_path);
+        self.apply_whiteout(relative_path)?;
+
+        let metadata = directories
+            .get(relative_path)
+            .cloned()
+            .unwrap_or_else(|| FileMetadata {
+                attributes: FILE_ATTRIBUTE_DIRECTORY.0,
+                ..marker.clone()
+            });
+        self.create_directory(relative_path.as_os_str(), &metadata)
+    }
+}
+
+/// Converts the path of a tar entry into a relative path in the image,
+///
+/// Leading `/` and `.` components are removed, paths containing `..` are rejected.
+///
+fn tar_path(path: &Path) -> Result<PathBuf> {
+    let mut relative_path = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::Normal(p) => relative_path.push(p),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(CimError::PathInvalid {
+                    relative_path: path.to_path_buf(),
+                    reason: "tar entry paths must be within the archive root".to_string(),
+                });
+            }
+        }
+    }
+
+    Ok(relative_path)
+}
+
+/// Returns the metadata for a tar entry,
+///
+/// All file times are set to the modified time of the entry, since that is the only time every tar format stores.
+///
+fn tar_metadata(header: &Header) -> std::io::Result<FileMetadata> {
+    let time = to_file_time(header.mtime()? as i64, 0);
+
+    let attributes = if header.mode().unwrap_or(0o644) & 0o222 == 0 {
+        FILE_ATTRIBUTE_READONLY.0
+    } else {
+        FILE_ATTRIBUTE_NORMAL.0
+    };
+
+    Ok(FileMetadata {
+        attributes,
+        creation_time: time,
+        last_write_time: time,
+        change_time: time,
+        last_access_time: time,
+        ..Default::default()
+    })
+}
+
+/// Returns the error returned when a link entry does not have a link name,
+///
+fn missing_link_name(relative_path: &Path) -> CimError {
+    source_error(relative_path, None)(Error::new(
+        ErrorKind::InvalidData,
+        "Tar link entry is missing a link name",
+    ))
+    .into()
+}
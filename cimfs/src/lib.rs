@@ -1,15 +1,57 @@
+mod backend;
+pub mod digest;
+pub mod ea;
+pub mod error;
 mod image;
+pub mod manifest;
 mod object;
+pub mod oci;
+pub mod provenance;
+mod region;
+pub mod reparse;
+#[cfg(test)]
+mod scratch;
+pub mod security;
+mod source;
+pub mod verify;
+
+/// Error returned by the image apis in `api`, see `error::CimError`,
+///
+pub type Error = error::CimError;
+
+/// Result returned by the image apis in `api`,
+///
+pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 /// Module contains wrapper-types that add convenience api's.
 /// 
 pub mod api {
     pub use super::image::Image;
+    pub use super::image::AsyncImage;
+    pub use super::image::BuildObserver;
+    pub use super::image::BuildOperation;
+    pub use super::image::BuildPlan;
+    pub use super::image::EntryKind;
+    pub use super::image::DedupStats;
+    pub use super::image::Normalization;
     pub use super::object::Object;
+    pub use super::object::Overrides;
+    pub use super::backend::CimBackend;
+    pub use super::backend::DefaultBackend;
+    pub use super::backend::FileMetadata;
+    pub use super::backend::parse_guid;
+    pub use super::backend::RecordedEntry;
+    pub use super::backend::RecordedImage;
+    pub use super::backend::RecordingBackend;
+    #[cfg(windows)]
+    pub use super::backend::CimFsBackend;
+    #[cfg(not(windows))]
+    pub use super::backend::UnsupportedBackend;
 }
 
 /// Module contains raw generated api's as well as utiltiies for working with the os.
 ///
+#[cfg(windows)]
 pub mod raw {
     use std::ffi::c_ulong;
     pub use cimfs_sys::CimCloseImage;
@@ -70,6 +112,7 @@ pub mod raw {
 
 /// Utilities for environment setup,
 /// 
+#[cfg(windows)]
 pub mod util {
     use cimfs_sys::TOKEN_QUERY;
     use cimfs_sys::TOKEN_ADJUST_PRIVILEGES;
@@ -0,0 +1,425 @@
+//! Declarative manifests describing how to build a CIM image,
+//!
+//! A manifest lists entries to add to the image, and paths to delete when forking an existing image. Manifests can be written in TOML or JSON,
+//!
+//! ```toml
+//! deletes = ["etc/old.conf"]
+//!
+//! [[entries]]
+//! src = "target/release/app.exe"
+//! dest = "bin/app.exe"
+//! type = "file"
+//! attributes = 1 # FILE_ATTRIBUTE_READONLY
+//! last_write_time = 1686000000
+//! security_descriptor = "O:BAG:SYD:P(A;;FA;;;BA)(A;;FRFX;;;BU)"
+//! extended_attributes = { "APP.VERSION" = "1.2.3" }
+//!
+//! [[entries]]
+//! src = "config"
+//! type = "directory"
+//! ```
+//!
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::path::Path;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::trace;
+
+use crate::backend::CimBackend;
+use crate::ea::ExtendedAttribute;
+use crate::image::BuildPlan;
+use crate::image::Image;
+use crate::object::Object;
+use crate::object::Overrides;
+use crate::security::SecurityDescriptor;
+use crate::source::to_file_time;
+
+/// Manifest describing the entries of an image,
+///
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Entries to add to the image, in order,
+    ///
+    #[serde(default)]
+    pub entries: Vec<Entry>,
+    /// Paths to delete from the existing image when forking, deletes are applied before any entries are added,
+    ///
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deletes: Vec<PathBuf>,
+    /// Directory relative src paths are resolved against, defaults to the directory containing the manifest file,
+    ///
+    #[serde(skip)]
+    pub base_dir: PathBuf,
+}
+
+/// Type of an entry,
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryType {
+    /// Regular file or reparse point,
+    ///
+    File,
+    /// Directory, added w/ all of its descendants,
+    ///
+    Directory,
+}
+
+/// Entry in a manifest,
+///
+/// File times are unix timestamps in seconds.
+///
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Path to the src, relative paths are resolved against the manifest's base directory,
+    ///
+    pub src: PathBuf,
+    /// Destination path in the image,
+    ///
+    /// If not set, a relative src is added at the same relative path in the image.
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dest: Option<PathBuf>,
+    /// Expected type of the src, if set the build will fail if the src is of a different type,
+    ///
+    #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+    pub entry_type: Option<EntryType>,
+    /// Limits how deep a directory is expanded, ex. 1 will only add the direct children,
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_depth: Option<usize>,
+    /// Replaces the file attributes of the src,
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attributes: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub creation_time: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_write_time: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub change_time: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_access_time: Option<i64>,
+    /// Replaces the security descriptor of the src, as an SDDL string,
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub security_descriptor: Option<SecurityDescriptor>,
+    /// Replaces the extended attributes of the src w/ names and text values, if not empty,
+    ///
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub extended_attributes: BTreeMap<String, String>,
+}
+
+impl Entry {
+    /// Returns the overrides for this entry's metadata,
+    ///
+    /// Returns an error if an extended attribute name is invalid.
+    ///
+    pub fn overrides(&self) -> Result<Overrides> {
+        let file_time = |t: Option<i64>| t.map(|t| to_file_time(t, 0));
+
+        let extended_attributes = if self.extended_attributes.is_empty() {
+            None
+        } else {
+            Some(
+                self.extended_attributes
+                    .iter()
+                    .map(|(name, value)| ExtendedAttribute::new(name, value.as_bytes()))
+                    .collect::<Result<Vec<_>>>()?,
+            )
+        };
+
+        Ok(Overrides {
+            attributes: self.attributes,
+            creation_time: file_time(self.creation_time),
+            last_write_time: file_time(self.last_write_time),
+            change_time: file_time(self.change_time),
+            last_access_time: file_time(self.last_access_time),
+            security_descriptor: self.security_descriptor.clone(),
+            extended_attributes,
+        })
+    }
+}
+
+impl Manifest {
+    /// Reads a manifest from a file, the format is selected by the file extension (`.toml` or `.json`),
+    ///
+    /// The base directory of the manifest is set to the directory containing the file.
+    ///
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| Error::new(e.kind(), format!("{e} -- {:?}", path)))?;
+
+        let mut manifest = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => Self::from_toml(&content)?,
+            Some("json") => Self::from_json(&content)?,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Manifest must be a .toml or .json file -- {:?}", path),
+                ))
+            }
+        };
+
+        manifest.base_dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        Ok(manifest)
+    }
+
+    /// Parses a manifest from TOML, relative src paths are resolved against the current directory,
+    ///
+    pub fn from_toml(content: &str) -> Result<Self> {
+        toml::from_str(content).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Could not parse manifest -- {e}"),
+            )
+        })
+    }
+
+    /// Parses a manifest from JSON, relative src paths are resolved against the current directory,
+    ///
+    pub fn from_json(content: &str) -> Result<Self> {
+        serde_json::from_str(content).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Could not parse manifest -- {e}"),
+            )
+        })
+    }
+
+    /// Returns the objects to add to the image and their required ancestors,
+    ///
+    /// Directories are expanded into their descendants. If entries have the same destination, the last entry wins, its object replaces
+    /// the object of the earlier entry in place, so the entry is still added before the descendants of an earlier directory entry.
+    ///
+    pub fn objects(&self) -> Result<(Vec<Object>, BTreeSet<Object>)> {
+        let mut objects = vec![];
+        let mut ancestors = BTreeSet::new();
+        let mut added = BTreeMap::new();
+
+        for entry in self.entries.iter() {
+            let src = self.base_dir.join(&entry.src);
+            self.check_type(entry, &src)?;
+
+            let object = match entry.dest.as_ref() {
+                Some(dest) => Object::with_destination(&src, dest),
+                None if entry.src.is_relative() => Object::with_destination(&src, &entry.src),
+                None => Object::new(&src),
+            };
+            let mut object = object.with_overrides(entry.overrides()?);
+            ancestors.append(&mut object.resolve_relative_path(true)?);
+
+            let descendants = object.expand(entry.max_depth)?;
+            for o in std::iter::once(object).chain(descendants) {
+                match added.get(o.get_relative_path()?) {
+                    Some(index) => {
+                        trace!("Replacing {:?} w/ {:?}", objects[*index], o);
+                        objects[*index] = o;
+                    }
+                    None => {
+                        added.insert(o.get_relative_path()?.clone(), objects.len());
+                        objects.push(o);
+                    }
+                }
+            }
+        }
+
+        Ok((objects, ancestors))
+    }
+
+    /// Builds an image from this manifest, applying deletes before adding entries,
+    ///
+    /// The image must already be created.
+    ///
+    pub fn build<B: CimBackend>(&self, image: &mut Image<B>) -> Result<()> {
+        let (objects, ancestors) = self.objects()?;
+
+        for d in self.deletes.iter() {
+            image.delete_path(d.as_os_str())?;
+        }
+
+        Ok(image.build(objects, ancestors)?)
+    }
+
+    /// Returns the operations `build()` would apply to an image, w/o writing to the image, see `Image::plan()`,
+    ///
+    pub fn plan<B: CimBackend>(&self, image: &Image<B>) -> Result<BuildPlan> {
+        let (objects, ancestors) = self.objects()?;
+
+        Ok(image
+            .plan(&objects, &ancestors)?
+            .with_deletes(self.deletes.iter().cloned()))
+    }
+
+    /// Checks that the src of an entry matches the entry's type,
+    ///
+    fn check_type(&self, entry: &Entry, src: &Path) -> Result<()> {
+        if let Some(entry_type) = entry.entry_type {
+            let metadata = src
+                .symlink_metadata()
+                .map_err(|e| Error::new(e.kind(), format!("{e} -- {:?}", src)))?;
+
+            let is_directory = metadata.is_dir() && !metadata.file_type().is_symlink();
+            if is_directory != (entry_type == EntryType::Directory) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Expected {:?} to be of type {:?}", src, entry_type),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use super::EntryType;
+    use super::Manifest;
+    use crate::api::Image;
+    use crate::backend::RecordedEntry;
+    use crate::backend::RecordingBackend;
+    use crate::ea::ExtendedAttribute;
+    use crate::security::SecurityDescriptor;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_manifest() {
+        let manifest = Manifest::from_toml(
+            r#"
+            deletes = ["old.txt"]
+
+            [[entries]]
+            src = "src/lib.rs"
+            dest = "bin/lib.rs"
+            type = "file"
+            attributes = 1
+            last_write_time = 0
+            security_descriptor = "O:BAG:SYD:P(A;;FA;;;BA)"
+            extended_attributes = { "APP.VERSION" = "1.2.3" }
+
+            [[entries]]
+            src = "src/bin"
+            type = "directory"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(Some(EntryType::File), manifest.entries[0].entry_type);
+
+        let json = serde_json::to_string(&manifest).unwrap();
+        assert_eq!(manifest, Manifest::from_json(&json).unwrap());
+
+        let mut image = Image::with_backend(".cimroot", "test.cim", RecordingBackend::default());
+        image.create(None).unwrap();
+        manifest.build(&mut image).unwrap();
+
+        let recorded = image.backend().last_image().unwrap();
+        assert_eq!(
+            vec![
+                PathBuf::from("old.txt"),
+                PathBuf::from("bin"),
+                PathBuf::from("src"),
+                PathBuf::from("bin/lib.rs"),
+                PathBuf::from("src/bin"),
+                PathBuf::from("src/bin/cimutil.rs"),
+            ],
+            recorded
+                .entries
+                .iter()
+                .map(|e| e.path().to_path_buf())
+                .collect::<Vec<_>>()
+        );
+
+        match &recorded.entries[3] {
+            RecordedEntry::File { metadata, data, .. } => {
+                assert_eq!(1, metadata.attributes);
+                assert_eq!(crate::source::to_file_time(0, 0), metadata.last_write_time);
+                assert_eq!(
+                    "O:BAG:SYD:P(A;;FA;;;BA)"
+                        .parse::<SecurityDescriptor>()
+                        .unwrap()
+                        .to_bytes(),
+                    metadata.security_descriptor
+                );
+                assert_eq!(
+                    vec![ExtendedAttribute::new("APP.VERSION", "1.2.3").unwrap()],
+                    crate::ea::decode(&metadata.ea_buffer).unwrap()
+                );
+                assert_eq!(std::fs::read("src/lib.rs").unwrap(), *data);
+            }
+            e => panic!("unexpected entry {:?}", e),
+        }
+
+        let mismatched = Manifest::from_toml(
+            r#"
+            [[entries]]
+            src = "src"
+            type = "file"
+            "#,
+        )
+        .unwrap();
+        assert!(mismatched.objects().is_err());
+
+        let invalid_ea = Manifest::from_toml(
+            r#"
+            [[entries]]
+            src = "src/lib.rs"
+            extended_attributes = { "A:B" = "" }
+            "#,
+        )
+        .unwrap();
+        assert!(invalid_ea.objects().is_err());
+
+        let invalid_sddl = Manifest::from_toml(
+            r#"
+            [[entries]]
+            src = "src/lib.rs"
+            security_descriptor = "O:BAD:(A;;FA;;;BA"
+            "#,
+        );
+        assert!(invalid_sddl.is_err());
+    }
+
+    #[test]
+    fn test_manifest_last_entry_wins() {
+        let manifest = Manifest::from_toml(
+            r#"
+            [[entries]]
+            src = "src/bin"
+
+            [[entries]]
+            src = "src/lib.rs"
+            dest = "src/bin/cimutil.rs"
+            attributes = 1
+            "#,
+        )
+        .unwrap();
+
+        let (objects, _) = manifest.objects().unwrap();
+        assert_eq!(
+            vec![
+                PathBuf::from("src/bin"),
+                PathBuf::from("src/bin/cimutil.rs")
+            ],
+            objects
+                .iter()
+                .map(|o| o.get_relative_path().unwrap().clone())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            std::fs::canonicalize("src/lib.rs").unwrap(),
+            objects[1].get_src_path().unwrap()
+        );
+        assert_eq!(Some(1), objects[1].get_overrides().attributes);
+    }
+}
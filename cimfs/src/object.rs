@@ -1,8 +1,16 @@
 use std::collections::BTreeSet;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::path::Path;
 use std::path::PathBuf;
 use tracing::trace;
-use windows::core::Error;
-use windows::Win32::Foundation::E_INVALIDARG;
+use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_DIRECTORY;
+use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_NORMAL;
+use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_REPARSE_POINT;
+
+use crate::backend::FileMetadata;
+use crate::ea::ExtendedAttribute;
+use crate::security::SecurityDescriptor;
 
 /// Struct containing data on the object being added to a CIM image,
 ///
@@ -14,6 +22,67 @@ pub struct Object {
     /// Path to the src object,
     ///
     src: PathBuf,
+    /// Overrides applied to the metadata read from src,
+    ///
+    overrides: Overrides,
+}
+
+/// Struct containing values that replace the metadata read from an object's src,
+///
+/// File times are in the windows file time format, the number of 100-nanosecond intervals since 1601-01-01 (UTC).
+///
+#[derive(Debug, Default, Clone, PartialEq, PartialOrd, Eq, Ord)]
+pub struct Overrides {
+    /// Replaces the file attributes, the directory and reparse point attributes of the src are always kept,
+    ///
+    pub attributes: Option<u32>,
+    pub creation_time: Option<i64>,
+    pub last_write_time: Option<i64>,
+    pub change_time: Option<i64>,
+    pub last_access_time: Option<i64>,
+    /// Replaces the security descriptor of the src,
+    ///
+    pub security_descriptor: Option<SecurityDescriptor>,
+    /// Replaces the extended attributes of the src,
+    ///
+    pub extended_attributes: Option<Vec<ExtendedAttribute>>,
+}
+
+impl Overrides {
+    /// Applies the overrides to metadata,
+    ///
+    pub fn apply(&self, metadata: &mut FileMetadata) {
+        if let Some(attributes) = self.attributes {
+            let kept =
+                metadata.attributes & (FILE_ATTRIBUTE_DIRECTORY.0 | FILE_ATTRIBUTE_REPARSE_POINT.0);
+            metadata.attributes = attributes | kept;
+            if metadata.attributes != FILE_ATTRIBUTE_NORMAL.0 {
+                metadata.attributes &= !FILE_ATTRIBUTE_NORMAL.0;
+            }
+            if metadata.attributes == 0 {
+                metadata.attributes = FILE_ATTRIBUTE_NORMAL.0;
+            }
+        }
+
+        for (value, time) in [
+            (self.creation_time, &mut metadata.creation_time),
+            (self.last_write_time, &mut metadata.last_write_time),
+            (self.change_time, &mut metadata.change_time),
+            (self.last_access_time, &mut metadata.last_access_time),
+        ] {
+            if let Some(value) = value {
+                *time = value;
+            }
+        }
+
+        if let Some(security_descriptor) = self.security_descriptor.as_ref() {
+            metadata.security_descriptor = security_descriptor.to_bytes();
+        }
+
+        if let Some(extended_attributes) = self.extended_attributes.as_ref() {
+            metadata.ea_buffer = crate::ea::encode(extended_attributes);
+        }
+    }
 }
 
 impl Object {
@@ -23,13 +92,82 @@ impl Object {
         Self {
             src: src.into(),
             relative_path: PathBuf::new(),
+            overrides: Overrides::default(),
+        }
+    }
+
+    /// Creates a new object from a src path w/ an explicit destination path in the image,
+    ///
+    /// When ancestors are resolved, each directory in the destination path is created w/ the metadata of the src directory at the same depth,
+    /// ex. for `C:\build\out\app.exe` at `bin\x64\app.exe`, `bin\x64` is created from `C:\build\out` and `bin` from `C:\build`.
+    ///
+    pub fn with_destination(src: impl Into<PathBuf>, dest: impl AsRef<Path>) -> Self {
+        Self {
+            src: src.into(),
+            relative_path: dest.as_ref().to_path_buf(),
+            overrides: Overrides::default(),
+        }
+    }
+
+    /// Creates a new object from a src path, w/ a destination path of src relative to base_dir joined to prefix,
+    ///
+    /// For example, `C:\build\out\bin\app.exe` w/ a base_dir of `C:\build\out` and a prefix of `app` will be added at `app\bin\app.exe`.
+    ///
+    /// Returns an error if src is not within base_dir, or if both the stripped path and prefix are empty.
+    ///
+    pub fn with_base_dir(
+        src: impl Into<PathBuf>,
+        base_dir: impl AsRef<Path>,
+        prefix: impl AsRef<Path>,
+    ) -> Result<Self, Error> {
+        let src = Object::new(src).get_src_path()?;
+        let base_dir = base_dir
+            .as_ref()
+            .canonicalize()
+            .map_err(|e| Error::new(e.kind(), format!("{e} -- {:?}", base_dir.as_ref())))?;
+
+        let dest = src
+            .strip_prefix(&base_dir)
+            .map(|stripped| prefix.as_ref().join(stripped))
+            .map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{:?} is not within the base directory {:?}", src, base_dir),
+                )
+            })?;
+
+        if dest.as_os_str().is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{:?} is the base directory, but a prefix was not set", src),
+            ));
         }
+
+        Ok(Self::with_destination(src, dest))
+    }
+
+    /// Returns self w/ overrides for the metadata read from src, chainable
+    ///
+    /// Overrides are not applied to ancestors or to descendants returned by `expand()`.
+    ///
+    pub fn with_overrides(mut self, overrides: Overrides) -> Self {
+        self.overrides = overrides;
+        self
+    }
+
+    /// Returns the overrides for the metadata read from src,
+    ///
+    pub fn get_overrides(&self) -> &Overrides {
+        &self.overrides
     }
 
     /// Resolves the relative path to use for this object, and returns a set of ancestors required to add this object,
     ///
     /// If the relative_path is not set, it will be interpreted from the src path.
     ///
+    /// Ancestors of different objects can share a relative path w/ different srcs, when their sets are merged `build()` and `plan()` only
+    /// create the first ancestor for each relative path.
+    ///
     pub fn resolve_relative_path(
         &mut self,
         parse_ancestors: bool,
@@ -38,7 +176,7 @@ impl Object {
         if self.relative_path.as_os_str().is_empty() {
             self.src
                 .canonicalize()
-                .map_err(|e| Error::new(E_INVALIDARG, format!("{e} -- {:?}", self.src).into()))?;
+                .map_err(|e| Error::new(e.kind(), format!("{e} -- {:?}", self.src)))?;
             let mut relative_path = PathBuf::new();
 
             let mut root = None::<PathBuf>;
@@ -64,8 +202,7 @@ impl Object {
                     std::path::Component::RootDir => {
                         root = Some(PathBuf::from(c.as_os_str()));
                     }
-                    std::path::Component::CurDir
-                    | std::path::Component::ParentDir => {
+                    std::path::Component::CurDir | std::path::Component::ParentDir => {
                         if let Some(root) = root.as_mut() {
                             *root = root.join(c.as_os_str());
                         } else {
@@ -80,8 +217,13 @@ impl Object {
 
             self.relative_path = relative_path;
             if parse_ancestors {
-                let src = root.take().unwrap_or(PathBuf::new());
-                for a in self.relative_path.ancestors().skip(1).filter(|a| !a.as_os_str().is_empty()) {
+                let src = root.take().unwrap_or_default();
+                for a in self
+                    .relative_path
+                    .ancestors()
+                    .skip(1)
+                    .filter(|a| !a.as_os_str().is_empty())
+                {
                     trace!("ancestor -- {:?}", a);
                     if a.is_file() {
                         break;
@@ -92,6 +234,43 @@ impl Object {
                     ancestors.insert(a);
                 }
             }
+        } else {
+            // An explicit destination only needs to be normalized
+            let mut relative_path = PathBuf::new();
+            for c in self.relative_path.components() {
+                match c {
+                    std::path::Component::Normal(p) => relative_path.push(p),
+                    std::path::Component::RootDir | std::path::Component::CurDir => {}
+                    std::path::Component::Prefix(_) | std::path::Component::ParentDir => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            format!(
+                                "Destination path must be relative to the image root -- {:?}",
+                                self.relative_path
+                            ),
+                        ));
+                    }
+                }
+            }
+            self.relative_path = relative_path;
+
+            if parse_ancestors {
+                let src = self.get_src_path()?;
+                let mut src_ancestors = src.ancestors().skip(1);
+                let mut src = src.as_path();
+
+                for a in self
+                    .relative_path
+                    .ancestors()
+                    .skip(1)
+                    .filter(|a| !a.as_os_str().is_empty())
+                {
+                    // Once the root of src is reached, the root is used for the remaining ancestors
+                    src = src_ancestors.next().unwrap_or(src);
+                    trace!("ancestor -- {:?} w/ src {:?}", a, src);
+                    ancestors.insert(Object::with_destination(src, a));
+                }
+            }
         }
 
         Ok(ancestors)
@@ -104,8 +283,8 @@ impl Object {
     pub fn get_relative_path(&self) -> Result<&PathBuf, Error> {
         if self.relative_path.as_os_str().is_empty() {
             Err(Error::new(
-                E_INVALIDARG,
-                "Object's relative path hasn't been resolved".into(),
+                ErrorKind::InvalidInput,
+                "Object's relative path hasn't been resolved",
             ))
         } else {
             Ok(&self.relative_path)
@@ -114,16 +293,113 @@ impl Object {
 
     /// Returns the fully qualified path to the src object,
     ///
+    /// If the src object is a reparse point, only the parent is canonicalized so that the reparse point itself is not followed.
+    ///
     pub fn get_src_path(&self) -> Result<PathBuf, Error> {
-        self.src
-            .canonicalize()
-            .map_err(|e| Error::new(E_INVALIDARG, format!("{e}").into()))
+        let map_err = |e: Error| Error::new(e.kind(), format!("{e} -- {:?}", self.src));
+
+        match (self.src.symlink_metadata(), self.src.file_name()) {
+            (Ok(metadata), Some(name)) if is_reparse_point(&metadata) => {
+                let parent = match self.src.parent() {
+                    Some(parent) if !parent.as_os_str().is_empty() => parent,
+                    _ => Path::new("."),
+                };
+
+                Ok(parent.canonicalize().map_err(map_err)?.join(name))
+            }
+            _ => self.src.canonicalize().map_err(map_err),
+        }
+    }
+
+    /// Expands a directory object into all of its descendants, in parent-before-child order,
+    ///
+    /// Reparse points (symlinks, junctions, etc) are added as-is, and are never followed. Entries of a directory are sorted by name.
+    ///
+    /// If max_depth is set, descendants deeper than max_depth levels below this object are skipped, ex. a max_depth of 1 will only add the direct children.
+    ///
+    /// If this object is not a directory, an empty vector is returned. The relative path must be resolved before calling this function.
+    ///
+    pub fn expand(&self, max_depth: Option<usize>) -> Result<Vec<Object>, Error> {
+        let relative_path = self.get_relative_path()?;
+        let mut descendants = vec![];
+
+        let metadata = self
+            .src
+            .symlink_metadata()
+            .map_err(|e| Error::new(e.kind(), format!("{e} -- {:?}", self.src)))?;
+        if metadata.is_dir() && !is_reparse_point(&metadata) {
+            walk(&self.src, relative_path, 1, max_depth, &mut descendants)?;
+        }
+
+        Ok(descendants)
+    }
+}
+
+/// Walks the directory at src, adding each entry before its children to descendants,
+///
+fn walk(
+    src: &Path,
+    relative_path: &Path,
+    depth: usize,
+    max_depth: Option<usize>,
+    descendants: &mut Vec<Object>,
+) -> Result<(), Error> {
+    if max_depth.is_some_and(|max| depth > max) {
+        return Ok(());
+    }
+
+    let mut entries = std::fs::read_dir(src)
+        .and_then(|entries| entries.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| Error::new(e.kind(), format!("{e} -- {:?}", src)))?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        // DirEntry::metadata does not traverse symlinks
+        let metadata = entry
+            .metadata()
+            .map_err(|e| Error::new(e.kind(), format!("{e} -- {:?}", entry.path())))?;
+
+        let object = Object {
+            relative_path: relative_path.join(entry.file_name()),
+            src: entry.path(),
+            overrides: Overrides::default(),
+        };
+        trace!("descendant -- {:?}", object.relative_path);
+
+        let (src, relative_path) = (object.src.clone(), object.relative_path.clone());
+        descendants.push(object);
+
+        if metadata.is_dir() && !is_reparse_point(&metadata) {
+            walk(&src, &relative_path, depth + 1, max_depth, descendants)?;
+        }
     }
+
+    Ok(())
+}
+
+/// Returns true if the metadata belongs to a reparse point,
+///
+#[cfg(windows)]
+fn is_reparse_point(metadata: &std::fs::Metadata) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_REPARSE_POINT;
+
+    metadata.file_attributes() & FILE_ATTRIBUTE_REPARSE_POINT.0 != 0
+}
+
+/// Returns true if the metadata belongs to a symlink, which are the only reparse points on this platform,
+///
+#[cfg(not(windows))]
+fn is_reparse_point(metadata: &std::fs::Metadata) -> bool {
+    metadata.file_type().is_symlink()
 }
 
 #[allow(unused_imports)]
 mod tests {
     use super::Object;
+    #[cfg(test)]
+    use crate::scratch::ScratchDir;
+    use std::path::PathBuf;
     #[test]
     #[tracing_test::traced_test]
     fn test_resolve() {
@@ -132,4 +408,91 @@ mod tests {
         let ancestors = t.resolve_relative_path(true);
         println!("{:#?}", ancestors);
     }
+
+    #[test]
+    fn test_expand() {
+        let scratch = ScratchDir::new("object-test-expand");
+        let root = scratch.path();
+        std::fs::create_dir_all(root.join("a/b")).unwrap();
+        std::fs::write(root.join("a/b/c.txt"), b"c").unwrap();
+        std::fs::write(root.join("a/z.txt"), b"z").unwrap();
+        std::fs::write(root.join("d.txt"), b"d").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(root.join("a"), root.join("link")).unwrap();
+
+        let mut t = Object::new(root);
+        t.resolve_relative_path(false).unwrap();
+        let base = t.get_relative_path().unwrap().clone();
+        let relative = |objects: Vec<Object>| {
+            objects
+                .iter()
+                .map(|o| {
+                    o.get_relative_path()
+                        .unwrap()
+                        .strip_prefix(&base)
+                        .unwrap()
+                        .to_path_buf()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut expected = vec![
+            PathBuf::from("a"),
+            PathBuf::from("a/b"),
+            PathBuf::from("a/b/c.txt"),
+            PathBuf::from("a/z.txt"),
+            PathBuf::from("d.txt"),
+        ];
+        #[cfg(unix)]
+        expected.push(PathBuf::from("link"));
+        assert_eq!(expected, relative(t.expand(None).unwrap()));
+
+        let mut expected = vec![PathBuf::from("a"), PathBuf::from("d.txt")];
+        #[cfg(unix)]
+        expected.push(PathBuf::from("link"));
+        assert_eq!(expected, relative(t.expand(Some(1)).unwrap()));
+
+        // Reparse points are not followed
+        #[cfg(unix)]
+        {
+            let mut link = Object::new(root.join("link"));
+            link.resolve_relative_path(false).unwrap();
+            assert!(link.expand(None).unwrap().is_empty());
+            assert_eq!(
+                root.canonicalize().unwrap().join("link"),
+                link.get_src_path().unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_with_base_dir() {
+        let mut t = Object::with_base_dir("src/bin/cimutil.rs", "src", "tools").unwrap();
+        let ancestors = t.resolve_relative_path(true).unwrap();
+        assert_eq!(
+            &PathBuf::from("tools/bin/cimutil.rs"),
+            t.get_relative_path().unwrap()
+        );
+
+        let src = std::path::Path::new("src").canonicalize().unwrap();
+        assert_eq!(
+            vec![
+                (PathBuf::from("tools"), src.clone()),
+                (PathBuf::from("tools/bin"), src.join("bin")),
+            ],
+            ancestors
+                .iter()
+                .map(|a| (
+                    a.get_relative_path().unwrap().clone(),
+                    a.get_src_path().unwrap()
+                ))
+                .collect::<Vec<_>>()
+        );
+
+        assert!(Object::with_base_dir("src", "src", "").is_err());
+        assert!(Object::with_base_dir("Cargo.toml", "src", "").is_err());
+
+        let mut t = Object::with_destination("src/lib.rs", "/../lib.rs");
+        assert!(t.resolve_relative_path(true).is_err());
+    }
 }
@@ -0,0 +1,695 @@
+//! Support for reading container images stored in an OCI image layout directory,
+//!
+//! An image layout contains an `index.json` listing the image manifests, and a `blobs/<algorithm>/<encoded>` directory containing
+//! the manifests, configs and layers addressed by their digest.
+//!
+//! Each layer of an image can be built into a CIM image w/ `Image::build_from_oci_layer()`, forking the image of the previous layer.
+//!
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Result;
+use std::io::Seek;
+use std::path::Path;
+use std::path::PathBuf;
+
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::trace;
+
+use crate::digest::Digest;
+
+/// Annotation containing the reference name of a manifest in an image index,
+///
+pub const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+/// Media type of an image index,
+///
+pub const MEDIA_TYPE_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+
+/// Describes a blob in the image layout,
+///
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Descriptor {
+    /// Media type of the blob,
+    ///
+    pub media_type: String,
+    /// Digest of the blob, ex. sha256:<hex>
+    ///
+    pub digest: String,
+    /// Size of the blob in bytes,
+    ///
+    pub size: u64,
+    /// Arbitrary metadata,
+    ///
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+    /// Platform of the image, only set on the manifests of an image index,
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
+}
+
+impl Descriptor {
+    /// Returns the reference name of this descriptor, if set,
+    ///
+    pub fn ref_name(&self) -> Option<&str> {
+        self.annotations
+            .get(REF_NAME_ANNOTATION)
+            .map(|r| r.as_str())
+    }
+}
+
+/// Platform an image manifest is built for,
+///
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Platform {
+    /// CPU architecture, ex. amd64 or arm64
+    ///
+    pub architecture: String,
+    /// Operating system, ex. windows or linux
+    ///
+    pub os: String,
+    /// Version of the operating system, ex. 10.0.20348.1970
+    ///
+    #[serde(
+        rename = "os.version",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub os_version: Option<String>,
+    /// Variant of the CPU architecture, ex. v8
+    ///
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+}
+
+impl Platform {
+    /// Returns the platform of the host, w/ the names Go uses for the architecture and operating system,
+    ///
+    pub fn host() -> Self {
+        let architecture = match std::env::consts::ARCH {
+            "x86_64" => "amd64",
+            "aarch64" => "arm64",
+            "x86" => "386",
+            arch => arch,
+        };
+        let os = match std::env::consts::OS {
+            "macos" => "darwin",
+            os => os,
+        };
+
+        Self {
+            architecture: architecture.to_string(),
+            os: os.to_string(),
+            os_version: None,
+            variant: None,
+        }
+    }
+
+    /// Returns true if an image built for other can run on this platform,
+    ///
+    /// Only the architecture and operating system are compared, the version and variant are not.
+    ///
+    pub fn matches(&self, other: &Platform) -> bool {
+        self.architecture == other.architecture && self.os == other.os
+    }
+}
+
+/// Image index, the entrypoint of an image layout,
+///
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Index {
+    pub schema_version: u32,
+    pub manifests: Vec<Descriptor>,
+}
+
+/// Image manifest, listing the config and layers of an image,
+///
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    pub schema_version: u32,
+    pub config: Descriptor,
+    /// Layers in the order they are applied, base layer first,
+    ///
+    pub layers: Vec<Descriptor>,
+}
+
+/// Compression used by a layer, derived from its media type,
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Returns the compression of a layer media type,
+    ///
+    /// Both OCI and docker media types are recognized, ex. `application/vnd.oci.image.layer.v1.tar+gzip` or `application/vnd.docker.image.rootfs.diff.tar.gzip`
+    ///
+    pub fn from_media_type(media_type: &str) -> Self {
+        if media_type.ends_with("+gzip") || media_type.ends_with(".gzip") {
+            Compression::Gzip
+        } else if media_type.ends_with("+zstd") || media_type.ends_with(".zstd") {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+/// OCI image layout directory,
+///
+pub struct Layout {
+    /// Root of the image layout,
+    ///
+    root: PathBuf,
+    /// Parsed index.json,
+    ///
+    index: Index,
+}
+
+impl Layout {
+    /// Opens an image layout directory, reading its index.json,
+    ///
+    pub fn open(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        let index = read_json(&root.join("index.json"))?;
+
+        Ok(Self { root, index })
+    }
+
+    /// Returns the image index,
+    ///
+    pub fn index(&self) -> &Index {
+        &self.index
+    }
+
+    /// Returns the manifest for a reference and the platform of the host,
+    ///
+    /// The reference can either be the reference name annotation of a manifest in the index, or the digest of a manifest. Nested image indexes are searched as well.
+    ///
+    pub fn manifest(&self, reference: &str) -> Result<Manifest> {
+        self.manifest_for(reference, &Platform::host())
+    }
+
+    /// Returns the manifest for a reference and a platform,
+    ///
+    /// If the reference names an image index, or several manifests, the first manifest built for platform is returned. Manifests w/o a
+    /// platform match every platform. A manifest referenced by its digest is returned regardless of its platform.
+    ///
+    pub fn manifest_for(&self, reference: &str, platform: &Platform) -> Result<Manifest> {
+        let descriptor = self
+            .find(&self.index, reference, platform)?
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!(
+                        "Could not find a manifest for {reference} and {}/{} in the image layout",
+                        platform.os, platform.architecture
+                    ),
+                )
+            })?;
+        trace!("Found manifest {:?}", descriptor);
+
+        self.read_blob_json(&descriptor)
+    }
+
+    /// Opens a layer for reading, decompressing it if required,
+    ///
+    /// The layer is verified against its descriptor before it is returned, see `open_blob()`.
+    ///
+    pub fn open_layer(&self, layer: &Descriptor) -> Result<Box<dyn Read>> {
+        let blob = BufReader::new(self.open_blob(layer)?);
+
+        match Compression::from_media_type(&layer.media_type) {
+            Compression::None => Ok(Box::new(blob)),
+            Compression::Gzip => Ok(Box::new(GzDecoder::new(blob))),
+            Compression::Zstd => Err(Error::new(
+                ErrorKind::Unsupported,
+                format!(
+                    "zstd compressed layers are not supported -- {}",
+                    layer.digest
+                ),
+            )),
+        }
+    }
+
+    /// Opens a blob for reading, after verifying that its size and sha256 digest match descriptor,
+    ///
+    /// Returns an `InvalidData` error if the blob does not match, and an `Unsupported` error if the digest uses an algorithm other than sha256.
+    ///
+    pub fn open_blob(&self, descriptor: &Descriptor) -> Result<File> {
+        let path = self.blob_path(&descriptor.digest)?;
+        let expected = match descriptor.digest.split_once(':') {
+            Some(("sha256", _)) => descriptor
+                .digest
+                .parse::<Digest>()
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!(
+                        "Cannot verify blob {}, only sha256 is supported",
+                        descriptor.digest
+                    ),
+                ))
+            }
+        };
+
+        let mut blob =
+            File::open(&path).map_err(|e| Error::new(e.kind(), format!("{e} -- {:?}", path)))?;
+        let len = blob.metadata()?.len();
+        if len != descriptor.size {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Blob {} is {len} bytes, but its descriptor has a size of {} bytes",
+                    descriptor.digest, descriptor.size
+                ),
+            ));
+        }
+
+        let actual = Digest::from_reader(BufReader::new(&mut blob))?;
+        if actual != expected {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Blob {} has a digest of {actual}", descriptor.digest),
+            ));
+        }
+        trace!("Verified blob {}", descriptor.digest);
+
+        blob.rewind()?;
+        Ok(blob)
+    }
+
+    /// Returns the path to a blob,
+    ///
+    /// The algorithm of the digest must match `[a-z0-9]+([+._-][a-z0-9]+)*`, and the encoded part `[a-zA-Z0-9=_-]+`, as defined by the image spec.
+    ///
+    pub fn blob_path(&self, digest: &str) -> Result<PathBuf> {
+        match digest.split_once(':') {
+            Some((algorithm, encoded))
+                if valid_algorithm(algorithm)
+                    && !encoded.is_empty()
+                    && encoded
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '=' | '_' | '-')) =>
+            {
+                Ok(self.root.join("blobs").join(algorithm).join(encoded))
+            }
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid digest {digest}"),
+            )),
+        }
+    }
+
+    /// Searches an index for a manifest matching a reference and platform,
+    ///
+    fn find(
+        &self,
+        index: &Index,
+        reference: &str,
+        platform: &Platform,
+    ) -> Result<Option<Descriptor>> {
+        let supported = |d: &Descriptor| d.platform.as_ref().is_none_or(|p| platform.matches(p));
+
+        for m in index.manifests.iter() {
+            let matched = m.ref_name() == Some(reference) || m.digest == reference;
+
+            if m.media_type == MEDIA_TYPE_INDEX {
+                let nested: Index = self.read_blob_json(m)?;
+                if let Some(found) = self.find(&nested, reference, platform)? {
+                    return Ok(Some(found));
+                }
+
+                if let Some(first) = nested
+                    .manifests
+                    .iter()
+                    .find(|n| matched && n.media_type != MEDIA_TYPE_INDEX && supported(n))
+                {
+                    return Ok(Some(first.clone()));
+                }
+            } else if m.digest == reference || (matched && supported(m)) {
+                return Ok(Some(m.clone()));
+            } else if matched {
+                trace!(
+                    "Skipping manifest {}, it is built for {:?}",
+                    m.digest,
+                    m.platform
+                );
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Reads and deserializes a json blob, after verifying it,
+    ///
+    fn read_blob_json<T: serde::de::DeserializeOwned>(&self, descriptor: &Descriptor) -> Result<T> {
+        serde_json::from_reader(BufReader::new(self.open_blob(descriptor)?)).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Could not parse blob {} -- {e}", descriptor.digest),
+            )
+        })
+    }
+}
+
+/// Returns true if the algorithm of a digest matches `[a-z0-9]+([+._-][a-z0-9]+)*`,
+///
+fn valid_algorithm(algorithm: &str) -> bool {
+    algorithm.split(['+', '.', '_', '-']).all(|c| {
+        !c.is_empty()
+            && c.chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+    })
+}
+
+/// Reads and deserializes a json file,
+///
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T> {
+    let file = File::open(path).map_err(|e| Error::new(e.kind(), format!("{e} -- {:?}", path)))?;
+
+    serde_json::from_reader(BufReader::new(file)).map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Could not parse {:?} -- {e}", path),
+        )
+    })
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use super::Descriptor;
+    use super::Index;
+    use super::Layout;
+    use super::Manifest;
+    use super::Platform;
+    use super::REF_NAME_ANNOTATION;
+    use crate::api::Image;
+    use crate::backend::RecordedEntry;
+    use crate::backend::RecordingBackend;
+    use crate::digest::Digest;
+    #[cfg(test)]
+    use crate::scratch::ScratchDir;
+    use std::collections::BTreeMap;
+    use std::io::Read;
+    use std::io::Write;
+    use std::path::Path;
+
+    /// Writes a blob to the layout and returns its descriptor,
+    ///
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn write_blob(root: &Path, media_type: &str, data: &[u8]) -> Descriptor {
+        let digest = Digest::from_reader(data).unwrap();
+        std::fs::create_dir_all(root.join("blobs/sha256")).unwrap();
+        std::fs::write(root.join("blobs/sha256").join(digest.hex()), data).unwrap();
+        Descriptor {
+            media_type: media_type.to_string(),
+            digest: digest.to_string(),
+            size: data.len() as u64,
+            annotations: BTreeMap::new(),
+            platform: None,
+        }
+    }
+
+    #[test]
+    fn test_import_layers() {
+        /// Returns a layer w/ a list of files,
+        ///
+        fn layer(files: &[&str]) -> Vec<u8> {
+            let mut builder = tar::Builder::new(vec![]);
+            for f in files {
+                let mut header = tar::Header::new_gnu();
+                header.set_mode(0o644);
+                header.set_size(f.len() as u64);
+                builder.append_data(&mut header, f, f.as_bytes()).unwrap();
+            }
+            builder.into_inner().unwrap()
+        }
+
+        let scratch = ScratchDir::new("oci-test-import");
+        let root = scratch.path();
+        let layout_root = root.join("layout");
+
+        let mut gzip = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        gzip.write_all(&layer(&["a/b.txt", "a/c.txt", "d/x"]))
+            .unwrap();
+        let base = write_blob(
+            &layout_root,
+            "application/vnd.oci.image.layer.v1.tar+gzip",
+            &gzip.finish().unwrap(),
+        );
+        let top = write_blob(
+            &layout_root,
+            "application/vnd.oci.image.layer.v1.tar",
+            &layer(&["a/.wh.b.txt", "d/.wh..wh..opq", "d/y"]),
+        );
+
+        let manifest = Manifest {
+            schema_version: 2,
+            config: Descriptor::default(),
+            layers: vec![base, top],
+        };
+        let mut manifest = write_blob(
+            &layout_root,
+            "application/vnd.oci.image.manifest.v1+json",
+            &serde_json::to_vec(&manifest).unwrap(),
+        );
+        manifest
+            .annotations
+            .insert(REF_NAME_ANNOTATION.to_string(), "latest".to_string());
+        let index = Index {
+            schema_version: 2,
+            manifests: vec![manifest.clone()],
+        };
+        std::fs::write(
+            layout_root.join("index.json"),
+            serde_json::to_vec(&index).unwrap(),
+        )
+        .unwrap();
+
+        let layout = Layout::open(&layout_root).unwrap();
+        assert!(layout.manifest("missing").is_err());
+        assert_eq!(
+            layout.manifest(&manifest.digest).unwrap(),
+            layout.manifest("latest").unwrap()
+        );
+
+        let manifest = layout.manifest("latest").unwrap();
+        let mut existing = None::<String>;
+        let mut recorded = vec![];
+        for (i, l) in manifest.layers.iter().enumerate() {
+            let name = format!("layer{i}.cim");
+            let mut image = Image::with_backend(root, &name, RecordingBackend::default());
+            image.create(existing.as_deref()).unwrap();
+            image
+                .build_from_oci_layer(layout.open_layer(l).unwrap())
+                .unwrap();
+            image.commit().unwrap();
+            recorded.push(image.backend().last_image().unwrap().clone());
+            existing = Some(name);
+        }
+
+        // Whiteouts are applied as deletes from the layer before
+        let paths = |entries: &[RecordedEntry]| {
+            entries
+                .iter()
+                .map(|e| match e {
+                    RecordedEntry::Delete { path } => format!("-{}", path.display()),
+                    e => e.path().display().to_string(),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(None, recorded[0].existing);
+        assert_eq!(
+            vec!["a", "a/b.txt", "a/c.txt", "d", "d/x"],
+            paths(&recorded[0].entries)
+        );
+        assert_eq!(Some("layer0.cim"), recorded[1].existing.as_deref());
+        assert_eq!(
+            vec!["-a/b.txt", "-d", "d", "d/y"],
+            paths(&recorded[1].entries)
+        );
+        match &recorded[1].entries[3] {
+            RecordedEntry::File { data, .. } => assert_eq!(b"d/y".to_vec(), *data),
+            e => panic!("unexpected entry {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_platform_selection() {
+        let scratch = ScratchDir::new("oci-test-platform");
+        let root = scratch.path();
+
+        let platform = |os: &str, architecture: &str| Platform {
+            architecture: architecture.to_string(),
+            os: os.to_string(),
+            ..Default::default()
+        };
+        let manifest = |layer: &[u8], platform: Option<Platform>| {
+            let layer = write_blob(root, "application/vnd.oci.image.layer.v1.tar", layer);
+            let manifest = Manifest {
+                schema_version: 2,
+                config: Descriptor::default(),
+                layers: vec![layer],
+            };
+            Descriptor {
+                platform,
+                ..write_blob(
+                    root,
+                    "application/vnd.oci.image.manifest.v1+json",
+                    &serde_json::to_vec(&manifest).unwrap(),
+                )
+            }
+        };
+        let linux = manifest(b"linux", Some(platform("linux", "amd64")));
+        let windows = manifest(b"windows", Some(platform("windows", "amd64")));
+        let arm = manifest(b"arm", Some(platform("windows", "arm64")));
+        let any = manifest(b"any", None);
+
+        let nested = Index {
+            schema_version: 2,
+            manifests: vec![linux.clone(), windows.clone(), arm.clone()],
+        };
+        let mut nested = write_blob(
+            root,
+            super::MEDIA_TYPE_INDEX,
+            &serde_json::to_vec(&nested).unwrap(),
+        );
+        nested
+            .annotations
+            .insert(REF_NAME_ANNOTATION.to_string(), "nested".to_string());
+
+        // Manifests of several platforms can also share a reference name in index.json
+        let named = |mut d: Descriptor| {
+            d.annotations
+                .insert(REF_NAME_ANNOTATION.to_string(), "flat".to_string());
+            d
+        };
+        let index = Index {
+            schema_version: 2,
+            manifests: vec![nested, named(linux.clone()), named(arm), named(any)],
+        };
+        std::fs::write(root.join("index.json"), serde_json::to_vec(&index).unwrap()).unwrap();
+        let layout = Layout::open(root).unwrap();
+
+        let layer = |reference: &str, platform: &Platform| {
+            let manifest = layout.manifest_for(reference, platform).unwrap();
+            let mut data = vec![];
+            layout
+                .open_layer(&manifest.layers[0])
+                .unwrap()
+                .read_to_end(&mut data)
+                .unwrap();
+            data
+        };
+        assert_eq!(
+            b"windows".to_vec(),
+            layer("nested", &platform("windows", "amd64"))
+        );
+        assert_eq!(
+            b"arm".to_vec(),
+            layer("nested", &platform("windows", "arm64"))
+        );
+        assert_eq!(
+            b"linux".to_vec(),
+            layer("flat", &platform("linux", "amd64"))
+        );
+        assert_eq!(
+            b"arm".to_vec(),
+            layer("flat", &platform("windows", "arm64"))
+        );
+        // The manifest w/o a platform matches every platform
+        assert_eq!(
+            b"any".to_vec(),
+            layer("flat", &platform("windows", "amd64"))
+        );
+        // A digest selects a manifest regardless of the platform
+        assert_eq!(
+            b"linux".to_vec(),
+            layer(&linux.digest, &platform("windows", "amd64"))
+        );
+
+        let err = layout
+            .manifest_for("nested", &platform("linux", "arm64"))
+            .unwrap_err();
+        assert_eq!(std::io::ErrorKind::NotFound, err.kind());
+        assert!(!Platform::host().os.is_empty());
+    }
+
+    #[test]
+    fn test_verify_blobs() {
+        let scratch = ScratchDir::new("oci-test-verify");
+        let root = scratch.path();
+        std::fs::write(
+            root.join("index.json"),
+            br#"{"schemaVersion":2,"manifests":[]}"#,
+        )
+        .unwrap();
+        let layout = Layout::open(root).unwrap();
+
+        for digest in [
+            "sha256",
+            ":00",
+            "SHA256:00",
+            "sha256+:00",
+            "a..b:00",
+            "../x:00",
+            "sha256:../00",
+            "sha256:a/b",
+        ] {
+            assert!(
+                layout.blob_path(digest).is_err(),
+                "{digest} should be invalid"
+            );
+        }
+        assert_eq!(
+            root.join("blobs/sha256+b64u.x_y-z/a=_-0"),
+            layout.blob_path("sha256+b64u.x_y-z:a=_-0").unwrap()
+        );
+
+        let data = b"layer".to_vec();
+        let digest = Digest::from_reader(&data[..]).unwrap();
+        std::fs::create_dir_all(root.join("blobs/sha256")).unwrap();
+        std::fs::write(root.join("blobs/sha256").join(digest.hex()), &data).unwrap();
+        let descriptor = Descriptor {
+            media_type: "application/vnd.oci.image.layer.v1.tar".to_string(),
+            digest: digest.to_string(),
+            size: data.len() as u64,
+            annotations: BTreeMap::new(),
+            platform: None,
+        };
+        assert!(layout.open_blob(&descriptor).is_ok());
+
+        let wrong_size = Descriptor {
+            size: 4,
+            ..descriptor.clone()
+        };
+        let err = layout.open_blob(&wrong_size).unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+
+        // Same length, different content
+        std::fs::write(root.join("blobs/sha256").join(digest.hex()), b"LAYER").unwrap();
+        let err = layout.open_layer(&descriptor).err().unwrap();
+        assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+
+        let sha512 = Descriptor {
+            digest: "sha512:00".to_string(),
+            ..descriptor
+        };
+        let err = layout.open_blob(&sha512).unwrap_err();
+        assert_eq!(std::io::ErrorKind::Unsupported, err.kind());
+    }
+}
@@ -0,0 +1,208 @@
+//! Content digests of committed images and provenance documents describing how they were built,
+//!
+//! The digest of an image covers the image file and every region and object id file it references, including the files of the images it
+//! was forked from, so an image can be pinned by digest independent of its name.
+//!
+//! If enabled w/ `Image::with_provenance()`, a provenance document is written next to the image when it is committed, ex. `app.cim.provenance.json`,
+//!
+//! ```json
+//! {
+//!   "image": "app.cim",
+//!   "digest": "sha256:...",
+//!   "files": [{ "name": "app.cim", "size": 120, "digest": "sha256:..." }, ...],
+//!   "base": { "image": "base.cim", "digest": "sha256:..." },
+//!   "inputs": [{ "relative_path": "bin/app.exe", "src": "C:\\build\\app.exe" }],
+//!   "options": { "alternate_streams": false, "deduplication": true, "normalization": null },
+//!   "builder": "cimfs 0.1.0"
+//! }
+//! ```
+//!
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::path::Path;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::digest::Digest;
+use crate::digest::Sha256;
+use crate::image::Normalization;
+use crate::region::referenced_files;
+
+/// Suffix of the name of provenance documents, appended to the image name,
+///
+const PROVENANCE_SUFFIX: &str = ".provenance.json";
+
+/// Digest of a committed image and of each of the files it consists of,
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageDigest {
+    /// Digest of the image,
+    ///
+    /// This is the SHA-256 digest of the concatenated digests of the files, in order.
+    ///
+    pub digest: Digest,
+    /// Image file, followed by the region and object id files the image references, sorted by name,
+    ///
+    pub files: Vec<FileDigest>,
+}
+
+/// Digest of a file in the root folder of an image,
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileDigest {
+    pub name: String,
+    pub size: u64,
+    pub digest: Digest,
+}
+
+impl ImageDigest {
+    /// Computes the digest of the image named name in root, w/ the region and object id files of the region sets it references,
+    ///
+    /// The image file name is not part of the digest, the names of the region and object id files are, since they are named after the id of
+    /// their region set, which is recorded in the image file. The region sets are found by their id, the header of the image is not parsed.
+    ///
+    pub fn compute(root: impl AsRef<Path>, name: &str) -> Result<Self> {
+        let root = root.as_ref();
+        Self::from_files(root, name, referenced_files(root, name)?)
+    }
+
+    /// Computes the digest of the image named name in root, over the region and object id files in files,
+    ///
+    /// The image header is not read, so this also works for images written by CimFS, as long as the files the image references are known.
+    ///
+    pub fn from_files(
+        root: impl AsRef<Path>,
+        name: &str,
+        files: impl IntoIterator<Item = String>,
+    ) -> Result<Self> {
+        let root = root.as_ref();
+        let names = std::iter::once(name.to_string())
+            .chain(files.into_iter().collect::<BTreeSet<_>>())
+            .collect::<Vec<_>>();
+
+        let mut files = vec![];
+        for file_name in names {
+            let path = root.join(&file_name);
+            let file = File::open(&path)
+                .map_err(|e| Error::new(e.kind(), format!("{e} -- {:?}", path)))?;
+            let size = file.metadata()?.len();
+            files.push(FileDigest {
+                name: file_name,
+                size,
+                digest: Digest::from_reader(file)?,
+            });
+        }
+
+        let mut hasher = Sha256::new();
+        for f in files.iter() {
+            hasher.update(&f.digest.0);
+        }
+
+        Ok(Self {
+            digest: hasher.finish(),
+            files,
+        })
+    }
+}
+
+/// Document describing how an image was built,
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// Name of the image,
+    ///
+    pub image: String,
+    /// Digest of the image, see `ImageDigest`,
+    ///
+    pub digest: Digest,
+    /// Files the image consists of and their digests,
+    ///
+    pub files: Vec<FileDigest>,
+    /// Image this image was forked from,
+    ///
+    pub base: Option<BaseImage>,
+    /// Srcs added to the image, in the order they were added,
+    ///
+    /// Lists the objects passed to `build()`, and the srcs of `create_file()`. Entries added from tar archives or readers do not have a src
+    /// and are not listed.
+    ///
+    pub inputs: Vec<Input>,
+    /// Options that affect the contents of the image,
+    ///
+    pub options: BuildOptions,
+    /// Name and version of the library that built the image,
+    ///
+    pub builder: String,
+}
+
+/// Image an image was forked from,
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BaseImage {
+    pub image: String,
+    pub digest: Digest,
+}
+
+/// Src added to an image,
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Input {
+    /// Path of the entry in the image,
+    ///
+    pub relative_path: PathBuf,
+    /// Path of the src the entry was added from,
+    ///
+    pub src: PathBuf,
+}
+
+/// Options of an image that affect its contents,
+///
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildOptions {
+    pub alternate_streams: bool,
+    pub deduplication: bool,
+    pub normalization: Option<Normalization>,
+}
+
+impl Provenance {
+    /// Returns the path of the provenance document of the image named name in root,
+    ///
+    pub fn path(root: impl AsRef<Path>, name: &str) -> PathBuf {
+        root.as_ref().join(format!("{name}{PROVENANCE_SUFFIX}"))
+    }
+
+    /// Reads the provenance document of the image named name in root,
+    ///
+    pub fn read(root: impl AsRef<Path>, name: &str) -> Result<Self> {
+        let path = Self::path(root, name);
+        let content = std::fs::read(&path)?;
+        serde_json::from_slice(&content).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Could not parse provenance {:?} -- {e}", path),
+            )
+        })
+    }
+
+    /// Writes the provenance document next to the image in root,
+    ///
+    /// The document is written to a temporary file first, so that a partial document is never read.
+    ///
+    pub fn write(&self, root: impl AsRef<Path>) -> Result<()> {
+        let path = Self::path(&root, &self.image);
+        let temp = root
+            .as_ref()
+            .join(format!(".{}{PROVENANCE_SUFFIX}.tmp", self.image));
+
+        let content = serde_json::to_vec_pretty(self).map_err(Error::other)?;
+        std::fs::write(&temp, content)?;
+        std::fs::rename(&temp, &path).inspect_err(|_| {
+            std::fs::remove_file(&temp).ok();
+        })
+    }
+}
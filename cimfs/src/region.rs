@@ -0,0 +1,161 @@
+//! Region and object id files of committed images,
+//!
+//! CimFS writes the data and metadata of an image to region files, and the object ids of its files to object id files. The files of a
+//! region set are named after the id of the set, `region_<id>_<index>` and `objectid_<id>_<index>`, and a committed image lists the ids
+//! of the region sets it uses in the header of the image file, including the sets of the images it was forked from.
+//!
+//! The header of the image file is not parsed, instead an image references a region set if the image file contains its id, in the binary
+//! form of a GUID. Since an id is 16 random bytes, the id of a set the image does not use is not expected to appear in the image file.
+//!
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::io::Error;
+use std::io::Result;
+use std::path::Path;
+
+use tracing::trace;
+use windows::core::GUID;
+
+use crate::backend::parse_guid;
+
+/// Prefix of region file names,
+///
+pub const REGION_PREFIX: &str = "region_";
+
+/// Prefix of object id file names,
+///
+pub const OBJECTID_PREFIX: &str = "objectid_";
+
+/// Returns the names of the region and object id files in a folder,
+///
+pub fn region_files(dir: &Path) -> Result<BTreeSet<OsString>> {
+    let mut files = BTreeSet::new();
+    for entry in
+        std::fs::read_dir(dir).map_err(|e| Error::new(e.kind(), format!("{e} -- {:?}", dir)))?
+    {
+        let name = entry?.file_name();
+        if region_set_id(&name.to_string_lossy()).is_some() {
+            files.insert(name);
+        }
+    }
+    Ok(files)
+}
+
+/// Returns the names of the region and object id files in root that the image named name references,
+///
+pub fn referenced_files(root: &Path, name: &str) -> Result<BTreeSet<String>> {
+    let path = root.join(name);
+    let image =
+        std::fs::read(&path).map_err(|e| Error::new(e.kind(), format!("{e} -- {:?}", path)))?;
+
+    let mut files = BTreeSet::new();
+    for file in region_files(root)? {
+        let file = file.to_string_lossy().into_owned();
+        if region_set_id(&file).is_some_and(|id| contains(&image, &guid_bytes(&id))) {
+            files.insert(file);
+        }
+    }
+    trace!("{name} references {:?}", files);
+    Ok(files)
+}
+
+/// Returns the id of the region set a region or object id file belongs to, parsed from its name,
+///
+/// The id can be enclosed in braces and either case, ex. `region_{04522DCD-F383-4F1C-AEA6-AF8F93E020D5}_0`.
+///
+pub fn region_set_id(file_name: &str) -> Option<GUID> {
+    let rest = file_name
+        .strip_prefix(REGION_PREFIX)
+        .or_else(|| file_name.strip_prefix(OBJECTID_PREFIX))?;
+    let (id, index) = rest.rsplit_once('_')?;
+    index.parse::<u16>().ok()?;
+    parse_guid(id.trim_start_matches('{').trim_end_matches('}')).ok()
+}
+
+/// Returns the bytes of a GUID as they are stored in a file,
+///
+pub fn guid_bytes(id: &GUID) -> [u8; 16] {
+    let mut bytes = [0; 16];
+    bytes[..4].copy_from_slice(&id.data1.to_le_bytes());
+    bytes[4..6].copy_from_slice(&id.data2.to_le_bytes());
+    bytes[6..8].copy_from_slice(&id.data3.to_le_bytes());
+    bytes[8..].copy_from_slice(&id.data4);
+    bytes
+}
+
+/// Returns true if data contains bytes,
+///
+fn contains(data: &[u8], bytes: &[u8]) -> bool {
+    data.windows(bytes.len()).any(|w| w == bytes)
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use super::guid_bytes;
+    use super::referenced_files;
+    use super::region_set_id;
+    use crate::backend::parse_guid;
+    #[cfg(test)]
+    use crate::scratch::ScratchDir;
+    use std::collections::BTreeSet;
+    use windows::core::GUID;
+
+    #[test]
+    fn test_region_set_id() {
+        let id = parse_guid("04522dcd-f383-4f1c-aea6-af8f93e020d5").unwrap();
+        assert_eq!(
+            Some(id),
+            region_set_id("region_04522dcd-f383-4f1c-aea6-af8f93e020d5_0")
+        );
+        assert_eq!(
+            Some(id),
+            region_set_id("objectid_{04522DCD-F383-4F1C-AEA6-AF8F93E020D5}_12")
+        );
+        assert_eq!(
+            None,
+            region_set_id("region_04522dcd-f383-4f1c-aea6-af8f93e020d5")
+        );
+        assert_eq!(None, region_set_id("region_04522dcd_0"));
+        assert_eq!(None, region_set_id("image.cim"));
+        assert_eq!(
+            [
+                0xcd, 0x2d, 0x52, 0x04, 0x83, 0xf3, 0x1c, 0x4f, 0xae, 0xa6, 0xaf, 0x8f, 0x93, 0xe0,
+                0x20, 0xd5
+            ],
+            guid_bytes(&id)
+        );
+    }
+
+    #[test]
+    fn test_referenced_files() {
+        let scratch = ScratchDir::new("region-test-referenced");
+        let root = scratch.path();
+
+        let base = parse_guid("04522dcd-f383-4f1c-aea6-af8f93e020d5").unwrap();
+        let fork = parse_guid("9c2a5f0e-1b7d-4e3a-8f6b-2d4c6e8a0b1c").unwrap();
+        let other = parse_guid("5d8e1f2a-3b4c-4d5e-9f6a-7b8c9d0e1f2a").unwrap();
+        let files = |id: &GUID| {
+            let id = format!("{:?}", id).to_lowercase();
+            [
+                format!("region_{id}_0"),
+                format!("region_{id}_1"),
+                format!("objectid_{id}_0"),
+            ]
+        };
+        for file in [base, fork, other].iter().flat_map(files) {
+            std::fs::write(root.join(file), b"").unwrap();
+        }
+        std::fs::write(root.join("region_notes.txt"), b"").unwrap();
+
+        let mut image = b"header".to_vec();
+        image.extend(guid_bytes(&fork));
+        image.extend(guid_bytes(&base));
+        std::fs::write(root.join("fork.cim"), &image).unwrap();
+
+        let expected = [base, fork].iter().flat_map(files).collect::<BTreeSet<_>>();
+        assert_eq!(expected, referenced_files(root, "fork.cim").unwrap());
+
+        let err = referenced_files(root, "missing.cim").unwrap_err();
+        assert_eq!(std::io::ErrorKind::NotFound, err.kind());
+    }
+}
@@ -0,0 +1,434 @@
+//! Reparse data of symlinks, mount points and other reparse points,
+//!
+//! Reparse data starts w/ a header containing the reparse tag and the length of the data that follows. Symlinks and mount points store a
+//! substitute name, the path the reparse point redirects to, and a print name, the path displayed to users, as UTF-16LE strings.
+//!
+//! ```
+//! use cimfs::reparse::ReparseData;
+//!
+//! let link = ReparseData::symlink("../lib/app.dll");
+//! assert_eq!(Some("..\\lib\\app.dll"), link.print_name());
+//! assert!(link.is_relative());
+//! assert_eq!(link, ReparseData::from_bytes(&link.to_bytes().unwrap()).unwrap());
+//! ```
+//!
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+
+use bytes::BufMut;
+
+/// Reparse tag of symbolic links,
+///
+pub const IO_REPARSE_TAG_SYMLINK: u32 = 0xA000000C;
+
+/// Reparse tag of mount points and junctions,
+///
+pub const IO_REPARSE_TAG_MOUNT_POINT: u32 = 0xA0000003;
+
+/// Flag set on symbolic links w/ a relative target,
+///
+pub const SYMLINK_FLAG_RELATIVE: u32 = 0x1;
+
+/// Prefix of NT paths in the object manager namespace, ex. `\??\C:\dir`,
+///
+const NT_PREFIX: &str = "\\??\\";
+
+/// Size of the reparse data header, the tag, data length and a reserved field,
+///
+const HEADER_SIZE: usize = 8;
+
+/// Bit set in reparse tags owned by microsoft,
+///
+const IO_REPARSE_TAG_MICROSOFT: u32 = 0x8000_0000;
+
+/// Size of the guid that follows the header of reparse data w/ a tag that is not owned by microsoft,
+///
+const GUID_SIZE: usize = 16;
+
+/// Parsed reparse data,
+///
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ReparseData {
+    /// Symbolic link, `IO_REPARSE_TAG_SYMLINK`
+    ///
+    Symlink {
+        substitute_name: String,
+        print_name: String,
+        /// Flags of the link, ex. `SYMLINK_FLAG_RELATIVE`
+        ///
+        flags: u32,
+    },
+    /// Mount point or junction, `IO_REPARSE_TAG_MOUNT_POINT`
+    ///
+    MountPoint {
+        substitute_name: String,
+        print_name: String,
+    },
+    /// Reparse point w/ any other tag, data is everything following the header,
+    ///
+    /// For tags w/o the microsoft bit set, the data starts w/ the 16 byte guid of the reparse point's owner, which is not counted in the data
+    /// length of the header.
+    ///
+    Generic { tag: u32, data: Vec<u8> },
+}
+
+impl ReparseData {
+    /// Returns reparse data for a symbolic link to target,
+    ///
+    /// `/` separators are converted to `\`. Fully qualified targets, ex. `C:\dir\file` or `\\server\share\file`, are absolute links w/ an NT substitute
+    /// name. Any other target, including `\dir\file` which is relative to the root of the link's volume, is a relative link.
+    ///
+    pub fn symlink(target: &str) -> Self {
+        let target = target.replace('/', "\\");
+
+        match nt_path(&target) {
+            Some(substitute_name) => ReparseData::Symlink {
+                substitute_name,
+                print_name: target,
+                flags: 0,
+            },
+            None => ReparseData::Symlink {
+                substitute_name: target.clone(),
+                print_name: target,
+                flags: SYMLINK_FLAG_RELATIVE,
+            },
+        }
+    }
+
+    /// Returns reparse data for a mount point (junction) to target,
+    ///
+    /// Returns an error if target is not fully qualified, ex. `C:\dir`.
+    ///
+    pub fn mount_point(target: &str) -> Result<Self> {
+        let target = target.replace('/', "\\");
+
+        let substitute_name = nt_path(&target).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Mount point target must be fully qualified -- {target}"),
+            )
+        })?;
+
+        Ok(ReparseData::MountPoint {
+            substitute_name,
+            print_name: target,
+        })
+    }
+
+    /// Returns the reparse tag,
+    ///
+    pub fn tag(&self) -> u32 {
+        match self {
+            ReparseData::Symlink { .. } => IO_REPARSE_TAG_SYMLINK,
+            ReparseData::MountPoint { .. } => IO_REPARSE_TAG_MOUNT_POINT,
+            ReparseData::Generic { tag, .. } => *tag,
+        }
+    }
+
+    /// Returns the substitute name of a symlink or mount point,
+    ///
+    pub fn substitute_name(&self) -> Option<&str> {
+        match self {
+            ReparseData::Symlink {
+                substitute_name, ..
+            }
+            | ReparseData::MountPoint {
+                substitute_name, ..
+            } => Some(substitute_name),
+            ReparseData::Generic { .. } => None,
+        }
+    }
+
+    /// Returns the print name of a symlink or mount point,
+    ///
+    pub fn print_name(&self) -> Option<&str> {
+        match self {
+            ReparseData::Symlink { print_name, .. }
+            | ReparseData::MountPoint { print_name, .. } => Some(print_name),
+            ReparseData::Generic { .. } => None,
+        }
+    }
+
+    /// Returns true if this is a symlink w/ a relative target,
+    ///
+    pub fn is_relative(&self) -> bool {
+        matches!(self, ReparseData::Symlink { flags, .. } if flags & SYMLINK_FLAG_RELATIVE != 0)
+    }
+
+    /// Parses reparse data, ex. as returned by `FSCTL_GET_REPARSE_POINT`,
+    ///
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        if buf.len() < HEADER_SIZE {
+            return Err(invalid_data("Reparse data is truncated"));
+        }
+
+        let tag = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        let len = u16::from_le_bytes([buf[4], buf[5]]) as usize + guid_len(tag);
+        let data = buf
+            .get(HEADER_SIZE..HEADER_SIZE + len)
+            .ok_or_else(|| invalid_data("Reparse data is shorter than its data length"))?;
+
+        let names = |path_buffer: usize| -> Result<(String, String)> {
+            let field = |i: usize| u16::from_le_bytes([data[i * 2], data[i * 2 + 1]]) as usize;
+            // usize::is_multiple_of() needs rust 1.87
+            #[allow(clippy::manual_is_multiple_of)]
+            let name = |offset: usize, len: usize| {
+                data.get(path_buffer + offset..path_buffer + offset + len)
+                    .filter(|_| len % 2 == 0)
+                    .map(decode_utf16)
+                    .ok_or_else(|| invalid_data("Reparse data name is out of range"))
+            };
+
+            if data.len() < path_buffer {
+                return Err(invalid_data("Reparse data is truncated"));
+            }
+            Ok((name(field(0), field(1))?, name(field(2), field(3))?))
+        };
+
+        match tag {
+            IO_REPARSE_TAG_SYMLINK => {
+                let (substitute_name, print_name) = names(12)?;
+                Ok(ReparseData::Symlink {
+                    substitute_name,
+                    print_name,
+                    flags: u32::from_le_bytes([data[8], data[9], data[10], data[11]]),
+                })
+            }
+            IO_REPARSE_TAG_MOUNT_POINT => {
+                let (substitute_name, print_name) = names(8)?;
+                Ok(ReparseData::MountPoint {
+                    substitute_name,
+                    print_name,
+                })
+            }
+            tag => Ok(ReparseData::Generic {
+                tag,
+                data: data.to_vec(),
+            }),
+        }
+    }
+
+    /// Encodes the reparse data, including its header,
+    ///
+    /// The substitute name is written before the print name. Names of mount points are null terminated, which is the layout written by windows.
+    ///
+    /// Returns an error if a name or the data does not fit in the 16 bit lengths of the layout, or if the data of a tag that is not owned by
+    /// microsoft does not start w/ a guid.
+    ///
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut data = vec![];
+
+        match self {
+            ReparseData::Symlink {
+                substitute_name,
+                print_name,
+                flags,
+            } => {
+                let (substitute_name, print_name) =
+                    (encode_utf16(substitute_name), encode_utf16(print_name));
+                let substitute_len = length_field("substitute name", substitute_name.len())?;
+                data.put_u16_le(0);
+                data.put_u16_le(substitute_len);
+                data.put_u16_le(substitute_len);
+                data.put_u16_le(length_field("print name", print_name.len())?);
+                data.put_u32_le(*flags);
+                data.put_slice(&substitute_name);
+                data.put_slice(&print_name);
+            }
+            ReparseData::MountPoint {
+                substitute_name,
+                print_name,
+            } => {
+                let (substitute_name, print_name) =
+                    (encode_utf16(substitute_name), encode_utf16(print_name));
+                data.put_u16_le(0);
+                data.put_u16_le(length_field("substitute name", substitute_name.len())?);
+                // Print name follows the null terminator of the substitute name
+                data.put_u16_le(length_field("substitute name", substitute_name.len() + 2)?);
+                data.put_u16_le(length_field("print name", print_name.len())?);
+                data.put_slice(&substitute_name);
+                data.put_u16_le(0);
+                data.put_slice(&print_name);
+                data.put_u16_le(0);
+            }
+            ReparseData::Generic { tag, data: d } => {
+                if d.len() < guid_len(*tag) {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Reparse data of tag {tag:#010x} must start w/ a guid"),
+                    ));
+                }
+                data.put_slice(d)
+            }
+        }
+
+        let len = length_field("data", data.len() - guid_len(self.tag()))?;
+        let mut buf = Vec::with_capacity(HEADER_SIZE + data.len());
+        buf.put_u32_le(self.tag());
+        buf.put_u16_le(len);
+        buf.put_u16_le(0);
+        buf.put_slice(&data);
+        Ok(buf)
+    }
+}
+
+/// Returns the NT path of a fully qualified path, or None if the path is not fully qualified,
+///
+fn nt_path(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+
+    if path.starts_with(NT_PREFIX) {
+        Some(path.to_string())
+    } else if bytes.len() >= 3 && bytes[0].is_ascii_alphabetic() && &bytes[1..3] == b":\\" {
+        Some(format!("{NT_PREFIX}{path}"))
+    } else {
+        path.strip_prefix("\\\\")
+            .filter(|unc| !unc.is_empty() && !unc.starts_with(['\\', '?', '.']))
+            .map(|unc| format!("{NT_PREFIX}UNC\\{unc}"))
+    }
+}
+
+/// Returns the length of the guid that follows the header for tag, which is not counted in the data length,
+///
+fn guid_len(tag: u32) -> usize {
+    if tag & IO_REPARSE_TAG_MICROSOFT == 0 {
+        GUID_SIZE
+    } else {
+        0
+    }
+}
+
+/// Returns len as a 16 bit length field, or an error if len does not fit,
+///
+fn length_field(what: &str, len: usize) -> Result<u16> {
+    u16::try_from(len).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("Reparse data {what} is too long -- {len} bytes"),
+        )
+    })
+}
+
+/// Encodes a string as UTF-16LE,
+///
+fn encode_utf16(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(|c| c.to_le_bytes()).collect()
+}
+
+/// Decodes a UTF-16LE string,
+///
+fn decode_utf16(b: &[u8]) -> String {
+    String::from_utf16_lossy(
+        &b.chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// Returns an invalid data error,
+///
+fn invalid_data(msg: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.into())
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use super::ReparseData;
+    use super::IO_REPARSE_TAG_MOUNT_POINT;
+
+    #[test]
+    fn test_reparse_data() {
+        let link = ReparseData::symlink("a/b");
+        #[rustfmt::skip]
+        let expected: &[u8] = &[
+            0x0c, 0x00, 0x00, 0xa0, 0x18, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x06, 0x00, 0x06, 0x00, 0x06, 0x00,
+            0x01, 0x00, 0x00, 0x00,
+            b'a', 0x00, b'\\', 0x00, b'b', 0x00,
+            b'a', 0x00, b'\\', 0x00, b'b', 0x00,
+        ];
+        assert_eq!(expected, link.to_bytes().unwrap());
+        assert_eq!(link, ReparseData::from_bytes(expected).unwrap());
+        assert!(link.is_relative());
+
+        let absolute = ReparseData::symlink("C:/Program Files/app");
+        assert_eq!(
+            Some("\\??\\C:\\Program Files\\app"),
+            absolute.substitute_name()
+        );
+        assert_eq!(Some("C:\\Program Files\\app"), absolute.print_name());
+        assert!(!absolute.is_relative());
+
+        let unc = ReparseData::symlink("\\\\server\\share");
+        assert_eq!(Some("\\??\\UNC\\server\\share"), unc.substitute_name());
+
+        let rooted = ReparseData::symlink("/usr/bin");
+        assert_eq!(Some("\\usr\\bin"), rooted.substitute_name());
+        assert!(rooted.is_relative());
+
+        let junction = ReparseData::mount_point("D:\\data").unwrap();
+        let bytes = junction.to_bytes().unwrap();
+        assert_eq!(IO_REPARSE_TAG_MOUNT_POINT.to_le_bytes(), bytes[..4]);
+        // Substitute and print names, each w/ a null terminator
+        assert_eq!(8 + 8 + 2 * (11 + 1) + 2 * (7 + 1), bytes.len());
+        assert_eq!(junction, ReparseData::from_bytes(&bytes).unwrap());
+        assert!(ReparseData::mount_point("data").is_err());
+
+        let generic = ReparseData::Generic {
+            tag: 0x8000_0023,
+            data: vec![1, 2, 3],
+        };
+        assert_eq!(
+            generic,
+            ReparseData::from_bytes(&generic.to_bytes().unwrap()).unwrap()
+        );
+
+        assert!(ReparseData::from_bytes(&expected[..10]).is_err());
+        assert!(ReparseData::from_bytes(&expected[..30]).is_err());
+    }
+
+    #[test]
+    fn test_reparse_data_guid() {
+        // Tag w/o the microsoft bit, the guid is not counted in the data length
+        let mut data = (0..16).collect::<Vec<u8>>();
+        data.extend_from_slice(&[1, 2, 3]);
+        let generic = ReparseData::Generic {
+            tag: 0x0000_0123,
+            data,
+        };
+        let bytes = generic.to_bytes().unwrap();
+        assert_eq!([0x23, 0x01, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00], bytes[..8]);
+        assert_eq!(8 + 16 + 3, bytes.len());
+        assert_eq!(generic, ReparseData::from_bytes(&bytes).unwrap());
+        assert!(ReparseData::from_bytes(&bytes[..8 + 16 + 2]).is_err());
+
+        let missing = ReparseData::Generic {
+            tag: 0x0000_0123,
+            data: vec![1, 2, 3],
+        };
+        assert!(missing.to_bytes().is_err());
+    }
+
+    #[test]
+    fn test_reparse_data_too_long() {
+        // Names would be truncated if their lengths were not checked
+        let target = "a".repeat(0x8000);
+        let err = ReparseData::symlink(&target).to_bytes().unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidInput, err.kind());
+        let err = ReparseData::mount_point(&format!("C:\\{target}"))
+            .unwrap()
+            .to_bytes()
+            .unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidInput, err.kind());
+
+        // Names that fit, but not together
+        let target = "a".repeat(0x4000);
+        assert!(ReparseData::symlink(&target).to_bytes().is_err());
+        let generic = ReparseData::Generic {
+            tag: 0x8000_0023,
+            data: vec![0; 0x10000],
+        };
+        assert!(generic.to_bytes().is_err());
+    }
+}
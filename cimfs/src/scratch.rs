@@ -0,0 +1,46 @@
+//! Scratch directories used by tests,
+//!
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+/// Number of scratch directories created by this process, so that each directory has a unique name,
+///
+static CREATED: AtomicUsize = AtomicUsize::new(0);
+
+/// Directory in the temp dir that is removed when dropped, used by tests to write srcs and images,
+///
+/// The name includes the process id and a counter, so that tests running in parallel, in the same or in different processes, do not
+/// share a directory. Since the directory is removed on drop, it is also removed when an assertion fails.
+///
+pub(crate) struct ScratchDir {
+    path: PathBuf,
+}
+
+impl ScratchDir {
+    /// Creates a new empty scratch directory, name is only used to make the directory easier to identify,
+    ///
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "cimfs-{name}-{}-{}",
+            std::process::id(),
+            CREATED.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path).expect("should be able to create a scratch directory");
+        Self { path }
+    }
+
+    /// Returns the path of the directory,
+    ///
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
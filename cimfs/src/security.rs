@@ -0,0 +1,626 @@
+//! Portable self-relative security descriptors,
+//!
+//! Security descriptors are stored in a CIM image in the self-relative format, a header followed by the SACL, DACL, owner and group
+//! of the descriptor, each located by an offset from the start of the buffer. This module can build and parse that format on any platform,
+//! and convert it to and from the security descriptor definition language (SDDL), ex.
+//!
+//! ```
+//! use cimfs::security::SecurityDescriptor;
+//!
+//! let sd = "O:BAG:SYD:P(A;OICI;FA;;;BA)(A;OICI;FR;;;WD)"
+//!     .parse::<SecurityDescriptor>()
+//!     .unwrap();
+//! let bytes = sd.to_bytes();
+//! assert_eq!(sd, SecurityDescriptor::from_bytes(&bytes).unwrap());
+//! assert_eq!("O:BAG:SYD:P(A;OICI;FA;;;BA)(A;OICI;FR;;;WD)", sd.to_string());
+//! ```
+//!
+//! Only the ACE types used by file systems are supported, ACEs w/ object types, conditions or resource attributes are rejected.
+//!
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::str::FromStr;
+
+use bytes::Buf;
+use bytes::BufMut;
+use serde::Deserialize;
+use serde::Serialize;
+
+mod sddl;
+
+/// Revision of the security descriptor structure,
+///
+pub const SECURITY_DESCRIPTOR_REVISION: u8 = 1;
+
+/// Revision of ACLs that only contain standard ACEs,
+///
+pub const ACL_REVISION: u8 = 2;
+
+/// Control flag set when the owner was set by a default mechanism,
+///
+pub const SE_OWNER_DEFAULTED: u16 = 0x0001;
+
+/// Control flag set when the group was set by a default mechanism,
+///
+pub const SE_GROUP_DEFAULTED: u16 = 0x0002;
+
+/// Control flag set when the descriptor has a DACL, if the DACL is not set this is a null DACL which grants full access to everyone,
+///
+pub const SE_DACL_PRESENT: u16 = 0x0004;
+
+/// Control flag set when the descriptor has a SACL,
+///
+pub const SE_SACL_PRESENT: u16 = 0x0010;
+
+/// Control flag requesting that the DACL is propagated to existing children,
+///
+pub const SE_DACL_AUTO_INHERIT_REQ: u16 = 0x0100;
+
+/// Control flag requesting that the SACL is propagated to existing children,
+///
+pub const SE_SACL_AUTO_INHERIT_REQ: u16 = 0x0200;
+
+/// Control flag set when the DACL was set up to support propagation of inheritable ACEs to children,
+///
+pub const SE_DACL_AUTO_INHERITED: u16 = 0x0400;
+
+/// Control flag set when the SACL was set up to support propagation of inheritable ACEs to children,
+///
+pub const SE_SACL_AUTO_INHERITED: u16 = 0x0800;
+
+/// Control flag preventing the DACL from being modified by inheritable ACEs,
+///
+pub const SE_DACL_PROTECTED: u16 = 0x1000;
+
+/// Control flag preventing the SACL from being modified by inheritable ACEs,
+///
+pub const SE_SACL_PROTECTED: u16 = 0x2000;
+
+/// Control flag set when the descriptor is in the self-relative format,
+///
+pub const SE_SELF_RELATIVE: u16 = 0x8000;
+
+/// Security identifier,
+///
+#[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord, Hash)]
+pub struct Sid {
+    /// 48-bit identifier authority,
+    ///
+    pub authority: u64,
+    /// Sub authorities, at most 15,
+    ///
+    pub sub_authorities: Vec<u32>,
+}
+
+/// Type of an ACE,
+///
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq, Ord, Hash)]
+pub enum AceType {
+    AccessAllowed,
+    AccessDenied,
+    SystemAudit,
+    SystemAlarm,
+    SystemMandatoryLabel,
+}
+
+/// Access control entry,
+///
+#[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord, Hash)]
+pub struct Ace {
+    pub ace_type: AceType,
+    /// Inheritance and audit flags, ex. `OBJECT_INHERIT_ACE`,
+    ///
+    pub flags: u8,
+    /// Access mask,
+    ///
+    pub mask: u32,
+    /// Trustee the ACE applies to,
+    ///
+    pub sid: Sid,
+}
+
+/// Access control list,
+///
+#[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord, Hash)]
+pub struct Acl {
+    /// Revision of the ACL, defaults to `ACL_REVISION`,
+    ///
+    pub revision: u8,
+    /// ACEs in the order they are evaluated,
+    ///
+    pub aces: Vec<Ace>,
+}
+
+/// Security descriptor, convertible to and from the self-relative format and SDDL,
+///
+/// Serialized as an SDDL string.
+///
+#[derive(Debug, Default, Clone, PartialEq, PartialOrd, Eq, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct SecurityDescriptor {
+    /// Control flags, `SE_SELF_RELATIVE` and the present flags of the DACL and SACL are set when the descriptor is encoded,
+    ///
+    pub control: u16,
+    pub owner: Option<Sid>,
+    pub group: Option<Sid>,
+    /// Discretionary ACL controlling access,
+    ///
+    pub dacl: Option<Acl>,
+    /// System ACL containing audit ACEs and the mandatory label,
+    ///
+    pub sacl: Option<Acl>,
+}
+
+impl Sid {
+    /// Size of a SID w/o sub authorities,
+    ///
+    const HEADER_SIZE: usize = 8;
+
+    /// Maximum number of sub authorities,
+    ///
+    const MAX_SUB_AUTHORITIES: usize = 15;
+
+    /// Returns a new SID,
+    ///
+    pub fn new(authority: u64, sub_authorities: &[u32]) -> Self {
+        Self {
+            authority,
+            sub_authorities: sub_authorities.to_vec(),
+        }
+    }
+
+    /// Returns the encoded length of this SID,
+    ///
+    pub fn encoded_len(&self) -> usize {
+        Self::HEADER_SIZE + self.sub_authorities.len() * 4
+    }
+
+    /// Encodes the SID into buf,
+    ///
+    pub fn encode(&self, buf: &mut impl BufMut) {
+        buf.put_u8(1);
+        buf.put_u8(self.sub_authorities.len() as u8);
+        buf.put_slice(&self.authority.to_be_bytes()[2..]);
+        for s in self.sub_authorities.iter() {
+            buf.put_u32_le(*s);
+        }
+    }
+
+    /// Decodes a SID from the start of buf,
+    ///
+    pub fn decode(mut buf: &[u8]) -> Result<Self> {
+        if buf.len() < Self::HEADER_SIZE || buf[0] != 1 {
+            return Err(invalid_data("Invalid SID"));
+        }
+
+        let count = buf[1] as usize;
+        if count > Self::MAX_SUB_AUTHORITIES || buf.len() < Self::HEADER_SIZE + count * 4 {
+            return Err(invalid_data("Invalid SID sub authority count"));
+        }
+
+        let mut authority = [0; 8];
+        authority[2..].copy_from_slice(&buf[2..8]);
+        buf.advance(Self::HEADER_SIZE);
+
+        Ok(Self {
+            authority: u64::from_be_bytes(authority),
+            sub_authorities: (0..count).map(|_| buf.get_u32_le()).collect(),
+        })
+    }
+}
+
+impl Display for Sid {
+    /// Formats the SID as a string, ex. `S-1-5-32-544`,
+    ///
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.authority < 1 << 32 {
+            write!(f, "S-1-{}", self.authority)?;
+        } else {
+            write!(f, "S-1-0x{:012X}", self.authority)?;
+        }
+
+        for s in self.sub_authorities.iter() {
+            write!(f, "-{s}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Sid {
+    type Err = Error;
+
+    /// Parses a SID string or an SDDL alias, ex. `S-1-5-32-544` or `BA`,
+    ///
+    fn from_str(s: &str) -> Result<Self> {
+        sddl::parse_sid(s)
+    }
+}
+
+impl AceType {
+    /// Returns the type of an encoded ACE,
+    ///
+    pub fn from_u8(value: u8) -> Result<Self> {
+        match value {
+            0x0 => Ok(AceType::AccessAllowed),
+            0x1 => Ok(AceType::AccessDenied),
+            0x2 => Ok(AceType::SystemAudit),
+            0x3 => Ok(AceType::SystemAlarm),
+            0x11 => Ok(AceType::SystemMandatoryLabel),
+            _ => Err(invalid_data(format!("Unsupported ACE type {value:#x}"))),
+        }
+    }
+
+    /// Returns the encoded value of this type,
+    ///
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            AceType::AccessAllowed => 0x0,
+            AceType::AccessDenied => 0x1,
+            AceType::SystemAudit => 0x2,
+            AceType::SystemAlarm => 0x3,
+            AceType::SystemMandatoryLabel => 0x11,
+        }
+    }
+}
+
+impl Ace {
+    /// Size of the ACE header and access mask,
+    ///
+    const HEADER_SIZE: usize = 8;
+
+    /// Returns the encoded length of this ACE,
+    ///
+    pub fn encoded_len(&self) -> usize {
+        Self::HEADER_SIZE + self.sid.encoded_len()
+    }
+
+    /// Encodes the ACE into buf,
+    ///
+    pub fn encode(&self, buf: &mut impl BufMut) {
+        buf.put_u8(self.ace_type.as_u8());
+        buf.put_u8(self.flags);
+        buf.put_u16_le(self.encoded_len() as u16);
+        buf.put_u32_le(self.mask);
+        self.sid.encode(buf);
+    }
+
+    /// Decodes an ACE from the start of buf, returning the ACE and its encoded size,
+    ///
+    pub fn decode(buf: &[u8]) -> Result<(Self, usize)> {
+        if buf.len() < Self::HEADER_SIZE {
+            return Err(invalid_data("ACE is truncated"));
+        }
+
+        let size = u16::from_le_bytes([buf[2], buf[3]]) as usize;
+        if size < Self::HEADER_SIZE || size > buf.len() {
+            return Err(invalid_data("Invalid ACE size"));
+        }
+
+        let ace = Self {
+            ace_type: AceType::from_u8(buf[0])?,
+            flags: buf[1],
+            mask: u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            sid: Sid::decode(&buf[Self::HEADER_SIZE..size])?,
+        };
+        Ok((ace, size))
+    }
+}
+
+impl Acl {
+    /// Size of the ACL header,
+    ///
+    const HEADER_SIZE: usize = 8;
+
+    /// Returns a new ACL w/ `ACL_REVISION`,
+    ///
+    pub fn new(aces: Vec<Ace>) -> Self {
+        Self {
+            revision: ACL_REVISION,
+            aces,
+        }
+    }
+
+    /// Returns the encoded length of this ACL,
+    ///
+    pub fn encoded_len(&self) -> usize {
+        Self::HEADER_SIZE + self.aces.iter().map(Ace::encoded_len).sum::<usize>()
+    }
+
+    /// Encodes the ACL into buf,
+    ///
+    pub fn encode(&self, buf: &mut impl BufMut) {
+        buf.put_u8(self.revision);
+        buf.put_u8(0);
+        buf.put_u16_le(self.encoded_len() as u16);
+        buf.put_u16_le(self.aces.len() as u16);
+        buf.put_u16_le(0);
+        for ace in self.aces.iter() {
+            ace.encode(buf);
+        }
+    }
+
+    /// Decodes an ACL from the start of buf,
+    ///
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() < Self::HEADER_SIZE {
+            return Err(invalid_data("ACL is truncated"));
+        }
+
+        let size = u16::from_le_bytes([buf[2], buf[3]]) as usize;
+        let count = u16::from_le_bytes([buf[4], buf[5]]);
+        if size < Self::HEADER_SIZE || size > buf.len() {
+            return Err(invalid_data("Invalid ACL size"));
+        }
+
+        let mut aces = vec![];
+        let mut offset = Self::HEADER_SIZE;
+        for _ in 0..count {
+            let (ace, len) = Ace::decode(&buf[offset..size])?;
+            aces.push(ace);
+            offset += len;
+        }
+
+        Ok(Self {
+            revision: buf[0],
+            aces,
+        })
+    }
+}
+
+impl SecurityDescriptor {
+    /// Size of the self-relative header,
+    ///
+    const HEADER_SIZE: usize = 20;
+
+    /// Parses a security descriptor from an SDDL string,
+    ///
+    pub fn from_sddl(sddl: &str) -> Result<Self> {
+        sddl::parse(sddl)
+    }
+
+    /// Returns the SDDL string of this security descriptor,
+    ///
+    pub fn to_sddl(&self) -> String {
+        sddl::format(self)
+    }
+
+    /// Decodes a security descriptor in the self-relative format,
+    ///
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        if buf.len() < Self::HEADER_SIZE || buf[0] != SECURITY_DESCRIPTOR_REVISION {
+            return Err(invalid_data("Invalid security descriptor header"));
+        }
+
+        let mut header = &buf[2..Self::HEADER_SIZE];
+        let control = header.get_u16_le();
+        if control & SE_SELF_RELATIVE == 0 {
+            return Err(invalid_data("Security descriptor is not self-relative"));
+        }
+
+        let mut offsets = [0usize; 4];
+        for o in offsets.iter_mut() {
+            *o = header.get_u32_le() as usize;
+        }
+        let [owner, group, sacl, dacl] = offsets;
+
+        let at = |offset: usize| -> Result<Option<&[u8]>> {
+            match offset {
+                0 => Ok(None),
+                o if o < Self::HEADER_SIZE || o >= buf.len() => Err(invalid_data(format!(
+                    "Security descriptor offset {o} is out of range"
+                ))),
+                o => Ok(Some(&buf[o..])),
+            }
+        };
+
+        Ok(Self {
+            control,
+            owner: at(owner)?.map(Sid::decode).transpose()?,
+            group: at(group)?.map(Sid::decode).transpose()?,
+            sacl: match at(sacl)? {
+                Some(b) if control & SE_SACL_PRESENT != 0 => Some(Acl::decode(b)?),
+                _ => None,
+            },
+            dacl: match at(dacl)? {
+                Some(b) if control & SE_DACL_PRESENT != 0 => Some(Acl::decode(b)?),
+                _ => None,
+            },
+        })
+    }
+
+    /// Encodes the security descriptor in the self-relative format,
+    ///
+    /// The SACL, DACL, owner and group follow the header in that order, which is the layout produced by windows.
+    ///
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut offset = Self::HEADER_SIZE;
+        let mut next = |len: Option<usize>| match len {
+            Some(len) => {
+                let o = offset;
+                offset += len;
+                o as u32
+            }
+            None => 0,
+        };
+
+        let sacl = next(self.sacl.as_ref().map(Acl::encoded_len));
+        let dacl = next(self.dacl.as_ref().map(Acl::encoded_len));
+        let owner = next(self.owner.as_ref().map(Sid::encoded_len));
+        let group = next(self.group.as_ref().map(Sid::encoded_len));
+
+        let mut buf = Vec::with_capacity(offset);
+        buf.put_u8(SECURITY_DESCRIPTOR_REVISION);
+        buf.put_u8(0);
+        buf.put_u16_le(self.encoded_control());
+        buf.put_u32_le(owner);
+        buf.put_u32_le(group);
+        buf.put_u32_le(sacl);
+        buf.put_u32_le(dacl);
+
+        for acl in [&self.sacl, &self.dacl].into_iter().flatten() {
+            acl.encode(&mut buf);
+        }
+        for sid in [&self.owner, &self.group].into_iter().flatten() {
+            sid.encode(&mut buf);
+        }
+        buf
+    }
+
+    /// Returns the control flags written when this descriptor is encoded,
+    ///
+    fn encoded_control(&self) -> u16 {
+        let mut control = self.control | SE_SELF_RELATIVE;
+        if self.dacl.is_some() {
+            control |= SE_DACL_PRESENT;
+        }
+        if self.sacl.is_some() {
+            control |= SE_SACL_PRESENT;
+        }
+        control
+    }
+}
+
+impl Display for SecurityDescriptor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.to_sddl())
+    }
+}
+
+impl FromStr for SecurityDescriptor {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::from_sddl(s)
+    }
+}
+
+impl TryFrom<String> for SecurityDescriptor {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        Self::from_sddl(&value)
+    }
+}
+
+impl From<SecurityDescriptor> for String {
+    fn from(value: SecurityDescriptor) -> Self {
+        value.to_sddl()
+    }
+}
+
+/// Returns an invalid data error,
+///
+fn invalid_data(msg: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.into())
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use super::Ace;
+    use super::AceType;
+    use super::Acl;
+    use super::SecurityDescriptor;
+    use super::Sid;
+    use super::SE_DACL_PRESENT;
+    use super::SE_DACL_PROTECTED;
+    use super::SE_SELF_RELATIVE;
+
+    #[test]
+    fn test_security_descriptor() {
+        let sd = SecurityDescriptor::from_sddl(
+            "O:BAG:SYD:P(A;OICI;FA;;;BA)(D;;0x1200a9;;;S-1-5-21-1-2-3-1001)",
+        )
+        .unwrap();
+        assert_eq!(Some(Sid::new(5, &[32, 544])), sd.owner);
+        assert_eq!(Some(Sid::new(5, &[18])), sd.group);
+        assert_eq!(
+            SE_SELF_RELATIVE | SE_DACL_PRESENT | SE_DACL_PROTECTED,
+            sd.control
+        );
+        assert_eq!(
+            Some(Acl::new(vec![
+                Ace {
+                    ace_type: AceType::AccessAllowed,
+                    flags: 0x3,
+                    mask: 0x1F01FF,
+                    sid: Sid::new(5, &[32, 544]),
+                },
+                Ace {
+                    ace_type: AceType::AccessDenied,
+                    flags: 0,
+                    mask: 0x1200A9,
+                    sid: Sid::new(5, &[21, 1, 2, 3, 1001]),
+                },
+            ])),
+            sd.dacl
+        );
+
+        #[rustfmt::skip]
+        let expected: &[u8] = &[
+            // Header, w/ the owner at 0x58, group at 0x68, no SACL and the DACL at 0x14
+            0x01, 0x00, 0x04, 0x90, 0x58, 0x00, 0x00, 0x00, 0x68, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x14, 0x00, 0x00, 0x00,
+            // DACL
+            0x02, 0x00, 0x44, 0x00, 0x02, 0x00, 0x00, 0x00,
+            0x00, 0x03, 0x18, 0x00, 0xff, 0x01, 0x1f, 0x00,
+            0x01, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x20, 0x00, 0x00, 0x00, 0x20, 0x02, 0x00, 0x00,
+            0x01, 0x00, 0x24, 0x00, 0xa9, 0x00, 0x12, 0x00,
+            0x01, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x15, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+            0x02, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0xe9, 0x03, 0x00, 0x00,
+            // Owner
+            0x01, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x20, 0x00, 0x00, 0x00, 0x20, 0x02, 0x00, 0x00,
+            // Group
+            0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x12, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(expected, sd.to_bytes());
+        assert_eq!(sd, SecurityDescriptor::from_bytes(expected).unwrap());
+        assert_eq!(
+            "O:BAG:SYD:P(A;OICI;FA;;;BA)(D;;0x1200a9;;;S-1-5-21-1-2-3-1001)",
+            sd.to_sddl()
+        );
+
+        let labeled = SecurityDescriptor::from_sddl("D:AI(A;ID;GRGX;;;WD)S:(ML;;NW;;;HI)").unwrap();
+        assert_eq!(None, labeled.owner);
+        assert_eq!(
+            labeled,
+            SecurityDescriptor::from_bytes(&labeled.to_bytes()).unwrap()
+        );
+        assert_eq!("D:AI(A;ID;GRGX;;;WD)S:(ML;;NW;;;HI)", labeled.to_string());
+
+        let null_dacl = SecurityDescriptor::from_sddl("D:NO_ACCESS_CONTROL").unwrap();
+        assert_eq!(None, null_dacl.dacl);
+        assert_eq!(
+            null_dacl,
+            SecurityDescriptor::from_bytes(&null_dacl.to_bytes()).unwrap()
+        );
+        assert_eq!("D:NO_ACCESS_CONTROL", null_dacl.to_sddl());
+
+        assert!(SecurityDescriptor::from_sddl("O:XX").is_err());
+        assert!(SecurityDescriptor::from_sddl("D:(A;;FA;;;BA").is_err());
+        assert!(SecurityDescriptor::from_sddl("D:(OA;;FA;;;BA)").is_err());
+        assert!(SecurityDescriptor::from_bytes(&expected[..30]).is_err());
+    }
+
+    #[test]
+    fn test_invalid_sddl() {
+        for sddl in [
+            "",
+            "X:BA",
+            "O:",
+            "O:S-1-x",
+            "D:(A;;FA;;;BA",
+            "D:(A;;FA;;BA)",
+            "D:(Q;;FA;;;BA)",
+            "D:(A;;ZZ;;;BA)",
+            "D:(A;XX;FA;;;BA)",
+            "D:PX(A;;FA;;;BA)",
+        ] {
+            assert!(
+                SecurityDescriptor::from_sddl(sddl).is_err(),
+                "{sddl:?} should be invalid"
+            );
+        }
+    }
+}
@@ -0,0 +1,484 @@
+use std::io::Result;
+
+use super::invalid_data;
+use super::Ace;
+use super::AceType;
+use super::Acl;
+use super::SecurityDescriptor;
+use super::Sid;
+use super::SE_DACL_AUTO_INHERITED;
+use super::SE_DACL_AUTO_INHERIT_REQ;
+use super::SE_DACL_PRESENT;
+use super::SE_DACL_PROTECTED;
+use super::SE_SACL_AUTO_INHERITED;
+use super::SE_SACL_AUTO_INHERIT_REQ;
+use super::SE_SACL_PRESENT;
+use super::SE_SACL_PROTECTED;
+use super::SE_SELF_RELATIVE;
+
+/// SDDL aliases of well-known SIDs, as (alias, authority, sub authorities),
+///
+const SID_ALIASES: &[(&str, u64, &[u32])] = &[
+    ("WD", 1, &[0]),
+    ("CO", 3, &[0]),
+    ("CG", 3, &[1]),
+    ("OW", 3, &[4]),
+    ("NU", 5, &[2]),
+    ("IU", 5, &[4]),
+    ("SU", 5, &[6]),
+    ("AN", 5, &[7]),
+    ("ED", 5, &[9]),
+    ("PS", 5, &[10]),
+    ("AU", 5, &[11]),
+    ("RC", 5, &[12]),
+    ("SY", 5, &[18]),
+    ("LS", 5, &[19]),
+    ("NS", 5, &[20]),
+    ("BA", 5, &[32, 544]),
+    ("BU", 5, &[32, 545]),
+    ("BG", 5, &[32, 546]),
+    ("PU", 5, &[32, 547]),
+    ("AO", 5, &[32, 548]),
+    ("SO", 5, &[32, 549]),
+    ("PO", 5, &[32, 550]),
+    ("BO", 5, &[32, 551]),
+    ("RE", 5, &[32, 552]),
+    ("RU", 5, &[32, 554]),
+    ("RD", 5, &[32, 555]),
+    ("NO", 5, &[32, 556]),
+    ("AC", 15, &[2, 1]),
+    ("LW", 16, &[4096]),
+    ("ME", 16, &[8192]),
+    ("MP", 16, &[8448]),
+    ("HI", 16, &[12288]),
+    ("SI", 16, &[16384]),
+];
+
+/// SDDL names of ACE types,
+///
+const ACE_TYPES: &[(&str, AceType)] = &[
+    ("A", AceType::AccessAllowed),
+    ("D", AceType::AccessDenied),
+    ("AU", AceType::SystemAudit),
+    ("AL", AceType::SystemAlarm),
+    ("ML", AceType::SystemMandatoryLabel),
+];
+
+/// SDDL names of ACE flags,
+///
+const ACE_FLAGS: &[(&str, u8)] = &[
+    ("OI", 0x01),
+    ("CI", 0x02),
+    ("NP", 0x04),
+    ("IO", 0x08),
+    ("ID", 0x10),
+    ("SA", 0x40),
+    ("FA", 0x80),
+];
+
+/// SDDL names of access rights that are formatted as a single value, ex. file all access,
+///
+const COMBINED_RIGHTS: &[(&str, u32)] = &[
+    ("FA", 0x001F_01FF),
+    ("FR", 0x0012_0089),
+    ("FW", 0x0012_0116),
+    ("FX", 0x0012_00A0),
+    ("KA", 0x000F_003F),
+    ("KR", 0x0002_0019),
+    ("KX", 0x0002_0019),
+    ("KW", 0x0002_0006),
+];
+
+/// SDDL names of single access rights,
+///
+const RIGHTS: &[(&str, u32)] = &[
+    ("GA", 0x1000_0000),
+    ("GR", 0x8000_0000),
+    ("GW", 0x4000_0000),
+    ("GX", 0x2000_0000),
+    ("RC", 0x0002_0000),
+    ("SD", 0x0001_0000),
+    ("WD", 0x0004_0000),
+    ("WO", 0x0008_0000),
+    ("CC", 0x0000_0001),
+    ("DC", 0x0000_0002),
+    ("LC", 0x0000_0004),
+    ("SW", 0x0000_0008),
+    ("RP", 0x0000_0010),
+    ("WP", 0x0000_0020),
+    ("DT", 0x0000_0040),
+    ("LO", 0x0000_0080),
+    ("CR", 0x0000_0100),
+];
+
+/// SDDL names of mandatory label access policies, only used by mandatory label ACEs,
+///
+const LABEL_RIGHTS: &[(&str, u32)] = &[("NW", 0x1), ("NR", 0x2), ("NX", 0x4)];
+
+/// SDDL name of a null DACL or SACL,
+///
+const NO_ACCESS_CONTROL: &str = "NO_ACCESS_CONTROL";
+
+/// Parses a SID string or SDDL alias,
+///
+pub fn parse_sid(s: &str) -> Result<Sid> {
+    if let Some((_, authority, sub_authorities)) = SID_ALIASES
+        .iter()
+        .find(|(alias, ..)| alias.eq_ignore_ascii_case(s))
+    {
+        return Ok(Sid::new(*authority, sub_authorities));
+    }
+
+    let invalid = || invalid_data(format!("Invalid SID {s:?}"));
+
+    let mut parts = s.split('-');
+    match (parts.next(), parts.next()) {
+        (Some(p), Some("1")) if p.eq_ignore_ascii_case("S") => {}
+        _ => return Err(invalid()),
+    }
+
+    let authority = parts.next().ok_or_else(invalid)?;
+    let authority = match authority
+        .strip_prefix("0x")
+        .or_else(|| authority.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => authority.parse::<u64>(),
+    }
+    .map_err(|_| invalid())?;
+
+    let sub_authorities = parts
+        .map(|p| p.parse::<u32>().map_err(|_| invalid()))
+        .collect::<Result<Vec<_>>>()?;
+
+    if authority >= 1 << 48 || sub_authorities.len() > Sid::MAX_SUB_AUTHORITIES {
+        return Err(invalid());
+    }
+
+    Ok(Sid {
+        authority,
+        sub_authorities,
+    })
+}
+
+/// Parses an SDDL string,
+///
+/// Components can be in any order, but each can only be set once.
+///
+pub fn parse(sddl: &str) -> Result<SecurityDescriptor> {
+    let mut sd = SecurityDescriptor {
+        control: SE_SELF_RELATIVE,
+        ..Default::default()
+    };
+
+    let mut seen = vec![];
+    for (tag, value) in components(sddl)? {
+        if seen.contains(&tag) {
+            return Err(invalid_data(format!("SDDL component {tag}: is repeated")));
+        }
+        seen.push(tag);
+
+        match tag {
+            'O' => sd.owner = Some(parse_sid(value)?),
+            'G' => sd.group = Some(parse_sid(value)?),
+            'D' => {
+                let (flags, acl) = parse_acl(value, SE_DACL_PROTECTED)?;
+                sd.control |= flags | SE_DACL_PRESENT;
+                sd.dacl = acl;
+            }
+            'S' => {
+                let (flags, acl) = parse_acl(value, SE_SACL_PROTECTED)?;
+                sd.control |= flags | SE_SACL_PRESENT;
+                sd.sacl = acl;
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    Ok(sd)
+}
+
+/// Formats a security descriptor as an SDDL string,
+///
+/// Well-known SIDs are formatted w/ their alias, and access masks w/ their names if every bit has one.
+///
+pub fn format(sd: &SecurityDescriptor) -> String {
+    let mut sddl = String::new();
+
+    if let Some(owner) = sd.owner.as_ref() {
+        sddl.push_str("O:");
+        sddl.push_str(&format_sid(owner));
+    }
+
+    if let Some(group) = sd.group.as_ref() {
+        sddl.push_str("G:");
+        sddl.push_str(&format_sid(group));
+    }
+
+    if sd.control & SE_DACL_PRESENT != 0 || sd.dacl.is_some() {
+        sddl.push_str("D:");
+        sddl.push_str(&format_acl(
+            sd.dacl.as_ref(),
+            sd.control,
+            [
+                SE_DACL_PROTECTED,
+                SE_DACL_AUTO_INHERIT_REQ,
+                SE_DACL_AUTO_INHERITED,
+            ],
+        ));
+    }
+
+    if sd.control & SE_SACL_PRESENT != 0 || sd.sacl.is_some() {
+        sddl.push_str("S:");
+        sddl.push_str(&format_acl(
+            sd.sacl.as_ref(),
+            sd.control,
+            [
+                SE_SACL_PROTECTED,
+                SE_SACL_AUTO_INHERIT_REQ,
+                SE_SACL_AUTO_INHERITED,
+            ],
+        ));
+    }
+
+    sddl
+}
+
+/// Splits an SDDL string into its components, ex. `O:BA` into ('O', "BA"),
+///
+fn components(sddl: &str) -> Result<Vec<(char, &str)>> {
+    let sddl = sddl.trim();
+    let bytes = sddl.as_bytes();
+
+    let is_tag = |i: usize| {
+        matches!(bytes[i].to_ascii_uppercase(), b'O' | b'G' | b'D' | b'S')
+            && bytes.get(i + 1) == Some(&b':')
+    };
+
+    let mut starts = vec![];
+    let mut depth = 0;
+    for (i, b) in bytes.iter().enumerate() {
+        match b {
+            b'(' => depth += 1,
+            b')' if depth > 0 => depth -= 1,
+            b')' => return Err(invalid_data("Unbalanced parentheses in SDDL")),
+            _ if depth == 0 && is_tag(i) => starts.push(i),
+            _ => {}
+        }
+    }
+
+    if depth != 0 {
+        return Err(invalid_data("Unbalanced parentheses in SDDL"));
+    }
+
+    if starts.first() != Some(&0) {
+        return Err(invalid_data(format!("Invalid SDDL {sddl:?}")));
+    }
+
+    Ok(starts
+        .iter()
+        .enumerate()
+        .map(|(i, start)| {
+            let end = starts.get(i + 1).copied().unwrap_or(bytes.len());
+            (
+                bytes[*start].to_ascii_uppercase() as char,
+                &sddl[start + 2..end],
+            )
+        })
+        .collect())
+}
+
+/// Parses the flags and ACEs of a DACL or SACL, returning the control flags and the ACL,
+///
+/// The ACL is not set if it is a null ACL, `NO_ACCESS_CONTROL`.
+///
+fn parse_acl(value: &str, protected: u16) -> Result<(u16, Option<Acl>)> {
+    let (auto_inherit_req, auto_inherited) = if protected == SE_DACL_PROTECTED {
+        (SE_DACL_AUTO_INHERIT_REQ, SE_DACL_AUTO_INHERITED)
+    } else {
+        (SE_SACL_AUTO_INHERIT_REQ, SE_SACL_AUTO_INHERITED)
+    };
+
+    let (mut flags_str, mut aces_str) = value.split_at(value.find('(').unwrap_or(value.len()));
+
+    let mut null = false;
+    let mut flags = 0;
+    while !flags_str.is_empty() {
+        if let Some(rest) = flags_str.strip_prefix(NO_ACCESS_CONTROL) {
+            null = true;
+            flags_str = rest;
+        } else if let Some(rest) = flags_str.strip_prefix("AR") {
+            flags |= auto_inherit_req;
+            flags_str = rest;
+        } else if let Some(rest) = flags_str.strip_prefix("AI") {
+            flags |= auto_inherited;
+            flags_str = rest;
+        } else if let Some(rest) = flags_str.strip_prefix('P') {
+            flags |= protected;
+            flags_str = rest;
+        } else {
+            return Err(invalid_data(format!("Invalid ACL flags {value:?}")));
+        }
+    }
+
+    let mut aces = vec![];
+    while let Some(rest) = aces_str.strip_prefix('(') {
+        let end = rest
+            .find(')')
+            .ok_or_else(|| invalid_data(format!("Invalid ACE in {value:?}")))?;
+        aces.push(parse_ace(&rest[..end])?);
+        aces_str = &rest[end + 1..];
+    }
+
+    if !aces_str.is_empty() {
+        return Err(invalid_data(format!("Invalid ACL {value:?}")));
+    }
+
+    if null {
+        if !aces.is_empty() {
+            return Err(invalid_data(format!("Null ACL w/ ACEs {value:?}")));
+        }
+        Ok((flags, None))
+    } else {
+        Ok((flags, Some(Acl::new(aces))))
+    }
+}
+
+/// Parses the fields of an ACE string, ex. `A;OICI;FA;;;BA`,
+///
+fn parse_ace(ace: &str) -> Result<Ace> {
+    let fields = ace.split(';').collect::<Vec<_>>();
+    let [ace_type, flags, rights, object_type, inherit_object_type, sid] = fields[..] else {
+        return Err(invalid_data(format!("Unsupported ACE ({ace})")));
+    };
+
+    if !object_type.is_empty() || !inherit_object_type.is_empty() {
+        return Err(invalid_data(format!(
+            "Object ACEs are not supported ({ace})"
+        )));
+    }
+
+    let ace_type = ACE_TYPES
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(ace_type))
+        .map(|(_, t)| *t)
+        .ok_or_else(|| invalid_data(format!("Unsupported ACE type ({ace})")))?;
+
+    let flags = parse_names(flags, ACE_FLAGS)
+        .ok_or_else(|| invalid_data(format!("Invalid ACE flags ({ace})")))?;
+
+    let mask = match rights
+        .strip_prefix("0x")
+        .or_else(|| rights.strip_prefix("0X"))
+    {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None if ace_type == AceType::SystemMandatoryLabel => parse_names(rights, LABEL_RIGHTS),
+        None => parse_names(rights, &[COMBINED_RIGHTS, RIGHTS].concat()),
+    }
+    .ok_or_else(|| invalid_data(format!("Invalid ACE rights ({ace})")))?;
+
+    Ok(Ace {
+        ace_type,
+        flags,
+        mask,
+        sid: parse_sid(sid)?,
+    })
+}
+
+/// Parses a string of concatenated 2 letter names, returning the combined value,
+///
+fn parse_names<T>(s: &str, names: &[(&str, T)]) -> Option<T>
+where
+    T: Copy + Default + std::ops::BitOr<Output = T>,
+{
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+
+    (0..s.len()).step_by(2).try_fold(T::default(), |value, i| {
+        names
+            .iter()
+            .find(|(name, _)| *name == &s[i..i + 2])
+            .map(|(_, v)| value | *v)
+    })
+}
+
+/// Formats a value as a string of concatenated names, if every bit has a name,
+///
+fn format_names<T>(value: T, names: &[(&str, T)]) -> Option<String>
+where
+    T: Copy + Default + PartialEq + std::ops::BitAnd<Output = T> + std::ops::Not<Output = T>,
+{
+    let mut remaining = value;
+    let mut s = String::new();
+    for (name, v) in names {
+        if value & *v == *v && *v != T::default() {
+            s.push_str(name);
+            remaining = remaining & !*v;
+        }
+    }
+
+    (remaining == T::default()).then_some(s)
+}
+
+/// Formats a SID w/ its alias if it has one,
+///
+fn format_sid(sid: &Sid) -> String {
+    SID_ALIASES
+        .iter()
+        .find(|(_, authority, sub_authorities)| {
+            sid.authority == *authority && sid.sub_authorities == *sub_authorities
+        })
+        .map(|(alias, ..)| alias.to_string())
+        .unwrap_or_else(|| sid.to_string())
+}
+
+/// Formats the flags and ACEs of an ACL,
+///
+fn format_acl(acl: Option<&Acl>, control: u16, flags: [u16; 3]) -> String {
+    let mut s = String::new();
+    for (flag, name) in flags.into_iter().zip(["P", "AR", "AI"]) {
+        if control & flag != 0 {
+            s.push_str(name);
+        }
+    }
+
+    match acl {
+        Some(acl) => {
+            for ace in acl.aces.iter() {
+                s.push('(');
+                s.push_str(&format_ace(ace));
+                s.push(')');
+            }
+        }
+        None => s.push_str(NO_ACCESS_CONTROL),
+    }
+
+    s
+}
+
+/// Formats an ACE w/o its enclosing parentheses,
+///
+fn format_ace(ace: &Ace) -> String {
+    let ace_type = ACE_TYPES
+        .iter()
+        .find(|(_, t)| *t == ace.ace_type)
+        .map(|(name, _)| *name)
+        .unwrap_or_default();
+
+    let flags = format_names(ace.flags, ACE_FLAGS).unwrap_or_else(|| format!("0x{:x}", ace.flags));
+
+    let rights = if ace.ace_type == AceType::SystemMandatoryLabel {
+        format_names(ace.mask, LABEL_RIGHTS)
+    } else {
+        COMBINED_RIGHTS
+            .iter()
+            .find(|(_, m)| *m == ace.mask)
+            .map(|(name, _)| name.to_string())
+            .or_else(|| format_names(ace.mask, RIGHTS))
+    }
+    .filter(|r| !r.is_empty())
+    .unwrap_or_else(|| format!("0x{:x}", ace.mask));
+
+    format!("{ace_type};{flags};{rights};;;{}", format_sid(&ace.sid))
+}
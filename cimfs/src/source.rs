@@ -0,0 +1,705 @@
+use std::fs::File;
+use std::io::Result;
+use std::path::Path;
+
+use crate::backend::FileMetadata;
+
+/// Seconds between the windows file time epoch (1601-01-01) and the unix epoch (1970-01-01),
+///
+const FILE_TIME_UNIX_EPOCH_SECS: i64 = 11_644_473_600;
+
+/// Converts a unix timestamp into the windows file time format,
+///
+pub fn to_file_time(secs: i64, nanos: i64) -> i64 {
+    (secs + FILE_TIME_UNIX_EPOCH_SECS) * 10_000_000 + nanos / 100
+}
+
+/// Returns the current time in the windows file time format,
+///
+pub fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| to_file_time(d.as_secs() as i64, d.subsec_nanos() as i64))
+        .unwrap_or_default()
+}
+
+/// Opens a src file for reading w/o following reparse points, directories can also be opened,
+///
+#[cfg(windows)]
+pub fn open(src: &Path) -> Result<File> {
+    use std::fs::OpenOptions;
+    use std::os::windows::fs::OpenOptionsExt;
+    use windows::Win32::Storage::FileSystem::FILE_FLAG_BACKUP_SEMANTICS;
+    use windows::Win32::Storage::FileSystem::FILE_FLAG_OPEN_REPARSE_POINT;
+    use windows::Win32::Storage::FileSystem::FILE_SHARE_READ;
+
+    OpenOptions::new()
+        .read(true)
+        .share_mode(FILE_SHARE_READ.0)
+        .custom_flags((FILE_FLAG_BACKUP_SEMANTICS | FILE_FLAG_OPEN_REPARSE_POINT).0)
+        .open(src)
+}
+
+/// Opens a src file for reading, directories can also be opened,
+///
+/// Symlinks are followed, use `symlink_metadata()` first to add symlinks as reparse points.
+///
+#[cfg(unix)]
+pub fn open(src: &Path) -> Result<File> {
+    File::open(src)
+}
+
+/// Returns the names of the alternate data streams of a src file, w/o the default stream,
+///
+#[cfg(windows)]
+pub fn alternate_streams(src: &Path) -> Result<Vec<String>> {
+    use crate::error::hresult_error;
+    use std::ffi::c_void;
+    use std::io::Error;
+    use windows::core::HSTRING;
+    use windows::Win32::Foundation::ERROR_HANDLE_EOF;
+    use windows::Win32::Storage::FileSystem::FindClose;
+    use windows::Win32::Storage::FileSystem::FindFileHandle;
+    use windows::Win32::Storage::FileSystem::FindFirstStreamW;
+    use windows::Win32::Storage::FileSystem::FindNextStreamW;
+    use windows::Win32::Storage::FileSystem::FindStreamInfoStandard;
+    use windows::Win32::Storage::FileSystem::WIN32_FIND_STREAM_DATA;
+
+    let mut data = WIN32_FIND_STREAM_DATA::default();
+    let handle = unsafe {
+        FindFirstStreamW(
+            &HSTRING::from(src.as_os_str()),
+            FindStreamInfoStandard,
+            std::ptr::addr_of_mut!(data) as *mut c_void,
+            0,
+        )
+    };
+
+    let handle = match handle {
+        Ok(handle) => handle,
+        Err(err) if err.code() == ERROR_HANDLE_EOF.to_hresult() => return Ok(vec![]),
+        Err(err) => return Err(hresult_error(err.code().0)),
+    };
+
+    let mut streams = vec![];
+    let result = loop {
+        let len = data
+            .cStreamName
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(data.cStreamName.len());
+        let name = String::from_utf16_lossy(&data.cStreamName[..len]);
+
+        // Names are formatted as `:<name>:$DATA`, the default stream has an empty name
+        if let Some(name) = name
+            .strip_prefix(':')
+            .and_then(|n| n.strip_suffix(":$DATA"))
+        {
+            if !name.is_empty() {
+                streams.push(name.to_string());
+            }
+        }
+
+        if !unsafe { FindNextStreamW(handle, std::ptr::addr_of_mut!(data) as *mut c_void) }
+            .as_bool()
+        {
+            let err = Error::last_os_error();
+            if err.raw_os_error() == Some(ERROR_HANDLE_EOF.0 as i32) {
+                break Ok(streams);
+            } else {
+                break Err(err);
+            }
+        }
+    };
+
+    unsafe {
+        FindClose(FindFileHandle(handle.0));
+    }
+    result
+}
+
+/// Returns the names of the alternate data streams of a src file,
+///
+/// Alternate data streams are not supported on this platform, so this is always empty.
+///
+#[cfg(unix)]
+pub fn alternate_streams(_src: &Path) -> Result<Vec<String>> {
+    Ok(vec![])
+}
+
+/// Opens an alternate data stream of a src file for reading,
+///
+#[cfg(windows)]
+pub fn open_alternate_stream(src: &Path, name: &str) -> Result<File> {
+    let mut path = src.as_os_str().to_os_string();
+    path.push(":");
+    path.push(name);
+    open(Path::new(&path))
+}
+
+/// Opens an alternate data stream of a src file for reading,
+///
+/// Alternate data streams are not supported on this platform, so this always returns an error.
+///
+#[cfg(unix)]
+pub fn open_alternate_stream(src: &Path, name: &str) -> Result<File> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!(
+            "Alternate data streams are not supported -- {:?}:{name}",
+            src
+        ),
+    ))
+}
+
+/// Returns the volume and file index identifying an opened src file, if the file has more than one hard link,
+///
+#[cfg(windows)]
+pub fn file_id(file: &File) -> Result<Option<(u64, u64)>> {
+    use std::io::Error;
+    use std::os::windows::io::AsRawHandle;
+    use windows::Win32::Foundation::HANDLE;
+    use windows::Win32::Storage::FileSystem::GetFileInformationByHandle;
+    use windows::Win32::Storage::FileSystem::BY_HANDLE_FILE_INFORMATION;
+    use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_DIRECTORY;
+
+    let handle = HANDLE(file.as_raw_handle() as isize);
+    let mut info = BY_HANDLE_FILE_INFORMATION::default();
+
+    unsafe {
+        if !GetFileInformationByHandle(handle, std::ptr::addr_of_mut!(info)).as_bool() {
+            return Err(Error::last_os_error());
+        }
+    }
+
+    if info.nNumberOfLinks > 1 && info.dwFileAttributes & FILE_ATTRIBUTE_DIRECTORY.0 == 0 {
+        Ok(Some((
+            info.dwVolumeSerialNumber as u64,
+            ((info.nFileIndexHigh as u64) << 32) | info.nFileIndexLow as u64,
+        )))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Returns the device and inode identifying an opened src file, if the file has more than one hard link,
+///
+#[cfg(unix)]
+pub fn file_id(file: &File) -> Result<Option<(u64, u64)>> {
+    use std::os::unix::fs::MetadataExt;
+
+    let m = file.metadata()?;
+    if m.nlink() > 1 && !m.is_dir() {
+        Ok(Some((m.dev(), m.ino())))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Reads the metadata to use in a CIM image from an opened src file,
+///
+#[cfg(windows)]
+pub fn metadata(file: &File) -> Result<FileMetadata> {
+    use std::ffi::c_void;
+    use std::io::Error;
+    use std::os::windows::io::AsRawHandle;
+    use windows::Win32::Foundation::HANDLE;
+    use windows::Win32::Storage::FileSystem::*;
+    use windows::Win32::System::IO::DeviceIoControl;
+
+    use crate::raw::FSCTL_GET_REPARSE_POINT;
+    use tracing::trace;
+
+    let handle = HANDLE(file.as_raw_handle() as isize);
+    let mut basic_info = FILE_BASIC_INFO::default();
+
+    unsafe {
+        if !GetFileInformationByHandleEx(
+            handle,
+            FileBasicInfo,
+            std::ptr::addr_of_mut!(basic_info) as *mut c_void,
+            std::mem::size_of_val(&basic_info) as u32,
+        )
+        .as_bool()
+        {
+            return Err(Error::last_os_error());
+        }
+    }
+    trace!("Got file info -- {:#?}", basic_info);
+
+    let mut metadata = FileMetadata {
+        attributes: basic_info.FileAttributes,
+        creation_time: basic_info.CreationTime,
+        last_write_time: basic_info.LastWriteTime,
+        change_time: basic_info.ChangeTime,
+        last_access_time: basic_info.LastAccessTime,
+        ..Default::default()
+    };
+
+    if !metadata.is_directory() {
+        metadata.file_size = file.metadata()?.len();
+    }
+    trace!("Getting file size -- {}", metadata.file_size);
+
+    // Check for reparse point
+    if metadata.is_reparse_point() {
+        trace!("Getting reparse data");
+        let mut buf = vec![0u8; MAXIMUM_REPARSE_DATA_BUFFER_SIZE as usize];
+        let mut bytes = 0u32;
+
+        unsafe {
+            if !DeviceIoControl(
+                handle,
+                FSCTL_GET_REPARSE_POINT,
+                None,
+                0,
+                Some(buf.as_mut_ptr() as *mut c_void),
+                buf.len() as u32,
+                Some(std::ptr::addr_of_mut!(bytes)),
+                None,
+            )
+            .as_bool()
+            {
+                return Err(Error::last_os_error());
+            }
+        }
+
+        buf.truncate(bytes as usize);
+        metadata.reparse_data = buf;
+    }
+
+    metadata.security_descriptor = security_descriptor(file)?;
+    trace!(
+        "Got security descriptor -- {} bytes",
+        metadata.security_descriptor.len()
+    );
+
+    metadata.ea_buffer = extended_attributes(file)?;
+    trace!(
+        "Got extended attributes -- {} bytes",
+        metadata.ea_buffer.len()
+    );
+
+    Ok(metadata)
+}
+
+/// Returns the self-relative security descriptor of an opened src file,
+///
+/// The owner, group, DACL and mandatory label are captured. Audit ACEs in the SACL are not, since reading them requires `SeSecurityPrivilege`
+/// and an `ACCESS_SYSTEM_SECURITY` handle.
+///
+#[cfg(windows)]
+pub fn security_descriptor(file: &File) -> Result<Vec<u8>> {
+    use std::io::Error;
+    use std::os::windows::io::AsRawHandle;
+    use windows::Win32::Foundation::ERROR_SUCCESS;
+    use windows::Win32::Foundation::HANDLE;
+    use windows::Win32::Foundation::HLOCAL;
+    use windows::Win32::Security::Authorization::GetSecurityInfo;
+    use windows::Win32::Security::Authorization::SE_FILE_OBJECT;
+    use windows::Win32::Security::GetSecurityDescriptorLength;
+    use windows::Win32::Security::DACL_SECURITY_INFORMATION;
+    use windows::Win32::Security::GROUP_SECURITY_INFORMATION;
+    use windows::Win32::Security::LABEL_SECURITY_INFORMATION;
+    use windows::Win32::Security::OWNER_SECURITY_INFORMATION;
+    use windows::Win32::Security::PSECURITY_DESCRIPTOR;
+    use windows::Win32::System::Memory::LocalFree;
+
+    let handle = HANDLE(file.as_raw_handle() as isize);
+    let info = OWNER_SECURITY_INFORMATION
+        | GROUP_SECURITY_INFORMATION
+        | DACL_SECURITY_INFORMATION
+        | LABEL_SECURITY_INFORMATION;
+    let mut desc = PSECURITY_DESCRIPTOR::default();
+
+    unsafe {
+        let result = GetSecurityInfo(
+            handle,
+            SE_FILE_OBJECT,
+            info.0,
+            None,
+            None,
+            None,
+            None,
+            Some(std::ptr::addr_of_mut!(desc)),
+        );
+        if result != ERROR_SUCCESS {
+            return Err(Error::from_raw_os_error(result.0 as i32));
+        }
+
+        // The descriptor returned is self-relative, so it can be copied as is
+        let len = GetSecurityDescriptorLength(desc) as usize;
+        let buf = std::slice::from_raw_parts(desc.0 as *const u8, len).to_vec();
+        let _ = LocalFree(HLOCAL(desc.0 as isize));
+        Ok(buf)
+    }
+}
+
+/// Returns the extended attributes of an opened src file, as a chain of `FILE_FULL_EA_INFORMATION` records,
+///
+/// The attributes are read from the EA stream returned by `BackupRead`, the file's data is skipped w/ `BackupSeek`. Returns an empty buffer if the file
+/// does not have extended attributes.
+///
+/// `BackupRead` and `BackupSeek` move the file pointer of the handle, so the file is rewound before returning, since the same handle is used
+/// to read the file's data.
+///
+#[cfg(windows)]
+pub fn extended_attributes(file: &File) -> Result<Vec<u8>> {
+    use std::ffi::c_void;
+    use std::io::Error;
+    use std::io::Seek;
+    use std::io::SeekFrom;
+    use std::os::windows::io::AsRawHandle;
+    use windows::Win32::Foundation::HANDLE;
+    use windows::Win32::Storage::FileSystem::BackupRead;
+    use windows::Win32::Storage::FileSystem::BackupSeek;
+    use windows::Win32::Storage::FileSystem::BACKUP_EA_DATA;
+
+    /// Size of a WIN32_STREAM_ID w/o its name,
+    ///
+    const STREAM_HEADER_SIZE: usize = 20;
+
+    /// Reads from the backup stream until buf is full, returns the number of bytes read,
+    ///
+    fn read(handle: HANDLE, buf: &mut [u8], context: &mut *mut c_void) -> Result<usize> {
+        let mut total = 0;
+        while total < buf.len() {
+            let mut read = 0u32;
+            unsafe {
+                if !BackupRead(
+                    handle,
+                    &mut buf[total..],
+                    std::ptr::addr_of_mut!(read),
+                    false,
+                    false,
+                    std::ptr::addr_of_mut!(*context),
+                )
+                .as_bool()
+                {
+                    return Err(Error::last_os_error());
+                }
+            }
+
+            if read == 0 {
+                break;
+            }
+            total += read as usize;
+        }
+        Ok(total)
+    }
+
+    /// Reads stream headers until the EA stream is found, skipping the data of other streams,
+    ///
+    fn find_ea_stream(handle: HANDLE, context: &mut *mut c_void) -> Result<Vec<u8>> {
+        loop {
+            let mut header = [0u8; STREAM_HEADER_SIZE];
+            if read(handle, &mut header, context)? < STREAM_HEADER_SIZE {
+                return Ok(vec![]);
+            }
+
+            let id = u32::from_le_bytes(header[0..4].try_into().unwrap_or_default());
+            let size = u64::from_le_bytes(header[8..16].try_into().unwrap_or_default());
+            let name_size = u32::from_le_bytes(header[16..20].try_into().unwrap_or_default());
+
+            let mut name = vec![0u8; name_size as usize];
+            read(handle, &mut name, context)?;
+
+            if id == BACKUP_EA_DATA.0 {
+                let mut buf = vec![0u8; size as usize];
+                let len = read(handle, &mut buf, context)?;
+                buf.truncate(len);
+                return Ok(buf);
+            }
+
+            let (mut low, mut high) = (0u32, 0u32);
+            unsafe {
+                if !BackupSeek(
+                    handle,
+                    size as u32,
+                    (size >> 32) as u32,
+                    std::ptr::addr_of_mut!(low),
+                    std::ptr::addr_of_mut!(high),
+                    std::ptr::addr_of_mut!(*context),
+                )
+                .as_bool()
+                    && ((high as u64) << 32 | low as u64) != size
+                {
+                    return Err(Error::last_os_error());
+                }
+            }
+        }
+    }
+
+    let handle = HANDLE(file.as_raw_handle() as isize);
+    let mut context = std::ptr::null_mut::<c_void>();
+
+    let result = find_ea_stream(handle, &mut context);
+
+    // Releases the context allocated by BackupRead
+    if !context.is_null() {
+        let mut read = 0u32;
+        unsafe {
+            let _ = BackupRead(
+                handle,
+                &mut [],
+                std::ptr::addr_of_mut!(read),
+                true,
+                false,
+                &mut context,
+            );
+        }
+    }
+
+    let ea = result?;
+    (&*file).seek(SeekFrom::Start(0))?;
+    Ok(ea)
+}
+
+/// Returns the metadata of a src symlink, w/ reparse data for its target, or None if src is not a symlink,
+///
+/// Symlinks are opened as reparse points by `open()` on this platform, so this always returns None.
+///
+#[cfg(windows)]
+pub fn symlink_metadata(_src: &Path) -> Result<Option<FileMetadata>> {
+    Ok(None)
+}
+
+/// Returns the metadata of a src symlink, w/ reparse data for its target, or None if src is not a symlink,
+///
+/// The directory attribute is set if the target is a directory, the same as a directory symlink created on windows.
+///
+#[cfg(unix)]
+pub fn symlink_metadata(src: &Path) -> Result<Option<FileMetadata>> {
+    use std::os::unix::fs::MetadataExt;
+    use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_DIRECTORY;
+    use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_REPARSE_POINT;
+
+    use crate::reparse::ReparseData;
+
+    let m = src.symlink_metadata()?;
+    if !m.file_type().is_symlink() {
+        return Ok(None);
+    }
+
+    let target = std::fs::read_link(src)?;
+    let mut attributes = FILE_ATTRIBUTE_REPARSE_POINT.0;
+    if src.is_dir() {
+        attributes |= FILE_ATTRIBUTE_DIRECTORY.0;
+    }
+
+    let last_write_time = to_file_time(m.mtime(), m.mtime_nsec());
+    Ok(Some(FileMetadata {
+        attributes,
+        creation_time: last_write_time,
+        last_write_time,
+        change_time: to_file_time(m.ctime(), m.ctime_nsec()),
+        last_access_time: to_file_time(m.atime(), m.atime_nsec()),
+        reparse_data: ReparseData::symlink(&target.to_string_lossy()).to_bytes()?,
+        ..Default::default()
+    }))
+}
+
+/// Reads the metadata to use in a CIM image from an opened src file,
+///
+/// Attributes are derived from the file type and permissions. A security descriptor is not set, since unix permissions do not map to
+/// windows ACLs, and extended attributes are not set either. Use `Overrides` to set them explicitly.
+///
+#[cfg(unix)]
+pub fn metadata(file: &File) -> Result<FileMetadata> {
+    use std::os::unix::fs::MetadataExt;
+    use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_DIRECTORY;
+    use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_NORMAL;
+    use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_READONLY;
+
+    let m = file.metadata()?;
+
+    let mut attributes = 0;
+    if m.is_dir() {
+        attributes |= FILE_ATTRIBUTE_DIRECTORY.0;
+    }
+    if m.permissions().readonly() {
+        attributes |= FILE_ATTRIBUTE_READONLY.0;
+    }
+    if attributes == 0 {
+        attributes = FILE_ATTRIBUTE_NORMAL.0;
+    }
+
+    let last_write_time = to_file_time(m.mtime(), m.mtime_nsec());
+    let creation_time = m
+        .created()
+        .ok()
+        .and_then(|c| c.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| to_file_time(d.as_secs() as i64, d.subsec_nanos() as i64))
+        .unwrap_or(last_write_time);
+
+    Ok(FileMetadata {
+        attributes,
+        file_size: if m.is_dir() { 0 } else { m.len() },
+        creation_time,
+        last_write_time,
+        change_time: to_file_time(m.ctime(), m.ctime_nsec()),
+        last_access_time: to_file_time(m.atime(), m.atime_nsec()),
+        ..Default::default()
+    })
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use crate::ea::ExtendedAttribute;
+    #[cfg(test)]
+    use crate::scratch::ScratchDir;
+    use crate::security::SecurityDescriptor;
+    use std::io::Read;
+    use std::path::Path;
+
+    /// Writes extended attributes to the file at path w/ `BackupWrite`, since std does not expose a way to set them,
+    ///
+    #[cfg_attr(not(test), allow(dead_code))]
+    #[cfg(windows)]
+    fn write_extended_attributes(path: &Path, attributes: &[ExtendedAttribute]) {
+        use std::ffi::c_void;
+        use std::os::windows::io::AsRawHandle;
+        use windows::Win32::Foundation::HANDLE;
+        use windows::Win32::Storage::FileSystem::BackupWrite;
+        use windows::Win32::Storage::FileSystem::BACKUP_EA_DATA;
+
+        let ea = crate::ea::encode(attributes);
+        let mut buf = vec![];
+        buf.extend_from_slice(&BACKUP_EA_DATA.0.to_le_bytes());
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        buf.extend_from_slice(&(ea.len() as u64).to_le_bytes());
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        buf.extend_from_slice(&ea);
+
+        let file = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+        let handle = HANDLE(file.as_raw_handle() as isize);
+        let mut context = std::ptr::null_mut::<c_void>();
+        let mut written = 0u32;
+        unsafe {
+            assert!(BackupWrite(
+                handle,
+                &buf,
+                std::ptr::addr_of_mut!(written),
+                false,
+                false,
+                &mut context,
+            )
+            .as_bool());
+            let _ = BackupWrite(
+                handle,
+                &[],
+                std::ptr::addr_of_mut!(written),
+                true,
+                false,
+                &mut context,
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_security_descriptor() {
+        let scratch = ScratchDir::new("source-test-sd");
+        let path = scratch.path().join("a.txt");
+        std::fs::write(&path, b"a").unwrap();
+
+        let metadata = super::metadata(&super::open(&path).unwrap()).unwrap();
+        let sd = SecurityDescriptor::from_bytes(&metadata.security_descriptor).unwrap();
+        assert!(sd.owner.is_some());
+        assert!(sd.group.is_some());
+        assert!(sd.dacl.is_some());
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_alternate_streams() {
+        let scratch = ScratchDir::new("source-test-ads");
+        let path = scratch.path().join("a.txt");
+        std::fs::write(&path, b"a").unwrap();
+        std::fs::write(scratch.path().join("a.txt:meta"), b"stream").unwrap();
+
+        assert_eq!(
+            vec!["meta".to_string()],
+            super::alternate_streams(&path).unwrap()
+        );
+        let mut data = vec![];
+        super::open_alternate_stream(&path, "meta")
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(b"stream".to_vec(), data);
+
+        let plain = scratch.path().join("b.txt");
+        std::fs::write(&plain, b"b").unwrap();
+        assert!(super::alternate_streams(&plain).unwrap().is_empty());
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_extended_attributes() {
+        let scratch = ScratchDir::new("source-test-ea");
+        let path = scratch.path().join("a.txt");
+        std::fs::write(&path, b"a").unwrap();
+
+        let file = super::open(&path).unwrap();
+        assert!(super::extended_attributes(&file).unwrap().is_empty());
+
+        let attributes = vec![ExtendedAttribute::new("APP.VERSION", "1.2.3").unwrap()];
+        write_extended_attributes(&path, &attributes);
+        let metadata = super::metadata(&super::open(&path).unwrap()).unwrap();
+        assert_eq!(attributes, crate::ea::decode(&metadata.ea_buffer).unwrap());
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_build_extended_attributes() {
+        use crate::api::Image;
+        use crate::api::Object;
+        use crate::api::RecordedEntry;
+        use crate::api::RecordingBackend;
+
+        let scratch = ScratchDir::new("source-test-build-ea");
+        let path = scratch.path().join("a.txt");
+        std::fs::write(&path, b"hello world").unwrap();
+        let attributes = vec![ExtendedAttribute::new("APP.VERSION", "1.2.3").unwrap()];
+        write_extended_attributes(&path, &attributes);
+
+        // The data is read from the same handle as the extended attributes
+        let mut image = Image::with_backend(".cimroot", "test.cim", RecordingBackend::default());
+        image.create(None).unwrap();
+        image
+            .build(
+                vec![Object::with_destination(&path, "a.txt")],
+                Default::default(),
+            )
+            .unwrap();
+        image.commit().unwrap();
+
+        match &image.backend().last_image().unwrap().entries[..] {
+            [RecordedEntry::File { metadata, data, .. }] => {
+                assert_eq!(b"hello world".to_vec(), *data);
+                assert_eq!(attributes, crate::ea::decode(&metadata.ea_buffer).unwrap());
+            }
+            entries => panic!("unexpected entries {entries:?}"),
+        }
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_reparse_point() {
+        use crate::reparse::IO_REPARSE_TAG_SYMLINK;
+
+        let scratch = ScratchDir::new("source-test-reparse");
+        std::fs::write(scratch.path().join("target.txt"), b"target").unwrap();
+        let link = scratch.path().join("link.txt");
+        std::os::windows::fs::symlink_file("target.txt", &link).unwrap();
+
+        // The link is opened as a reparse point instead of following it
+        let metadata = super::metadata(&super::open(&link).unwrap()).unwrap();
+        assert!(metadata.is_reparse_point());
+        assert_eq!(
+            IO_REPARSE_TAG_SYMLINK.to_le_bytes(),
+            metadata.reparse_data[..4]
+        );
+    }
+}
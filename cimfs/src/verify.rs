@@ -0,0 +1,972 @@
+//! Verification of an image against the src tree it was built from,
+//!
+//! Walks the src tree and the image, and reports files that are missing from the image, files in the image that are not in the src tree,
+//! files whose content differs, and differences in attributes, file times, reparse data, security descriptors, extended attributes and
+//! alternate data streams,
+//!
+//! Images committed by CimFS are read through the volume they are mounted as, w/ `Verifier::mounted()`. The on-disk format of CimFS
+//! images is not documented, so images can't be verified w/o mounting them, which requires Windows and elevated permissions. The entries
+//! recorded by `RecordingBackend` can be verified w/ `Verifier::recorded()`, to check what a build adds on any platform,
+//!
+//! ```no_run
+//! use cimfs::api::Image;
+//! use cimfs::verify::Verifier;
+//!
+//! let mut image = Image::new(".cimroot", "app.cim");
+//! let volume = image.mount(None)?;
+//! let report = Verifier::mounted(format!("\\\\?\\Volume{{{:?}}}\\", volume))
+//!     .with_prefix("app")
+//!     .verify("target/release/app")?;
+//! for difference in report.differences.iter() {
+//!     println!("{difference}");
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Result;
+use std::path::Path;
+use std::path::PathBuf;
+
+use tracing::trace;
+use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_REPARSE_POINT;
+
+use crate::backend::FileMetadata;
+use crate::backend::RecordedEntry;
+use crate::backend::RecordedImage;
+use crate::error::source_error;
+use crate::image::Normalization;
+use crate::security::SecurityDescriptor;
+
+/// Length of the chunks compared when comparing the content of a file,
+///
+const CHUNK_LEN: usize = 64 * 1024;
+
+/// Compares an image against a src tree,
+///
+pub struct Verifier<'a> {
+    target: Target<'a>,
+    /// Path in the image that corresponds to the root of the src tree,
+    ///
+    prefix: PathBuf,
+    /// Normalization the image was built w/, applied to the metadata of each src before it is compared,
+    ///
+    normalization: Option<Normalization>,
+    /// If true, file times are compared,
+    ///
+    timestamps: bool,
+    /// If true, alternate data streams are compared,
+    ///
+    alternate_streams: bool,
+    /// If true, security descriptors are compared,
+    ///
+    security_descriptors: bool,
+    /// If true, extended attributes are compared,
+    ///
+    extended_attributes: bool,
+}
+
+/// Image a `Verifier` compares a src tree against,
+///
+enum Target<'a> {
+    /// Root of the volume the image is mounted as, ex. `\\?\Volume{...}\`,
+    ///
+    Mounted(PathBuf),
+    /// Entries recorded by `RecordingBackend`,
+    ///
+    Recorded(RecordedFiles<'a>),
+}
+
+/// Files of an image recorded by `RecordingBackend`, w/ the last entry recorded for each path,
+///
+struct RecordedFiles<'a> {
+    /// Metadata and data of each file, by its path in the image,
+    ///
+    files: BTreeMap<PathBuf, (&'a FileMetadata, &'a [u8])>,
+    /// Data of each alternate data stream, by the path of its file and its name,
+    ///
+    streams: BTreeMap<(PathBuf, String), &'a [u8]>,
+}
+
+/// Result of verifying an image,
+///
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Report {
+    /// Number of src entries that were compared,
+    ///
+    pub checked: usize,
+    /// Differences between the src tree and the image, in order of their path,
+    ///
+    pub differences: Vec<Difference>,
+}
+
+impl Report {
+    /// Returns true if the image matches the src tree,
+    ///
+    pub fn is_ok(&self) -> bool {
+        self.differences.is_empty()
+    }
+}
+
+/// Difference between an entry of the src tree and the image,
+///
+/// Paths are relative to the root of the src tree.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Difference {
+    /// Entry of the src tree does not exist in the image,
+    ///
+    Missing { relative_path: PathBuf },
+    /// Entry of the image does not exist in the src tree,
+    ///
+    Extra { relative_path: PathBuf },
+    /// The default data stream of the file differs,
+    ///
+    Content { relative_path: PathBuf },
+    /// Metadata of the entry differs,
+    ///
+    Metadata {
+        relative_path: PathBuf,
+        field: Field,
+        src: String,
+        image: String,
+    },
+}
+
+/// Metadata compared by `Verifier`,
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Field {
+    Attributes,
+    CreationTime,
+    LastWriteTime,
+    ChangeTime,
+    ReparseData,
+    SecurityDescriptor,
+    ExtendedAttributes,
+    /// Alternate data stream w/ name,
+    ///
+    AlternateStream(String),
+}
+
+impl Difference {
+    /// Returns the path the difference was found at,
+    ///
+    pub fn relative_path(&self) -> &Path {
+        match self {
+            Difference::Missing { relative_path }
+            | Difference::Extra { relative_path }
+            | Difference::Content { relative_path }
+            | Difference::Metadata { relative_path, .. } => relative_path,
+        }
+    }
+}
+
+impl Display for Difference {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Difference::Missing { relative_path } => {
+                write!(f, "missing  {}", relative_path.display())
+            }
+            Difference::Extra { relative_path } => {
+                write!(f, "extra    {}", relative_path.display())
+            }
+            Difference::Content { relative_path } => {
+                write!(f, "content  {}", relative_path.display())
+            }
+            Difference::Metadata {
+                relative_path,
+                field,
+                src,
+                image,
+            } => write!(
+                f,
+                "metadata {} {field:?}, src: {src}, image: {image}",
+                relative_path.display()
+            ),
+        }
+    }
+}
+
+impl<'a> Verifier<'a> {
+    /// Returns a new verifier for the image mounted as the volume at root, ex. the volume path of the volume id returned by `Image::mount()`,
+    ///
+    /// The entries of the image are read w/ the same functions as the srcs. By default, the src tree is compared against the root of the
+    /// image, and file times, security descriptors, extended attributes and alternate data streams are compared.
+    ///
+    pub fn mounted(root: impl Into<PathBuf>) -> Self {
+        Self::with_target(Target::Mounted(root.into()))
+    }
+
+    /// Returns a new verifier for the entries recorded by `RecordingBackend` for image, ex. `backend.last_image()`,
+    ///
+    /// Entries are compared as CimFS would apply them, a later entry for the same path replaces the earlier one, hard links share the
+    /// entry of the existing file, and deletes remove a path and its children. The defaults are the same as `mounted()`.
+    ///
+    pub fn recorded(image: &'a RecordedImage) -> Self {
+        Self::with_target(Target::Recorded(RecordedFiles::new(image)))
+    }
+
+    /// Returns a new verifier for target, w/ the default options,
+    ///
+    fn with_target(target: Target<'a>) -> Self {
+        Self {
+            target,
+            prefix: PathBuf::new(),
+            normalization: None,
+            timestamps: true,
+            alternate_streams: true,
+            security_descriptors: true,
+            extended_attributes: true,
+        }
+    }
+
+    /// Returns self comparing the src tree against the directory at prefix in the image, ex. `app` if the tree was added under `app`,
+    ///
+    pub fn with_prefix(mut self, prefix: impl Into<PathBuf>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Returns self w/ the normalization the image was built w/, see `Image::with_normalization()`,
+    ///
+    pub fn with_normalization(mut self, normalization: Normalization) -> Self {
+        self.normalization = Some(normalization);
+        self
+    }
+
+    /// Returns self w/ comparing file times enabled or disabled,
+    ///
+    /// The last access time is never compared, since reading a src can update it.
+    ///
+    pub fn with_timestamps(mut self, enabled: bool) -> Self {
+        self.timestamps = enabled;
+        self
+    }
+
+    /// Returns self w/ comparing alternate data streams enabled or disabled,
+    ///
+    /// Disable this if the image was built w/o copying alternate data streams.
+    ///
+    pub fn with_alternate_streams(mut self, enabled: bool) -> Self {
+        self.alternate_streams = enabled;
+        self
+    }
+
+    /// Returns self w/ comparing security descriptors enabled or disabled,
+    ///
+    /// Security descriptors are compared by their SDDL string, so descriptors that only differ in the layout of their buffer are the same.
+    ///
+    pub fn with_security_descriptors(mut self, enabled: bool) -> Self {
+        self.security_descriptors = enabled;
+        self
+    }
+
+    /// Returns self w/ comparing extended attributes enabled or disabled,
+    ///
+    /// Attributes are compared regardless of their order and of the case of their names, since NTFS stores names in upper case.
+    ///
+    pub fn with_extended_attributes(mut self, enabled: bool) -> Self {
+        self.extended_attributes = enabled;
+        self
+    }
+
+    /// Compares the src tree at dir against the image,
+    ///
+    /// Returns an error if the prefix does not exist in the image, or if a src cannot be read.
+    ///
+    pub fn verify(&self, dir: impl AsRef<Path>) -> Result<Report> {
+        let dir = dir.as_ref();
+        trace!("Verifying {:?} against {:?} in the image", dir, self.prefix);
+
+        let mut image = self.target.entries(&self.prefix)?;
+
+        let mut report = Report::default();
+        let mut srcs = vec![];
+        walk(dir, Path::new(""), &mut srcs)?;
+
+        for (relative_path, src) in srcs {
+            report.checked += 1;
+            match image.remove(&relative_path) {
+                Some(entry) => self
+                    .compare(&relative_path, &src, &entry, &mut report.differences)
+                    .map_err(source_error(&relative_path, Some(&src)))?,
+                None => report
+                    .differences
+                    .push(Difference::Missing { relative_path }),
+            }
+        }
+
+        report.differences.extend(
+            image
+                .into_keys()
+                .map(|relative_path| Difference::Extra { relative_path }),
+        );
+        report
+            .differences
+            .sort_by(|a, b| a.relative_path().cmp(b.relative_path()));
+        Ok(report)
+    }
+
+    /// Compares a src against the entry at relative_path in the image,
+    ///
+    fn compare(
+        &self,
+        relative_path: &Path,
+        src: &Path,
+        image: &FileMetadata,
+        differences: &mut Vec<Difference>,
+    ) -> Result<()> {
+        let path = self.prefix.join(relative_path);
+        let (mut metadata, file) = read_metadata(src)?;
+        if let Some(normalization) = self.normalization.as_ref() {
+            normalization.apply(&mut metadata);
+        }
+
+        let mut differs = |field: Field, src: String, image: String| {
+            differences.push(Difference::Metadata {
+                relative_path: relative_path.to_path_buf(),
+                field,
+                src,
+                image,
+            })
+        };
+
+        if metadata.attributes != image.attributes {
+            differs(
+                Field::Attributes,
+                format!("{:#x}", metadata.attributes),
+                format!("{:#x}", image.attributes),
+            );
+        }
+
+        if self.timestamps {
+            for (field, src, image) in [
+                (
+                    Field::CreationTime,
+                    metadata.creation_time,
+                    image.creation_time,
+                ),
+                (
+                    Field::LastWriteTime,
+                    metadata.last_write_time,
+                    image.last_write_time,
+                ),
+                (Field::ChangeTime, metadata.change_time, image.change_time),
+            ] {
+                if src != image {
+                    differs(field, src.to_string(), image.to_string());
+                }
+            }
+        }
+
+        if metadata.reparse_data != image.reparse_data {
+            differs(
+                Field::ReparseData,
+                format!("{} bytes", metadata.reparse_data.len()),
+                format!("{} bytes", image.reparse_data.len()),
+            );
+        }
+
+        if self.security_descriptors {
+            let (src, image) = (
+                sddl(&metadata.security_descriptor),
+                sddl(&image.security_descriptor),
+            );
+            if src != image {
+                differs(Field::SecurityDescriptor, src, image);
+            }
+        }
+
+        if self.extended_attributes {
+            let (src, image) = (
+                extended_attributes(&metadata.ea_buffer),
+                extended_attributes(&image.ea_buffer),
+            );
+            if src != image {
+                differs(Field::ExtendedAttributes, src, image);
+            }
+        }
+
+        if self.alternate_streams && !is_reparse_point(&metadata) {
+            self.compare_streams(relative_path, src, &path, &mut differs)?;
+        }
+
+        if let Some(file) =
+            file.filter(|_| !metadata.is_directory() && !is_reparse_point(&metadata))
+        {
+            if metadata.file_size != image.file_size
+                || !same_content(file, self.target.open_file(&path)?)?
+            {
+                differences.push(Difference::Content {
+                    relative_path: relative_path.to_path_buf(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Compares the alternate data streams of a src against the streams of the file at path in the image,
+    ///
+    fn compare_streams(
+        &self,
+        relative_path: &Path,
+        src: &Path,
+        path: &Path,
+        differs: &mut impl FnMut(Field, String, String),
+    ) -> Result<()> {
+        let mut image = self
+            .target
+            .alternate_streams(path)?
+            .into_iter()
+            .collect::<BTreeMap<_, _>>();
+
+        for name in crate::source::alternate_streams(src)? {
+            let stream = crate::source::open_alternate_stream(src, &name)?;
+            let len = stream.metadata()?.len();
+            match image.remove(&name) {
+                None => differs(
+                    Field::AlternateStream(name),
+                    format!("{len} bytes"),
+                    "missing".to_string(),
+                ),
+                Some(image_len) if image_len != len => differs(
+                    Field::AlternateStream(name),
+                    format!("{len} bytes"),
+                    format!("{image_len} bytes"),
+                ),
+                Some(_) => {
+                    if !same_content(stream, self.target.open_alternate_stream(path, &name)?)? {
+                        differs(
+                            Field::AlternateStream(name),
+                            "content".to_string(),
+                            "differs".to_string(),
+                        );
+                    }
+                }
+            }
+        }
+
+        for (name, len) in image {
+            trace!(
+                "Alternate stream {name} of {:?} is not in the src",
+                relative_path
+            );
+            differs(
+                Field::AlternateStream(name),
+                "missing".to_string(),
+                format!("{len} bytes"),
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Target<'_> {
+    /// Returns the metadata of the entries under prefix, by their path relative to prefix,
+    ///
+    /// Returns an error if prefix does not exist in the image.
+    ///
+    fn entries(&self, prefix: &Path) -> Result<BTreeMap<PathBuf, FileMetadata>> {
+        let not_found = || {
+            std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("{:?} not found in image", prefix),
+            )
+        };
+
+        let mut entries = BTreeMap::new();
+        match self {
+            Target::Recorded(recorded) => {
+                if !prefix.as_os_str().is_empty() && !recorded.files.contains_key(prefix) {
+                    return Err(not_found());
+                }
+                for (path, (metadata, _)) in recorded.files.iter() {
+                    if let Ok(relative_path) = path.strip_prefix(prefix) {
+                        if !relative_path.as_os_str().is_empty() {
+                            entries.insert(relative_path.to_path_buf(), (*metadata).clone());
+                        }
+                    }
+                }
+            }
+            Target::Mounted(root) => {
+                let dir = root.join(prefix);
+                if !dir.is_dir() {
+                    return Err(not_found());
+                }
+
+                let mut paths = vec![];
+                walk(&dir, Path::new(""), &mut paths)?;
+                for (relative_path, path) in paths {
+                    let (metadata, _) =
+                        read_metadata(&path).map_err(source_error(&relative_path, Some(&path)))?;
+                    entries.insert(relative_path, metadata);
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Returns a reader over the default data stream of the file at path in the image,
+    ///
+    fn open_file(&self, path: &Path) -> Result<Box<dyn Read + '_>> {
+        match self {
+            Target::Recorded(recorded) => Ok(Box::new(recorded.file(path)?.1)),
+            Target::Mounted(root) => Ok(Box::new(crate::source::open(&root.join(path))?)),
+        }
+    }
+
+    /// Returns the names and lengths of the alternate data streams of the file at path in the image,
+    ///
+    fn alternate_streams(&self, path: &Path) -> Result<Vec<(String, u64)>> {
+        match self {
+            Target::Recorded(recorded) => Ok(recorded
+                .streams
+                .iter()
+                .filter(|((p, _), _)| p == path)
+                .map(|((_, name), data)| (name.clone(), data.len() as u64))
+                .collect()),
+            Target::Mounted(root) => {
+                let path = root.join(path);
+                crate::source::alternate_streams(&path)?
+                    .into_iter()
+                    .map(|name| {
+                        let len = crate::source::open_alternate_stream(&path, &name)?
+                            .metadata()?
+                            .len();
+                        Ok((name, len))
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Returns a reader over the alternate data stream w/ name of the file at path in the image,
+    ///
+    fn open_alternate_stream(&self, path: &Path, name: &str) -> Result<Box<dyn Read + '_>> {
+        match self {
+            Target::Recorded(recorded) => recorded
+                .streams
+                .get(&(path.to_path_buf(), name.to_string()))
+                .map(|data| Box::new(*data) as Box<dyn Read>)
+                .ok_or_else(|| {
+                    std::io::Error::new(
+                        ErrorKind::NotFound,
+                        format!("{:?} does not have a stream named {name}", path),
+                    )
+                }),
+            Target::Mounted(root) => Ok(Box::new(crate::source::open_alternate_stream(
+                &root.join(path),
+                name,
+            )?)),
+        }
+    }
+}
+
+impl<'a> RecordedFiles<'a> {
+    /// Returns the files of a recorded image, applying its entries in order,
+    ///
+    fn new(image: &'a RecordedImage) -> Self {
+        let mut files = BTreeMap::new();
+        let mut streams = BTreeMap::<(PathBuf, String), &[u8]>::new();
+        for entry in image.entries.iter() {
+            match entry {
+                RecordedEntry::File {
+                    path,
+                    metadata,
+                    data,
+                } => {
+                    files.insert(path.clone(), (metadata, data.as_slice()));
+                    streams.retain(|(p, _), _| p != path);
+                }
+                RecordedEntry::AlternateStream { path, data, .. } => {
+                    // Streams are recorded as `<path>:<name>`
+                    if let Some((file, name)) = path.to_string_lossy().rsplit_once(':') {
+                        streams.insert((PathBuf::from(file), name.to_string()), data);
+                    }
+                }
+                RecordedEntry::HardLink { path, existing } => {
+                    if let Some(file) = files.get(existing).copied() {
+                        files.insert(path.clone(), file);
+                    }
+                    let linked = streams
+                        .iter()
+                        .filter(|((p, _), _)| p == existing)
+                        .map(|((_, name), data)| ((path.clone(), name.clone()), *data))
+                        .collect::<Vec<_>>();
+                    streams.extend(linked);
+                }
+                RecordedEntry::Delete { path } => {
+                    files.retain(|p, _| !p.starts_with(path));
+                    streams.retain(|(p, _), _| !p.starts_with(path));
+                }
+            }
+        }
+
+        Self { files, streams }
+    }
+
+    /// Returns the metadata and data of the file at path,
+    ///
+    fn file(&self, path: &Path) -> Result<(&'a FileMetadata, &'a [u8])> {
+        self.files.get(path).copied().ok_or_else(|| {
+            std::io::Error::new(ErrorKind::NotFound, format!("{:?} was not recorded", path))
+        })
+    }
+}
+
+/// Reads the metadata of a src, and returns the opened src unless it is a symlink,
+///
+fn read_metadata(src: &Path) -> Result<(FileMetadata, Option<File>)> {
+    match crate::source::symlink_metadata(src)? {
+        Some(metadata) => Ok((metadata, None)),
+        None => {
+            let file = crate::source::open(src)?;
+            Ok((crate::source::metadata(&file)?, Some(file)))
+        }
+    }
+}
+
+/// Formats a security descriptor for comparison, as its SDDL string if it can be decoded,
+///
+fn sddl(security_descriptor: &[u8]) -> String {
+    if security_descriptor.is_empty() {
+        return "none".to_string();
+    }
+
+    SecurityDescriptor::from_bytes(security_descriptor)
+        .map(|sd| sd.to_sddl())
+        .unwrap_or_else(|_| format!("{} bytes", security_descriptor.len()))
+}
+
+/// Formats an extended attributes buffer for comparison, as the sorted names and values of the attributes if it can be decoded,
+///
+fn extended_attributes(ea_buffer: &[u8]) -> String {
+    if ea_buffer.is_empty() {
+        return "none".to_string();
+    }
+
+    match crate::ea::decode(ea_buffer) {
+        Ok(attributes) => {
+            let mut attributes = attributes
+                .iter()
+                .map(|ea| {
+                    format!(
+                        "{}={}",
+                        ea.name().to_ascii_uppercase(),
+                        ea.value()
+                            .iter()
+                            .map(|b| format!("{b:02x}"))
+                            .collect::<String>()
+                    )
+                })
+                .collect::<Vec<_>>();
+            attributes.sort();
+            attributes.join(", ")
+        }
+        Err(_) => format!("{} bytes", ea_buffer.len()),
+    }
+}
+
+/// Walks the directory at src, adding each entry before its children w/ its path relative to the root of the walk,
+///
+/// Reparse points are not followed. Entries of a directory are sorted by name.
+///
+fn walk(src: &Path, relative_path: &Path, srcs: &mut Vec<(PathBuf, PathBuf)>) -> Result<()> {
+    let mut entries = std::fs::read_dir(src)
+        .and_then(|entries| entries.collect::<Result<Vec<_>>>())
+        .map_err(source_error(relative_path, Some(src)))?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let relative_path = relative_path.join(entry.file_name());
+        let src = entry.path();
+        let metadata = entry
+            .metadata()
+            .map_err(source_error(&relative_path, Some(&src)))?;
+        srcs.push((relative_path.clone(), src.clone()));
+
+        if metadata.is_dir() && !metadata.is_symlink() && !is_reparse_dir(&src)? {
+            walk(&src, &relative_path, srcs)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns true if the metadata is of a reparse point,
+///
+fn is_reparse_point(metadata: &FileMetadata) -> bool {
+    metadata.attributes & FILE_ATTRIBUTE_REPARSE_POINT.0 != 0
+}
+
+/// Returns true if the directory at src is a reparse point, ex. a junction,
+///
+#[cfg(windows)]
+fn is_reparse_dir(src: &Path) -> Result<bool> {
+    use std::os::windows::fs::MetadataExt;
+
+    Ok(src.symlink_metadata()?.file_attributes() & FILE_ATTRIBUTE_REPARSE_POINT.0 != 0)
+}
+
+/// Returns true if the directory at src is a reparse point, symlinks are the only reparse points on this platform,
+///
+#[cfg(unix)]
+fn is_reparse_dir(_: &Path) -> Result<bool> {
+    Ok(false)
+}
+
+/// Returns true if both readers return the same data,
+///
+fn same_content(mut a: impl Read, mut b: impl Read) -> Result<bool> {
+    let (mut buf_a, mut buf_b) = (vec![0; CHUNK_LEN], vec![0; CHUNK_LEN]);
+    loop {
+        let len_a = read_full(&mut a, &mut buf_a)?;
+        let len_b = read_full(&mut b, &mut buf_b)?;
+        if buf_a[..len_a] != buf_b[..len_b] {
+            return Ok(false);
+        }
+        if len_a == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Reads until buf is full or the reader returns no more data, returning the number of bytes read,
+///
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(read) => len += read,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use super::Difference;
+    use super::Field;
+    use super::Verifier;
+    use crate::api::Image;
+    use crate::api::Object;
+    use crate::api::Overrides;
+    use crate::backend::RecordingBackend;
+    use crate::ea::ExtendedAttribute;
+    #[cfg(test)]
+    use crate::scratch::ScratchDir;
+    use crate::security::SecurityDescriptor;
+    use std::collections::BTreeSet;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_verify() {
+        let scratch = ScratchDir::new("verify-test");
+        let root = scratch.path();
+        let tree = root.join("tree");
+        std::fs::create_dir_all(tree.join("dir")).unwrap();
+        std::fs::write(tree.join("a.txt"), b"a").unwrap();
+        std::fs::write(tree.join("dir/b.txt"), b"b").unwrap();
+        std::fs::write(tree.join("dir/c.txt"), b"c").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("a.txt", tree.join("link")).unwrap();
+
+        let mut image = Image::with_backend(root, "test.cim", RecordingBackend::default());
+        image.create(None).unwrap();
+        let mut o = Object::with_base_dir(&tree, root, "").unwrap();
+        let ancestors = o.resolve_relative_path(true).unwrap();
+        let mut objects = o.expand(None).unwrap();
+        objects.insert(0, o);
+        image.build(objects, ancestors).unwrap();
+        image.commit().unwrap();
+
+        let recorded = image.backend().last_image().unwrap();
+        let verifier = Verifier::recorded(recorded).with_prefix("tree");
+        let report = verifier.verify(&tree).unwrap();
+        assert!(report.is_ok(), "{:?}", report.differences);
+        assert_eq!(if cfg!(unix) { 5 } else { 4 }, report.checked);
+
+        // Same length, different content
+        std::fs::write(tree.join("a.txt"), b"x").unwrap();
+        std::fs::remove_file(tree.join("dir/b.txt")).unwrap();
+        std::fs::write(tree.join("dir/d.txt"), b"d").unwrap();
+        let mut permissions = std::fs::metadata(tree.join("dir/c.txt"))
+            .unwrap()
+            .permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(tree.join("dir/c.txt"), permissions).unwrap();
+
+        let report = verifier
+            .with_timestamps(false)
+            .with_alternate_streams(false)
+            .verify(&tree)
+            .unwrap();
+        assert_eq!(
+            vec![
+                Difference::Content {
+                    relative_path: PathBuf::from("a.txt")
+                },
+                Difference::Extra {
+                    relative_path: PathBuf::from("dir/b.txt")
+                },
+                Difference::Metadata {
+                    relative_path: PathBuf::from("dir/c.txt"),
+                    field: Field::Attributes,
+                    src: "0x1".to_string(),
+                    image: "0x80".to_string(),
+                },
+                Difference::Missing {
+                    relative_path: PathBuf::from("dir/d.txt")
+                },
+            ],
+            report.differences
+        );
+
+        // A different prefix is missing from the image
+        assert!(Verifier::recorded(recorded)
+            .with_prefix("other")
+            .verify(&tree)
+            .is_err());
+    }
+
+    #[test]
+    fn test_verify_security_descriptors_and_extended_attributes() {
+        let scratch = ScratchDir::new("verify-test-sd-ea");
+        let root = scratch.path();
+        let tree = root.join("tree");
+        std::fs::create_dir_all(&tree).unwrap();
+        std::fs::write(tree.join("a.txt"), b"a").unwrap();
+
+        let security_descriptor = SecurityDescriptor::from_sddl("O:BAG:BAD:(A;;FA;;;BA)").unwrap();
+        let overrides = Overrides {
+            security_descriptor: Some(security_descriptor.clone()),
+            extended_attributes: Some(vec![ExtendedAttribute::new("app.version", "1").unwrap()]),
+            ..Default::default()
+        };
+
+        let mut image = Image::with_backend(root, "test.cim", RecordingBackend::default());
+        image.create(None).unwrap();
+        image
+            .create_file_with_overrides(
+                "a.txt".as_ref(),
+                tree.join("a.txt").as_os_str(),
+                &overrides,
+            )
+            .unwrap();
+        image.commit().unwrap();
+
+        // The srcs have neither on this platform, or different ones
+        let recorded = image.backend().last_image().unwrap();
+        let report = Verifier::recorded(recorded)
+            .with_timestamps(false)
+            .verify(&tree)
+            .unwrap();
+        let fields = report
+            .differences
+            .iter()
+            .map(|d| match d {
+                Difference::Metadata { field, image, .. } => (field.clone(), image.clone()),
+                d => panic!("unexpected difference {d}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (Field::SecurityDescriptor, security_descriptor.to_sddl()),
+                (Field::ExtendedAttributes, "APP.VERSION=31".to_string()),
+            ],
+            fields
+        );
+
+        let report = Verifier::recorded(recorded)
+            .with_timestamps(false)
+            .with_security_descriptors(false)
+            .with_extended_attributes(false)
+            .verify(&tree)
+            .unwrap();
+        assert!(report.is_ok(), "{:?}", report.differences);
+    }
+
+    #[test]
+    fn test_verify_recorded_links_and_deletes() {
+        let scratch = ScratchDir::new("verify-test-recorded");
+        let root = scratch.path();
+        let tree = root.join("tree");
+        std::fs::create_dir_all(&tree).unwrap();
+        std::fs::write(tree.join("a.txt"), b"a").unwrap();
+        std::fs::write(tree.join("b.txt"), b"a").unwrap();
+
+        let mut image = Image::with_backend(root, "test.cim", RecordingBackend::default());
+        image.create(None).unwrap();
+        let src = tree.join("a.txt");
+        image
+            .create_file("a.txt".as_ref(), src.as_os_str())
+            .unwrap();
+        image
+            .create_file("c.txt".as_ref(), src.as_os_str())
+            .unwrap();
+        image
+            .create_hard_link("a.txt".as_ref(), "b.txt".as_ref())
+            .unwrap();
+        image.delete_path("c.txt".as_ref()).unwrap();
+        image.commit().unwrap();
+
+        let recorded = image.backend().last_image().unwrap();
+        let report = Verifier::recorded(recorded)
+            .with_timestamps(false)
+            .verify(&tree)
+            .unwrap();
+        assert!(report.is_ok(), "{:?}", report.differences);
+        assert_eq!(2, report.checked);
+    }
+
+    #[test]
+    fn test_verify_mounted() {
+        let scratch = ScratchDir::new("verify-test-mounted");
+        let root = scratch.path();
+        let tree = root.join("tree");
+        std::fs::create_dir_all(tree.join("dir")).unwrap();
+        std::fs::write(tree.join("a.txt"), b"a").unwrap();
+        std::fs::write(tree.join("dir/b.txt"), b"b").unwrap();
+
+        // A copy of the tree stands in for the volume of a mounted image
+        let volume = root.join("volume");
+        std::fs::create_dir_all(volume.join("app/dir")).unwrap();
+        std::fs::copy(tree.join("a.txt"), volume.join("app/a.txt")).unwrap();
+        std::fs::copy(tree.join("dir/b.txt"), volume.join("app/dir/b.txt")).unwrap();
+
+        let verifier = Verifier::mounted(&volume)
+            .with_prefix("app")
+            .with_timestamps(false);
+        let report = verifier.verify(&tree).unwrap();
+        assert!(report.is_ok(), "{:?}", report.differences);
+        assert_eq!(3, report.checked);
+
+        std::fs::write(volume.join("app/dir/b.txt"), b"x").unwrap();
+        std::fs::write(volume.join("app/c.txt"), b"c").unwrap();
+        assert_eq!(
+            vec![
+                Difference::Extra {
+                    relative_path: PathBuf::from("c.txt")
+                },
+                Difference::Content {
+                    relative_path: PathBuf::from("dir/b.txt")
+                },
+            ],
+            verifier.verify(&tree).unwrap().differences
+        );
+
+        assert!(Verifier::mounted(&volume)
+            .with_prefix("other")
+            .verify(&tree)
+            .is_err());
+    }
+}